@@ -0,0 +1,201 @@
+//! Allocation of the internal buffers of the encoder.
+//!
+//! The hash chains, input window, lz77 buffer and output buffer of the encoder are allocated
+//! through a `StateAllocator`. Without the `allocator` feature this is simply the global
+//! allocator. With the `allocator` feature (which requires a nightly compiler, as it depends on
+//! the unstable `allocator_api`), any type implementing `Allocator`, such as an arena or a
+//! bump allocator, can be used instead, so all the memory used by an encoder can be kept within
+//! a pre-reserved region.
+#[cfg(feature = "allocator")]
+use std::alloc::{AllocError, Allocator, Global, Layout};
+#[cfg(feature = "allocator")]
+use std::fmt;
+#[cfg(feature = "allocator")]
+use std::mem;
+#[cfg(feature = "allocator")]
+use std::ptr::NonNull;
+#[cfg(feature = "allocator")]
+use std::sync::Arc;
+
+/// The allocator used for the internal buffers of an encoder.
+///
+/// The default value, and [`StateAllocator::global`](#method.global), use the global
+/// allocator. A custom allocator can be used with
+/// [`StateAllocator::new`](#method.new). Cloning a `StateAllocator` gives a handle to the same
+/// allocator, so it can be shared between several encoders.
+#[cfg(feature = "allocator")]
+#[derive(Clone, Default)]
+pub struct StateAllocator {
+    inner: Option<Arc<dyn Allocator + Send + Sync>>,
+}
+
+#[cfg(not(feature = "allocator"))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StateAllocator;
+
+/// A vector allocated with a `StateAllocator`.
+#[cfg(feature = "allocator")]
+pub type StateVec<T> = Vec<T, StateAllocator>;
+#[cfg(not(feature = "allocator"))]
+pub type StateVec<T> = Vec<T>;
+
+impl StateAllocator {
+    /// A handle using the global allocator.
+    #[cfg(feature = "allocator")]
+    pub fn global() -> StateAllocator {
+        StateAllocator { inner: None }
+    }
+
+    /// A handle using the global allocator.
+    #[cfg(not(feature = "allocator"))]
+    pub fn global() -> StateAllocator {
+        StateAllocator
+    }
+
+    /// Create a handle allocating the internal buffers of an encoder with `allocator`.
+    ///
+    /// The allocator has to be `Send` and `Sync`, as the encoders can be sent between threads.
+    #[cfg(feature = "allocator")]
+    pub fn new<A: Allocator + Send + Sync + 'static>(allocator: A) -> StateAllocator {
+        StateAllocator {
+            inner: Some(Arc::new(allocator)),
+        }
+    }
+
+    /// Create an empty vector with the specified capacity using this allocator.
+    #[cfg(feature = "allocator")]
+    pub(crate) fn vec_with_capacity<T>(&self, capacity: usize) -> StateVec<T> {
+        Vec::with_capacity_in(capacity, self.clone())
+    }
+
+    /// Create an empty vector with the specified capacity using this allocator.
+    #[cfg(not(feature = "allocator"))]
+    pub(crate) fn vec_with_capacity<T>(&self, capacity: usize) -> StateVec<T> {
+        Vec::with_capacity(capacity)
+    }
+}
+
+/// Use `vec` as the buffer of an encoder without copying it.
+#[cfg(not(feature = "allocator"))]
+pub fn vec_into_state(vec: Vec<u8>) -> StateVec<u8> {
+    vec
+}
+
+/// Use `vec` as the buffer of an encoder without copying it.
+#[cfg(feature = "allocator")]
+pub fn vec_into_state(vec: Vec<u8>) -> StateVec<u8> {
+    let mut vec = mem::ManuallyDrop::new(vec);
+    // Safety: The global handle allocates with `Global`, which is what `Vec<u8>` uses.
+    unsafe {
+        Vec::from_raw_parts_in(
+            vec.as_mut_ptr(),
+            vec.len(),
+            vec.capacity(),
+            StateAllocator::global(),
+        )
+    }
+}
+
+/// Take the buffer of an encoder as a `Vec<u8>`.
+///
+/// This only copies the data if the buffer was allocated with a custom allocator.
+#[cfg(not(feature = "allocator"))]
+pub fn state_into_vec(vec: StateVec<u8>) -> Vec<u8> {
+    vec
+}
+
+/// Take the buffer of an encoder as a `Vec<u8>`.
+///
+/// This only copies the data if the buffer was allocated with a custom allocator.
+#[cfg(feature = "allocator")]
+pub fn state_into_vec(vec: StateVec<u8>) -> Vec<u8> {
+    if vec.allocator().inner.is_some() {
+        return vec.to_vec();
+    }
+    let mut vec = mem::ManuallyDrop::new(vec);
+    // Safety: The buffer was allocated with `Global`, which is what `Vec<u8>` uses.
+    unsafe { Vec::from_raw_parts(vec.as_mut_ptr(), vec.len(), vec.capacity()) }
+}
+
+#[cfg(feature = "allocator")]
+impl fmt::Debug for StateAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StateAllocator")
+            .field("custom", &self.inner.is_some())
+            .finish()
+    }
+}
+
+#[cfg(feature = "allocator")]
+unsafe impl Allocator for StateAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        match self.inner {
+            Some(ref allocator) => allocator.allocate(layout),
+            None => Global.allocate(layout),
+        }
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        match self.inner {
+            Some(ref allocator) => allocator.allocate_zeroed(layout),
+            None => Global.allocate_zeroed(layout),
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // Memory is only deallocated by the same allocator (or a clone of the handle) it was
+        // allocated with, as required by the caller.
+        match self.inner {
+            Some(ref allocator) => unsafe { allocator.deallocate(ptr, layout) },
+            None => unsafe { Global.deallocate(ptr, layout) },
+        }
+    }
+}
+
+#[cfg(all(test, feature = "allocator"))]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::test_utils::{decompress_to_end, get_test_data};
+    use crate::write::DeflateEncoder;
+    use crate::CompressionOptions;
+    use std::io::Write;
+
+    /// An allocator counting the number of bytes currently allocated.
+    #[derive(Default)]
+    struct CountingAllocator {
+        allocated: Arc<AtomicUsize>,
+    }
+
+    unsafe impl Allocator for CountingAllocator {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.allocated.fetch_add(layout.size(), Ordering::SeqCst);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.allocated.fetch_sub(layout.size(), Ordering::SeqCst);
+            unsafe { Global.deallocate(ptr, layout) }
+        }
+    }
+
+    #[test]
+    fn custom_allocator() {
+        let data = get_test_data();
+        let allocator = CountingAllocator::default();
+        let allocated = allocator.allocated.clone();
+
+        let mut encoder = DeflateEncoder::new_in(
+            Vec::new(),
+            CompressionOptions::default(),
+            StateAllocator::new(allocator),
+        );
+        // The hash chains alone take up 256 KiB.
+        assert!(allocated.load(Ordering::SeqCst) > 256 * 1024);
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(decompress_to_end(&compressed) == data);
+        assert_eq!(allocated.load(Ordering::SeqCst), 0);
+    }
+}
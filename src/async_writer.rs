@@ -0,0 +1,347 @@
+//! Encoders implementing the `AsyncWrite` trait from tokio, enabled by the `tokio` feature.
+use std::cmp;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::AsyncWrite;
+
+use crate::compression_options::CompressionOptions;
+use crate::error::Error;
+use crate::writer::{Encoder, Format};
+
+/// The largest amount of input compressed in one call to `poll_write`, so a single poll doesn't
+/// block the task for long.
+const INPUT_CHUNK_SIZE: usize = 32 * 1024;
+
+/// An encoder implementing tokio's [`AsyncWrite`] interface, where the container format is chosen
+/// at runtime.
+///
+/// The input is compressed synchronously in chunks of at most 32 KiB per call to `poll_write`,
+/// and the compressed data is kept in a buffer until the wrapped writer accepts it. Each write
+/// first waits for the compressed data of the previous ones to be written, so the buffer stays
+/// small. `poll_flush` does a sync flush like `flush()` on the synchronous encoders, and
+/// `poll_shutdown` finishes the stream by writing the last block and the trailer before shutting
+/// down the wrapped writer.
+///
+/// # Examples
+///
+/// ```rust
+/// # async fn compress() -> std::io::Result<Vec<u8>> {
+/// use tokio::io::AsyncWriteExt;
+///
+/// use deflate::async_write::AsyncEncoder;
+/// use deflate::write::Format;
+/// use deflate::Compression;
+///
+/// let mut encoder = AsyncEncoder::new(Vec::new(), Format::Zlib, Compression::Default);
+/// encoder.write_all(b"This is some test data").await?;
+/// encoder.shutdown().await?;
+/// let compressed_data = encoder.into_inner();
+/// # Ok(compressed_data)
+/// # }
+/// ```
+/// [`AsyncWrite`]: https://docs.rs/tokio/1/tokio/io/trait.AsyncWrite.html
+pub struct AsyncEncoder<W: AsyncWrite + Unpin> {
+    inner: W,
+    /// Encoder writing the compressed data to a buffer until it's written to `inner`.
+    encoder: Encoder<Vec<u8>>,
+    /// How much of the compressed data in the buffer of the encoder has been written.
+    output_pos: usize,
+    /// Whether a sync flush has been done and its output not fully written to `inner` yet.
+    flushing: bool,
+    finished: bool,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncEncoder<W> {
+    /// Create a new encoder writing data in the format `format` to `writer`, using the provided
+    /// compression options.
+    ///
+    /// # Panics
+    /// Panics if `format` is `Format::Custom`, which has no framing of its own.
+    pub fn new<O: Into<CompressionOptions>>(
+        writer: W,
+        format: Format,
+        options: O,
+    ) -> AsyncEncoder<W> {
+        AsyncEncoder::try_new(writer, format, options).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like [`new`](#method.new), but returns an error rather than panicking if `format` is
+    /// `Format::Custom`.
+    pub fn try_new<O: Into<CompressionOptions>>(
+        writer: W,
+        format: Format,
+        options: O,
+    ) -> Result<AsyncEncoder<W>, Error> {
+        Ok(AsyncEncoder {
+            inner: writer,
+            encoder: Encoder::try_new(Vec::new(), format, options)?,
+            output_pos: 0,
+            flushing: false,
+            finished: false,
+        })
+    }
+
+    /// The container format this encoder produces.
+    pub fn format(&self) -> Format {
+        self.encoder.format()
+    }
+
+    /// Return the total number of bytes of input consumed by the encoder.
+    pub fn total_in(&self) -> u64 {
+        self.encoder.total_in()
+    }
+
+    /// Get a reference to the wrapped writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the wrapped writer.
+    ///
+    /// Writing directly to the writer will corrupt the compressed stream unless the encoder has
+    /// been flushed or shut down first.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Consume the encoder, returning the wrapped writer.
+    ///
+    /// Any compressed data that hasn't been written to the writer yet is lost, so the encoder
+    /// should be shut down first.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Write the buffered compressed data to the wrapped writer.
+    fn poll_output(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let output = self.encoder.get_mut();
+        while self.output_pos < output.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &output[self.output_pos..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.output_pos += written;
+        }
+        output.clear();
+        self.output_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncEncoder<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_output(cx))?;
+        // Any flush in progress has been written out along with the rest of the output.
+        this.flushing = false;
+        let len = cmp::min(buf.len(), INPUT_CHUNK_SIZE);
+        this.encoder.write_all(&buf[..len])?;
+        Poll::Ready(Ok(len))
+    }
+
+    /// Perform a sync flush, write all the compressed data and flush the wrapped writer.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if !this.flushing {
+            this.encoder.flush()?;
+            this.flushing = true;
+        }
+        ready!(this.poll_output(cx))?;
+        this.flushing = false;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    /// Finish the stream, write all the compressed data and shut down the wrapped writer.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if !this.finished {
+            this.encoder.try_finish()?;
+            this.finished = true;
+        }
+        ready!(this.poll_output(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+macro_rules! async_encoder {
+    ($(#[$attr:meta])* $name:ident, $format:expr) => {
+        $(#[$attr])*
+        pub struct $name<W: AsyncWrite + Unpin>(AsyncEncoder<W>);
+
+        impl<W: AsyncWrite + Unpin> $name<W> {
+            /// Create a new encoder writing the compressed data to `writer`, using the provided
+            /// compression options.
+            pub fn new<O: Into<CompressionOptions>>(writer: W, options: O) -> $name<W> {
+                $name(AsyncEncoder::new(writer, $format, options))
+            }
+
+            /// Return the total number of bytes of input consumed by the encoder.
+            pub fn total_in(&self) -> u64 {
+                self.0.total_in()
+            }
+
+            /// Get a reference to the wrapped writer.
+            pub fn get_ref(&self) -> &W {
+                self.0.get_ref()
+            }
+
+            /// Get a mutable reference to the wrapped writer.
+            ///
+            /// Writing directly to the writer will corrupt the compressed stream unless the
+            /// encoder has been flushed or shut down first.
+            pub fn get_mut(&mut self) -> &mut W {
+                self.0.get_mut()
+            }
+
+            /// Consume the encoder, returning the wrapped writer.
+            ///
+            /// Any compressed data that hasn't been written to the writer yet is lost, so the
+            /// encoder should be shut down first.
+            pub fn into_inner(self) -> W {
+                self.0.into_inner()
+            }
+        }
+
+        impl<W: AsyncWrite + Unpin> AsyncWrite for $name<W> {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.0).poll_write(cx, buf)
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.0).poll_flush(cx)
+            }
+
+            fn poll_shutdown(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.0).poll_shutdown(cx)
+            }
+        }
+    };
+}
+
+async_encoder!(
+    /// A raw DEFLATE encoder implementing tokio's `AsyncWrite` interface.
+    ///
+    /// See [`AsyncEncoder`](struct.AsyncEncoder.html) for how the input is compressed and
+    /// written.
+    AsyncDeflateEncoder,
+    Format::Raw
+);
+
+async_encoder!(
+    /// A zlib encoder implementing tokio's `AsyncWrite` interface.
+    ///
+    /// See [`AsyncEncoder`](struct.AsyncEncoder.html) for how the input is compressed and
+    /// written.
+    AsyncZlibEncoder,
+    Format::Zlib
+);
+
+#[cfg(feature = "gzip")]
+async_encoder!(
+    /// A gzip encoder implementing tokio's `AsyncWrite` interface, writing a gzip header with
+    /// blank fields.
+    ///
+    /// See [`AsyncEncoder`](struct.AsyncEncoder.html) for how the input is compressed and
+    /// written.
+    AsyncGzEncoder,
+    Format::Gzip
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, decompress_zlib, get_test_data};
+    use tokio::io::AsyncWriteExt;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// A writer that only accepts a few bytes at a time, and is not ready every other poll.
+    struct SlowWriter {
+        data: Vec<u8>,
+        ready: bool,
+        flushes: usize,
+    }
+
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let len = cmp::min(buf.len(), 7);
+            self.data.extend_from_slice(&buf[..len]);
+            Poll::Ready(Ok(len))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.flushes += 1;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn async_deflate() {
+        let data = get_test_data();
+        let compressed = block_on(async {
+            let mut encoder = AsyncDeflateEncoder::new(Vec::new(), CompressionOptions::default());
+            encoder.write_all(&data).await.unwrap();
+            encoder.shutdown().await.unwrap();
+            assert_eq!(encoder.total_in(), data.len() as u64);
+            encoder.into_inner()
+        });
+        assert!(decompress_to_end(&compressed) == data);
+    }
+
+    #[test]
+    fn async_slow_writer() {
+        let data = get_test_data();
+        let writer = SlowWriter {
+            data: Vec::new(),
+            ready: false,
+            flushes: 0,
+        };
+        let writer = block_on(async {
+            let mut encoder = AsyncZlibEncoder::new(writer, CompressionOptions::fast());
+            let (first, second) = data.split_at(data.len() / 2);
+            encoder.write_all(first).await.unwrap();
+            encoder.flush().await.unwrap();
+            // Everything written so far is output by the flush, ending with an empty stored
+            // block.
+            assert!(encoder.get_ref().data.ends_with(&[0, 0, 0xFF, 0xFF]));
+            assert_eq!(encoder.get_ref().flushes, 1);
+            encoder.write_all(second).await.unwrap();
+            encoder.shutdown().await.unwrap();
+            assert!(encoder.write_all(b"more").await.is_err());
+            encoder.into_inner()
+        });
+        assert!(decompress_zlib(&writer.data) == data);
+    }
+}
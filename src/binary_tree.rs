@@ -0,0 +1,359 @@
+//! Binary trees of the positions in the window, like the `bt4` match finder in LZMA.
+//!
+//! The positions with the same hash of their first four bytes are kept in a binary search tree,
+//! ordered by the data following them, with the most recent position at the root. A new position
+//! is inserted by splitting the tree in two around it, which leaves the positions whose data sorts
+//! closest to it on the two paths from the new root going towards it. The longest match is
+//! always found on these paths, so unlike hash chains, the search doesn't slow down on highly
+//! redundant data where the chains get long and full of short matches.
+use std::cmp;
+
+use crate::allocator::{StateAllocator, StateVec};
+use crate::chained_hash_table::{
+    rebase_array, INITIAL_BASE, MAX_BASE, RESET_STRIDE, WINDOW_MASK, WINDOW_SIZE,
+};
+use crate::huffman_table::MAX_MATCH;
+use crate::matching::get_match_length;
+
+/// The number of bits of the hash of the first four bytes, used to pick the tree.
+const HASH4_BITS: u32 = 16;
+/// The number of bits of the hash of the first three bytes, used to find matches of three bytes.
+const HASH3_BITS: u32 = 16;
+/// The maximum number of nodes visited when inserting a position. Any older positions below the
+/// point the insertion stops at are dropped from the tree.
+const MAX_INSERT_DEPTH: usize = 256;
+/// The value of `last_position` when there is no position whose three byte match candidate is
+/// known.
+const NO_POSITION: usize = usize::MAX;
+
+/// The index in the tree of the child of a node holding the positions sorting before it.
+const SMALLER: usize = 0;
+/// The index in the tree of the child of a node holding the positions sorting after it.
+const LARGER: usize = 1;
+
+/// Multiplicative hash of the first `bytes` bytes of `value` to `bits` bits.
+#[inline]
+fn hash(value: u32, bytes: u32, bits: u32) -> usize {
+    let masked = value << (32 - bytes * 8);
+    (masked.wrapping_mul(0x9E37_79B1) >> (32 - bits)) as usize
+}
+
+/// The first four bytes at `position` in `data` as a little-endian number.
+#[inline]
+fn read_u32(data: &[u8], position: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[position..position + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+/// Allocate a table of `size` entries that don't refer to any positions.
+fn create_table(allocator: &StateAllocator, size: usize) -> StateVec<u32> {
+    let mut table = allocator.vec_with_capacity(size);
+    table.resize(size, 0);
+    table
+}
+
+/// Binary trees of the positions in the window, one for each hash of the first four bytes.
+///
+/// Like in `ChainedHashTable`, positions are stored as `base + position`, so sliding the window
+/// only changes `base`, and stored values below `base` don't refer to anything.
+#[derive(Clone)]
+pub struct BinaryTree {
+    /// The root of the tree for each hash of four bytes.
+    head: StateVec<u32>,
+    /// The last position added for each hash of three bytes.
+    head3: StateVec<u32>,
+    /// The two children of each node, indexed by the position modulo the window size.
+    tree: StateVec<u32>,
+    /// The previous position with the same hash of three bytes as `last_position`.
+    short_candidate: u32,
+    /// The position `last_position` replaced in the tree when it was added, if any.
+    replaced: u32,
+    /// The last position added.
+    last_position: usize,
+    /// The maximum distance of a match.
+    window_size: usize,
+    /// The value added to the stored positions.
+    base: u32,
+}
+
+impl BinaryTree {
+    #[cfg(test)]
+    pub fn new() -> BinaryTree {
+        BinaryTree::new_in(&StateAllocator::global())
+    }
+
+    /// Create empty trees, allocating them with `allocator`.
+    pub fn new_in(allocator: &StateAllocator) -> BinaryTree {
+        BinaryTree {
+            head: create_table(allocator, 1 << HASH4_BITS),
+            head3: create_table(allocator, 1 << HASH3_BITS),
+            tree: create_table(allocator, WINDOW_SIZE * 2),
+            short_candidate: 0,
+            replaced: 0,
+            last_position: NO_POSITION,
+            window_size: WINDOW_SIZE,
+            base: INITIAL_BASE,
+        }
+    }
+
+    /// The number of bytes following a position that have to be available before the position
+    /// can be added.
+    #[inline]
+    pub fn lookahead(&self) -> usize {
+        3
+    }
+
+    /// The maximum distance of a match.
+    #[inline]
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    /// Limit the distance of matches to `window_size`, which has to be a power of two no larger
+    /// than `WINDOW_SIZE`.
+    pub fn set_window_size(&mut self, window_size: usize) {
+        debug_assert!(window_size.is_power_of_two() && window_size <= WINDOW_SIZE);
+        self.window_size = window_size;
+    }
+
+    /// The number of bytes of heap memory used by the trees.
+    pub fn heap_size(&self) -> usize {
+        (self.head.capacity() + self.head3.capacity() + self.tree.capacity())
+            * std::mem::size_of::<u32>()
+    }
+
+    /// Convert the stored value `stored` to a position that can be matched against from
+    /// `position`, if it is in the window.
+    ///
+    /// As the tree nodes are indexed modulo `WINDOW_SIZE`, the node of a position `WINDOW_SIZE`
+    /// bytes back has been reused by `position` itself, so matches at this distance aren't used
+    /// even with the largest window.
+    #[inline]
+    fn candidate(&self, stored: u32, position: usize) -> Option<usize> {
+        if stored < self.base {
+            return None;
+        }
+        let candidate = (stored - self.base) as usize;
+        if candidate < position
+            && position - candidate <= self.window_size
+            && position - candidate < WINDOW_SIZE
+        {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    /// The index in `tree` of the child `side` of the node of `position`.
+    #[inline]
+    fn child(position: usize, side: usize) -> usize {
+        ((position & WINDOW_MASK) << 1) + side
+    }
+
+    /// Insert `position`, which has to be after all the positions already added, making it the
+    /// root of its tree.
+    ///
+    /// `data` has to hold at least four bytes from `position`. The data is compared up to the
+    /// maximum match length, or the end of `data` if that comes first.
+    pub fn add(&mut self, data: &[u8], position: usize) {
+        let stored = self.base + position as u32;
+        let value = read_u32(data, position);
+
+        let hash3 = hash(value, 3, HASH3_BITS);
+        self.short_candidate = self.head3[hash3];
+        self.head3[hash3] = stored;
+        self.last_position = position;
+        self.replaced = 0;
+
+        let hash4 = hash(value, 4, HASH4_BITS);
+        let mut current = self.head[hash4];
+        self.head[hash4] = stored;
+
+        let length_limit = cmp::min(data.len() - position, usize::from(MAX_MATCH));
+        // The slots the next nodes found sorting before and after the new position go into.
+        let mut smaller_slot = BinaryTree::child(position, SMALLER);
+        let mut larger_slot = BinaryTree::child(position, LARGER);
+        for _ in 0..MAX_INSERT_DEPTH {
+            let candidate = match self.candidate(current, position) {
+                Some(candidate) => candidate,
+                None => break,
+            };
+            let length = get_match_length(data, position, candidate);
+            if length == length_limit {
+                // The candidate can't be told apart from the new position, so the new position
+                // takes its place in the tree.
+                self.replaced = current;
+                self.tree[smaller_slot] = self.tree[BinaryTree::child(candidate, SMALLER)];
+                self.tree[larger_slot] = self.tree[BinaryTree::child(candidate, LARGER)];
+                return;
+            }
+            if data[candidate + length] < data[position + length] {
+                self.tree[smaller_slot] = current;
+                smaller_slot = BinaryTree::child(candidate, LARGER);
+                current = self.tree[smaller_slot];
+            } else {
+                self.tree[larger_slot] = current;
+                larger_slot = BinaryTree::child(candidate, SMALLER);
+                current = self.tree[larger_slot];
+            }
+        }
+        self.tree[smaller_slot] = 0;
+        self.tree[larger_slot] = 0;
+    }
+
+    /// Find the longest match for the data at `position`, which has to be the last position
+    /// added, that is longer than `prev_length`.
+    ///
+    /// At most `max_checks` positions in the tree are checked, and the search stops at a match
+    /// of at least `nice_length` bytes. Returns the length and distance of the match, or `(0, 0)`
+    /// if there is none.
+    pub fn longest_match(
+        &self,
+        data: &[u8],
+        position: usize,
+        prev_length: usize,
+        max_checks: u16,
+        nice_length: usize,
+    ) -> (usize, usize) {
+        let max_match = usize::from(MAX_MATCH);
+        if prev_length >= max_match || position + prev_length >= data.len() {
+            return (0, 0);
+        }
+        let max_length = cmp::min(data.len() - position, max_match);
+
+        let mut best_length = prev_length;
+        let mut best_distance = 0;
+
+        // Matches of three bytes aren't in the tree, so check the last position with the same
+        // hash of three bytes first, along with the position replaced in the tree by this one,
+        // which is as long a match as there can be.
+        if position == self.last_position {
+            for &stored in &[self.replaced, self.short_candidate] {
+                if let Some(candidate) = self.candidate(stored, position) {
+                    let length = get_match_length(data, position, candidate);
+                    if length > best_length {
+                        best_length = length;
+                        best_distance = position - candidate;
+                    }
+                }
+            }
+        }
+
+        // The positions sorting closest to this one are found by going down the largest side of
+        // the smaller subtree and the smallest side of the larger one. The matches get longer,
+        // and the positions older, the further down these paths they are.
+        let mut checks = max_checks;
+        for &(first, next) in &[(SMALLER, LARGER), (LARGER, SMALLER)] {
+            let mut current = self.tree[BinaryTree::child(position, first)];
+            while checks > 0 {
+                let candidate = match self.candidate(current, position) {
+                    Some(candidate) => candidate,
+                    None => break,
+                };
+                checks -= 1;
+                let length = get_match_length(data, position, candidate);
+                let distance = position - candidate;
+                if length > best_length
+                    || (length == best_length && best_distance != 0 && distance < best_distance)
+                {
+                    best_length = length;
+                    best_distance = distance;
+                    if length >= max_length || length >= nice_length {
+                        return (best_length, best_distance);
+                    }
+                }
+                current = self.tree[BinaryTree::child(candidate, next)];
+            }
+        }
+
+        if best_length > prev_length {
+            (best_length, best_distance)
+        } else {
+            (0, 0)
+        }
+    }
+
+    /// Slide the stored positions back by `bytes`, to match the input buffer being slid.
+    pub fn slide(&mut self, bytes: usize) {
+        self.last_position = NO_POSITION;
+        self.base += bytes as u32;
+        if self.base > MAX_BASE {
+            let amount = self.base - INITIAL_BASE;
+            rebase_array(&mut self.head, amount);
+            rebase_array(&mut self.head3, amount);
+            rebase_array(&mut self.tree, amount);
+            self.short_candidate = self.short_candidate.saturating_sub(amount);
+            self.replaced = self.replaced.saturating_sub(amount);
+            self.base = INITIAL_BASE;
+        }
+    }
+
+    /// Forget about all the positions added, by moving `base` past them.
+    pub fn reset(&mut self) {
+        self.slide(RESET_STRIDE);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::get_test_data;
+
+    /// The length of the longest match at `position`, found by checking every earlier position.
+    fn brute_force_length(data: &[u8], position: usize) -> usize {
+        (position.saturating_sub(WINDOW_SIZE - 1)..position)
+            .map(|candidate| get_match_length(data, position, candidate))
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn finds_longest_match() {
+        let test_data = get_test_data();
+        // Text with many repeated words, and a few long repeats.
+        let mut data = test_data[..6000].to_vec();
+        data.extend_from_slice(&test_data[1000..3000]);
+        data.extend_from_slice(&test_data[100..400]);
+        let mut tree = BinaryTree::new();
+        for position in 0..data.len() - tree.lookahead() {
+            tree.add(&data, position);
+            let (length, distance) = tree.longest_match(&data, position, 0, u16::MAX, 258);
+            let expected = brute_force_length(&data, position);
+            if expected >= 4 {
+                assert_eq!(length, expected, "position {}", position);
+            }
+            if length > 0 {
+                assert_eq!(
+                    get_match_length(&data, position, position - distance),
+                    length
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn long_runs() {
+        let data = vec![5; 2000];
+        let mut tree = BinaryTree::new();
+        tree.add(&data, 0);
+        assert_eq!(tree.longest_match(&data, 0, 0, 100, 258), (0, 0));
+        for position in 1..1000 {
+            tree.add(&data, position);
+            assert_eq!(tree.longest_match(&data, position, 0, 100, 258), (258, 1));
+        }
+    }
+
+    #[test]
+    fn reset_and_slide() {
+        let data = b"abcdefgh abcdefgh abcdefgh";
+        let mut tree = BinaryTree::new();
+        for position in 0..10 {
+            tree.add(data, position);
+        }
+        assert_eq!(tree.longest_match(data, 9, 0, 100, 258), (17, 9));
+        tree.reset();
+        tree.add(data, 18);
+        assert_eq!(tree.longest_match(data, 18, 0, 100, 258), (0, 0));
+    }
+}
@@ -5,6 +5,8 @@
 //! This module provides a bit writer
 use std::io::{self, Write};
 
+use crate::allocator::StateVec;
+
 #[cfg(target_pointer_width = "64")]
 #[macro_use]
 mod arch_dep {
@@ -51,16 +53,17 @@ mod arch_dep {
 use self::arch_dep::*;
 
 /// Writes bits to a byte stream, LSB first.
+#[derive(Clone)]
 pub struct LsbWriter {
     // Public for now so it can be replaced after initialization.
-    pub w: Vec<u8>,
+    pub w: StateVec<u8>,
     bits: u8,
     acc: AccType,
 }
 
 impl LsbWriter {
     /// Creates a new bit reader
-    pub fn new(writer: Vec<u8>) -> LsbWriter {
+    pub fn new(writer: StateVec<u8>) -> LsbWriter {
         LsbWriter {
             w: writer,
             bits: 0,
@@ -96,6 +99,15 @@ impl LsbWriter {
         }
     }
 
+    /// Write the whole bytes of the pending bits to the vec, keeping the remaining bits pending.
+    pub fn write_whole_bytes(&mut self) {
+        while self.bits >= 8 {
+            self.w.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
     pub fn flush_raw(&mut self) {
         let missing = FLUSH_AT - self.bits;
         // Have to test for self.bits > 0 here,
@@ -127,6 +139,7 @@ impl Write for LsbWriter {
 #[cfg(test)]
 mod test {
     use super::LsbWriter;
+    use crate::allocator::StateAllocator;
 
     #[test]
     fn write_bits() {
@@ -169,7 +182,7 @@ mod test {
             83, 192, 2, 220, 253, 66, 21, 220, 93, 253, 92, 131, 28, 125, 20, 2, 66, 157, 124, 60,
             157, 21, 128, 216, 213, 47, 216, 21,
         ];
-        let mut writer = LsbWriter::new(Vec::new());
+        let mut writer = LsbWriter::new(StateAllocator::global().vec_with_capacity(0));
         for v in input.iter() {
             writer.write_bits(v.0, v.1);
         }
@@ -181,6 +194,7 @@ mod test {
 #[cfg(all(test, feature = "benchmarks"))]
 mod bench {
     use super::LsbWriter;
+    use crate::allocator::StateAllocator;
     use test_std::Bencher;
     #[bench]
     fn bit_writer(b: &mut Bencher) {
@@ -219,7 +233,7 @@ mod bench {
             (193, 8),
             (174, 8),
         ];
-        let mut writer = LsbWriter::new(Vec::with_capacity(100));
+        let mut writer = LsbWriter::new(StateAllocator::global().vec_with_capacity(100));
         b.iter(|| {
             for v in input.iter() {
                 let _ = writer.write_bits(v.0, v.1);
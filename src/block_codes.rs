@@ -0,0 +1,176 @@
+//! Information about the huffman codes chosen for each block, for use by analysis tools.
+use crate::huffman_table::{HuffmanTable, NUM_DISTANCE_CODES, NUM_LITERALS_AND_LENGTHS};
+
+/// The type of a DEFLATE block.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum BlockKind {
+    /// The data is stored without compression.
+    Stored,
+    /// The data is compressed using the fixed huffman codes defined by the DEFLATE specification.
+    Fixed,
+    /// The data is compressed using huffman codes stored in the block header.
+    Dynamic,
+}
+
+/// Information about a block that has just been output, passed to the callback set with
+/// `set_block_callback` on the encoders.
+///
+/// Like for `BlockCodes`, stored blocks longer than 65535 bytes are reported as one block, and
+/// empty blocks only used for flushing or ending the stream are not reported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct BlockInfo {
+    /// The type of the block.
+    pub kind: BlockKind,
+    /// The position in the input of the first byte represented by the block.
+    pub input_start: u64,
+    /// The number of input bytes represented by the block.
+    pub input_bytes: u64,
+    /// The position in the output data of the first bit of the block header.
+    pub output_bit: u64,
+    /// The number of bits the block takes up in the output, including the header and the end of
+    /// block code.
+    pub output_bits: u64,
+}
+
+/// The huffman code lengths chosen for a block, along with where the block is in the input and
+/// output.
+///
+/// Stored blocks longer than 65535 bytes have to be split into several DEFLATE blocks, these are
+/// reported as one. Empty blocks only used for flushing or ending the stream are not reported.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct BlockCodes {
+    /// The type of the block.
+    pub kind: BlockKind,
+    /// The number of input bytes represented by the block.
+    pub input_bytes: u64,
+    /// The position in the output data of the first bit of the block header.
+    pub output_bit: u64,
+    /// The number of bits the block takes up in the output, including the header and the end of
+    /// block code.
+    pub output_bits: u64,
+    /// The lengths of the codes for the 286 literal/length symbols, where 0 means the symbol is
+    /// not used. Empty for stored blocks.
+    pub literal_length_lengths: Vec<u8>,
+    /// The lengths of the codes for the 30 distance symbols, where 0 means the symbol is not
+    /// used. Empty for stored blocks.
+    pub distance_lengths: Vec<u8>,
+}
+
+impl BlockCodes {
+    /// Record the codes of a block that has just been written using `table`.
+    pub(crate) fn from_table(
+        kind: BlockKind,
+        table: &HuffmanTable,
+        input_bytes: u64,
+        output_bit: u64,
+        output_bits: u64,
+    ) -> BlockCodes {
+        let (literal_length_lengths, distance_lengths) = if kind == BlockKind::Stored {
+            (Vec::new(), Vec::new())
+        } else {
+            let (l_lengths, d_lengths) = table.get_lengths();
+            (
+                l_lengths[..NUM_LITERALS_AND_LENGTHS].to_vec(),
+                d_lengths[..NUM_DISTANCE_CODES].to_vec(),
+            )
+        };
+        BlockCodes {
+            kind,
+            input_bytes,
+            output_bit,
+            output_bits,
+            literal_length_lengths,
+            distance_lengths,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression_options::CompressionOptions;
+    use crate::test_utils::{decompress_to_end, get_test_data};
+    use crate::write::DeflateEncoder;
+    use std::io::Write;
+
+    /// Check that the code lengths describe a valid prefix code.
+    fn kraft_sum_ok(lengths: &[u8]) -> bool {
+        let sum: f64 = lengths
+            .iter()
+            .filter(|&&l| l > 0)
+            .map(|&l| 2f64.powi(-i32::from(l)))
+            .sum();
+        sum <= 1.0
+    }
+
+    #[test]
+    fn block_codes_recorded() {
+        let data = get_test_data();
+        let mut encoder = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        encoder.set_record_block_codes(true);
+        encoder.write_all(&data).unwrap();
+        encoder.flush().unwrap();
+        let mut codes = encoder.take_block_codes();
+        // Short input is output using a fixed block.
+        encoder.write_all(b"abc").unwrap();
+        encoder.flush().unwrap();
+        codes.extend(encoder.take_block_codes());
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(decompress_to_end(&compressed), [&data[..], b"abc"].concat());
+
+        assert!(codes.len() > 2);
+        assert_eq!(codes.last().unwrap().kind, BlockKind::Fixed);
+        assert_eq!(
+            codes.iter().map(|c| c.input_bytes).sum::<u64>(),
+            data.len() as u64 + 3
+        );
+        assert_eq!(codes[0].output_bit, 0);
+        for pair in codes.windows(2) {
+            // Blocks in the first flush follow directly after each other.
+            if pair[1].input_bytes != 3 {
+                assert_eq!(pair[1].output_bit, pair[0].output_bit + pair[0].output_bits);
+            }
+        }
+        for block in &codes {
+            assert_eq!(block.literal_length_lengths.len(), NUM_LITERALS_AND_LENGTHS);
+            assert_eq!(block.distance_lengths.len(), NUM_DISTANCE_CODES);
+            // The end of block code is always used.
+            assert!(block.literal_length_lengths[256] > 0);
+            assert!(kraft_sum_ok(&block.literal_length_lengths));
+            assert!(kraft_sum_ok(&block.distance_lengths));
+        }
+    }
+
+    #[test]
+    fn block_callback() {
+        use std::sync::{Arc, Mutex};
+
+        let data = get_test_data();
+        let blocks = Arc::new(Mutex::new(Vec::new()));
+        let mut encoder = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        encoder.set_record_block_codes(true);
+        let reported = blocks.clone();
+        encoder.set_block_callback(move |info| reported.lock().unwrap().push(*info));
+        encoder.write_all(&data).unwrap();
+        encoder.flush().unwrap();
+        let codes = encoder.take_block_codes();
+        let compressed = encoder.finish().unwrap();
+        assert!(decompress_to_end(&compressed) == data);
+
+        let blocks = blocks.lock().unwrap();
+        // The callback is also called for the last block, output when finishing.
+        assert!(codes.len() > 1);
+        assert_eq!(blocks.len(), codes.len() + 1);
+        let mut input_start = 0;
+        for (info, codes) in blocks.iter().zip(&codes) {
+            assert_eq!(info.kind, codes.kind);
+            assert_eq!(info.input_start, input_start);
+            assert_eq!(info.input_bytes, codes.input_bytes);
+            assert_eq!(info.output_bit, codes.output_bit);
+            assert_eq!(info.output_bits, codes.output_bits);
+            input_start += info.input_bytes;
+        }
+        assert_eq!(input_start, data.len() as u64);
+        assert_eq!(blocks.last().unwrap().input_start, input_start);
+    }
+}
@@ -0,0 +1,283 @@
+//! Policies deciding where the encoders should insert block and stream boundaries.
+//!
+//! By default, the encoders only end a block when the internal buffer of lz77-encoded data is
+//! full, and never restart the compression state. Some uses need more control over this, e.g
+//! to make the output "rsyncable" (so a local change in the input only causes a local change
+//! in the output), to split the output into independently decompressable chunks, or to end a
+//! block at the end of each packet of a network protocol. A [`BoundaryPolicy`] can be set on an
+//! encoder to make these decisions based on the data that is written.
+//!
+//! [`BoundaryPolicy`]: trait.BoundaryPolicy.html
+
+/// The kind of boundary to insert in the compressed stream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Boundary {
+    /// End the current DEFLATE block, and start a new one with new huffman codes.
+    ///
+    /// The output is not padded to a byte boundary, and later data can still reference data
+    /// before the boundary.
+    EndBlock,
+    /// End the current block and align the output to a byte boundary with an empty stored block,
+    /// and clear the window so later data can not reference data before the boundary.
+    ///
+    /// This allows decompression to start at the boundary, and corresponds to `Z_FULL_FLUSH` in
+    /// zlib.
+    FullFlush,
+    /// Finish the current gzip member and start a new one.
+    ///
+    /// This is only meaningful for gzip output, for other formats a full flush is performed
+    /// instead.
+    NewMember,
+}
+
+/// Information about the state of the encoder passed to a [`BoundaryPolicy`].
+///
+/// [`BoundaryPolicy`]: trait.BoundaryPolicy.html
+#[derive(Clone, Copy, Debug)]
+pub struct BoundaryContext<'a> {
+    /// Number of input bytes written to the encoder since the last boundary inserted by the
+    /// policy (or since the start of the stream.)
+    pub bytes_since_boundary: u64,
+    /// Total number of input bytes written to the encoder.
+    pub total_in: u64,
+    /// Frequencies of the literal/length symbols of the lz77-encoded data in the current block
+    /// that has not been output yet.
+    ///
+    /// The counts may be scaled down to avoid overflowing, so only their relative values are
+    /// meaningful. Input that has been written, but not yet lz77-encoded is not included.
+    pub literal_length_frequencies: &'a [u16],
+    /// Frequencies of the distance symbols of the lz77-encoded data in the current block that has
+    /// not been output yet.
+    pub distance_frequencies: &'a [u16],
+}
+
+/// A trait for deciding where to insert block or stream boundaries in the compressed output.
+///
+/// The policy is consulted each time data is written to the encoder, and sees every byte of
+/// input exactly once, in order, so it can keep a rolling state (such as a rolling hash) of the
+/// data.
+pub trait BoundaryPolicy {
+    /// Look at `data`, which is about to be compressed, and decide if a boundary should be
+    /// inserted.
+    ///
+    /// Returning `Some((n, boundary))` inserts `boundary` after the first `n` bytes of `data`,
+    /// and the rest of the data will be passed to this function again afterwards. `n` should be
+    /// between `1` and `data.len()`, values outside of this range are clamped. Returning `None`
+    /// compresses all of `data` without inserting a boundary.
+    fn next_boundary(
+        &mut self,
+        data: &[u8],
+        context: &BoundaryContext,
+    ) -> Option<(usize, Boundary)>;
+}
+
+/// The default policy, which never inserts any boundaries.
+///
+/// Blocks are ended when the internal buffer of lz77-encoded data is full.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct DefaultBoundaryPolicy;
+
+impl BoundaryPolicy for DefaultBoundaryPolicy {
+    fn next_boundary(&mut self, _: &[u8], _: &BoundaryContext) -> Option<(usize, Boundary)> {
+        None
+    }
+}
+
+/// The window size used by `gzip --rsyncable`.
+pub const DEFAULT_RSYNC_WINDOW: usize = 4096;
+
+/// A policy producing "rsyncable" output, similar to `gzip --rsyncable`.
+///
+/// A rolling sum of the last `window` bytes of input is kept, and a full flush is performed
+/// whenever it is divisible by the window size. As the boundaries only depend on the
+/// surrounding data rather than the position in the input, a change in the input only affects
+/// the output up to the next boundary after it, which lets tools like rsync transfer only the
+/// changed parts of the compressed file.
+#[derive(Clone, Debug)]
+pub struct RsyncablePolicy {
+    window: Vec<u8>,
+    position: usize,
+    filled: bool,
+    sum: usize,
+}
+
+impl RsyncablePolicy {
+    /// Create a new policy using the default window size.
+    pub fn new() -> RsyncablePolicy {
+        RsyncablePolicy::with_window(DEFAULT_RSYNC_WINDOW)
+    }
+
+    /// Create a new policy with the specified window size.
+    ///
+    /// Larger windows give fewer boundaries, and thus better compression, at the expense of
+    /// a change affecting a larger part of the output.
+    ///
+    /// # Panics
+    /// Panics if `window` is not a power of two.
+    pub fn with_window(window: usize) -> RsyncablePolicy {
+        assert!(
+            window.is_power_of_two(),
+            "The window size has to be a power of two!"
+        );
+        RsyncablePolicy {
+            window: vec![0; window],
+            position: 0,
+            filled: false,
+            sum: 0,
+        }
+    }
+}
+
+impl Default for RsyncablePolicy {
+    fn default() -> RsyncablePolicy {
+        RsyncablePolicy::new()
+    }
+}
+
+impl BoundaryPolicy for RsyncablePolicy {
+    fn next_boundary(&mut self, data: &[u8], _: &BoundaryContext) -> Option<(usize, Boundary)> {
+        let window_size = self.window.len();
+        let mask = window_size - 1;
+        for (n, &byte) in data.iter().enumerate() {
+            self.sum = self.sum + usize::from(byte) - usize::from(self.window[self.position]);
+            self.window[self.position] = byte;
+            self.position += 1;
+            if self.position == window_size {
+                self.position = 0;
+                self.filled = true;
+            }
+            if self.filled && self.sum & mask == 0 {
+                return Some((n + 1, Boundary::FullFlush));
+            }
+        }
+        None
+    }
+}
+
+/// A policy doing a full flush every `interval` bytes of input.
+///
+/// Combined with recording access points on the encoder, this gives an index that lets a reader
+/// start decompressing close to any position in the input, instead of from the start of the
+/// stream, like the index built by zlib's `zran` example. Smaller intervals make the seeking
+/// cheaper, but each full flush adds a few bytes of output and resets the window, which costs
+/// compression.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct IntervalPolicy {
+    interval: u64,
+}
+
+impl IntervalPolicy {
+    /// Create a policy doing a full flush after every `interval` bytes of input.
+    ///
+    /// # Panics
+    /// Panics if `interval` is 0.
+    pub fn new(interval: u64) -> IntervalPolicy {
+        assert!(interval > 0, "The interval can not be 0!");
+        IntervalPolicy { interval }
+    }
+}
+
+impl BoundaryPolicy for IntervalPolicy {
+    fn next_boundary(
+        &mut self,
+        data: &[u8],
+        context: &BoundaryContext,
+    ) -> Option<(usize, Boundary)> {
+        let left = self.interval.saturating_sub(context.bytes_since_boundary);
+        if left <= data.len() as u64 {
+            Some((left as usize, Boundary::FullFlush))
+        } else {
+            None
+        }
+    }
+}
+
+/// A position in the compressed stream where decompression can start, recorded at each full
+/// flush or new gzip member when recording access points is enabled on an encoder.
+///
+/// A decompressor can start reading raw DEFLATE data (or a new gzip member) at `output_bit`
+/// without any earlier data, and will produce the input starting at `input_offset`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct AccessPoint {
+    /// The position in the uncompressed input.
+    pub input_offset: u64,
+    /// The position in the compressed output, in bits, including any header. This is always
+    /// a multiple of 8 unless bits were inserted into the output with `prime`.
+    pub output_bit: u64,
+}
+
+/// Clamp the position returned by a policy to a valid one.
+pub fn clamp_boundary(position: usize, data_len: usize) -> usize {
+    if position == 0 {
+        1
+    } else if position > data_len {
+        data_len
+    } else {
+        position
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn context() -> BoundaryContext<'static> {
+        BoundaryContext {
+            bytes_since_boundary: 0,
+            total_in: 0,
+            literal_length_frequencies: &[],
+            distance_frequencies: &[],
+        }
+    }
+
+    #[test]
+    fn rsyncable_boundaries_follow_content() {
+        let data = crate::test_utils::get_test_data();
+        let boundaries = |data: &[u8]| {
+            let mut policy = RsyncablePolicy::with_window(256);
+            let mut found = Vec::new();
+            let mut pos = 0;
+            while let Some((n, kind)) = policy.next_boundary(&data[pos..], &context()) {
+                assert_eq!(kind, Boundary::FullFlush);
+                pos += n;
+                found.push(pos);
+            }
+            found
+        };
+
+        let original = boundaries(&data);
+        assert!(!original.is_empty());
+        // Inserting data at the start should shift the boundaries after it by the same amount.
+        let mut shifted = vec![7u8; 1000];
+        shifted.extend_from_slice(&data);
+        let shifted = boundaries(&shifted);
+        assert!(original
+            .iter()
+            .filter(|&&p| p > 256)
+            .all(|p| shifted.contains(&(p + 1000))));
+    }
+
+    #[test]
+    fn interval_boundaries() {
+        let mut policy = IntervalPolicy::new(100);
+        let mut context = context();
+        assert_eq!(policy.next_boundary(&[0; 50], &context), None);
+        context.bytes_since_boundary = 50;
+        assert_eq!(
+            policy.next_boundary(&[0; 80], &context),
+            Some((50, Boundary::FullFlush))
+        );
+        context.bytes_since_boundary = 0;
+        assert_eq!(
+            policy.next_boundary(&[0; 100], &context),
+            Some((100, Boundary::FullFlush))
+        );
+    }
+
+    #[test]
+    fn clamp() {
+        assert_eq!(clamp_boundary(0, 10), 1);
+        assert_eq!(clamp_boundary(5, 10), 5);
+        assert_eq!(clamp_boundary(20, 10), 10);
+    }
+}
@@ -0,0 +1,809 @@
+//! The compression side of the zlib C API, enabled by the `capi` feature.
+//!
+//! The functions are exported with the same names and semantics as in zlib, operating on a
+//! `z_stream` with the same layout, so C code using zlib for compression can use this crate
+//! instead by linking against a static or dynamic library built from a crate depending on this
+//! one with the feature enabled.
+//!
+//! A few things differ from zlib:
+//!
+//! * `zalloc`, `zfree` and `opaque` are ignored, the state is always allocated with the global
+//!   allocator.
+//! * All the input passed to `deflate` is consumed on each call, with the compressed data that
+//!   doesn't fit in the output kept until the next call.
+//! * `memLevel` sets the size of the hash table and the number of symbols per block like in
+//!   zlib, and `deflateBound` accounts for the smaller blocks of low levels. `Z_FIXED` compresses
+//!   like `Z_DEFAULT_STRATEGY`.
+//! * Preset dictionaries are not supported for gzip streams, as in zlib.
+//! * `deflateParams` can't switch to or from `Z_FILTERED`, as it changes how the hash chains are
+//!   built. `Z_STREAM_ERROR` is returned instead.
+#![allow(non_camel_case_types, non_snake_case)]
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_uint, c_ulong, c_void};
+use std::{cmp, ptr, slice};
+
+#[cfg(feature = "gzip")]
+use crate::checksum::Crc32;
+use crate::checksum::{Adler32Checksum, RollingChecksum};
+use crate::compression_options::CompressionOptions;
+use crate::flate2_compat::{Compress, Compression, FlushCompress, Status};
+use crate::writer::Format;
+
+pub const Z_NO_FLUSH: c_int = 0;
+pub const Z_PARTIAL_FLUSH: c_int = 1;
+pub const Z_SYNC_FLUSH: c_int = 2;
+pub const Z_FULL_FLUSH: c_int = 3;
+pub const Z_FINISH: c_int = 4;
+pub const Z_BLOCK: c_int = 5;
+
+pub const Z_OK: c_int = 0;
+pub const Z_STREAM_END: c_int = 1;
+pub const Z_STREAM_ERROR: c_int = -2;
+pub const Z_DATA_ERROR: c_int = -3;
+pub const Z_MEM_ERROR: c_int = -4;
+pub const Z_BUF_ERROR: c_int = -5;
+pub const Z_VERSION_ERROR: c_int = -6;
+
+pub const Z_DEFAULT_COMPRESSION: c_int = -1;
+pub const Z_FILTERED: c_int = 1;
+pub const Z_HUFFMAN_ONLY: c_int = 2;
+pub const Z_RLE: c_int = 3;
+pub const Z_FIXED: c_int = 4;
+pub const Z_DEFAULT_STRATEGY: c_int = 0;
+pub const Z_DEFLATED: c_int = 8;
+
+const MAX_MEM_LEVEL: c_int = 9;
+const DEF_MEM_LEVEL: c_int = 8;
+const MAX_WBITS: c_int = 15;
+
+pub type alloc_func = Option<unsafe extern "C" fn(*mut c_void, c_uint, c_uint) -> *mut c_void>;
+pub type free_func = Option<unsafe extern "C" fn(*mut c_void, *mut c_void)>;
+
+/// The stream struct of the zlib API.
+#[repr(C)]
+pub struct z_stream {
+    pub next_in: *const u8,
+    pub avail_in: c_uint,
+    pub total_in: c_ulong,
+    pub next_out: *mut u8,
+    pub avail_out: c_uint,
+    pub total_out: c_ulong,
+    pub msg: *const c_char,
+    pub state: *mut c_void,
+    pub zalloc: alloc_func,
+    pub zfree: free_func,
+    pub opaque: *mut c_void,
+    pub data_type: c_int,
+    pub adler: c_ulong,
+    pub reserved: c_ulong,
+}
+
+/// The checksum reported in `adler`, which depends on the format.
+enum Checksum {
+    None,
+    Adler32(Adler32Checksum),
+    #[cfg(feature = "gzip")]
+    Crc32(Crc32),
+}
+
+impl Checksum {
+    fn new(format: Format) -> Checksum {
+        match format {
+            Format::Zlib => Checksum::Adler32(Adler32Checksum::new()),
+            #[cfg(feature = "gzip")]
+            Format::Gzip => Checksum::Crc32(Crc32::new()),
+            _ => Checksum::None,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match *self {
+            Checksum::None => (),
+            Checksum::Adler32(ref mut adler) => adler.update_from_slice(data),
+            #[cfg(feature = "gzip")]
+            Checksum::Crc32(ref mut crc) => crc.update(data),
+        }
+    }
+
+    fn value(&self) -> c_ulong {
+        match *self {
+            Checksum::None => 0,
+            Checksum::Adler32(ref adler) => c_ulong::from(adler.current_hash()),
+            #[cfg(feature = "gzip")]
+            Checksum::Crc32(ref crc) => c_ulong::from(crc.sum()),
+        }
+    }
+}
+
+/// The state pointed to by `z_stream::state`.
+struct StreamState {
+    compress: Compress,
+    checksum: Checksum,
+}
+
+impl StreamState {
+    fn new(format: Format, options: CompressionOptions) -> StreamState {
+        StreamState {
+            compress: Compress::with_options(format, options),
+            checksum: Checksum::new(format),
+        }
+    }
+}
+
+/// Get the state of a stream, if it has been initialized.
+unsafe fn stream_state<'a>(strm: *mut z_stream) -> Option<(&'a mut z_stream, &'a mut StreamState)> {
+    let strm = strm.as_mut()?;
+    let state = (strm.state as *mut StreamState).as_mut()?;
+    Some((strm, state))
+}
+
+/// Check that the caller was compiled against a compatible version of the zlib header.
+unsafe fn version_ok(version: *const c_char, stream_size: c_int) -> bool {
+    !version.is_null()
+        && CStr::from_ptr(version).to_bytes().first() == Some(&b'1')
+        && stream_size as usize == std::mem::size_of::<z_stream>()
+}
+
+/// Convert the arguments of `deflateInit2` to a format and compression options.
+fn stream_settings(
+    level: c_int,
+    method: c_int,
+    window_bits: c_int,
+    mem_level: c_int,
+    strategy: c_int,
+) -> Option<(Format, CompressionOptions)> {
+    if method != Z_DEFLATED || !(1..=MAX_MEM_LEVEL).contains(&mem_level) {
+        return None;
+    }
+    let (format, window_bits) = match window_bits {
+        -15..=-8 => (Format::Raw, -window_bits),
+        8..=15 => (Format::Zlib, window_bits),
+        #[cfg(feature = "gzip")]
+        24..=31 => (Format::Gzip, window_bits - 16),
+        _ => return None,
+    };
+    let mut options = strategy_options(level, strategy)?;
+    // Like zlib, a window of 256 bytes is not supported, and 512 bytes are used instead.
+    options.window_bits = cmp::max(window_bits, 9) as u8;
+    options.mem_level = mem_level as u8;
+    Some((format, options))
+}
+
+/// Convert a compression level and strategy to compression options.
+fn strategy_options(level: c_int, strategy: c_int) -> Option<CompressionOptions> {
+    let level = match level {
+        Z_DEFAULT_COMPRESSION => Compression::default(),
+        0..=9 => Compression::new(level as u32),
+        _ => return None,
+    };
+    let options = match strategy {
+        // Like zlib, level 0 only outputs stored blocks whatever the strategy.
+        Z_DEFAULT_STRATEGY | Z_FILTERED | Z_HUFFMAN_ONLY | Z_RLE | Z_FIXED
+            if level == Compression::none() =>
+        {
+            CompressionOptions::stored()
+        }
+        Z_DEFAULT_STRATEGY | Z_FIXED => CompressionOptions::from(level),
+        Z_FILTERED => CompressionOptions {
+            filtered: true,
+            ..CompressionOptions::from(level)
+        },
+        Z_HUFFMAN_ONLY => CompressionOptions::huffman_only(),
+        Z_RLE => CompressionOptions::rle(),
+        _ => return None,
+    };
+    Some(options)
+}
+
+/// Initialize `strm` for compression, like `deflateInit2_` in zlib.
+///
+/// # Safety
+/// `strm` has to point to a `z_stream`, and `version` to a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn deflateInit2_(
+    strm: *mut z_stream,
+    level: c_int,
+    method: c_int,
+    windowBits: c_int,
+    memLevel: c_int,
+    strategy: c_int,
+    version: *const c_char,
+    stream_size: c_int,
+) -> c_int {
+    if !version_ok(version, stream_size) {
+        return Z_VERSION_ERROR;
+    }
+    let strm = match strm.as_mut() {
+        Some(strm) => strm,
+        None => return Z_STREAM_ERROR,
+    };
+    let (format, options) = match stream_settings(level, method, windowBits, memLevel, strategy) {
+        Some(settings) => settings,
+        None => return Z_STREAM_ERROR,
+    };
+    let state = Box::new(StreamState::new(format, options));
+    strm.msg = ptr::null();
+    strm.state = Box::into_raw(state) as *mut c_void;
+    deflateReset(strm)
+}
+
+/// Initialize `strm` for compression in the zlib format, like `deflateInit_` in zlib.
+///
+/// # Safety
+/// `strm` has to point to a `z_stream`, and `version` to a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn deflateInit_(
+    strm: *mut z_stream,
+    level: c_int,
+    version: *const c_char,
+    stream_size: c_int,
+) -> c_int {
+    deflateInit2_(
+        strm,
+        level,
+        Z_DEFLATED,
+        MAX_WBITS,
+        DEF_MEM_LEVEL,
+        Z_DEFAULT_STRATEGY,
+        version,
+        stream_size,
+    )
+}
+
+/// Compress the input of `strm` and copy as much of the output as fits to it, like `deflate` in
+/// zlib.
+///
+/// # Safety
+/// `strm` has to point to a `z_stream` initialized with `deflateInit_` or `deflateInit2_`, with
+/// `next_in` and `next_out` pointing to at least `avail_in` and `avail_out` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn deflate(strm: *mut z_stream, flush: c_int) -> c_int {
+    let (strm, state) = match stream_state(strm) {
+        Some(s) => s,
+        None => return Z_STREAM_ERROR,
+    };
+    let flush = match flush {
+        Z_NO_FLUSH => FlushCompress::None,
+        Z_PARTIAL_FLUSH => FlushCompress::Partial,
+        Z_SYNC_FLUSH => FlushCompress::Sync,
+        Z_FULL_FLUSH => FlushCompress::Full,
+        Z_FINISH => FlushCompress::Finish,
+        Z_BLOCK => FlushCompress::Block,
+        _ => return Z_STREAM_ERROR,
+    };
+    if strm.next_out.is_null() || (strm.next_in.is_null() && strm.avail_in != 0) {
+        return Z_STREAM_ERROR;
+    }
+
+    let input = if strm.avail_in == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(strm.next_in, strm.avail_in as usize)
+    };
+    let output = if strm.avail_out == 0 {
+        &mut []
+    } else {
+        slice::from_raw_parts_mut(strm.next_out, strm.avail_out as usize)
+    };
+    let (status, consumed, produced) = match state.compress.compress_counted(input, output, flush) {
+        Ok(result) => result,
+        Err(_) => return Z_STREAM_ERROR,
+    };
+
+    state.checksum.update(&input[..consumed]);
+    strm.adler = state.checksum.value();
+    strm.next_in = strm.next_in.wrapping_add(consumed);
+    strm.avail_in -= consumed as c_uint;
+    strm.total_in += consumed as c_ulong;
+    strm.next_out = strm.next_out.add(produced);
+    strm.avail_out -= produced as c_uint;
+    strm.total_out += produced as c_ulong;
+
+    match status {
+        Status::Ok => Z_OK,
+        Status::BufError => Z_BUF_ERROR,
+        Status::StreamEnd => Z_STREAM_END,
+    }
+}
+
+/// Free the state of `strm`, like `deflateEnd` in zlib.
+///
+/// Returns `Z_DATA_ERROR` if the stream was not finished, or not all the output was taken.
+///
+/// # Safety
+/// `strm` has to point to a `z_stream` initialized with `deflateInit_` or `deflateInit2_`.
+#[no_mangle]
+pub unsafe extern "C" fn deflateEnd(strm: *mut z_stream) -> c_int {
+    let strm = match strm.as_mut() {
+        Some(strm) if !strm.state.is_null() => strm,
+        _ => return Z_STREAM_ERROR,
+    };
+    let state = Box::from_raw(strm.state as *mut StreamState);
+    strm.state = ptr::null_mut();
+    let compress = &state.compress;
+    if compress.is_done() || (compress.total_in() == 0 && !compress.has_pending_output()) {
+        Z_OK
+    } else {
+        Z_DATA_ERROR
+    }
+}
+
+/// Reset `strm` to start compressing a new stream with the same settings, like `deflateReset` in
+/// zlib.
+///
+/// # Safety
+/// `strm` has to point to a `z_stream` initialized with `deflateInit_` or `deflateInit2_`.
+#[no_mangle]
+pub unsafe extern "C" fn deflateReset(strm: *mut z_stream) -> c_int {
+    let (strm, state) = match stream_state(strm) {
+        Some(s) => s,
+        None => return Z_STREAM_ERROR,
+    };
+    state.compress.reset();
+    state.checksum = Checksum::new(state.compress.format());
+    strm.total_in = 0;
+    strm.total_out = 0;
+    strm.msg = ptr::null();
+    strm.data_type = 2;
+    strm.adler = match state.compress.format() {
+        Format::Zlib => 1,
+        _ => 0,
+    };
+    Z_OK
+}
+
+/// Use a preset dictionary, like `deflateSetDictionary` in zlib.
+///
+/// Has to be called before the first call to `deflate`. For zlib streams, `adler` is set to the
+/// Adler-32 checksum of the dictionary, which is included in the header. `Z_STREAM_ERROR` is
+/// returned for gzip streams.
+///
+/// # Safety
+/// `strm` has to point to a `z_stream` initialized with `deflateInit_` or `deflateInit2_`, and
+/// `dictionary` to at least `dictLength` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn deflateSetDictionary(
+    strm: *mut z_stream,
+    dictionary: *const u8,
+    dictLength: c_uint,
+) -> c_int {
+    let (strm, state) = match stream_state(strm) {
+        Some(s) => s,
+        None => return Z_STREAM_ERROR,
+    };
+    if dictionary.is_null() {
+        return Z_STREAM_ERROR;
+    }
+    let dictionary = slice::from_raw_parts(dictionary, dictLength as usize);
+    match state.compress.set_dictionary(dictionary) {
+        Ok(id) => {
+            if state.compress.format() == Format::Zlib {
+                strm.adler = c_ulong::from(id);
+            }
+            Z_OK
+        }
+        Err(_) => Z_STREAM_ERROR,
+    }
+}
+
+/// Insert the lowest `bits` bits of `value` into the output, like `deflatePrime` in zlib.
+///
+/// Has to be called before the first call to `deflate` that compresses any input.
+/// `Z_STREAM_ERROR` is returned if `bits` is not between 0 and 16.
+///
+/// # Safety
+/// `strm` has to point to a `z_stream` initialized with `deflateInit_` or `deflateInit2_`.
+#[no_mangle]
+pub unsafe extern "C" fn deflatePrime(strm: *mut z_stream, bits: c_int, value: c_int) -> c_int {
+    let (_, state) = match stream_state(strm) {
+        Some(s) => s,
+        None => return Z_STREAM_ERROR,
+    };
+    if !(0..=16).contains(&bits) {
+        return Z_STREAM_ERROR;
+    }
+    match state.compress.prime(bits as u8, value as u16) {
+        Ok(()) => Z_OK,
+        Err(_) => Z_STREAM_ERROR,
+    }
+}
+
+/// Change the compression level and strategy in the middle of the stream, like `deflateParams`
+/// in zlib.
+///
+/// The input compressed so far is ended with the old parameters, and the output is kept until
+/// the next call to `deflate`.
+///
+/// # Safety
+/// `strm` has to point to a `z_stream` initialized with `deflateInit_` or `deflateInit2_`.
+#[no_mangle]
+pub unsafe extern "C" fn deflateParams(
+    strm: *mut z_stream,
+    level: c_int,
+    strategy: c_int,
+) -> c_int {
+    let (_, state) = match stream_state(strm) {
+        Some(s) => s,
+        None => return Z_STREAM_ERROR,
+    };
+    let current = state.compress.options();
+    let options = match strategy_options(level, strategy) {
+        Some(options) => CompressionOptions {
+            window_bits: current.window_bits,
+            mem_level: current.mem_level,
+            matcher: current.matcher,
+            ..options
+        },
+        None => return Z_STREAM_ERROR,
+    };
+    match state.compress.set_options(options) {
+        Ok(()) => Z_OK,
+        Err(_) => Z_STREAM_ERROR,
+    }
+}
+
+/// Store the number of bytes and bits of output that have been generated but not returned yet
+/// in `pending` and `bits`, like `deflatePending` in zlib. Either pointer can be null.
+///
+/// # Safety
+/// `strm` has to point to a `z_stream` initialized with `deflateInit_` or `deflateInit2_`, and
+/// `pending` and `bits` have to be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn deflatePending(
+    strm: *mut z_stream,
+    pending: *mut c_uint,
+    bits: *mut c_int,
+) -> c_int {
+    let (_, state) = match stream_state(strm) {
+        Some(s) => s,
+        None => return Z_STREAM_ERROR,
+    };
+    let (pending_bytes, pending_bits) = state.compress.pending();
+    if !pending.is_null() {
+        *pending = pending_bytes as c_uint;
+    }
+    if !bits.is_null() {
+        *bits = c_int::from(pending_bits);
+    }
+    Z_OK
+}
+
+/// Returns an upper bound on the compressed size of `sourceLen` bytes, including the header and
+/// trailer, like `deflateBound` in zlib.
+///
+/// # Safety
+/// `strm` has to be null or point to a `z_stream` initialized with `deflateInit_` or
+/// `deflateInit2_`.
+#[no_mangle]
+pub unsafe extern "C" fn deflateBound(strm: *mut z_stream, sourceLen: c_ulong) -> c_ulong {
+    let source_len = sourceLen as usize;
+    let (bound, wrapper) = match stream_state(strm) {
+        Some((_, state)) => (
+            crate::deflate_bound_conf(source_len, state.compress.options()),
+            match state.compress.format() {
+                Format::Zlib => 6,
+                #[cfg(feature = "gzip")]
+                Format::Gzip => 18,
+                _ => 0,
+            },
+        ),
+        // zlib assumes a zlib stream if there is no state.
+        None => (crate::deflate_bound(source_len), 6),
+    };
+    (bound + wrapper) as c_ulong
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, decompress_zlib, get_test_data};
+
+    const VERSION: &[u8] = b"1.2.11\0";
+
+    fn new_stream() -> z_stream {
+        z_stream {
+            next_in: ptr::null(),
+            avail_in: 0,
+            total_in: 0,
+            next_out: ptr::null_mut(),
+            avail_out: 0,
+            total_out: 0,
+            msg: ptr::null(),
+            state: ptr::null_mut(),
+            zalloc: None,
+            zfree: None,
+            opaque: ptr::null_mut(),
+            data_type: 0,
+            adler: 0,
+            reserved: 0,
+        }
+    }
+
+    fn init(strm: &mut z_stream, level: c_int, window_bits: c_int) -> c_int {
+        unsafe {
+            deflateInit2_(
+                strm,
+                level,
+                Z_DEFLATED,
+                window_bits,
+                DEF_MEM_LEVEL,
+                Z_DEFAULT_STRATEGY,
+                VERSION.as_ptr() as *const c_char,
+                std::mem::size_of::<z_stream>() as c_int,
+            )
+        }
+    }
+
+    /// Compress `data` passing it in pieces of `in_size` bytes, with an output buffer of
+    /// `out_size` bytes.
+    fn compress(strm: &mut z_stream, data: &[u8], in_size: usize, out_size: usize) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut buf = vec![0; out_size];
+        let mut chunks = data.chunks(in_size).peekable();
+        loop {
+            let chunk = chunks.next().unwrap_or(&[]);
+            let flush = if chunks.peek().is_none() {
+                Z_FINISH
+            } else {
+                Z_NO_FLUSH
+            };
+            strm.next_in = chunk.as_ptr();
+            strm.avail_in = chunk.len() as c_uint;
+            loop {
+                strm.next_out = buf.as_mut_ptr();
+                strm.avail_out = buf.len() as c_uint;
+                let ret = unsafe { deflate(strm, flush) };
+                assert!(ret == Z_OK || ret == Z_STREAM_END || ret == Z_BUF_ERROR);
+                output.extend_from_slice(&buf[..out_size - strm.avail_out as usize]);
+                if ret == Z_STREAM_END {
+                    return output;
+                }
+                if strm.avail_out != 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn capi_zlib() {
+        let data = get_test_data();
+        let mut strm = new_stream();
+        assert_eq!(init(&mut strm, 6, 15), Z_OK);
+        let compressed = compress(&mut strm, &data, 10_000, 1000);
+        assert_eq!(strm.total_in as usize, data.len());
+        assert_eq!(strm.total_out as usize, compressed.len());
+        assert!(compressed.len() as c_ulong <= unsafe { deflateBound(&mut strm, data.len() as _) });
+        assert!(decompress_zlib(&compressed) == data);
+        let mut adler = Adler32Checksum::new();
+        adler.update_from_slice(&data);
+        assert_eq!(strm.adler, c_ulong::from(adler.current_hash()));
+        assert_eq!(unsafe { deflateEnd(&mut strm) }, Z_OK);
+        assert!(strm.state.is_null());
+
+        // The output is the same as from the encoder.
+        assert!(compressed == crate::deflate_bytes_zlib(&data));
+    }
+
+    #[test]
+    fn capi_raw_with_dictionary() {
+        let data = get_test_data();
+        let mut strm = new_stream();
+        assert_eq!(init(&mut strm, 9, -15), Z_OK);
+        let dict = &data[..1000];
+        assert_eq!(
+            unsafe { deflateSetDictionary(&mut strm, dict.as_ptr(), dict.len() as c_uint) },
+            Z_OK
+        );
+        let compressed = compress(&mut strm, &data, 100_000, 1 << 16);
+        assert!(crate::test_utils::decompress_with_dictionary(dict, &compressed) == data);
+
+        // Reset and compress again without a dictionary.
+        assert_eq!(unsafe { deflateReset(&mut strm) }, Z_OK);
+        let compressed = compress(&mut strm, &data, 7, 10);
+        assert!(decompress_to_end(&compressed) == data);
+        assert_eq!(unsafe { deflateEnd(&mut strm) }, Z_OK);
+    }
+
+    #[test]
+    fn capi_prime() {
+        let data = get_test_data();
+        let mut strm = new_stream();
+        assert_eq!(init(&mut strm, 6, -15), Z_OK);
+        assert_eq!(unsafe { deflatePrime(&mut strm, 17, 0) }, Z_STREAM_ERROR);
+        // An empty non-final block with fixed codes.
+        assert_eq!(unsafe { deflatePrime(&mut strm, 10, 0b010) }, Z_OK);
+        let compressed = compress(&mut strm, &data, 10_000, 1000);
+        assert!(decompress_to_end(&compressed) == data);
+        assert_eq!(unsafe { deflatePrime(&mut strm, 1, 0) }, Z_STREAM_ERROR);
+        assert_eq!(unsafe { deflateEnd(&mut strm) }, Z_OK);
+    }
+
+    #[test]
+    fn capi_zlib_with_dictionary() {
+        let data = get_test_data();
+        let mut strm = new_stream();
+        assert_eq!(init(&mut strm, 6, 15), Z_OK);
+        let dict = &data[..1000];
+        assert_eq!(
+            unsafe { deflateSetDictionary(&mut strm, dict.as_ptr(), dict.len() as c_uint) },
+            Z_OK
+        );
+        let mut adler = Adler32Checksum::new();
+        adler.update_from_slice(dict);
+        assert_eq!(strm.adler, c_ulong::from(adler.current_hash()));
+        let compressed = compress(&mut strm, &data, 100_000, 1 << 16);
+        assert!(crate::inflate_bytes_zlib_with_dictionary(dict, &compressed).unwrap() == data);
+        assert_eq!(unsafe { deflateEnd(&mut strm) }, Z_OK);
+    }
+
+    #[test]
+    fn capi_flush() {
+        let data = get_test_data();
+        let mut strm = new_stream();
+        assert_eq!(init(&mut strm, Z_DEFAULT_COMPRESSION, -15), Z_OK);
+        let mut out = vec![0; data.len()];
+        strm.next_in = data.as_ptr();
+        strm.avail_in = 5000;
+        strm.next_out = out.as_mut_ptr();
+        strm.avail_out = out.len() as c_uint;
+        assert_eq!(unsafe { deflate(&mut strm, Z_SYNC_FLUSH) }, Z_OK);
+        let written = out.len() - strm.avail_out as usize;
+        // A sync flush ends with an empty stored block.
+        assert_eq!(&out[written - 4..written], &[0, 0, 0xFF, 0xFF]);
+        // Flushing again without new input makes no progress.
+        assert_eq!(unsafe { deflate(&mut strm, Z_SYNC_FLUSH) }, Z_BUF_ERROR);
+        // Ending the stream early is reported.
+        assert_eq!(unsafe { deflateEnd(&mut strm) }, Z_DATA_ERROR);
+
+        // The same flush with a single byte of output space at a time.
+        assert_eq!(init(&mut strm, Z_DEFAULT_COMPRESSION, -15), Z_OK);
+        strm.next_in = data.as_ptr();
+        strm.avail_in = 5000;
+        let mut byte_out = Vec::new();
+        loop {
+            let mut byte = 0;
+            strm.next_out = &mut byte;
+            strm.avail_out = 1;
+            let ret = unsafe { deflate(&mut strm, Z_SYNC_FLUSH) };
+            if strm.avail_out != 0 {
+                // No progress once all the output has been returned.
+                assert_eq!(ret, Z_BUF_ERROR);
+                break;
+            }
+            assert_eq!(ret, Z_OK);
+            byte_out.push(byte);
+            assert!(byte_out.len() <= written);
+        }
+        assert_eq!(byte_out, &out[..written]);
+        unsafe { deflateEnd(&mut strm) };
+    }
+
+    #[test]
+    fn capi_block_flush() {
+        let data = get_test_data();
+        let mut strm = new_stream();
+        assert_eq!(init(&mut strm, Z_DEFAULT_COMPRESSION, -15), Z_OK);
+        let mut out = vec![0; data.len()];
+        strm.next_in = data.as_ptr();
+        strm.avail_in = 5000;
+        strm.next_out = out.as_mut_ptr();
+        strm.avail_out = out.len() as c_uint;
+        assert_eq!(unsafe { deflate(&mut strm, Z_BLOCK) }, Z_OK);
+        let written = out.len() - strm.avail_out as usize;
+        // The block is output without an empty stored block after it.
+        assert_ne!(&out[written - 4..written], &[0, 0, 0xFF, 0xFF]);
+        assert_eq!(unsafe { deflate(&mut strm, Z_BLOCK) }, Z_BUF_ERROR);
+        let mut bits = -1;
+        assert_eq!(
+            unsafe { deflatePending(&mut strm, ptr::null_mut(), &mut bits) },
+            Z_OK
+        );
+        assert!((0..8).contains(&bits));
+        // A sync flush after it still adds the empty stored block.
+        assert_eq!(unsafe { deflate(&mut strm, Z_SYNC_FLUSH) }, Z_OK);
+        let written = out.len() - strm.avail_out as usize;
+        assert_eq!(&out[written - 4..written], &[0, 0, 0xFF, 0xFF]);
+
+        strm.avail_in = (data.len() - 5000) as c_uint;
+        assert_eq!(unsafe { deflate(&mut strm, Z_FINISH) }, Z_STREAM_END);
+        let written = out.len() - strm.avail_out as usize;
+        assert!(decompress_to_end(&out[..written]) == data);
+        assert_eq!(unsafe { deflateEnd(&mut strm) }, Z_OK);
+    }
+
+    #[test]
+    fn capi_params() {
+        let data = get_test_data();
+        let mut strm = new_stream();
+        assert_eq!(init(&mut strm, 9, 15), Z_OK);
+        let mut out = vec![0; data.len()];
+        strm.next_in = data.as_ptr();
+        strm.avail_in = 50_000;
+        strm.next_out = out.as_mut_ptr();
+        strm.avail_out = out.len() as c_uint;
+        assert_eq!(unsafe { deflate(&mut strm, Z_NO_FLUSH) }, Z_OK);
+        assert_eq!(
+            unsafe { deflateParams(&mut strm, 1, Z_DEFAULT_STRATEGY) },
+            Z_OK
+        );
+        assert_eq!(
+            unsafe { deflateParams(&mut strm, 6, Z_FILTERED) },
+            Z_STREAM_ERROR
+        );
+        assert_eq!(
+            unsafe { deflateParams(&mut strm, 10, Z_RLE) },
+            Z_STREAM_ERROR
+        );
+        strm.avail_in = (data.len() - 50_000) as c_uint;
+        assert_eq!(unsafe { deflate(&mut strm, Z_FINISH) }, Z_STREAM_END);
+        let written = out.len() - strm.avail_out as usize;
+        assert!(decompress_zlib(&out[..written]) == data);
+        assert_eq!(unsafe { deflateEnd(&mut strm) }, Z_OK);
+    }
+
+    #[test]
+    fn capi_pending() {
+        let data = get_test_data();
+        let mut strm = new_stream();
+        assert_eq!(init(&mut strm, Z_DEFAULT_COMPRESSION, -15), Z_OK);
+        let mut out = [0; 100];
+        strm.next_in = data.as_ptr();
+        strm.avail_in = 5000;
+        strm.next_out = out.as_mut_ptr();
+        strm.avail_out = out.len() as c_uint;
+        assert_eq!(unsafe { deflate(&mut strm, Z_SYNC_FLUSH) }, Z_OK);
+        let mut pending = 0;
+        let mut bits = -1;
+        assert_eq!(
+            unsafe { deflatePending(&mut strm, &mut pending, &mut bits) },
+            Z_OK
+        );
+        // The output didn't fit, and the sync flush ends on a byte boundary.
+        assert!(pending > 0);
+        assert_eq!(bits, 0);
+
+        let mut rest = vec![0; pending as usize];
+        strm.next_out = rest.as_mut_ptr();
+        strm.avail_out = pending;
+        assert_eq!(unsafe { deflate(&mut strm, Z_SYNC_FLUSH) }, Z_OK);
+        assert_eq!(strm.avail_out, 0);
+        assert_eq!(
+            unsafe { deflatePending(&mut strm, &mut pending, std::ptr::null_mut()) },
+            Z_OK
+        );
+        assert_eq!(pending, 0);
+        unsafe { deflateEnd(&mut strm) };
+    }
+
+    #[test]
+    fn capi_invalid() {
+        let mut strm = new_stream();
+        assert_eq!(init(&mut strm, 10, 15), Z_STREAM_ERROR);
+        assert_eq!(init(&mut strm, 6, 7), Z_STREAM_ERROR);
+        assert_eq!(unsafe { deflate(&mut strm, Z_FINISH) }, Z_STREAM_ERROR);
+        let ret = unsafe {
+            deflateInit_(
+                &mut strm,
+                6,
+                b"2.0\0".as_ptr() as *const c_char,
+                std::mem::size_of::<z_stream>() as c_int,
+            )
+        };
+        assert_eq!(ret, Z_VERSION_ERROR);
+
+        // A dictionary can't be set once compression has started.
+        assert_eq!(init(&mut strm, 6, 15), Z_OK);
+        let mut out = [0; 64];
+        strm.next_in = b"abc".as_ptr();
+        strm.avail_in = 3;
+        strm.next_out = out.as_mut_ptr();
+        strm.avail_out = out.len() as c_uint;
+        assert_eq!(unsafe { deflate(&mut strm, Z_SYNC_FLUSH) }, Z_OK);
+        assert_eq!(
+            unsafe { deflateSetDictionary(&mut strm, b"abc".as_ptr(), 3) },
+            Z_STREAM_ERROR
+        );
+        assert_eq!(unsafe { deflateEnd(&mut strm) }, Z_DATA_ERROR);
+    }
+}
@@ -1,56 +1,47 @@
+use crate::allocator::{StateAllocator, StateVec};
+
 pub const WINDOW_SIZE: usize = 32768;
 pub const WINDOW_MASK: usize = WINDOW_SIZE - 1;
 #[cfg(test)]
 pub const HASH_BYTES: usize = 3;
+#[cfg(test)]
 const HASH_SHIFT: u16 = 5;
+#[cfg(test)]
 const HASH_MASK: u16 = WINDOW_MASK as u16;
-
-/// Helper struct to let us allocate both head and prev in the same block.
-struct Tables {
-    /// Starts of hash chains (in prev)
-    pub head: [u16; WINDOW_SIZE],
-    /// Link to previous occurence of this hash value
-    pub prev: [u16; WINDOW_SIZE],
+/// The largest number of bits of the hash used to index the heads of the chains.
+pub const MAX_HASH_BITS: u8 = 15;
+/// The smallest number of bits of the hash used to index the heads of the chains.
+pub const MIN_HASH_BITS: u8 = 8;
+/// The value of `base` when the tables are empty. Entries of zero are below it, so they are
+/// treated as not referring to anything.
+pub const INITIAL_BASE: u32 = 1;
+/// Once `base` grows past this, the entries are rebased so the positions don't overflow.
+pub const MAX_BASE: u32 = u32::MAX - (WINDOW_SIZE * 4) as u32;
+/// The amount `base` is advanced by when the table is reset, which is more than any position
+/// stored in the tables.
+pub const RESET_STRIDE: usize = WINDOW_SIZE * 4;
+
+/// Allocate a table of `size` entries that don't refer to any positions yet.
+///
+/// The positions are stored as `base + position`, where `base` grows by the number of bytes the
+/// input buffer is slid by, so sliding doesn't require going through the tables.
+fn create_table(allocator: &StateAllocator, size: usize) -> StateVec<u32> {
+    let mut table = allocator.vec_with_capacity(size);
+    table.resize(size, 0);
+    table
 }
 
-impl Default for Tables {
-    #[inline]
-    fn default() -> Tables {
-        Tables {
-            head: [0; WINDOW_SIZE],
-            prev: [0; WINDOW_SIZE],
-        }
-    }
-}
-
-impl Tables {
-    #[inline]
-    fn fill_prev(&mut self) {
-        self.prev.copy_from_slice(&self.head);
-    }
-}
-
-/// Create and box the hash chains.
-fn create_tables() -> Box<Tables> {
-    // Using default here is a trick to get around the lack of box syntax on stable rust.
-    //
-    // Box::new([0u16,n]) ends up creating an temporary array on the stack which is not optimised
-    // but using default, which simply calls `box value` internally allows us to get around this.
-    //
-    // We could use vec instead, but using a boxed array helps the compiler optimise
-    // away bounds checks as `n & WINDOW_MASK < WINDOW_SIZE` will always be true.
-    let mut t: Box<Tables> = Box::default();
-
-    for (n, b) in t.head.iter_mut().enumerate() {
-        *b = n as u16;
-    }
-
-    t.fill_prev();
-
-    t
-}
+/// A function computing a new rolling hash value from the previous hash value and the next byte
+/// of input.
+///
+/// The hash value should only depend on the last three bytes that have been inserted (or four
+/// when hashing four bytes), as these are the bytes that have to be equal for a match to be
+/// usable. Only the lowest 15 bits of the returned value are used, or fewer if the table is
+/// smaller.
+pub type HashFunction = fn(current_hash: u16, to_insert: u8) -> u16;
 
 /// Returns a new hash value based on the previous value and the next byte
+#[cfg(test)]
 #[inline]
 pub fn update_hash(current_hash: u16, to_insert: u8) -> u16 {
     update_hash_conf(current_hash, to_insert, HASH_SHIFT, HASH_MASK)
@@ -61,28 +52,142 @@ fn update_hash_conf(current_hash: u16, to_insert: u8, shift: u16, mask: u16) ->
     ((current_hash << shift) ^ (u16::from(to_insert))) & mask
 }
 
-#[inline]
-fn reset_array(arr: &mut [u16; WINDOW_SIZE]) {
-    for (n, b) in arr.iter_mut().enumerate() {
-        *b = n as u16;
+/// Shift the stored positions in `arr` down by `amount`, clearing the ones that would end up
+/// below `INITIAL_BASE`.
+pub fn rebase_array(arr: &mut [u32], amount: u32) {
+    for b in arr.iter_mut() {
+        *b = b.saturating_sub(amount);
     }
 }
 
+#[derive(Clone)]
 pub struct ChainedHashTable {
-    // Current running hash value of the last 3 bytes
+    // Current running hash value of the last 3 (or 4) bytes
     current_hash: u16,
-    // Hash chains.
-    c: Box<Tables>,
+    // Starts of the hash chains (in prev), indexed by the hash value.
+    head: StateVec<u32>,
+    // Hash chains, linking each position to the previous one with the same hash. Indexed by the
+    // position modulo the window size, as older positions can't be matched against anyhow.
+    prev: StateVec<u32>,
+    // Custom hash function to use instead of the default one, if any.
+    hash_function: Option<HashFunction>,
+    // The maximum distance of a match, which is also the size of the chains.
+    window_size: usize,
+    // The number of bytes hashed for each position, the number of bits of the hash that are
+    // used, and the shift and mask used by the default hash function for those numbers.
+    hash_bytes: usize,
+    hash_bits: u8,
+    hash_shift: u16,
+    hash_mask: u16,
+    // The value added to the positions stored in the chains. Positions stored below this have
+    // been slid out of the buffer.
+    base: u32,
     // Used for testing
     // count: DebugCounter,
 }
 
 impl ChainedHashTable {
+    #[cfg(test)]
     pub fn new() -> ChainedHashTable {
-        ChainedHashTable {
+        ChainedHashTable::new_in(&StateAllocator::global())
+    }
+
+    /// Create a new hash table, allocating the hash chains with `allocator`.
+    pub fn new_in(allocator: &StateAllocator) -> ChainedHashTable {
+        let mut table = ChainedHashTable {
             current_hash: 0,
-            c: create_tables(),
+            head: create_table(allocator, 1 << MAX_HASH_BITS),
+            prev: create_table(allocator, WINDOW_SIZE),
+            hash_function: None,
+            window_size: WINDOW_SIZE,
+            hash_bytes: 3,
+            hash_bits: MAX_HASH_BITS,
+            hash_shift: 0,
+            hash_mask: 0,
+            base: INITIAL_BASE,
             //count: DebugCounter::default(),
+        };
+        table.update_hash_parameters();
+        table
+    }
+
+    /// Update the shift and mask of the default hash function after the number of bytes hashed
+    /// or the number of bits used has changed.
+    fn update_hash_parameters(&mut self) {
+        self.hash_mask = (1 << self.hash_bits) - 1;
+        // Shift the bytes so the oldest one is shifted out when `hash_bytes` more are inserted.
+        self.hash_shift = u16::from(self.hash_bits).div_ceil(self.hash_bytes as u16);
+    }
+
+    /// Use only the lowest `hash_bits` bits of the hash values, which has to be between
+    /// `MIN_HASH_BITS` and `MAX_HASH_BITS`. Fewer bits make the table smaller, at the cost of
+    /// more unrelated positions ending up in the same chain.
+    ///
+    /// This should not be changed after values have been added to the table.
+    pub fn set_hash_bits(&mut self, hash_bits: u8) {
+        debug_assert!((MIN_HASH_BITS..=MAX_HASH_BITS).contains(&hash_bits));
+        if hash_bits != self.hash_bits {
+            self.hash_bits = hash_bits;
+            self.update_hash_parameters();
+            let size = 1 << hash_bits;
+            self.head.clear();
+            self.head.resize(size, 0);
+            self.head.shrink_to_fit();
+        }
+    }
+
+    /// Use the provided hash function rather than the default one.
+    ///
+    /// This should not be changed after values have been added to the table.
+    pub fn set_hash_function(&mut self, hash_function: Option<HashFunction>) {
+        self.hash_function = hash_function;
+    }
+
+    /// Limit the distance of the positions found in the chains to `window_size`, which has to be
+    /// a power of two no larger than `WINDOW_SIZE`. The chains are reallocated to the window
+    /// size, so a smaller window uses less memory.
+    ///
+    /// This should not be changed after values have been added to the table.
+    pub fn set_window_size(&mut self, window_size: usize) {
+        debug_assert!(window_size.is_power_of_two() && window_size <= WINDOW_SIZE);
+        if window_size != self.window_size {
+            self.window_size = window_size;
+            self.prev.clear();
+            self.prev.resize(window_size, 0);
+            self.prev.shrink_to_fit();
+        }
+    }
+
+    /// The maximum distance of a match.
+    #[inline]
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    /// Hash four bytes rather than three for each position if `four_bytes` is true, so that only
+    /// matches of at least four bytes are found.
+    ///
+    /// This should not be changed after values have been added to the table.
+    pub fn set_hash_four_bytes(&mut self, four_bytes: bool) {
+        self.hash_bytes = if four_bytes { 4 } else { 3 };
+        self.update_hash_parameters();
+    }
+
+    /// The number of bytes following a position that have to be available before the position
+    /// can be hashed.
+    #[inline]
+    pub fn hash_lookahead(&self) -> usize {
+        self.hash_bytes - 1
+    }
+
+    /// Returns a new hash value based on the previous value and the next byte using the hash
+    /// function this table is configured with.
+    #[inline]
+    pub fn next_hash(&self, current_hash: u16, to_insert: u8) -> u16 {
+        match self.hash_function {
+            None => update_hash_conf(current_hash, to_insert, self.hash_shift, self.hash_mask),
+            // Mask the value to make sure it's within the bounds of the table.
+            Some(f) => f(current_hash, to_insert) & self.hash_mask,
         }
     }
 
@@ -94,23 +199,30 @@ impl ChainedHashTable {
         t
     }
 
+    /// The number of bytes of heap memory used by the hash chains.
+    pub fn heap_size(&self) -> usize {
+        (self.prev.capacity() + self.head.capacity()) * std::mem::size_of::<u32>()
+    }
+
     /// Resets the hash value and hash chains
+    ///
+    /// Rather than clearing the tables, `base` is moved past every position stored in them, so
+    /// they are all treated as slid out of the buffer. This makes resetting cheap when the table
+    /// is reused for many small inputs, with the tables only being gone through when they are
+    /// rebased.
     pub fn reset(&mut self) {
         self.current_hash = 0;
-        reset_array(&mut self.c.head);
-        {
-            let h = self.c.head;
-            let mut c = self.c.prev;
-            c[..].copy_from_slice(&h[..]);
-        }
+        self.slide(RESET_STRIDE);
         /*if cfg!(debug_assertions) {
             self.count.reset();
         }*/
     }
 
-    pub fn add_initial_hash_values(&mut self, v1: u8, v2: u8) {
-        self.current_hash = update_hash(self.current_hash, v1);
-        self.current_hash = update_hash(self.current_hash, v2);
+    /// Start the running hash with the bytes of the lookahead at the start of the input.
+    pub fn add_initial_hash_values(&mut self, values: &[u8]) {
+        for &v in values {
+            self.current_hash = self.next_hash(self.current_hash, v);
+        }
     }
 
     /// Insert a byte into the hash table
@@ -129,7 +241,7 @@ impl ChainedHashTable {
         );
         // Storing the hash in a temporary variable here makes the compiler avoid the
         // bounds checks in this function.
-        let new_hash = update_hash(self.current_hash, value);
+        let new_hash = self.next_hash(self.current_hash, value);
 
         self.add_with_hash(position, new_hash);
 
@@ -150,18 +262,26 @@ impl ChainedHashTable {
             self.count.add(1);
         }*/
 
-        self.c.prev[position & WINDOW_MASK] = self.c.head[hash as usize];
+        self.prev[position & (self.window_size - 1)] = self.head[hash as usize];
+        self.head[hash as usize] = self.base + position as u32;
+    }
 
-        // Ignoring any bits over 16 here is deliberate, as we only concern ourselves about
-        // where in the buffer (which is 64k bytes) we are referring to.
-        self.c.head[hash as usize] = position as u16;
+    /// Convert a stored value to a position in the buffer, if it hasn't been slid out of it.
+    #[inline]
+    fn to_position(&self, stored: u32) -> Option<u16> {
+        if stored >= self.base {
+            Some((stored - self.base) as u16)
+        } else {
+            None
+        }
     }
 
     // Get the head of the hash chain for the current hash value
     #[cfg(test)]
     #[inline]
     pub fn current_head(&self) -> u16 {
-        self.c.head[self.current_hash as usize]
+        self.to_position(self.head[self.current_hash as usize])
+            .unwrap_or(0)
     }
 
     #[inline]
@@ -169,9 +289,13 @@ impl ChainedHashTable {
         self.current_hash
     }
 
+    /// Get the previous position in the chain of the position `bytes`.
+    ///
+    /// If there is none, `bytes` itself is returned, which ends the chain.
     #[inline]
     pub fn get_prev(&self, bytes: usize) -> u16 {
-        self.c.prev[bytes & WINDOW_MASK]
+        self.to_position(self.prev[bytes & (self.window_size - 1)])
+            .unwrap_or(bytes as u16)
     }
 
     #[cfg(test)]
@@ -193,29 +317,23 @@ impl ChainedHashTable {
         smallest_pos
     }
 
-    #[inline]
-    fn slide_value(b: u16, pos: u16, bytes: u16) -> u16 {
-        if b >= bytes {
-            b - bytes
-        } else {
-            pos
-        }
-    }
-
-    #[inline]
-    fn slide_table(table: &mut [u16; WINDOW_SIZE], bytes: u16) {
-        for (n, b) in table.iter_mut().enumerate() {
-            *b = ChainedHashTable::slide_value(*b, n as u16, bytes);
-        }
-    }
-
+    /// Slide the positions in the chains back by `bytes`, to match the input buffer being slid.
+    ///
+    /// Positions that end up before the start of the buffer no longer refer to anything. This
+    /// only changes the base the positions are stored relative to, except for every few GiB of
+    /// input where the stored values have to be rebased to avoid overflowing.
     pub fn slide(&mut self, bytes: usize) {
         /*if cfg!(debug_assertions) && bytes != WINDOW_SIZE {
             // This should only happen in tests in this file.
             self.count.reset();
         }*/
-        ChainedHashTable::slide_table(&mut self.c.head, bytes as u16);
-        ChainedHashTable::slide_table(&mut self.c.prev, bytes as u16);
+        self.base += bytes as u32;
+        if self.base > MAX_BASE {
+            let amount = self.base - INITIAL_BASE;
+            rebase_array(&mut self.head, amount);
+            rebase_array(&mut self.prev, amount);
+            self.base = INITIAL_BASE;
+        }
     }
 }
 
@@ -257,7 +375,8 @@ mod test {
 
         // Test that the positions in the chain are valid
         let mut prev_value = hash_table.get_prev(hash_table.current_head() as usize) as usize;
-        let mut count = 0;
+        // Count the head of the chain as well.
+        let mut count = 1;
         let mut current = hash_table.current_head() as usize;
         while current != prev_value {
             count += 1;
@@ -276,9 +395,10 @@ mod test {
         test_data.extend(0u8..255);
         test_data.extend(255u8..0);
         let hash_table = filled_hash_table(&test_data);
-        let prev_pos = hash_table.get_prev(hash_table.current_head() as usize);
+        let head = hash_table.current_head();
+        let prev_pos = hash_table.get_prev(head as usize);
         // Since all sequences in the input are unique, there shouldn't be any previous values.
-        assert_eq!(prev_pos, hash_table.current_hash());
+        assert_eq!(prev_pos, head);
     }
 
     #[test]
@@ -304,11 +424,16 @@ mod test {
         hash_table.slide(window_size);
 
         {
-            let max_head = hash_table.c.head.iter().max().unwrap();
+            let max_head = hash_table
+                .head
+                .iter()
+                .filter_map(|&h| hash_table.to_position(h))
+                .max()
+                .unwrap();
             // After sliding there should be no hashes referring to values
             // higher than the window size
-            assert!(*max_head < window_size16);
-            assert!(*max_head > 0);
+            assert!(max_head < window_size16);
+            assert!(max_head > 0);
             let pos = hash_table.get_prev(hash_table.current_head() as usize);
             // There should be a previous occurence since we inserted the data 3 times
             assert!(pos < window_size16);
@@ -321,8 +446,13 @@ mod test {
 
         // There should hashes referring to values in the upper part of the input window
         // at this point
-        let max_prev = hash_table.c.prev.iter().max().unwrap();
-        assert!(*max_prev > window_size16);
+        let max_prev = hash_table
+            .prev
+            .iter()
+            .filter_map(|&p| hash_table.to_position(p))
+            .max()
+            .unwrap();
+        assert!(max_prev > window_size16);
 
         let mut pos = hash_table.current_head();
         // There should be a previous occurence since we inserted the data 3 times
@@ -337,15 +467,79 @@ mod test {
         }
     }
 
+    #[test]
+    fn custom_hash_function() {
+        fn hash(_: u16, to_insert: u8) -> u16 {
+            // Deliberately returning values larger than the table.
+            0xFF00 | u16::from(to_insert)
+        }
+        let mut t = ChainedHashTable::new();
+        t.set_hash_function(Some(hash));
+        t.add_initial_hash_values(&[1, 2]);
+        assert_eq!(t.current_hash(), 0x7F02);
+        t.add_hash_value(0, 7);
+        assert_eq!(t.current_hash(), 0x7F07);
+        assert_eq!(t.current_head(), 0);
+    }
+
+    #[test]
+    fn slide_rebase() {
+        let window_size = super::WINDOW_SIZE;
+        let mut t = ChainedHashTable::new();
+        // Start just below the point where the stored positions are rebased.
+        t.base = super::MAX_BASE - window_size as u32 + 1;
+        t.add_with_hash(100, 1);
+        t.add_with_hash(window_size + 100, 1);
+        t.add_with_hash(window_size + 200, 1);
+        t.slide(window_size);
+        assert_eq!(t.base, super::INITIAL_BASE);
+
+        // Only the positions that are still in the buffer should be left after rebasing.
+        t.add_with_hash(window_size + 300, 1);
+        assert_eq!(t.get_prev(window_size + 300), 200);
+        assert_eq!(t.get_prev(200), 100);
+        assert_eq!(t.get_prev(100), 100);
+    }
+
+    #[test]
+    fn reset_forgets_positions() {
+        let mut t = ChainedHashTable::new();
+        for _ in 0..(u32::MAX as usize / super::RESET_STRIDE + 2) {
+            t.add_with_hash(100, 1);
+            t.add_with_hash(200, 1);
+            assert_eq!(t.get_prev(200), 100);
+            t.reset();
+            t.set_hash(1);
+            assert_eq!(t.current_head(), 0);
+            assert_eq!(t.get_prev(200), 200);
+        }
+    }
+
     #[test]
     /// Ensure that the initial hash values are correct.
     fn initial_chains() {
         let t = ChainedHashTable::new();
-        for (n, &b) in t.c.head.iter().enumerate() {
-            assert_eq!(n, b as usize);
+        for &b in t.head.iter() {
+            assert_eq!(t.to_position(b), None);
         }
-        for (n, &b) in t.c.prev.iter().enumerate() {
-            assert_eq!(n, b as usize);
+        for n in 0..super::WINDOW_SIZE {
+            assert_eq!(t.get_prev(n), n as u16);
         }
     }
+
+    #[test]
+    fn window_sized_chains() {
+        let mut t = ChainedHashTable::new();
+        let full = t.heap_size();
+        t.set_window_size(512);
+        assert_eq!(full - t.heap_size(), (super::WINDOW_SIZE - 512) * 4);
+        // Positions are linked within the smaller window, which also wraps at its size.
+        t.add_with_hash(1000, 1);
+        t.add_with_hash(1300, 1);
+        t.add_with_hash(1600, 1);
+        assert_eq!(t.get_prev(1600), 1300);
+        assert_eq!(t.get_prev(1300), 1000);
+        t.set_window_size(super::WINDOW_SIZE);
+        assert_eq!(t.heap_size(), full);
+    }
 }
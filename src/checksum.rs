@@ -30,6 +30,7 @@ impl<'a> RollingChecksum for &'a mut NoChecksum {
     }
 }
 
+#[derive(Clone)]
 pub struct Adler32Checksum {
     adler32: RollingAdler32,
 }
@@ -69,3 +70,262 @@ impl<'a> RollingChecksum for &'a mut Adler32Checksum {
         self.adler32.hash()
     }
 }
+
+/// The modulus used by Adler-32.
+const ADLER32_BASE: u32 = 65521;
+
+/// Compute the Adler-32 checksum of the concatenation of two pieces of data from the checksums
+/// of each piece and the length of the second one.
+pub fn adler32_combine(adler1: u32, adler2: u32, len2: u64) -> u32 {
+    let base = u64::from(ADLER32_BASE);
+    let rem = len2 % base;
+    let a1 = u64::from(adler1 & 0xFFFF);
+    let b1 = u64::from(adler1 >> 16);
+    let a2 = u64::from(adler2 & 0xFFFF);
+    let b2 = u64::from(adler2 >> 16);
+
+    // Both first sums start at 1, which is only counted once in the combined sum. The first sum of
+    // the first piece (without the initial 1) is added to the second sum once per byte of the
+    // second piece.
+    let a = (a1 + a2 + base - 1) % base;
+    let b = (rem * a1 + b1 + b2 + base - rem) % base;
+    (b << 16 | a) as u32
+}
+
+/// Multiply a 32x32 matrix over GF(2) by a vector.
+#[cfg(feature = "gzip")]
+fn gf2_matrix_times(matrix: &[u32; 32], mut vector: u32) -> u32 {
+    let mut sum = 0;
+    let mut row = matrix.iter();
+    while vector != 0 {
+        let r = row.next().unwrap();
+        if vector & 1 != 0 {
+            sum ^= r;
+        }
+        vector >>= 1;
+    }
+    sum
+}
+
+/// Square a 32x32 matrix over GF(2).
+#[cfg(feature = "gzip")]
+fn gf2_matrix_square(matrix: &[u32; 32]) -> [u32; 32] {
+    let mut square = [0; 32];
+    for (s, &m) in square.iter_mut().zip(matrix.iter()) {
+        *s = gf2_matrix_times(matrix, m);
+    }
+    square
+}
+
+/// The reversed CRC-32 polynomial used by gzip, zip and PNG.
+const CRC32_POLYNOMIAL: u32 = 0xEDB8_8320;
+
+/// Lookup tables for computing CRC-32 eight bytes at a time.
+///
+/// The first table gives the CRC of a single byte, and table `n` the CRC of a byte followed by
+/// `n` zero bytes.
+static CRC32_TABLES: [[u32; 256]; 8] = crc32_tables();
+
+const fn crc32_tables() -> [[u32; 256]; 8] {
+    let mut tables = [[0; 256]; 8];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        tables[0][n] = crc;
+        n += 1;
+    }
+    let mut table = 1;
+    while table < 8 {
+        let mut n = 0;
+        while n < 256 {
+            let prev = tables[table - 1][n];
+            tables[table][n] = (prev >> 8) ^ tables[0][(prev & 0xFF) as usize];
+            n += 1;
+        }
+        table += 1;
+    }
+    tables
+}
+
+/// A streaming CRC-32 checksum, as used in the gzip trailer and zip files.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{crc32, Crc32};
+///
+/// let mut crc = Crc32::new();
+/// crc.update(b"Some data, ");
+/// crc.update(b"in two parts");
+/// assert_eq!(crc.sum(), crc32(b"Some data, in two parts"));
+/// assert_eq!(crc.amount(), 23);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Crc32 {
+    crc: u32,
+    amount: u64,
+}
+
+impl Crc32 {
+    /// Create a checksum of no data.
+    pub fn new() -> Crc32 {
+        Crc32::default()
+    }
+
+    /// Add `data` to the checksum.
+    pub fn update(&mut self, data: &[u8]) {
+        let tables = &CRC32_TABLES;
+        let mut crc = !self.crc;
+        let mut chunks = data.chunks_exact(8);
+        for chunk in &mut chunks {
+            let low = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) ^ crc;
+            let high = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+            crc = tables[7][(low & 0xFF) as usize]
+                ^ tables[6][((low >> 8) & 0xFF) as usize]
+                ^ tables[5][((low >> 16) & 0xFF) as usize]
+                ^ tables[4][(low >> 24) as usize]
+                ^ tables[3][(high & 0xFF) as usize]
+                ^ tables[2][((high >> 8) & 0xFF) as usize]
+                ^ tables[1][((high >> 16) & 0xFF) as usize]
+                ^ tables[0][(high >> 24) as usize];
+        }
+        for &byte in chunks.remainder() {
+            crc = (crc >> 8) ^ tables[0][((crc ^ u32::from(byte)) & 0xFF) as usize];
+        }
+        self.crc = !crc;
+        self.amount += data.len() as u64;
+    }
+
+    /// The checksum of the data added so far.
+    pub fn sum(&self) -> u32 {
+        self.crc
+    }
+
+    /// The number of bytes added so far.
+    pub fn amount(&self) -> u64 {
+        self.amount
+    }
+
+    /// Reset the checksum to the checksum of no data.
+    pub fn reset(&mut self) {
+        *self = Crc32::new();
+    }
+}
+
+/// Compute the CRC-32 checksum of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// Compute the CRC-32 of the concatenation of two pieces of data from the CRCs of each piece and
+/// the length of the second one.
+///
+/// This is done the same way as in zlib, by applying the operator for appending `len2` zero bytes
+/// to `crc1`, computed by repeated squaring.
+#[cfg(feature = "gzip")]
+pub fn crc32_combine(crc1: u32, crc2: u32, mut len2: u64) -> u32 {
+    if len2 == 0 {
+        return crc1;
+    }
+
+    // The operator for one zero bit.
+    let mut operator = [0; 32];
+    operator[0] = 0xEDB8_8320;
+    for (n, o) in operator.iter_mut().enumerate().skip(1) {
+        *o = 1 << (n - 1);
+    }
+    // Square it three times to get the operator for one zero byte.
+    for _ in 0..3 {
+        operator = gf2_matrix_square(&operator);
+    }
+
+    let mut crc = crc1;
+    loop {
+        if len2 & 1 != 0 {
+            crc = gf2_matrix_times(&operator, crc);
+        }
+        len2 >>= 1;
+        if len2 == 0 {
+            break;
+        }
+        operator = gf2_matrix_square(&operator);
+    }
+    crc ^ crc2
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn adler32_combine_matches() {
+        let data = b"Some data to take the checksum of, split in two pieces";
+        let checksum = |data: &[u8]| {
+            let mut c = Adler32Checksum::new();
+            c.update_from_slice(data);
+            c.current_hash()
+        };
+        for split in &[0, 1, 20, data.len()] {
+            let (first, second) = data.split_at(*split);
+            assert_eq!(
+                adler32_combine(checksum(first), checksum(second), second.len() as u64),
+                checksum(data)
+            );
+        }
+    }
+
+    #[test]
+    fn crc32_check_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414F_A339
+        );
+
+        // Splitting the input at any point gives the same result.
+        let data = crate::test_utils::get_test_data();
+        let whole = crc32(&data[..1000]);
+        for split in 0..20 {
+            let mut crc = Crc32::new();
+            crc.update(&data[..split]);
+            crc.update(&data[split..1000]);
+            assert_eq!(crc.sum(), whole);
+            assert_eq!(crc.amount(), 1000);
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn crc32_matches_gzip_header() {
+        use gzip_header::Crc;
+        let data = crate::test_utils::get_test_data();
+        let mut c = Crc::new();
+        c.update(&data);
+        assert_eq!(crc32(&data), c.sum());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn crc32_combine_matches() {
+        let data = crate::test_utils::get_test_data();
+        for split in &[0, 1, 1000, data.len()] {
+            let (first, second) = data.split_at(*split);
+            assert_eq!(
+                crc32_combine(crc32(first), crc32(second), second.len() as u64),
+                crc32(&data)
+            );
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Huffman code lengths supplied by the caller for the dynamic blocks of an encoder.
+//!
+//! The codes of a dynamic block are normally built from the frequencies of the symbols in that
+//! block. Formats embedding deflate data may know the distribution of the symbols better than
+//! the statistics of a single block, and can set the code lengths to use directly instead.
+use std::mem;
+
+use crate::error::Error;
+use crate::huffman_table::{MAX_CODE_LENGTH, NUM_DISTANCE_CODES, NUM_LITERALS_AND_LENGTHS};
+use crate::output_writer::FrequencyType;
+
+/// The position of the end of block symbol, which every block uses.
+const END_OF_BLOCK: usize = 256;
+
+/// Huffman code lengths of the literal/length and distance symbols to use for dynamic blocks.
+///
+/// The lengths have to describe valid deflate codes: No length can be longer than 15 bits, the
+/// end of block symbol needs a code, and each table has to be a complete code, apart from a
+/// distance table using one code of 1 bit or none at all. Blocks using a symbol that has no code
+/// in the tables get codes built from their own frequencies instead, as usual.
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::io;
+/// #
+/// # fn try_main() -> io::Result<Vec<u8>> {
+/// #
+/// use std::io::Write;
+///
+/// use deflate::{CodeLengths, Compression};
+/// use deflate::write::DeflateEncoder;
+///
+/// // Codes for the symbols `a` and `b` and the end of block symbol.
+/// let mut literal_length = [0; 257];
+/// literal_length[usize::from(b'a')] = 1;
+/// literal_length[usize::from(b'b')] = 2;
+/// literal_length[256] = 2;
+/// let lengths = CodeLengths::new(&literal_length, &[]).unwrap();
+///
+/// let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+/// encoder.set_code_lengths(Some(lengths));
+/// encoder.write_all(b"abaabbaaab")?;
+/// let compressed_data = encoder.finish()?;
+/// # Ok(compressed_data)
+/// #
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct CodeLengths {
+    literal_length: Vec<u8>,
+    distance: Vec<u8>,
+}
+
+impl CodeLengths {
+    /// Create code lengths from the lengths of the 286 literal/length symbols and the 30
+    /// distance symbols.
+    ///
+    /// Shorter slices are padded with zeroes, which means the symbols have no code.
+    ///
+    /// Returns `Error::InvalidOptions` if the lengths don't describe valid deflate codes.
+    pub fn new(literal_length: &[u8], distance: &[u8]) -> Result<CodeLengths, Error> {
+        if literal_length.len() > NUM_LITERALS_AND_LENGTHS || distance.len() > NUM_DISTANCE_CODES {
+            return Err(Error::InvalidOptions("Too many code lengths"));
+        }
+        if literal_length
+            .iter()
+            .chain(distance)
+            .any(|&l| usize::from(l) > MAX_CODE_LENGTH)
+        {
+            return Err(Error::InvalidOptions(
+                "Code lengths can't be longer than 15",
+            ));
+        }
+        if literal_length.get(END_OF_BLOCK).map_or(true, |&l| l == 0) {
+            return Err(Error::InvalidOptions(
+                "The end of block symbol needs a code length",
+            ));
+        }
+        if !is_valid_code(literal_length) || !is_valid_code(distance) {
+            return Err(Error::InvalidOptions(
+                "The code lengths don't describe a complete code",
+            ));
+        }
+
+        let mut lengths = CodeLengths {
+            literal_length: vec![0; NUM_LITERALS_AND_LENGTHS],
+            distance: vec![0; NUM_DISTANCE_CODES],
+        };
+        lengths.literal_length[..literal_length.len()].copy_from_slice(literal_length);
+        lengths.distance[..distance.len()].copy_from_slice(distance);
+        Ok(lengths)
+    }
+
+    /// The code lengths of the literal/length symbols.
+    pub fn literal_length(&self) -> &[u8] {
+        &self.literal_length
+    }
+
+    /// The code lengths of the distance symbols.
+    pub fn distance(&self) -> &[u8] {
+        &self.distance
+    }
+
+    /// The amount of heap memory used by the code lengths.
+    pub fn heap_size(&self) -> usize {
+        (self.literal_length.capacity() + self.distance.capacity()) * mem::size_of::<u8>()
+    }
+
+    /// Whether every symbol used in a block with the provided frequencies has a code.
+    pub fn covers(&self, l_freqs: &[FrequencyType], d_freqs: &[FrequencyType]) -> bool {
+        let covers_table = |freqs: &[FrequencyType], lengths: &[u8]| {
+            freqs
+                .iter()
+                .enumerate()
+                .all(|(n, &f)| f == 0 || lengths.get(n).is_some_and(|&l| l != 0))
+        };
+        covers_table(l_freqs, &self.literal_length) && covers_table(d_freqs, &self.distance)
+    }
+
+    /// Copy the code lengths to the tables of an encoder, clearing the rest of them.
+    pub fn copy_to(&self, l_lengths: &mut [u8], d_lengths: &mut [u8]) {
+        for (dest, source) in [
+            (l_lengths, &self.literal_length),
+            (d_lengths, &self.distance),
+        ] {
+            dest.iter_mut().for_each(|l| *l = 0);
+            dest[..source.len()].copy_from_slice(source);
+        }
+    }
+}
+
+/// Check that `lengths` describes a complete prefix code, or one that uses a single code of one
+/// bit, or no codes at all, which decoders also accept.
+fn is_valid_code(lengths: &[u8]) -> bool {
+    let used = lengths.iter().filter(|&&l| l != 0);
+    match used.clone().count() {
+        0 => true,
+        1 => used.clone().all(|&l| l == 1),
+        _ => {
+            let kraft_sum: u32 = used.map(|&l| 1 << (MAX_CODE_LENGTH - usize::from(l))).sum();
+            kraft_sum == 1 << MAX_CODE_LENGTH
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::huffman_table::FIXED_CODE_LENGTHS;
+    use crate::test_utils::decompress_to_end;
+    use crate::write::DeflateEncoder;
+    use crate::{BlockKind, CompressionOptions};
+    use std::io::Write;
+
+    #[test]
+    fn validate_code_lengths() {
+        // The fixed code is complete with all 288 symbols, but not without the two unused ones.
+        assert!(CodeLengths::new(&FIXED_CODE_LENGTHS[..], &[5; 30]).is_err());
+        assert!(CodeLengths::new(&FIXED_CODE_LENGTHS[..286], &[5; 30]).is_err());
+
+        let mut literal_length = [0; 286];
+        literal_length[..256].iter_mut().for_each(|l| *l = 9);
+        literal_length[256..272].iter_mut().for_each(|l| *l = 5);
+        assert!(CodeLengths::new(&literal_length, &[]).is_ok());
+        assert!(CodeLengths::new(&literal_length, &[1]).is_ok());
+        assert!(CodeLengths::new(&literal_length, &[0, 2]).is_err());
+        assert!(CodeLengths::new(&literal_length, &[1, 1]).is_ok());
+        assert!(CodeLengths::new(&literal_length, &[1, 2, 2]).is_ok());
+        assert!(CodeLengths::new(&literal_length, &[1, 1, 1]).is_err());
+        assert!(CodeLengths::new(&literal_length, &[16, 0]).is_err());
+        assert!(CodeLengths::new(&literal_length, &[1; 31]).is_err());
+
+        // No end of block code.
+        literal_length[256] = 0;
+        literal_length[272] = 5;
+        assert!(CodeLengths::new(&literal_length, &[]).is_err());
+        assert!(CodeLengths::new(&literal_length[..256], &[]).is_err());
+    }
+
+    #[test]
+    fn code_lengths_cover_block() {
+        let lengths = CodeLengths::new(&[1, 0, 2, 0, 0, 0, 0], &[]);
+        assert!(lengths.is_err());
+
+        let mut literal_length = [0; 257];
+        literal_length[0] = 1;
+        literal_length[2] = 2;
+        literal_length[256] = 2;
+        let lengths = CodeLengths::new(&literal_length, &[1]).unwrap();
+        let mut l_freqs = [0; 286];
+        l_freqs[0] = 4;
+        l_freqs[256] = 1;
+        assert!(lengths.covers(&l_freqs, &[3]));
+        assert!(lengths.covers(&l_freqs, &[]));
+        assert!(!lengths.covers(&l_freqs, &[0, 1]));
+        l_freqs[1] = 1;
+        assert!(!lengths.covers(&l_freqs, &[]));
+    }
+
+    #[test]
+    fn code_lengths_used_for_blocks() {
+        // The message uses 'a' more than 'b', but the supplied lengths favour 'b'.
+        let mut message = vec![b'a'; 400];
+        message.extend_from_slice(&[b'b'; 200]);
+        let mut literal_length = [0; 257];
+        literal_length[usize::from(b'a')] = 2;
+        literal_length[usize::from(b'b')] = 1;
+        literal_length[256] = 2;
+        let lengths = CodeLengths::new(&literal_length, &[]).unwrap();
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), CompressionOptions::huffman_only());
+        encoder.set_code_lengths(Some(lengths.clone()));
+        encoder.set_record_block_codes(true);
+        encoder.write_all(&message).unwrap();
+        encoder.flush().unwrap();
+        // This block uses a symbol that has no code, so the lengths are generated for it.
+        encoder.write_all(b"abcabc").unwrap();
+        encoder.write_all(&message).unwrap();
+        encoder.flush().unwrap();
+        encoder.set_code_lengths(None);
+        encoder.write_all(&message).unwrap();
+        encoder.flush().unwrap();
+        let codes = encoder.take_block_codes();
+        let mut expected = message.clone();
+        expected.extend_from_slice(b"abcabc");
+        expected.extend_from_slice(&message);
+        expected.extend_from_slice(&message);
+        assert!(decompress_to_end(&encoder.finish().unwrap()) == expected);
+
+        let dynamic: Vec<_> = codes
+            .iter()
+            .filter(|c| c.kind == BlockKind::Dynamic)
+            .collect();
+        assert_eq!(dynamic.len(), 3);
+        assert_eq!(dynamic[0].literal_length_lengths[..257], literal_length[..]);
+        for codes in &dynamic[1..] {
+            let lengths = &codes.literal_length_lengths;
+            assert!(lengths[usize::from(b'a')] < lengths[usize::from(b'b')]);
+        }
+    }
+}
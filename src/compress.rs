@@ -1,15 +1,25 @@
+use std::cmp;
 use std::io;
 use std::io::Write;
 
 use crate::bitstream::LsbWriter;
-use crate::deflate_state::DeflateState;
+use crate::block_codes::BlockKind;
+use crate::code_lengths::CodeLengths;
+use crate::compression_options::SpecialOptions;
+use crate::deflate_state::{DeflateState, LengthBuffers};
 use crate::encoder_state::EncoderState;
+use crate::error::{Error, MISSING_WRITER};
 use crate::huffman_lengths::{gen_huffman_lengths, write_huffman_lengths, BlockType};
+use crate::input_buffer::InputWindow;
 use crate::lz77::{lz77_compress_block, LZ77Status};
 use crate::lzvalue::LZValue;
-use crate::stored_block::{compress_block_stored, write_stored_header, MAX_STORED_BLOCK_LENGTH};
+use crate::output_writer::DynamicWriter;
+use crate::priors::FrequencyPriors;
+use crate::stored_block::{
+    compress_block_stored, write_stored_header, MAX_STORED_BLOCK_LENGTH, STORED_ONLY_BLOCK_LENGTH,
+};
 
-const LARGEST_OUTPUT_BUF_SIZE: usize = 1024 * 32;
+pub const LARGEST_OUTPUT_BUF_SIZE: usize = 1024 * 32;
 
 /// Flush mode to use when compressing input received in multiple steps.
 ///
@@ -22,8 +32,13 @@ pub enum Flush {
     // outputting all pending data, and then outputs an empty stored block.
     // (That is, the block header indicating a stored block followed by `0000FFFF`).
     Sync,
-    _Partial,
-    _Block,
+    // Finish compressing the pending data and end the current block, followed by an empty fixed
+    // block, so all the data can be decoded from the whole bytes that are output. The output is not
+    // padded to a byte boundary. Corresponds to Z_PARTIAL_FLUSH in zlib.
+    Partial,
+    // Finish compressing and outputting the pending data, ending the current block, without
+    // padding the output to a byte boundary. Corresponds to Z_BLOCK in zlib.
+    Block,
     _Full,
     // Finish compressing and output all remaining input.
     Finish,
@@ -42,10 +57,11 @@ pub fn flush_to_bitstream(buffer: &[LZValue], state: &mut EncoderState) {
 ///
 /// Currently only used in tests.
 #[cfg(test)]
-pub fn compress_data_fixed(input: &[u8]) -> Vec<u8> {
+pub fn compress_data_fixed(input: &[u8]) -> crate::allocator::StateVec<u8> {
+    use crate::allocator::StateAllocator;
     use crate::lz77::lz77_compress;
 
-    let mut state = EncoderState::fixed(Vec::new());
+    let mut state = EncoderState::fixed(StateAllocator::global().vec_with_capacity(0));
     let compressed = lz77_compress(input).unwrap();
 
     // We currently don't split blocks here(this function is just used for tests anyhow)
@@ -53,10 +69,10 @@ pub fn compress_data_fixed(input: &[u8]) -> Vec<u8> {
     flush_to_bitstream(&compressed, &mut state);
 
     state.flush();
-    state.reset(Vec::new())
+    state.reset(StateAllocator::global().vec_with_capacity(0))
 }
 
-fn write_stored_block(input: &[u8], mut writer: &mut LsbWriter, final_block: bool) {
+pub fn write_stored_block(input: &[u8], mut writer: &mut LsbWriter, final_block: bool) {
     // If the input is not zero, we write stored blocks for the input data.
     if !input.is_empty() {
         let mut i = input.chunks(MAX_STORED_BLOCK_LENGTH).peekable();
@@ -76,10 +92,170 @@ fn write_stored_block(input: &[u8], mut writer: &mut LsbWriter, final_block: boo
     }
 }
 
+/// Write `input` as a sequence of stored blocks of the largest size allowed, used when only stored
+/// blocks are output.
+///
+/// Gives the same output as feeding the input to `compress_data_dynamic_n` in any number of
+/// steps, without flushing.
+pub fn write_stored_only(input: &[u8], writer: &mut LsbWriter, final_block: bool) {
+    let mut i = input.chunks(STORED_ONLY_BLOCK_LENGTH).peekable();
+    if i.peek().is_none() {
+        write_stored_header(writer, final_block);
+        compress_block_stored(&[], writer).expect("Write error");
+    }
+    while let Some(chunk) = i.next() {
+        write_stored_header(writer, final_block && i.peek().is_none());
+        compress_block_stored(chunk, writer).expect("Write error");
+    }
+}
+
+/// Add input to the pending stored block, and output the block once it's full or the data is to
+/// be flushed. A full block is only output when more input follows it, so the last block can be
+/// marked as such when finishing.
+///
+/// Returns the number of bytes consumed, and whether more input is needed or all pending data has
+/// been output.
+fn stored_only_step<W: Write, B: InputWindow>(
+    input: &[u8],
+    deflate_state: &mut DeflateState<W, B>,
+    flush: Flush,
+) -> (usize, LZ77Status) {
+    let pending = &mut deflate_state.stored_input;
+    let written = cmp::min(input.len(), STORED_ONLY_BLOCK_LENGTH - pending.len());
+    pending.extend_from_slice(&input[..written]);
+    let more_input = written < input.len();
+    if !more_input && flush == Flush::None {
+        return (written, LZ77Status::NeedInput);
+    }
+
+    let last_block = !more_input && flush == Flush::Finish;
+    // An empty block is only needed to end the stream.
+    if !pending.is_empty() || last_block {
+        let block_input_bytes = pending.len() as u64;
+        if cfg!(debug_assertions) {
+            deflate_state.bytes_written_control.add(block_input_bytes);
+        }
+        deflate_state.record_block_start(block_input_bytes);
+        let block_start = deflate_state.output_bits();
+        write_stored_header(&mut deflate_state.encoder_state.writer, last_block);
+        compress_block_stored(
+            &deflate_state.stored_input,
+            &mut deflate_state.encoder_state.writer,
+        )
+        .expect("Write error");
+        deflate_state.stored_input.clear();
+        if last_block {
+            deflate_state.lz77_state.set_last();
+        }
+        let kind = BlockKind::Stored;
+        deflate_state.record_block_codes(kind, block_input_bytes, block_start);
+        deflate_state.record_block_stats(kind, block_input_bytes, block_start);
+        deflate_state.report_block(kind, block_input_bytes, block_start);
+    }
+
+    let status = if more_input {
+        LZ77Status::EndBlock
+    } else {
+        LZ77Status::Finished
+    };
+    (written, status)
+}
+
+/// Write the lz77-compressed data in `lz77_writer` as a block, using the block type that gives
+/// the smallest output.
+///
+/// `raw_data` is the input data the block represents, which is needed if the block is output as a
+/// stored block. `final_stored` indicates whether a stored block should be marked as the last one.
+/// `priors` are blended into the symbol frequencies when generating dynamic codes, and
+/// `code_lengths` are used for them instead if they cover the symbols of the block.
+///
+/// Returns the type of block that was written.
+#[allow(clippy::too_many_arguments)]
+pub fn write_block(
+    encoder_state: &mut EncoderState,
+    length_buffers: &mut LengthBuffers,
+    lz77_writer: &DynamicWriter,
+    block_input_bytes: u64,
+    raw_data: Option<&[u8]>,
+    final_stored: bool,
+    last_block: bool,
+    priors: Option<&FrequencyPriors>,
+    code_lengths: Option<&CodeLengths>,
+) -> BlockKind {
+    let partial_bits = encoder_state.writer.pending_bits();
+
+    let res = {
+        let (l_freqs, d_freqs) = lz77_writer.get_frequencies();
+        let frequency_shift = lz77_writer.frequency_shift();
+        let (l_lengths, d_lengths) = encoder_state.huffman_table.get_lengths_mut();
+
+        gen_huffman_lengths(
+            l_freqs,
+            d_freqs,
+            frequency_shift,
+            block_input_bytes,
+            partial_bits,
+            l_lengths,
+            d_lengths,
+            length_buffers,
+            priors,
+            code_lengths,
+        )
+        .0
+    };
+
+    // Check if we've actually managed to compress the input, and output stored blocks
+    // if not.
+    match res {
+        BlockType::Dynamic(header) => {
+            // Write the block header.
+            encoder_state.write_start_of_block(false, last_block);
+
+            // Output the lengths of the huffman codes used in this block.
+            write_huffman_lengths(
+                &header,
+                &encoder_state.huffman_table,
+                &length_buffers.length_buf,
+                &mut encoder_state.writer,
+            );
+
+            // Uupdate the huffman codes that will be used to encode the
+            // lz77-compressed data.
+            encoder_state.huffman_table.update_from_lengths();
+
+            // Write the huffman compressed data and the end of block marker.
+            flush_to_bitstream(lz77_writer.get_buffer(), encoder_state);
+            BlockKind::Dynamic
+        }
+        BlockType::Fixed => {
+            // Write the block header for fixed code blocks.
+            encoder_state.write_start_of_block(true, last_block);
+
+            // Use the pre-defined static huffman codes.
+            encoder_state.set_huffman_to_fixed();
+
+            // Write the compressed data and the end of block marker.
+            flush_to_bitstream(lz77_writer.get_buffer(), encoder_state);
+            BlockKind::Fixed
+        }
+        BlockType::Stored => {
+            // If compression fails, output a stored block instead.
+            let raw_data = raw_data.expect(
+                "Error! Trying to output a stored block with forgotten data!\
+                 if you encounter this error, please file an issue!",
+            );
+            debug_assert_eq!(raw_data.len() as u64, block_input_bytes);
+
+            write_stored_block(raw_data, &mut encoder_state.writer, final_stored);
+            BlockKind::Stored
+        }
+    }
+}
+
 /// Inner compression function used by both the writers and the simple compression functions.
-pub fn compress_data_dynamic_n<W: Write>(
+pub fn compress_data_dynamic_n<W: Write, B: InputWindow>(
     input: &[u8],
-    deflate_state: &mut DeflateState<W>,
+    deflate_state: &mut DeflateState<W, B>,
     flush: Flush,
 ) -> io::Result<usize> {
     let mut bytes_written = 0;
@@ -91,20 +267,20 @@ pub fn compress_data_dynamic_n<W: Write>(
         let output_buf_pos = deflate_state.output_buf_pos;
         // If the output buffer has too much data in it already, flush it before doing anything
         // else.
-        if output_buf_len > LARGEST_OUTPUT_BUF_SIZE {
+        if output_buf_len > LARGEST_OUTPUT_BUF_SIZE && !deflate_state.direct_output {
             let written = deflate_state
                 .inner
                 .as_mut()
-                .expect("Missing writer!")
+                .ok_or(Error::InvalidState(MISSING_WRITER))?
                 .write(&deflate_state.encoder_state.inner_vec()[output_buf_pos..])?;
+            deflate_state.total_out += written as u64;
 
             if written < output_buf_len.checked_sub(output_buf_pos).unwrap() {
                 // Only some of the data was flushed, so keep track of where we were.
                 deflate_state.output_buf_pos += written;
             } else {
                 // If we flushed all of the output, reset the output buffer.
-                deflate_state.output_buf_pos = 0;
-                deflate_state.output_buf().clear();
+                deflate_state.clear_output_buf();
             }
 
             if bytes_written == 0 {
@@ -125,136 +301,101 @@ pub fn compress_data_dynamic_n<W: Write>(
             break;
         }
 
-        let (written, status, position) = lz77_compress_block(
-            slice,
-            &mut deflate_state.lz77_state,
-            &mut deflate_state.input_buffer,
-            &mut deflate_state.lz77_writer,
-            flush,
-        );
-
-        // Bytes written in this call
-        bytes_written += written;
-        // Total bytes written since the compression process started
-        // TODO: Should we realistically have to worry about overflowing here?
-        deflate_state.bytes_written += written as u64;
-
-        if status == LZ77Status::NeedInput {
-            // If we've consumed all the data input so far, and we're not
-            // finishing or syncing or ending the block here, simply return
-            // the number of bytes consumed so far.
-            return Ok(bytes_written);
-        }
-
-        // Increment start of input data
-        slice = &slice[written..];
-
-        // We need to check if this is the last block as the header will then be
-        // slightly different to indicate this.
-        let last_block = deflate_state.lz77_state.is_last_block();
-
-        let current_block_input_bytes = deflate_state.lz77_state.current_block_input_bytes();
-
-        if cfg!(debug_assertions) {
-            deflate_state
-                .bytes_written_control
-                .add(current_block_input_bytes);
-        }
+        let status = if deflate_state.compression_options.special == SpecialOptions::ForceStored {
+            let (written, status) = stored_only_step(slice, deflate_state, flush);
+            bytes_written += written;
+            deflate_state.bytes_written += written as u64;
+            deflate_state.total_in += written as u64;
+            if status == LZ77Status::NeedInput {
+                return Ok(bytes_written);
+            }
+            slice = &slice[written..];
+            status
+        } else {
+            let (written, status, position) = lz77_compress_block(
+                slice,
+                &mut deflate_state.lz77_state,
+                &mut deflate_state.input_buffer,
+                &mut deflate_state.lz77_writer,
+                flush,
+            );
+
+            // Bytes written in this call
+            bytes_written += written;
+            // Total bytes written since the compression process started
+            // TODO: Should we realistically have to worry about overflowing here?
+            deflate_state.bytes_written += written as u64;
+            deflate_state.total_in += written as u64;
+
+            if status == LZ77Status::NeedInput {
+                // If we've consumed all the data input so far, and we're not
+                // finishing or syncing or ending the block here, simply return
+                // the number of bytes consumed so far.
+                return Ok(bytes_written);
+            }
 
-        let partial_bits = deflate_state.encoder_state.writer.pending_bits();
+            // Increment start of input data
+            slice = &slice[written..];
 
-        let res = {
-            let (l_freqs, d_freqs) = deflate_state.lz77_writer.get_frequencies();
-            let (l_lengths, d_lengths) =
-                deflate_state.encoder_state.huffman_table.get_lengths_mut();
+            // We need to check if this is the last block as the header will then be
+            // slightly different to indicate this.
+            let last_block = deflate_state.lz77_state.is_last_block();
 
-            gen_huffman_lengths(
-                l_freqs,
-                d_freqs,
-                current_block_input_bytes,
-                partial_bits,
-                l_lengths,
-                d_lengths,
-                &mut deflate_state.length_buffers,
-            )
-        };
+            let current_block_input_bytes = deflate_state.lz77_state.current_block_input_bytes();
 
-        // Check if we've actually managed to compress the input, and output stored blocks
-        // if not.
-        match res {
-            BlockType::Dynamic(header) => {
-                // Write the block header.
+            if cfg!(debug_assertions) {
                 deflate_state
-                    .encoder_state
-                    .write_start_of_block(false, last_block);
-
-                // Output the lengths of the huffman codes used in this block.
-                write_huffman_lengths(
-                    &header,
-                    &deflate_state.encoder_state.huffman_table,
-                    &deflate_state.length_buffers.length_buf,
-                    &mut deflate_state.encoder_state.writer,
-                );
-
-                // Uupdate the huffman codes that will be used to encode the
-                // lz77-compressed data.
-                deflate_state
-                    .encoder_state
-                    .huffman_table
-                    .update_from_lengths();
-
-                // Write the huffman compressed data and the end of block marker.
-                flush_to_bitstream(
-                    deflate_state.lz77_writer.get_buffer(),
-                    &mut deflate_state.encoder_state,
-                );
+                    .bytes_written_control
+                    .add(current_block_input_bytes);
             }
-            BlockType::Fixed => {
-                // Write the block header for fixed code blocks.
-                deflate_state
-                    .encoder_state
-                    .write_start_of_block(true, last_block);
 
-                // Use the pre-defined static huffman codes.
-                deflate_state.encoder_state.set_huffman_to_fixed();
+            deflate_state.record_block_start(current_block_input_bytes);
 
-                // Write the compressed data and the end of block marker.
-                flush_to_bitstream(
-                    deflate_state.lz77_writer.get_buffer(),
-                    &mut deflate_state.encoder_state,
-                );
-            }
-            BlockType::Stored => {
-                // If compression fails, output a stored block instead.
-
-                let start_pos = position.saturating_sub(current_block_input_bytes as usize);
-
-                assert!(
-                    position >= current_block_input_bytes as usize,
-                    "Error! Trying to output a stored block with forgotten data!\
-                     if you encounter this error, please file an issue!"
-                );
-
-                write_stored_block(
-                    &deflate_state.input_buffer.get_buffer()[start_pos..position],
-                    &mut deflate_state.encoder_state.writer,
-                    flush == Flush::Finish && last_block,
-                );
-            }
-        };
+            // The input data for the block, used if it's output as a stored block.
+            let raw_data = if position >= current_block_input_bytes as usize {
+                let start_pos = position - current_block_input_bytes as usize;
+                Some(&deflate_state.input_buffer.get_buffer()[start_pos..position])
+            } else {
+                None
+            };
 
-        // Clear the current lz77 data in the writer for the next call.
-        deflate_state.lz77_writer.clear();
-        // We are done with the block, so we reset the number of bytes taken
-        // for the next one.
-        deflate_state.lz77_state.reset_input_bytes();
+            let block_start = deflate_state.output_bits();
+            let kind = write_block(
+                &mut deflate_state.encoder_state,
+                &mut deflate_state.length_buffers,
+                &deflate_state.lz77_writer,
+                current_block_input_bytes,
+                raw_data,
+                flush == Flush::Finish && last_block,
+                last_block,
+                deflate_state.frequency_priors.as_ref(),
+                deflate_state.code_lengths.as_ref(),
+            );
+            deflate_state.record_block_codes(kind, current_block_input_bytes, block_start);
+            deflate_state.record_block_stats(kind, current_block_input_bytes, block_start);
+            deflate_state.report_block(kind, current_block_input_bytes, block_start);
+
+            // Clear the current lz77 data in the writer for the next call.
+            deflate_state.lz77_writer.clear();
+            // We are done with the block, so we reset the number of bytes taken
+            // for the next one.
+            deflate_state.lz77_state.reset_input_bytes();
+            status
+        };
 
         // We are done for now.
         if status == LZ77Status::Finished {
             // This flush mode means that there should be an empty stored block at the end.
             if flush == Flush::Sync {
                 write_stored_block(&[], &mut deflate_state.encoder_state.writer, false);
-            } else if !deflate_state.lz77_state.is_last_block() {
+            } else if flush == Flush::Partial {
+                // The empty block pushes the end of the previous block out of the bits that are
+                // kept pending.
+                let es = &mut deflate_state.encoder_state;
+                es.set_huffman_to_fixed();
+                es.write_start_of_block(true, false);
+                es.write_end_of_block();
+            } else if flush == Flush::Finish && !deflate_state.lz77_state.is_last_block() {
                 // Make sure a block with the last block header has been output.
                 // Not sure this can actually happen, but we make sure to finish properly
                 // if it somehow does.
@@ -269,15 +410,25 @@ pub fn compress_data_dynamic_n<W: Write>(
     }
 
     // If we reach this point, the remaining data in the buffers is to be flushed.
-    deflate_state.encoder_state.flush();
+    // When only ending the block, the bits that don't fill a whole byte are kept so the next
+    // block can follow directly after.
+    match flush {
+        Flush::Block | Flush::Partial => deflate_state.encoder_state.writer.write_whole_bytes(),
+        _ => deflate_state.encoder_state.flush(),
+    }
+    if deflate_state.direct_output {
+        // The output stays in the buffer until it's taken.
+        return Ok(bytes_written);
+    }
     // Make sure we've output everything, and return the number of bytes written if everything
     // went well.
     let output_buf_pos = deflate_state.output_buf_pos;
     let written_to_writer = deflate_state
         .inner
         .as_mut()
-        .expect("Missing writer!")
+        .ok_or(Error::InvalidState(MISSING_WRITER))?
         .write(&deflate_state.encoder_state.inner_vec()[output_buf_pos..])?;
+    deflate_state.total_out += written_to_writer as u64;
     if written_to_writer
         < deflate_state
             .output_buf()
@@ -288,8 +439,7 @@ pub fn compress_data_dynamic_n<W: Write>(
         deflate_state.output_buf_pos += written_to_writer;
     } else {
         // If we sucessfully wrote all the data, we can clear the output buffer.
-        deflate_state.output_buf_pos = 0;
-        deflate_state.output_buf().clear();
+        deflate_state.clear_output_buf();
     }
     Ok(bytes_written)
 }
@@ -337,6 +487,34 @@ mod test {
         assert_eq!(&decompressed, test_data)
     }
 
+    #[test]
+    /// The block type is chosen by the exact cost, so the output should never be larger than
+    /// using fixed codes, and short blocks should use them rather than paying for a dynamic
+    /// header.
+    fn block_type_by_cost() {
+        use crate::compression_options::CompressionOptions;
+        use crate::write::{Encoder, Format};
+        use crate::BlockKind;
+        use std::io::Write;
+
+        let data = get_test_data();
+        let inputs: [&[u8]; 4] = [b"Deflate late", b"Hello, world!\n", &data[..200], &data];
+        for &input in &inputs {
+            let mut encoder = Encoder::new(Vec::new(), Format::Raw, CompressionOptions::default());
+            encoder.set_record_block_codes(true);
+            encoder.write_all(input).unwrap();
+            encoder.try_finish().unwrap();
+            let codes = encoder.take_block_codes();
+            let compressed = encoder.finish().unwrap();
+
+            assert!(compressed.len() <= compress_data_fixed(input).len());
+            if input.len() < 100 {
+                assert_eq!(codes.len(), 1);
+                assert_eq!(codes[0].kind, BlockKind::Fixed);
+            }
+        }
+    }
+
     #[test]
     /// Test compression from a file.
     fn fixed_string_file() {
@@ -350,4 +528,61 @@ mod test {
         // Not using assert_eq here deliberately to avoid massive amounts of output spam.
         assert!(input == result);
     }
+
+    #[test]
+    /// Only stored blocks of up to 65535 bytes are output, and the output doesn't depend on the
+    /// size of the writes.
+    fn stored_only() {
+        use crate::compression_options::CompressionOptions;
+        use crate::test_utils::{decompress_zlib, get_random_data};
+        use crate::write::{Encoder, Format};
+        use crate::BlockKind;
+        use std::io::Write;
+
+        let data = get_random_data(200_000);
+        for &len in &[0, 1, 65_535, 65_536, 200_000] {
+            let input = &data[..len];
+            let blocks = cmp::max(len.div_ceil(STORED_ONLY_BLOCK_LENGTH), 1);
+            let compressed = crate::deflate_bytes_conf(input, CompressionOptions::stored());
+            assert_eq!(compressed.len(), len + blocks * 5);
+            assert!(decompress_to_end(&compressed) == input);
+
+            for &write_size in &[1000, 65_535, 100_000] {
+                let mut encoder =
+                    Encoder::new(Vec::new(), Format::Zlib, CompressionOptions::stored());
+                encoder.set_record_block_codes(true);
+                for chunk in input.chunks(write_size) {
+                    encoder.write_all(chunk).unwrap();
+                }
+                encoder.try_finish().unwrap();
+                let codes = encoder.take_block_codes();
+                assert_eq!(codes.len(), blocks);
+                assert!(codes.iter().all(|c| c.kind == BlockKind::Stored));
+                let zlib = encoder.finish().unwrap();
+                assert!(zlib[2..zlib.len() - 4] == compressed[..]);
+                assert!(decompress_zlib(&zlib) == input);
+            }
+        }
+    }
+
+    #[test]
+    fn stored_only_sync_flush() {
+        use crate::compression_options::CompressionOptions;
+        use crate::write::{Encoder, Format};
+        use std::io::Write;
+
+        let mut encoder = Encoder::new(Vec::new(), Format::Raw, CompressionOptions::stored());
+        encoder.write_all(b"Hello").unwrap();
+        encoder.flush().unwrap();
+        let flushed = encoder.get_mut().clone();
+        assert_eq!(
+            flushed,
+            [0, 5, 0, 0xFA, 0xFF, b'H', b'e', b'l', b'l', b'o', 0, 0, 0, 0xFF, 0xFF]
+        );
+        encoder.write_all(b", world").unwrap();
+        assert_eq!(
+            decompress_to_end(&encoder.finish().unwrap()),
+            b"Hello, world"
+        );
+    }
 }
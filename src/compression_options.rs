@@ -8,8 +8,11 @@
 //! compressor, which uses a specialised (but slow) algorithm to figure out the maximum
 //! of compression for the provided data.
 //!
+use crate::chained_hash_table::HashFunction;
 use crate::lz77::MatchingType;
+use crate::matcher::MatcherType;
 use std::convert::From;
+use std::hash::{Hash, Hasher};
 
 pub const HIGH_MAX_HASH_CHECKS: u16 = 1768;
 pub const HIGH_LAZY_IF_LESS_THAN: u16 = 128;
@@ -18,6 +21,48 @@ pub const HIGH_LAZY_IF_LESS_THAN: u16 = 128;
 pub const MAX_HASH_CHECKS: u16 = 32 * 1024;
 pub const DEFAULT_MAX_HASH_CHECKS: u16 = 128;
 pub const DEFAULT_LAZY_IF_LESS_THAN: u16 = 32;
+pub const DEFAULT_GOOD_LENGTH: u16 = 32;
+/// By default, only stop searching at a match of the maximum length.
+pub const DEFAULT_NICE_LENGTH: u16 = 258;
+/// By default, the positions inside all matches are added to the hash chains.
+pub const DEFAULT_MAX_INSERT_LENGTH: u16 = 258;
+/// The smallest window size allowed by the zlib format, as a base-2 logarithm.
+pub const MIN_WINDOW_BITS: u8 = 9;
+/// The largest window size allowed by DEFLATE, as a base-2 logarithm.
+pub const MAX_WINDOW_BITS: u8 = 15;
+/// The shortest match used by the filtered strategy.
+pub const FILTERED_MIN_MATCH: usize = 6;
+/// By default, matches of any length allowed by DEFLATE are used.
+pub const DEFAULT_MIN_MATCH: u16 = 3;
+/// The smallest memory level, using the least memory.
+pub const MIN_MEM_LEVEL: u8 = 1;
+/// The largest memory level.
+pub const MAX_MEM_LEVEL: u8 = 9;
+/// The default memory level, the lowest one using buffers of the full size.
+pub const DEFAULT_MEM_LEVEL: u8 = 8;
+
+/// The number of bytes at the start of a sample that are compressed to pick options with
+/// `CompressionOptions::for_sample`.
+pub const SAMPLE_PROBE_LENGTH: usize = 64 * 1024;
+
+/// Clamp a window size in bits to the range supported by the format.
+pub fn clamp_window_bits(window_bits: u8) -> u8 {
+    window_bits.clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS)
+}
+
+/// Clamp a memory level to the supported range.
+pub fn clamp_mem_level(mem_level: u8) -> u8 {
+    mem_level.clamp(MIN_MEM_LEVEL, MAX_MEM_LEVEL)
+}
+
+/// The address of a hash function, used to compare and hash the hash functions of options.
+///
+/// The same function can end up at different addresses in different codegen units, and different
+/// functions can be merged into one, so this only tells whether two hash functions are most
+/// likely the same one.
+pub fn hash_function_address(hash_function: Option<HashFunction>) -> Option<usize> {
+    hash_function.map(|f| f as usize)
+}
 
 /// An enum describing the level of compression to be used by the encoder
 ///
@@ -47,15 +92,18 @@ impl Default for Compression {
     }
 }
 
-/// Enum allowing some special options (not implemented yet)!
+/// Enum allowing some special options.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum SpecialOptions {
     /// Compress normally.
     Normal,
     /// Force fixed huffman tables. (Unimplemented!).
     _ForceFixed,
-    /// Force stored (uncompressed) blocks only. (Unimplemented!).
-    _ForceStored,
+    /// Output the input as it is in stored (uncompressed) blocks, without looking for matches
+    /// or huffman coding it.
+    ///
+    /// [See `CompressionOptions::stored`](./struct.CompressionOptions.html#method.stored).
+    ForceStored,
 }
 
 impl Default for SpecialOptions {
@@ -69,12 +117,30 @@ pub const DEFAULT_OPTIONS: CompressionOptions = CompressionOptions {
     lazy_if_less_than: DEFAULT_LAZY_IF_LESS_THAN,
     matching_type: MatchingType::Lazy,
     special: SpecialOptions::Normal,
+    hash_function: None,
+    detect_repeats: false,
+    window_bits: MAX_WINDOW_BITS,
+    filtered: false,
+    min_match: DEFAULT_MIN_MATCH,
+    good_length: DEFAULT_GOOD_LENGTH,
+    nice_length: DEFAULT_NICE_LENGTH,
+    max_insert_length: DEFAULT_MAX_INSERT_LENGTH,
+    hash_four_bytes: false,
+    mem_level: DEFAULT_MEM_LEVEL,
+    block_length: None,
+    matcher: MatcherType::HashChains,
+    two_step_lazy: false,
+    skip_incompressible: false,
 };
 
 /// A struct describing the options for a compressor or compression function.
 ///
 /// These values are not stable and still subject to change!
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+///
+/// Custom hash functions are compared by their address. The same function can have different
+/// addresses in different parts of a program, so two sets of options with the same hash function
+/// can compare as different.
+#[derive(Copy, Clone, Debug, Eq)]
 pub struct CompressionOptions {
     /// The maximum number of checks to make in the hash table for matches.
     ///
@@ -101,9 +167,10 @@ pub struct CompressionOptions {
     pub lazy_if_less_than: u16,
 
     // pub _decent_match: u16,
-    /// Whether to use lazy or greedy matching.
+    /// Whether to use lazy, greedy or optimal matching.
     ///
     /// Lazy matching will provide better compression, at the expense of compression speed.
+    /// Optimal parsing compresses slightly better still, but is very slow.
     ///
     /// As a special case, if max_hash_checks is set to 0, and matching_type is set to lazy,
     /// compression using only run-length encoding (i.e maximum match distance of 1) is performed.
@@ -114,9 +181,141 @@ pub struct CompressionOptions {
     ///
     /// * Default value: `MatchingType::Lazy`
     pub matching_type: MatchingType,
-    /// Force fixed/stored blocks (Not implemented yet).
+    /// Force stored blocks, or fixed blocks (not implemented yet).
+    ///
+    /// When forcing stored blocks, the other options have no effect.
+    ///
     /// * Default value: `SpecialOptions::Normal`
     pub special: SpecialOptions,
+    /// Custom rolling hash function used to find match candidates, or `None` to use the default
+    /// one.
+    ///
+    /// The function is passed the previous hash value and the next input byte. Data with a
+    /// specialised structure (e.g DNA sequences or numeric columns) may find more matches with a
+    /// hash that mixes the bytes differently. Only the lowest 15 bits of the value are used (fewer
+    /// with a `mem_level` below 8), and the value should only depend on the last three bytes
+    /// inserted.
+    ///
+    /// * Default value: `None`
+    pub hash_function: Option<HashFunction>,
+    /// Whether to look for 4 KiB blocks of input that are exact copies of earlier data in the
+    /// window, and output maximal matches for them without searching the hash chains.
+    ///
+    /// This speeds up compression of data with many duplicated pages, like disk images or
+    /// database dumps, at a small cost for other data. It has no effect if `max_hash_checks` is 0.
+    ///
+    /// * Default value: `false`
+    pub detect_repeats: bool,
+    /// The base-2 logarithm of the size of the window, i.e the maximum distance of a match.
+    ///
+    /// A smaller window lets the data be decompressed with less memory, at the cost of finding
+    /// fewer matches. The window size is recorded in the zlib header, so a zlib decompressor can
+    /// allocate a smaller window. Values are clamped to the range `9...15`. The hash chains of
+    /// the compressor are sized to the window, but the input buffer is not reduced.
+    ///
+    /// * Default value: `15` (a 32 KiB window)
+    pub window_bits: u8,
+    /// Whether to only use matches of at least 6 bytes, outputting shorter ones as literals.
+    ///
+    /// This is similar to the `Z_FILTERED` strategy in zlib. Data consisting of small values
+    /// with some noise in the lower bits, like the output of PNG filters or audio deltas, has
+    /// many short matches that cost more than the literals they replace, as the literals
+    /// themselves are cheap to huffman code.
+    ///
+    /// * Default value: `false`
+    pub filtered: bool,
+    /// Only use matches of at least this many bytes, outputting shorter ones as literals.
+    ///
+    /// Like `filtered`, this helps with data where the literals are cheap to huffman code, and
+    /// allows picking the length that works best for it. Values are clamped to the match lengths
+    /// allowed by the format, and the larger of this and the length used by `filtered` applies.
+    /// Four bytes are always hashed for each position when it's above `3`.
+    ///
+    /// * Default value: `3`
+    pub min_match: u16,
+    /// When lazy matching, only do a quarter of the hash checks when looking for a better match
+    /// after a match of at least this length.
+    ///
+    /// Lower values speed up compression, at the cost of missing some better matches.
+    ///
+    /// * Default value: `32`
+    pub good_length: u16,
+    /// Stop searching the hash chains once a match of at least this length has been found.
+    ///
+    /// Lower values speed up compression of data with many long matches, at the cost of
+    /// sometimes missing a longer match further back. As the maximum length of a match is `258`,
+    /// values higher than this will have no further effect.
+    ///
+    /// * Default value: `258`
+    pub nice_length: u16,
+    /// With greedy matching, only add the positions inside a match to the hash chains if the
+    /// match is at most this long, like the fastest levels of zlib.
+    ///
+    /// Adding every position takes a large part of the time spent on data with many long
+    /// matches, while the positions inside a long match are rarely needed to find later matches.
+    /// Lower values speed up compression at the cost of missing some matches.
+    ///
+    /// * Default value: `258`
+    pub max_insert_length: u16,
+    /// Whether to hash four bytes rather than three for each position in the hash chains.
+    ///
+    /// The chains are then shorter and contain fewer false candidates, which speeds up searching
+    /// them, particularly on binary data. Matches of only three bytes can't be found, which
+    /// usually costs very little compression as these rarely save much space. Four bytes are
+    /// always hashed when `filtered` is set, as shorter matches aren't used then anyhow.
+    ///
+    /// * Default value: `false`
+    pub hash_four_bytes: bool,
+    /// How much memory to use for the hash table and the buffer of symbols waiting to be output,
+    /// like the `memLevel` parameter of zlib.
+    ///
+    /// Each level below the default halves the size of the symbol buffer, which makes the blocks
+    /// shorter, and the hash table uses `mem_level + 7` bits of the hash, up to 15 bits. Lower
+    /// levels reduce the memory used by each compressor, at the cost of compressing worse and
+    /// finding matches more slowly. Values are clamped to the range `1...9`, level 9 currently
+    /// uses as much memory as level 8.
+    ///
+    /// * Default value: `8`
+    pub mem_level: u8,
+    /// The number of literals and matches in each block, overriding the number set by
+    /// `mem_level`.
+    ///
+    /// A new block with its own huffman codes is started once this many have been found, so
+    /// shorter blocks adapt faster to changes in the data, and let the encoders output data
+    /// sooner, while longer blocks spend less of the output on block headers. How much input a
+    /// block covers depends on how long the matches are, not on the window size. Values are
+    /// clamped to the range `256...64512`, and the memory used for the buffer grows along with
+    /// the length.
+    ///
+    /// * Default value: `None` (`31744` at the default memory level)
+    pub block_length: Option<u16>,
+    /// The search structure used to find matches in the window.
+    ///
+    /// [See `MatcherType`](./enum.MatcherType.html)
+    ///
+    /// * Default value: `MatcherType::HashChains`
+    pub matcher: MatcherType,
+    /// Whether lazy matching also checks for a match at the byte after next before using a
+    /// match, rather than only at the next byte.
+    ///
+    /// If there is a match there that is longer by at least two bytes, the two bytes before it
+    /// are output as literals instead. This finds some better matches when a long match
+    /// overlaps the start of the one found, at the cost of searching for more matches. It only
+    /// has an effect with `MatchingType::Lazy`.
+    ///
+    /// * Default value: `false`
+    pub two_step_lazy: bool,
+    /// Whether to stop searching for matches in input that looks incompressible, like encrypted
+    /// or already compressed data.
+    ///
+    /// When few matches are found in a chunk of input and its bytes look close to random, the
+    /// following chunks are output as literals without searching for matches for as long as they
+    /// still look random, which ends up as stored blocks. Matching is tried again every few chunks
+    /// in case the data has started repeating. This makes compressing such data much faster, but
+    /// may miss matches to data far back in the window, so it has to be enabled explicitly.
+    ///
+    /// * Default value: `false`
+    pub skip_incompressible: bool,
 }
 
 // Some standard profiles for the compression options.
@@ -129,6 +328,20 @@ impl CompressionOptions {
             lazy_if_less_than: HIGH_LAZY_IF_LESS_THAN,
             matching_type: MatchingType::Lazy,
             special: SpecialOptions::Normal,
+            hash_function: None,
+            detect_repeats: false,
+            window_bits: MAX_WINDOW_BITS,
+            filtered: false,
+            min_match: DEFAULT_MIN_MATCH,
+            good_length: DEFAULT_GOOD_LENGTH,
+            nice_length: DEFAULT_NICE_LENGTH,
+            max_insert_length: DEFAULT_MAX_INSERT_LENGTH,
+            hash_four_bytes: false,
+            mem_level: DEFAULT_MEM_LEVEL,
+            block_length: None,
+            matcher: MatcherType::HashChains,
+            two_step_lazy: false,
+            skip_incompressible: false,
         }
     }
 
@@ -144,6 +357,34 @@ impl CompressionOptions {
             lazy_if_less_than: 0,
             matching_type: MatchingType::Greedy,
             special: SpecialOptions::Normal,
+            hash_function: None,
+            detect_repeats: false,
+            window_bits: MAX_WINDOW_BITS,
+            filtered: false,
+            min_match: DEFAULT_MIN_MATCH,
+            good_length: DEFAULT_GOOD_LENGTH,
+            nice_length: DEFAULT_NICE_LENGTH,
+            max_insert_length: DEFAULT_MAX_INSERT_LENGTH,
+            hash_four_bytes: false,
+            mem_level: DEFAULT_MEM_LEVEL,
+            block_length: None,
+            matcher: MatcherType::HashChains,
+            two_step_lazy: false,
+            skip_incompressible: false,
+        }
+    }
+
+    /// Returns the `fast` settings, but only adding the positions inside matches of at most four
+    /// bytes to the hash chains, corresponding to level 1.
+    ///
+    /// This is faster than the `fast` settings on data with many long matches, at the cost of
+    /// somewhat worse compression.
+    ///
+    /// [See `max_insert_length`](#structfield.max_insert_length).
+    pub fn fastest() -> CompressionOptions {
+        CompressionOptions {
+            max_insert_length: 4,
+            ..CompressionOptions::fast()
         }
     }
 
@@ -158,6 +399,20 @@ impl CompressionOptions {
             lazy_if_less_than: 0,
             matching_type: MatchingType::Greedy,
             special: SpecialOptions::Normal,
+            hash_function: None,
+            detect_repeats: false,
+            window_bits: MAX_WINDOW_BITS,
+            filtered: false,
+            min_match: DEFAULT_MIN_MATCH,
+            good_length: DEFAULT_GOOD_LENGTH,
+            nice_length: DEFAULT_NICE_LENGTH,
+            max_insert_length: DEFAULT_MAX_INSERT_LENGTH,
+            hash_four_bytes: false,
+            mem_level: DEFAULT_MEM_LEVEL,
+            block_length: None,
+            matcher: MatcherType::HashChains,
+            two_step_lazy: false,
+            skip_incompressible: false,
         }
     }
 
@@ -174,8 +429,208 @@ impl CompressionOptions {
             lazy_if_less_than: 0,
             matching_type: MatchingType::Lazy,
             special: SpecialOptions::Normal,
+            hash_function: None,
+            detect_repeats: false,
+            window_bits: MAX_WINDOW_BITS,
+            filtered: false,
+            min_match: DEFAULT_MIN_MATCH,
+            good_length: DEFAULT_GOOD_LENGTH,
+            nice_length: DEFAULT_NICE_LENGTH,
+            max_insert_length: DEFAULT_MAX_INSERT_LENGTH,
+            hash_four_bytes: false,
+            mem_level: DEFAULT_MEM_LEVEL,
+            block_length: None,
+            matcher: MatcherType::HashChains,
+            two_step_lazy: false,
+            skip_incompressible: false,
+        }
+    }
+
+    /// Returns a set of compression settings that makes the compressor output the input as it is
+    /// in stored blocks, corresponding to level 0 in zlib.
+    ///
+    /// The data is only split into blocks of up to 65535 bytes, each with a five byte header, and
+    /// wrapped in the zlib or gzip format with its checksum, if any. This is by far the fastest
+    /// setting, and is useful for data that is already compressed, or when the latency of
+    /// compressing matters more than the size.
+    pub fn stored() -> CompressionOptions {
+        CompressionOptions {
+            special: SpecialOptions::ForceStored,
+            ..CompressionOptions::huffman_only()
+        }
+    }
+
+    /// Returns compression settings that search as many matches as the `high` settings, and use
+    /// optimal parsing to pick the ones giving the smallest output.
+    ///
+    /// This is many times slower than the `high` settings, for output that is usually a few
+    /// percent smaller.
+    pub fn optimal() -> CompressionOptions {
+        CompressionOptions {
+            matching_type: MatchingType::Optimal,
+            ..CompressionOptions::high()
+        }
+    }
+
+    /// Returns compression settings like the `high` settings, but finding matches with binary
+    /// trees rather than hash chains, and checking two bytes ahead when lazy matching.
+    ///
+    /// This is slower than the `high` settings on most data, and finds longer matches, in
+    /// particular on highly redundant data.
+    ///
+    /// [See `MatcherType::BinaryTree`](./enum.MatcherType.html#variant.BinaryTree).
+    pub fn maximum() -> CompressionOptions {
+        CompressionOptions {
+            matcher: MatcherType::BinaryTree,
+            two_step_lazy: true,
+            ..CompressionOptions::high()
         }
     }
+
+    /// Returns the default compression settings, but only using matches of 6 bytes or more.
+    ///
+    /// [See `filtered`](#structfield.filtered).
+    pub fn filtered() -> CompressionOptions {
+        CompressionOptions {
+            filtered: true,
+            ..DEFAULT_OPTIONS
+        }
+    }
+
+    /// Pick the settings to use for data like `sample`, by compressing its first 64 KiB with a
+    /// few candidate settings and comparing the sizes of the output.
+    ///
+    /// The candidates are tried from the fastest (storing the data without compressing it) to
+    /// the slowest (the `high` settings), and a slower one is only picked if its output is at
+    /// least 1% smaller than that of the best faster one. The result only depends on the
+    /// sample, not on how long compressing it took, so it is the same everywhere. An empty sample
+    /// gives the default settings.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use deflate::{deflate_bytes_conf, CompressionOptions};
+    ///
+    /// let data = b"This text is repeated. This text is repeated. This text is repeated.";
+    /// let options = CompressionOptions::for_sample(data);
+    /// let compressed = deflate_bytes_conf(data, options);
+    /// # let _ = compressed;
+    /// ```
+    pub fn for_sample(sample: &[u8]) -> CompressionOptions {
+        if sample.is_empty() {
+            return CompressionOptions::default();
+        }
+        let sample = &sample[..sample.len().min(SAMPLE_PROBE_LENGTH)];
+        let candidates = [
+            CompressionOptions::stored(),
+            CompressionOptions::huffman_only(),
+            CompressionOptions::rle(),
+            CompressionOptions::fast(),
+            CompressionOptions::filtered(),
+            CompressionOptions::default(),
+            CompressionOptions::high(),
+        ];
+
+        let mut best = candidates[0];
+        let mut best_size = crate::deflate_bytes_conf(sample, best).len();
+        for &candidate in &candidates[1..] {
+            let size = crate::deflate_bytes_conf(sample, candidate).len();
+            // Require the slower settings to be worth it.
+            if size * 100 < best_size * 99 {
+                best = candidate;
+                best_size = size;
+            }
+        }
+        best
+    }
+}
+
+// Implemented by hand to compare and hash the hash functions by their address, see
+// `hash_function_address`. The fields are destructured so new ones can't be left out.
+impl PartialEq for CompressionOptions {
+    fn eq(&self, other: &CompressionOptions) -> bool {
+        let CompressionOptions {
+            max_hash_checks,
+            lazy_if_less_than,
+            matching_type,
+            special,
+            hash_function,
+            detect_repeats,
+            window_bits,
+            filtered,
+            min_match,
+            good_length,
+            nice_length,
+            max_insert_length,
+            hash_four_bytes,
+            mem_level,
+            block_length,
+            matcher,
+            two_step_lazy,
+            skip_incompressible,
+        } = *self;
+        max_hash_checks == other.max_hash_checks
+            && lazy_if_less_than == other.lazy_if_less_than
+            && matching_type == other.matching_type
+            && special == other.special
+            && hash_function_address(hash_function) == hash_function_address(other.hash_function)
+            && detect_repeats == other.detect_repeats
+            && window_bits == other.window_bits
+            && filtered == other.filtered
+            && min_match == other.min_match
+            && good_length == other.good_length
+            && nice_length == other.nice_length
+            && max_insert_length == other.max_insert_length
+            && hash_four_bytes == other.hash_four_bytes
+            && mem_level == other.mem_level
+            && block_length == other.block_length
+            && matcher == other.matcher
+            && two_step_lazy == other.two_step_lazy
+            && skip_incompressible == other.skip_incompressible
+    }
+}
+
+impl Hash for CompressionOptions {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let CompressionOptions {
+            max_hash_checks,
+            lazy_if_less_than,
+            matching_type,
+            special,
+            hash_function,
+            detect_repeats,
+            window_bits,
+            filtered,
+            min_match,
+            good_length,
+            nice_length,
+            max_insert_length,
+            hash_four_bytes,
+            mem_level,
+            block_length,
+            matcher,
+            two_step_lazy,
+            skip_incompressible,
+        } = *self;
+        max_hash_checks.hash(state);
+        lazy_if_less_than.hash(state);
+        matching_type.hash(state);
+        special.hash(state);
+        hash_function_address(hash_function).hash(state);
+        detect_repeats.hash(state);
+        window_bits.hash(state);
+        filtered.hash(state);
+        min_match.hash(state);
+        good_length.hash(state);
+        nice_length.hash(state);
+        max_insert_length.hash(state);
+        hash_four_bytes.hash(state);
+        mem_level.hash(state);
+        block_length.hash(state);
+        matcher.hash(state);
+        two_step_lazy.hash(state);
+        skip_incompressible.hash(state);
+    }
 }
 
 impl Default for CompressionOptions {
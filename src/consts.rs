@@ -0,0 +1,52 @@
+//! Constants from the DEFLATE, zlib and gzip specifications, as used by this library.
+//!
+//! These are provided so code working with the output of this library (e.g for framing or
+//! calculating output bounds) doesn't have to duplicate them.
+
+/// The size of the sliding window, i.e the maximum distance back a match can refer to.
+pub const WINDOW_SIZE: usize = crate::chained_hash_table::WINDOW_SIZE;
+
+/// The minimum length of a match.
+pub const MIN_MATCH: usize = crate::huffman_table::MIN_MATCH as usize;
+
+/// The maximum length of a match.
+pub const MAX_MATCH: usize = crate::huffman_table::MAX_MATCH as usize;
+
+/// The maximum number of bytes a single stored (uncompressed) block can contain.
+pub const MAX_STORED_BLOCK_LENGTH: usize = crate::stored_block::STORED_ONLY_BLOCK_LENGTH;
+
+/// The number of bytes used by the header of a stored block, excluding the 3 block type bits and
+/// padding (the length and its complement).
+pub const STORED_BLOCK_HEADER_LENGTH: usize = 4;
+
+/// The length of a zlib header without a preset dictionary.
+pub const ZLIB_HEADER_LENGTH: usize = 2;
+
+/// The length of the zlib trailer (the Adler-32 checksum).
+pub const ZLIB_TRAILER_LENGTH: usize = 4;
+
+/// The minimum length of a gzip header, i.e a header without any of the optional fields.
+pub const GZIP_MIN_HEADER_LENGTH: usize = 10;
+
+/// The length of the gzip trailer (CRC-32 checksum and the input size modulo 2^32).
+pub const GZIP_TRAILER_LENGTH: usize = 8;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::zlib::{get_zlib_header, CompressionLevel};
+
+    #[test]
+    fn wrapper_lengths() {
+        assert_eq!(
+            get_zlib_header(CompressionLevel::Default).len(),
+            ZLIB_HEADER_LENGTH
+        );
+        let compressed = crate::deflate_bytes_zlib(&[]);
+        let raw = crate::deflate_bytes(&[]);
+        assert_eq!(
+            compressed.len(),
+            raw.len() + ZLIB_HEADER_LENGTH + ZLIB_TRAILER_LENGTH
+        );
+    }
+}
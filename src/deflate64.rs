@@ -0,0 +1,467 @@
+//! Compression in the Deflate64 ("enhanced deflate") format.
+//!
+//! Deflate64 is a variant of DEFLATE used by ZIP archives with compression method 9. The window
+//! is 64 KiB, using the two distance codes that are unused in DEFLATE, and the last length code
+//! is followed by 16 extra bits so a match can be up to 65538 bytes long. The block structure and
+//! the huffman coding are otherwise the same, so most of the block writing is shared with the
+//! other encoders.
+//!
+//! The hash chains and the input buffer of the other encoders are sized for a 32 KiB window at
+//! compile time, so the input is compressed in one go with a matcher of its own, using the whole
+//! input as the window. The output is not valid DEFLATE data, and most zlib-compatible
+//! decompressors can't read it.
+use std::{cmp, mem};
+
+use crate::allocator::{state_into_vec, StateAllocator};
+use crate::block_codes::BlockKind;
+use crate::compress::{write_stored_block, write_stored_only};
+use crate::compression_options::{CompressionOptions, SpecialOptions, FILTERED_MIN_MATCH};
+use crate::deflate_state::LengthBuffers;
+use crate::encoder_state::EncoderState;
+use crate::huffman_lengths::{gen_huffman_lengths, write_huffman_lengths, BlockType};
+use crate::huffman_table::{
+    get_deflate64_distance_code_and_extra_bits, get_deflate64_length_code_and_extra_bits,
+    DEFLATE64_MAX_DISTANCE, DEFLATE64_MAX_MATCH, END_OF_BLOCK_POSITION, MIN_MATCH,
+    NUM_DISTANCE_CODES, NUM_LITERALS_AND_LENGTHS,
+};
+use crate::lz77::MatchingType;
+use crate::output_writer::MAX_BUFFER_LENGTH;
+
+const WINDOW_SIZE: usize = DEFLATE64_MAX_DISTANCE as usize;
+const WINDOW_MASK: usize = WINDOW_SIZE - 1;
+const HASH_BITS: u32 = 16;
+
+/// A literal or a match found in the input.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Literal(u8),
+    Match { length: u32, distance: u32 },
+}
+
+/// Hash chains covering the whole 64 KiB window.
+struct Matcher<'a> {
+    input: &'a [u8],
+    /// The position after the last occurrence of each hash value, or 0 if there is none.
+    head: Vec<u32>,
+    /// The position after the previous occurrence of the hash value at each position in the
+    /// window, or 0 if there is none.
+    prev: Vec<u32>,
+    max_hash_checks: u16,
+    nice_length: u32,
+    rle: bool,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(input: &'a [u8], options: &CompressionOptions) -> Matcher<'a> {
+        let rle = options.max_hash_checks == 0 && options.matching_type != MatchingType::Greedy;
+        Matcher {
+            input,
+            head: if options.max_hash_checks > 0 {
+                vec![0; 1 << HASH_BITS]
+            } else {
+                Vec::new()
+            },
+            prev: if options.max_hash_checks > 0 {
+                vec![0; WINDOW_SIZE]
+            } else {
+                Vec::new()
+            },
+            max_hash_checks: options.max_hash_checks,
+            nice_length: cmp::max(u32::from(options.nice_length), u32::from(MIN_MATCH)),
+            rle,
+        }
+    }
+
+    fn hash(&self, position: usize) -> usize {
+        let bytes = &self.input[position..position + 3];
+        let value = u32::from(bytes[0]) | u32::from(bytes[1]) << 8 | u32::from(bytes[2]) << 16;
+        (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    }
+
+    /// Add `position` to the hash chains.
+    fn insert(&mut self, position: usize) {
+        if self.head.is_empty() || position + 3 > self.input.len() {
+            return;
+        }
+        let hash = self.hash(position);
+        self.prev[position & WINDOW_MASK] = self.head[hash];
+        self.head[hash] = position as u32 + 1;
+    }
+
+    fn match_length(&self, position: usize, candidate: usize, max_length: usize) -> usize {
+        self.input[position..position + max_length]
+            .iter()
+            .zip(&self.input[candidate..])
+            .take_while(|&(a, b)| a == b)
+            .count()
+    }
+
+    /// Find the longest match for the data at `position`, which has not been inserted yet.
+    ///
+    /// Returns the length and distance of the match, with a length of 0 if there is none.
+    fn longest_match(&self, position: usize) -> (u32, u32) {
+        let max_length = cmp::min(self.input.len() - position, DEFLATE64_MAX_MATCH as usize);
+        if max_length < usize::from(MIN_MATCH) {
+            return (0, 0);
+        }
+        if self.rle {
+            if position == 0 {
+                return (0, 0);
+            }
+            let length = self.match_length(position, position - 1, max_length);
+            return (length as u32, 1);
+        }
+        if self.head.is_empty() {
+            return (0, 0);
+        }
+
+        let limit = position.saturating_sub(WINDOW_SIZE);
+        let mut best_length = 0;
+        let mut best_distance = 0;
+        let mut next = self.head[self.hash(position)] as usize;
+        for _ in 0..self.max_hash_checks {
+            if next == 0 || next - 1 < limit {
+                break;
+            }
+            let candidate = next - 1;
+            // Check the byte that would make the match longer than the best one first.
+            if self.input[candidate + best_length] == self.input[position + best_length] {
+                let length = self.match_length(position, candidate, max_length);
+                if length > best_length {
+                    best_length = length;
+                    best_distance = position - candidate;
+                    if length >= max_length || length as u32 >= self.nice_length {
+                        break;
+                    }
+                }
+            }
+            let previous = self.prev[candidate & WINDOW_MASK] as usize;
+            // The chain has to go backwards, an entry pointing forwards has been overwritten.
+            if previous >= next {
+                break;
+            }
+            next = previous;
+        }
+        (best_length as u32, best_distance as u32)
+    }
+}
+
+/// The tokens of the block currently being gathered, and their frequencies.
+struct Block {
+    tokens: Vec<Token>,
+    l_freqs: [u16; NUM_LITERALS_AND_LENGTHS],
+    d_freqs: [u16; NUM_DISTANCE_CODES + 2],
+    input_start: usize,
+    input_bytes: usize,
+}
+
+impl Block {
+    fn new() -> Block {
+        Block {
+            tokens: Vec::with_capacity(MAX_BUFFER_LENGTH),
+            l_freqs: [0; NUM_LITERALS_AND_LENGTHS],
+            d_freqs: [0; NUM_DISTANCE_CODES + 2],
+            input_start: 0,
+            input_bytes: 0,
+        }
+    }
+
+    fn push(&mut self, token: Token) {
+        match token {
+            Token::Literal(literal) => {
+                self.l_freqs[usize::from(literal)] += 1;
+                self.input_bytes += 1;
+            }
+            Token::Match { length, distance } => {
+                let length_code = get_deflate64_length_code_and_extra_bits(length).code_number;
+                let distance_code =
+                    get_deflate64_distance_code_and_extra_bits(distance).code_number;
+                self.l_freqs[usize::from(length_code)] += 1;
+                self.d_freqs[usize::from(distance_code)] += 1;
+                self.input_bytes += length as usize;
+            }
+        }
+        self.tokens.push(token);
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens.len() >= MAX_BUFFER_LENGTH
+    }
+
+    /// Write the block, using the block type that gives the smallest output, and clear it for
+    /// the next one.
+    fn write(
+        &mut self,
+        input: &[u8],
+        encoder_state: &mut EncoderState,
+        length_buffers: &mut LengthBuffers,
+        last_block: bool,
+    ) -> BlockKind {
+        self.l_freqs[END_OF_BLOCK_POSITION] = 1;
+        let raw_data = &input[self.input_start..self.input_start + self.input_bytes];
+        // The extra bits of the matches longer than 258 bytes aren't included in the estimated
+        // size, which only makes a stored block slightly less likely to be picked for data that
+        // has them. Each of them saves far more than 16 bits anyhow.
+        let block_type = {
+            let (l_lengths, d_lengths) = encoder_state.huffman_table.get_lengths_mut();
+            gen_huffman_lengths(
+                &self.l_freqs,
+                &self.d_freqs,
+                0,
+                self.input_bytes as u64,
+                encoder_state.writer.pending_bits(),
+                l_lengths,
+                d_lengths,
+                length_buffers,
+                None,
+                None,
+            )
+            .0
+        };
+
+        let kind = match block_type {
+            BlockType::Dynamic(header) => {
+                encoder_state.write_start_of_block(false, last_block);
+                write_huffman_lengths(
+                    &header,
+                    &encoder_state.huffman_table,
+                    &length_buffers.length_buf,
+                    &mut encoder_state.writer,
+                );
+                encoder_state.huffman_table.update_from_lengths();
+                self.write_tokens(encoder_state);
+                BlockKind::Dynamic
+            }
+            BlockType::Fixed => {
+                encoder_state.write_start_of_block(true, last_block);
+                encoder_state.set_huffman_to_fixed();
+                self.write_tokens(encoder_state);
+                BlockKind::Fixed
+            }
+            BlockType::Stored => {
+                write_stored_block(raw_data, &mut encoder_state.writer, last_block);
+                BlockKind::Stored
+            }
+        };
+
+        self.tokens.clear();
+        self.l_freqs = [0; NUM_LITERALS_AND_LENGTHS];
+        self.d_freqs = [0; NUM_DISTANCE_CODES + 2];
+        self.input_start += self.input_bytes;
+        self.input_bytes = 0;
+        kind
+    }
+
+    fn write_tokens(&self, encoder_state: &mut EncoderState) {
+        for &token in &self.tokens {
+            match token {
+                Token::Literal(literal) => {
+                    let code = encoder_state.huffman_table.get_literal(literal);
+                    encoder_state.writer.write_bits(code.code, code.length);
+                }
+                Token::Match { length, distance } => {
+                    encoder_state.write_deflate64_match(length, distance)
+                }
+            }
+        }
+        encoder_state.write_end_of_block();
+    }
+}
+
+/// Find the literals and matches of `input`, passing each of them to `emit`.
+fn tokenize<F: FnMut(Token, usize)>(input: &[u8], options: &CompressionOptions, mut emit: F) {
+    let mut matcher = Matcher::new(input, options);
+    let min_match = cmp::max(u32::from(options.min_match), u32::from(MIN_MATCH));
+    let min_match = if options.filtered {
+        cmp::max(min_match, FILTERED_MIN_MATCH as u32)
+    } else {
+        min_match
+    };
+    let lazy = options.matching_type != MatchingType::Greedy;
+    let lazy_if_less_than = u32::from(options.lazy_if_less_than);
+
+    // A match found at the previous position, held back to see if the next position has a
+    // longer one.
+    let mut previous: Option<(u32, u32)> = None;
+    let mut position = 0;
+    while position < input.len() {
+        let (length, distance) = matcher.longest_match(position);
+        matcher.insert(position);
+
+        if let Some((previous_length, previous_distance)) = previous.take() {
+            if length <= previous_length {
+                let start = position - 1;
+                let end = start + previous_length as usize;
+                emit(
+                    Token::Match {
+                        length: previous_length,
+                        distance: previous_distance,
+                    },
+                    end,
+                );
+                (position + 1..end).for_each(|p| matcher.insert(p));
+                position = end;
+                continue;
+            }
+            emit(Token::Literal(input[position - 1]), position);
+        }
+
+        if length >= min_match {
+            if lazy && length < lazy_if_less_than && position + 1 < input.len() {
+                previous = Some((length, distance));
+                position += 1;
+                continue;
+            }
+            let end = position + length as usize;
+            emit(Token::Match { length, distance }, end);
+            (position + 1..end).for_each(|p| matcher.insert(p));
+            position = end;
+        } else {
+            emit(Token::Literal(input[position]), position + 1);
+            position += 1;
+        }
+    }
+}
+
+/// Compress the given slice of bytes in the Deflate64 format, using the specified compression
+/// level.
+///
+/// Most of the options work like they do for DEFLATE, except `window_bits`, as the window is
+/// always 64 KiB, and `detect_repeats`, `hash_function` and `hash_four_bytes`, which are
+/// ignored. Optimal parsing is not supported, and lazy matching is used instead.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate64_bytes_conf, inflate_bytes_deflate64, Compression};
+///
+/// let data = b"This is some test data";
+/// let compressed_data = deflate64_bytes_conf(data, Compression::Best);
+/// assert_eq!(inflate_bytes_deflate64(&compressed_data).unwrap(), data);
+/// ```
+pub fn deflate64_bytes_conf<O: Into<CompressionOptions>>(input: &[u8], options: O) -> Vec<u8> {
+    let options = options.into();
+    let allocator = StateAllocator::global();
+    let mut encoder_state = EncoderState::new(allocator.vec_with_capacity(input.len() / 2 + 64));
+
+    if options.special == SpecialOptions::ForceStored {
+        write_stored_only(input, &mut encoder_state.writer, true);
+    } else {
+        let mut length_buffers = LengthBuffers::new_in(&allocator);
+        let mut block = Block::new();
+        tokenize(input, &options, |token, end| {
+            block.push(token);
+            // The last block is written once all of the input has been processed.
+            if block.is_full() && end < input.len() {
+                block.write(input, &mut encoder_state, &mut length_buffers, false);
+            }
+        });
+        block.write(input, &mut encoder_state, &mut length_buffers, true);
+    }
+
+    encoder_state.flush();
+    let output = encoder_state.inner_vec();
+    state_into_vec(mem::replace(output, allocator.vec_with_capacity(0)))
+}
+
+/// Compress the given slice of bytes in the Deflate64 format, using the default compression
+/// level.
+pub fn deflate64_bytes(input: &[u8]) -> Vec<u8> {
+    deflate64_bytes_conf(input, CompressionOptions::default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inflate::inflate_bytes_deflate64;
+    use crate::test_utils::{get_random_data, get_test_data};
+
+    #[test]
+    fn deflate64_roundtrip() {
+        let text = get_test_data();
+        let random = get_random_data(60_000);
+        let mut repeated = random.clone();
+        repeated.extend_from_slice(&random);
+        let inputs: [&[u8]; 5] = [&[], b"a", b"Deflate late", &text, &repeated];
+        for &input in &inputs {
+            for &options in &[
+                CompressionOptions::default(),
+                CompressionOptions::fast(),
+                CompressionOptions::high(),
+                CompressionOptions::rle(),
+                CompressionOptions::huffman_only(),
+                CompressionOptions::filtered(),
+                CompressionOptions::stored(),
+            ] {
+                let compressed = deflate64_bytes_conf(input, options);
+                assert!(inflate_bytes_deflate64(&compressed).unwrap() == input);
+            }
+        }
+    }
+
+    #[test]
+    fn deflate64_long_window() {
+        // Random data repeated 60000 bytes later can only be compressed with distances above
+        // 32768.
+        let random = get_random_data(60_000);
+        let mut input = random.clone();
+        input.extend_from_slice(&random);
+        let compressed = deflate64_bytes(&input);
+        assert!(compressed.len() < random.len() + 1000);
+        // The distance codes above 32768 are not valid DEFLATE.
+        assert!(crate::inflate_bytes(&compressed).is_err());
+        assert!(crate::deflate_bytes(&input).len() > input.len());
+    }
+
+    #[test]
+    fn deflate64_long_matches() {
+        // A run is encoded with matches of up to 65538 bytes.
+        let input = vec![7; 1_000_000];
+        let compressed = deflate64_bytes_conf(&input, CompressionOptions::rle());
+        assert!(compressed.len() < 100);
+        assert!(inflate_bytes_deflate64(&compressed).unwrap() == input);
+
+        let mut tokens = Vec::new();
+        tokenize(
+            &input[..70_000],
+            &CompressionOptions::default(),
+            |token, _| tokens.push(token),
+        );
+        assert_eq!(
+            tokens,
+            [
+                Token::Literal(7),
+                Token::Match {
+                    length: 65538,
+                    distance: 1
+                },
+                Token::Match {
+                    length: 70_000 - 65539,
+                    distance: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn deflate64_codes() {
+        let code = |length| {
+            let bits = get_deflate64_length_code_and_extra_bits(length);
+            (bits.code_number, bits.num_bits, bits.value)
+        };
+        assert_eq!(code(3), (257, 0, 0));
+        assert_eq!(code(257), (284, 5, 30));
+        assert_eq!(code(258), (284, 5, 31));
+        assert_eq!(code(259), (285, 16, 256));
+        assert_eq!(code(65538), (285, 16, 65535));
+
+        let code = |distance| {
+            let bits = get_deflate64_distance_code_and_extra_bits(distance);
+            (bits.code_number, bits.num_bits, bits.value)
+        };
+        assert_eq!(code(32768), (29, 13, 8191));
+        assert_eq!(code(32769), (30, 14, 0));
+        assert_eq!(code(49153), (31, 14, 0));
+        assert_eq!(code(65536), (31, 14, 16383));
+    }
+}
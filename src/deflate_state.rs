@@ -1,19 +1,33 @@
 use std::io::Write;
 use std::{cmp, io, mem};
 
+use crate::allocator::{state_into_vec, vec_into_state, StateAllocator, StateVec};
+use crate::block_codes::{BlockCodes, BlockInfo, BlockKind};
+use crate::boundary::{AccessPoint, BoundaryPolicy};
+use crate::chained_hash_table::MAX_HASH_BITS;
+use crate::code_lengths::CodeLengths;
 use crate::compress::Flush;
-use crate::compression_options::{CompressionOptions, MAX_HASH_CHECKS};
+use crate::compression_options::{
+    clamp_mem_level, clamp_window_bits, hash_function_address, CompressionOptions,
+    DEFAULT_MEM_LEVEL, FILTERED_MIN_MATCH, MAX_HASH_CHECKS,
+};
 use crate::encoder_state::EncoderState;
+use crate::error::{Error, MISSING_WRITER};
 pub use crate::huffman_table::MAX_MATCH;
-use crate::huffman_table::NUM_LITERALS_AND_LENGTHS;
-use crate::input_buffer::InputBuffer;
-use crate::length_encode::{EncodedLength, LeafVec};
+use crate::huffman_table::{MIN_MATCH, NUM_LITERALS_AND_LENGTHS};
+use crate::input_buffer::{InputBuffer, InputWindow};
+use crate::length_encode::{EncodedLength, LeafVec, Node};
 use crate::lz77::LZ77State;
-use crate::output_writer::DynamicWriter;
+use crate::output_writer::{
+    DynamicWriter, LARGEST_BUFFER_LENGTH, MAX_BUFFER_LENGTH, SMALLEST_BUFFER_LENGTH,
+};
+use crate::priors::FrequencyPriors;
+use crate::recompress::BlockBoundary;
+use crate::stats::Stats;
 
 /// A counter used for checking values in debug mode.
 /// Does nothing when debug assertions are disabled.
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct DebugCounter {
     #[cfg(debug_assertions)]
     count: u64,
@@ -47,26 +61,178 @@ impl DebugCounter {
     pub fn add(&self, _: u64) {}
 }
 
+/// The heap memory used by an encoder, broken down by component.
+///
+/// All sizes are in bytes, and are based on the amount of memory allocated for each buffer rather
+/// than how much of it is currently in use. Memory used by the wrapped writer or a boundary policy
+/// is not included, nor is the size of the encoder struct itself, which can be found with
+/// `mem::size_of`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct MemoryUsage {
+    /// The hash chains used to find matches.
+    pub hash_table: usize,
+    /// The window of input data.
+    pub window: usize,
+    /// The lz77-encoded symbols waiting to be written as a block.
+    pub symbol_buffer: usize,
+    /// Compressed data waiting to be written to the wrapped writer.
+    pub output_buffer: usize,
+    /// Other buffers, such as the ones used when building huffman codes and recorded block
+    /// boundaries.
+    pub other: usize,
+}
+
+impl MemoryUsage {
+    /// The total number of bytes used.
+    pub fn total(&self) -> usize {
+        self.hash_table + self.window + self.symbol_buffer + self.output_buffer + self.other
+    }
+}
+
 pub struct LengthBuffers {
     pub leaf_buf: LeafVec,
-    pub length_buf: Vec<EncodedLength>,
+    pub length_buf: StateVec<EncodedLength>,
 }
 
 impl LengthBuffers {
     #[inline]
-    fn new() -> LengthBuffers {
+    pub fn new() -> LengthBuffers {
+        LengthBuffers::new_in(&StateAllocator::global())
+    }
+
+    /// Create the buffers, allocating them with `allocator`.
+    pub fn new_in(allocator: &StateAllocator) -> LengthBuffers {
         LengthBuffers {
-            leaf_buf: Vec::with_capacity(NUM_LITERALS_AND_LENGTHS),
-            length_buf: Vec::with_capacity(19),
+            leaf_buf: allocator.vec_with_capacity(NUM_LITERALS_AND_LENGTHS),
+            length_buf: allocator.vec_with_capacity(19),
+        }
+    }
+
+    /// The number of bytes of heap memory allocated for the buffers.
+    pub fn heap_size(&self) -> usize {
+        self.leaf_buf.capacity() * mem::size_of::<Node>()
+            + self.length_buf.capacity() * mem::size_of::<EncodedLength>()
+    }
+}
+
+/// Create a new lz77 state using the matching parameters in `compression_options`, allocating
+/// the hash chains with `allocator`.
+pub fn new_lz77_state(
+    compression_options: &CompressionOptions,
+    allocator: &StateAllocator,
+) -> LZ77State {
+    let mut lz77_state = LZ77State::new(
+        compression_options.max_hash_checks,
+        cmp::min(compression_options.lazy_if_less_than, MAX_HASH_CHECKS),
+        compression_options.matching_type,
+        allocator,
+    );
+    lz77_state.set_matcher(compression_options.matcher, allocator);
+    lz77_state.set_hash_function(compression_options.hash_function);
+    lz77_state.set_window_size(1 << clamp_window_bits(compression_options.window_bits));
+    lz77_state.set_detect_repeats(compression_options.detect_repeats);
+    lz77_state.set_min_match(min_match(compression_options));
+    lz77_state.set_hash_four_bytes(hash_four_bytes(compression_options));
+    lz77_state.set_hash_bits(hash_bits(compression_options));
+    lz77_state.set_search_lengths(
+        usize::from(compression_options.good_length),
+        usize::from(compression_options.nice_length),
+    );
+    lz77_state.set_two_step_lazy(compression_options.two_step_lazy);
+    lz77_state.set_skip_incompressible(compression_options.skip_incompressible);
+    lz77_state.set_max_insert_length(usize::from(compression_options.max_insert_length));
+    lz77_state
+}
+
+/// Change the matching parameters of `lz77_state` to the ones in `compression_options`, keeping
+/// the hash chains unless the matcher type changes, in which case the new matcher is allocated
+/// with `allocator`.
+fn apply_lz77_options(
+    lz77_state: &mut LZ77State,
+    compression_options: &CompressionOptions,
+    allocator: &StateAllocator,
+) {
+    lz77_state.set_matcher(compression_options.matcher, allocator);
+    lz77_state.set_matching_parameters(
+        compression_options.max_hash_checks,
+        cmp::min(compression_options.lazy_if_less_than, MAX_HASH_CHECKS),
+        compression_options.matching_type,
+    );
+    lz77_state.set_hash_function(compression_options.hash_function);
+    lz77_state.set_window_size(1 << clamp_window_bits(compression_options.window_bits));
+    lz77_state.set_detect_repeats(compression_options.detect_repeats);
+    lz77_state.set_min_match(min_match(compression_options));
+    lz77_state.set_hash_four_bytes(hash_four_bytes(compression_options));
+    lz77_state.set_hash_bits(hash_bits(compression_options));
+    lz77_state.set_search_lengths(
+        usize::from(compression_options.good_length),
+        usize::from(compression_options.nice_length),
+    );
+    lz77_state.set_two_step_lazy(compression_options.two_step_lazy);
+    lz77_state.set_skip_incompressible(compression_options.skip_incompressible);
+    lz77_state.set_max_insert_length(usize::from(compression_options.max_insert_length));
+}
+
+/// Whether `new` only differs from `old` in the options that can be changed in the middle of a
+/// stream, that is the ones that don't affect the window, the hash chains or the repeat index.
+// The hash functions are compared by address, so a false negative just means the options can't
+// be changed.
+fn can_change_options(old: &CompressionOptions, new: &CompressionOptions) -> bool {
+    clamp_window_bits(old.window_bits) == clamp_window_bits(new.window_bits)
+        && hash_bits(old) == hash_bits(new)
+        && hash_four_bytes(old) == hash_four_bytes(new)
+        && hash_function_address(old.hash_function) == hash_function_address(new.hash_function)
+        && old.detect_repeats == new.detect_repeats
+        && old.matcher == new.matcher
+}
+
+/// The shortest match the lz77 state should use with `compression_options`.
+fn min_match(compression_options: &CompressionOptions) -> usize {
+    let min_match = usize::from(compression_options.min_match);
+    if compression_options.filtered {
+        cmp::max(min_match, FILTERED_MIN_MATCH)
+    } else {
+        min_match
+    }
+}
+
+/// Whether the hash chains should hash four bytes for each position with `compression_options`.
+fn hash_four_bytes(compression_options: &CompressionOptions) -> bool {
+    compression_options.hash_four_bytes || min_match(compression_options) > usize::from(MIN_MATCH)
+}
+
+/// The number of bits of the hash used by the hash chains with `compression_options`.
+fn hash_bits(compression_options: &CompressionOptions) -> u8 {
+    cmp::min(
+        clamp_mem_level(compression_options.mem_level) + 7,
+        MAX_HASH_BITS,
+    )
+}
+
+/// The number of literals/lengths buffered before a block is output with `compression_options`.
+pub fn symbol_buffer_length(compression_options: &CompressionOptions) -> usize {
+    match compression_options.block_length {
+        Some(block_length) => {
+            usize::from(block_length).clamp(SMALLEST_BUFFER_LENGTH, LARGEST_BUFFER_LENGTH)
+        }
+        None => {
+            MAX_BUFFER_LENGTH
+                >> DEFAULT_MEM_LEVEL.saturating_sub(clamp_mem_level(compression_options.mem_level))
         }
     }
 }
 
+/// A function called with information about each block after it has been output.
+pub type BlockCallback = Box<dyn FnMut(&BlockInfo) + Send>;
+
 /// A struct containing all the stored state used for the encoder.
-pub struct DeflateState<W: Write> {
+///
+/// `B` is the window the input data is read from, which is normally an `InputBuffer` that input
+/// data is copied into.
+pub struct DeflateState<W: Write, B: InputWindow = InputBuffer> {
     /// State of lz77 compression.
     pub lz77_state: LZ77State,
-    pub input_buffer: InputBuffer,
+    pub input_buffer: B,
     pub compression_options: CompressionOptions,
     /// State the huffman part of the compression and the output buffer.
     pub encoder_state: EncoderState,
@@ -74,6 +240,10 @@ pub struct DeflateState<W: Write> {
     pub lz77_writer: DynamicWriter,
     /// Buffers used when generating huffman code lengths.
     pub length_buffers: LengthBuffers,
+    /// Input waiting to be output as a stored block, when only stored blocks are used.
+    pub stored_input: StateVec<u8>,
+    /// The allocator the internal buffers are allocated with.
+    pub allocator: StateAllocator,
     /// Total number of bytes consumed/written to the input buffer.
     pub bytes_written: u64,
     /// Wrapped writer.
@@ -84,6 +254,40 @@ pub struct DeflateState<W: Write> {
     /// what data has been output in case not all data is output when writing to the wrapped
     /// writer.
     pub output_buf_pos: usize,
+    /// Total number of bytes that have been written to the wrapped writer from the output buffer.
+    pub output_bytes_flushed: u64,
+    /// Total number of input bytes consumed since the state was created, across all streams.
+    pub total_in: u64,
+    /// Total number of bytes written to the wrapped writers since the state was created, across
+    /// all streams.
+    pub total_out: u64,
+    /// Number of input bytes represented by the blocks that have been output so far.
+    pub block_input_start: u64,
+    /// The start of each block that has been output, if recording them has been enabled.
+    pub block_boundaries: Option<Vec<BlockBoundary>>,
+    /// The huffman codes used for each block that has been output, if recording them has been
+    /// enabled.
+    pub block_codes: Option<Vec<BlockCodes>>,
+    /// The positions where decompression can start, if recording them has been enabled.
+    pub access_points: Option<Vec<AccessPoint>>,
+    /// The value of `total_in` at the start of the current stream, which the input offsets of
+    /// access points are relative to.
+    pub stream_start_in: u64,
+    /// Statistics about the blocks that have been output, if gathering them has been enabled.
+    pub stats: Option<Stats>,
+    /// Policy deciding where to insert additional boundaries in the output, if any.
+    pub boundary_policy: Option<Box<dyn BoundaryPolicy + Send>>,
+    /// Function called with information about each block after it has been output, if any.
+    pub block_callback: Option<BlockCallback>,
+    /// Number of input bytes consumed since the boundary policy last inserted a boundary.
+    pub bytes_since_boundary: u64,
+    /// Prior symbol frequencies blended into the observed ones when generating dynamic codes.
+    pub frequency_priors: Option<FrequencyPriors>,
+    /// Code lengths to use for dynamic blocks instead of generating them, if any.
+    pub code_lengths: Option<CodeLengths>,
+    /// Whether the output is left in the output buffer rather than written to the wrapped
+    /// writer, so it can be taken directly without going through the `Write` trait.
+    pub direct_output: bool,
     pub flush_mode: Flush,
     /// Number of bytes written as calculated by sum of block input lengths.
     /// Used to check that they are correct when `debug_assertions` are enabled.
@@ -92,28 +296,17 @@ pub struct DeflateState<W: Write> {
 
 impl<W: Write> DeflateState<W> {
     pub fn new(compression_options: CompressionOptions, writer: W) -> DeflateState<W> {
-        DeflateState {
-            input_buffer: InputBuffer::empty(),
-            lz77_state: LZ77State::new(
-                compression_options.max_hash_checks,
-                cmp::min(compression_options.lazy_if_less_than, MAX_HASH_CHECKS),
-                compression_options.matching_type,
-            ),
-            encoder_state: EncoderState::new(Vec::with_capacity(1024 * 32)),
-            lz77_writer: DynamicWriter::new(),
-            length_buffers: LengthBuffers::new(),
-            compression_options,
-            bytes_written: 0,
-            inner: Some(writer),
-            output_buf_pos: 0,
-            flush_mode: Flush::None,
-            bytes_written_control: DebugCounter::default(),
-        }
+        DeflateState::new_in(compression_options, writer, StateAllocator::global())
     }
 
-    #[inline]
-    pub fn output_buf(&mut self) -> &mut Vec<u8> {
-        self.encoder_state.inner_vec()
+    /// Create a new state, allocating all the internal buffers with `allocator`.
+    pub fn new_in(
+        compression_options: CompressionOptions,
+        writer: W,
+        allocator: StateAllocator,
+    ) -> DeflateState<W> {
+        let input_buffer = InputBuffer::empty_in(&allocator);
+        DeflateState::with_input_window_in(compression_options, writer, input_buffer, allocator)
     }
 
     /// Resets the status of the decoder, leaving the compression options intact
@@ -124,22 +317,469 @@ impl<W: Write> DeflateState<W> {
     ///
     /// If flushing fails, the rest of the writer is not cleared.
     pub fn reset(&mut self, writer: W) -> io::Result<W> {
+        self.reset_state()?;
+        self.stream_start_in = self.total_in;
+        if let Some(ref mut points) = self.access_points {
+            points.clear();
+        }
+        mem::replace(&mut self.inner, Some(writer))
+            .ok_or_else(|| io::Error::from(Error::InvalidState(MISSING_WRITER)))
+    }
+
+    /// Like `reset`, but also replaces the compression options.
+    ///
+    /// The buffers and hash chains are kept, so no memory is reallocated unless the memory level
+    /// changes.
+    pub fn reset_with_options(
+        &mut self,
+        writer: W,
+        compression_options: CompressionOptions,
+    ) -> io::Result<W> {
+        let w = self.reset(writer)?;
+        self.compression_options = compression_options;
+        apply_lz77_options(&mut self.lz77_state, &compression_options, &self.allocator);
+        self.lz77_writer
+            .set_buffer_length(symbol_buffer_length(&compression_options));
+        Ok(w)
+    }
+
+    /// Check that the options can be changed to `compression_options` in the middle of the
+    /// stream with `change_options`.
+    pub fn check_options_change(&self, compression_options: &CompressionOptions) -> io::Result<()> {
+        if can_change_options(&self.compression_options, compression_options) {
+            Ok(())
+        } else {
+            Err(Error::InvalidOptions(
+                "The window, memory level and hashing can not be changed in the middle of a stream",
+            )
+            .into())
+        }
+    }
+
+    /// Change the options in the middle of the stream, keeping the window.
+    ///
+    /// This should only be done at the end of a block, with no pending input, after checking the
+    /// options with `check_options_change`. Switching to or from only outputting stored blocks
+    /// clears the window, as the stored data is not added to it.
+    pub fn change_options(&mut self, compression_options: CompressionOptions) {
+        debug_assert!(can_change_options(
+            &self.compression_options,
+            &compression_options
+        ));
+        let stored_changed = self.compression_options.special != compression_options.special;
+        self.compression_options = compression_options;
+        self.lz77_state.set_matching_parameters(
+            compression_options.max_hash_checks,
+            cmp::min(compression_options.lazy_if_less_than, MAX_HASH_CHECKS),
+            compression_options.matching_type,
+        );
+        self.lz77_state
+            .set_min_match(min_match(&compression_options));
+        self.lz77_state.set_search_lengths(
+            usize::from(compression_options.good_length),
+            usize::from(compression_options.nice_length),
+        );
+        self.lz77_state
+            .set_two_step_lazy(compression_options.two_step_lazy);
+        self.lz77_state
+            .set_skip_incompressible(compression_options.skip_incompressible);
+        self.lz77_writer
+            .set_buffer_length(symbol_buffer_length(&compression_options));
+        self.lz77_state
+            .set_max_insert_length(usize::from(compression_options.max_insert_length));
+        if stored_changed {
+            self.reset_window();
+        }
+    }
+
+    /// Flush any remaining output to the current writer and reset the status of the encoder,
+    /// leaving the writer and the compression options intact.
+    pub fn reset_state(&mut self) -> io::Result<()> {
         self.encoder_state.flush();
         self.inner
             .as_mut()
-            .expect("Missing writer!")
+            .ok_or(Error::InvalidState(MISSING_WRITER))?
             .write_all(self.encoder_state.inner_vec())?;
+        self.total_out += self.encoder_state.inner_vec().len() as u64;
         self.encoder_state.inner_vec().clear();
-        self.input_buffer = InputBuffer::empty();
+        self.input_buffer.clear();
         self.lz77_writer.clear();
+        self.stored_input.clear();
         self.lz77_state.reset();
         self.bytes_written = 0;
         self.output_buf_pos = 0;
+        self.output_bytes_flushed = 0;
+        self.block_input_start = 0;
+        self.bytes_since_boundary = 0;
+        if let Some(ref mut boundaries) = self.block_boundaries {
+            boundaries.clear();
+        }
+        if let Some(ref mut codes) = self.block_codes {
+            codes.clear();
+        }
+        if let Some(ref mut stats) = self.stats {
+            *stats = Stats::default();
+        }
         self.flush_mode = Flush::None;
         if cfg!(debug_assertions) {
             self.bytes_written_control.reset();
         }
-        mem::replace(&mut self.inner, Some(writer))
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Missing writer"))
+        Ok(())
+    }
+
+    /// The heap memory currently used by the state.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let boundaries = self
+            .block_boundaries
+            .as_ref()
+            .map_or(0, |b| b.capacity() * mem::size_of::<BlockBoundary>());
+        let codes = self.block_codes.as_ref().map_or(0, |codes| {
+            codes.capacity() * mem::size_of::<BlockCodes>()
+                + codes
+                    .iter()
+                    .map(|c| c.literal_length_lengths.capacity() + c.distance_lengths.capacity())
+                    .sum::<usize>()
+        });
+        let access_points = self
+            .access_points
+            .as_ref()
+            .map_or(0, |p| p.capacity() * mem::size_of::<AccessPoint>());
+        let priors = self
+            .frequency_priors
+            .as_ref()
+            .map_or(0, FrequencyPriors::heap_size);
+        let code_lengths = self.code_lengths.as_ref().map_or(0, CodeLengths::heap_size);
+        let stats = self.stats.as_ref().map_or(0, Stats::heap_size);
+        MemoryUsage {
+            hash_table: self.lz77_state.hash_table_size(),
+            window: self.input_buffer.heap_size() + self.stored_input.capacity(),
+            symbol_buffer: self.lz77_writer.heap_size(),
+            output_buffer: self.encoder_state.writer.w.capacity(),
+            other: self.length_buffers.heap_size()
+                + boundaries
+                + codes
+                + access_points
+                + priors
+                + code_lengths
+                + stats,
+        }
+    }
+
+    /// Clear the window and hash chains, so data compressed after this can not reference
+    /// earlier data.
+    ///
+    /// This should only be done after a sync flush, when there is no pending input.
+    pub fn reset_window(&mut self) {
+        debug_assert!(self.lz77_writer.get_buffer().is_empty());
+        self.input_buffer.clear();
+        self.lz77_state.reset();
+    }
+}
+
+impl DeflateState<io::Sink> {
+    /// Create a new state appending the compressed data directly to `output`, which can be taken
+    /// with `take_direct_output` once compression is done.
+    ///
+    /// This avoids the overhead of copying the data to a writer when compressing to memory.
+    pub fn with_direct_output(
+        compression_options: CompressionOptions,
+        output: Vec<u8>,
+    ) -> DeflateState<io::Sink> {
+        let allocator = StateAllocator::global();
+        let mut state = DeflateState::with_buffers(
+            compression_options,
+            io::sink(),
+            InputBuffer::empty_in(&allocator),
+            vec_into_state(output),
+            allocator,
+        );
+        state.direct_output = true;
+        state
+    }
+
+    /// Take the output of a state created with `with_direct_output`.
+    ///
+    /// Any bits that don't fill a whole byte are padded with zeroes.
+    pub fn take_direct_output(&mut self) -> Vec<u8> {
+        debug_assert!(self.direct_output);
+        self.encoder_state.flush();
+        let output = mem::replace(
+            &mut self.encoder_state.writer.w,
+            StateAllocator::global().vec_with_capacity(0),
+        );
+        state_into_vec(output)
+    }
+
+    /// Create a copy of a state created with `with_direct_output`, including the output and any
+    /// input that has not been compressed yet, so compression can continue from the same point
+    /// in both.
+    ///
+    /// The boundary policy, if any, is not copied.
+    // `StateAllocator` is only `Copy` without the `allocator` feature.
+    #[allow(clippy::clone_on_copy)]
+    pub fn clone_direct(&self) -> DeflateState<io::Sink> {
+        debug_assert!(self.direct_output);
+        DeflateState {
+            input_buffer: self.input_buffer.clone(),
+            lz77_state: self.lz77_state.clone(),
+            encoder_state: self.encoder_state.clone(),
+            lz77_writer: self.lz77_writer.clone(),
+            length_buffers: LengthBuffers::new(),
+            stored_input: self.stored_input.clone(),
+            allocator: self.allocator.clone(),
+            compression_options: self.compression_options,
+            bytes_written: self.bytes_written,
+            inner: Some(io::sink()),
+            output_buf_pos: self.output_buf_pos,
+            output_bytes_flushed: self.output_bytes_flushed,
+            total_in: self.total_in,
+            total_out: self.total_out,
+            block_input_start: self.block_input_start,
+            block_boundaries: self.block_boundaries.clone(),
+            block_codes: self.block_codes.clone(),
+            access_points: self.access_points.clone(),
+            stream_start_in: self.stream_start_in,
+            stats: self.stats.clone(),
+            boundary_policy: None,
+            block_callback: None,
+            frequency_priors: self.frequency_priors.clone(),
+            code_lengths: self.code_lengths.clone(),
+            bytes_since_boundary: self.bytes_since_boundary,
+            direct_output: true,
+            flush_mode: self.flush_mode,
+            bytes_written_control: self.bytes_written_control.clone(),
+        }
+    }
+}
+
+impl<W: Write, B: InputWindow> DeflateState<W, B> {
+    /// Create a new state reading input data from the provided input window.
+    pub fn with_input_window(
+        compression_options: CompressionOptions,
+        writer: W,
+        input_buffer: B,
+    ) -> DeflateState<W, B> {
+        DeflateState::with_input_window_in(
+            compression_options,
+            writer,
+            input_buffer,
+            StateAllocator::global(),
+        )
+    }
+
+    /// Create a new state reading input data from the provided input window, allocating the
+    /// other internal buffers with `allocator`.
+    pub fn with_input_window_in(
+        compression_options: CompressionOptions,
+        writer: W,
+        input_buffer: B,
+        allocator: StateAllocator,
+    ) -> DeflateState<W, B> {
+        let output_buf = allocator.vec_with_capacity(1024 * 32);
+        DeflateState::with_buffers(
+            compression_options,
+            writer,
+            input_buffer,
+            output_buf,
+            allocator,
+        )
+    }
+
+    fn with_buffers(
+        compression_options: CompressionOptions,
+        writer: W,
+        input_buffer: B,
+        output_buf: StateVec<u8>,
+        allocator: StateAllocator,
+    ) -> DeflateState<W, B> {
+        let lz77_state = new_lz77_state(&compression_options, &allocator);
+        DeflateState {
+            input_buffer,
+            lz77_state,
+            encoder_state: EncoderState::new(output_buf),
+            lz77_writer: DynamicWriter::with_buffer_length_in(
+                symbol_buffer_length(&compression_options),
+                &allocator,
+            ),
+            length_buffers: LengthBuffers::new_in(&allocator),
+            stored_input: allocator.vec_with_capacity(0),
+            allocator,
+            compression_options,
+            bytes_written: 0,
+            inner: Some(writer),
+            output_buf_pos: 0,
+            output_bytes_flushed: 0,
+            total_in: 0,
+            total_out: 0,
+            block_input_start: 0,
+            block_boundaries: None,
+            block_codes: None,
+            access_points: None,
+            stream_start_in: 0,
+            stats: None,
+            boundary_policy: None,
+            block_callback: None,
+            frequency_priors: None,
+            code_lengths: None,
+            bytes_since_boundary: 0,
+            direct_output: false,
+            flush_mode: Flush::None,
+            bytes_written_control: DebugCounter::default(),
+        }
+    }
+
+    #[inline]
+    pub fn output_buf(&mut self) -> &mut StateVec<u8> {
+        self.encoder_state.inner_vec()
+    }
+
+    /// Fill the window with data preceding the input, so the start of the input can reference it.
+    ///
+    /// This has to be called before any data has been compressed.
+    pub fn prime_window(&mut self, data: &[u8]) {
+        self.lz77_state.prime_window(&mut self.input_buffer, data);
+    }
+
+    /// Use `dictionary` as a preset dictionary, which the compressed data can refer back to.
+    ///
+    /// Returns an error if any data has been compressed, or a dictionary has already been set.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) -> io::Result<()> {
+        if self.bytes_written != 0 || self.input_buffer.current_end() != 0 {
+            return Err(Error::InvalidState(
+                "The dictionary has to be set before compressing any data",
+            )
+            .into());
+        }
+        self.prime_window(dictionary);
+        Ok(())
+    }
+
+    /// Insert the lowest `bits` bits of `value` into the output, like `deflatePrime` in zlib.
+    ///
+    /// Returns an error if more than 16 bits are given, or if any data has been compressed.
+    pub fn prime_bits(&mut self, bits: u8, value: u16) -> io::Result<()> {
+        if bits > 16 {
+            return Err(Error::InvalidOptions("At most 16 bits can be inserted at a time").into());
+        }
+        if self.bytes_written != 0 {
+            return Err(Error::InvalidState(
+                "Bits have to be inserted before compressing any data",
+            )
+            .into());
+        }
+        if bits > 0 {
+            let value = (u32::from(value) & ((1 << bits) - 1)) as u16;
+            self.encoder_state.writer.write_bits(value, bits);
+        }
+        Ok(())
+    }
+
+    /// Record the start of the block that is about to be output, if block recording is enabled,
+    /// and advance the input position by the number of bytes in the block.
+    pub fn record_block_start(&mut self, block_input_bytes: u64) {
+        let output_bit = self.output_bits();
+        if let Some(ref mut boundaries) = self.block_boundaries {
+            boundaries.push(BlockBoundary {
+                input_offset: self.block_input_start,
+                output_bit,
+            });
+        }
+        self.block_input_start += block_input_bytes;
+    }
+
+    /// Record the codes used for a block that has just been written, if recording them has been
+    /// enabled.
+    pub fn record_block_codes(&mut self, kind: BlockKind, input_bytes: u64, output_bit: u64) {
+        let output_bits = self.output_bits() - output_bit;
+        if let Some(ref mut codes) = self.block_codes {
+            codes.push(BlockCodes::from_table(
+                kind,
+                &self.encoder_state.huffman_table,
+                input_bytes,
+                output_bit,
+                output_bits,
+            ));
+        }
+    }
+
+    /// Record the current position as an access point, if recording them is enabled.
+    ///
+    /// This should only be done right after a full flush or at the start of a new gzip member.
+    pub fn record_access_point(&mut self) {
+        let input_offset = self.total_in - self.stream_start_in;
+        let output_bit = self.output_bits();
+        if let Some(ref mut points) = self.access_points {
+            points.push(AccessPoint {
+                input_offset,
+                output_bit,
+            });
+        }
+    }
+
+    /// Pass information about a block that has just been written to the block callback, if one
+    /// has been set.
+    pub fn report_block(&mut self, kind: BlockKind, input_bytes: u64, output_bit: u64) {
+        let output_bits = self.output_bits() - output_bit;
+        // The start of the next block has already been recorded.
+        let input_start = self.block_input_start - input_bytes;
+        if let Some(ref mut callback) = self.block_callback {
+            callback(&BlockInfo {
+                kind,
+                input_start,
+                input_bytes,
+                output_bit,
+                output_bits,
+            });
+        }
+    }
+
+    /// Add a block that has just been written to the statistics, if gathering them has been
+    /// enabled.
+    pub fn record_block_stats(&mut self, kind: BlockKind, input_bytes: u64, output_bit: u64) {
+        let output_bits = self.output_bits() - output_bit;
+        if let Some(ref mut stats) = self.stats {
+            stats.add_block(
+                kind,
+                input_bytes,
+                output_bits,
+                self.lz77_writer.get_buffer(),
+            );
+        }
+    }
+
+    /// Write `trailer` directly to the wrapped writer, after all the compressed data has been
+    /// output.
+    pub fn write_trailer(&mut self, trailer: &[u8]) -> io::Result<()> {
+        self.inner
+            .as_mut()
+            .ok_or(Error::InvalidState(MISSING_WRITER))?
+            .write_all(trailer)?;
+        self.total_out += trailer.len() as u64;
+        Ok(())
+    }
+
+    /// Clear the output buffer after all of it has been written to the wrapped writer.
+    pub fn clear_output_buf(&mut self) {
+        self.output_bytes_flushed += self.encoder_state.writer.w.len() as u64;
+        self.output_buf_pos = 0;
+        self.output_buf().clear();
+    }
+
+    /// The number of whole bytes and extra bits of output held in the output buffer that have
+    /// not been written to the wrapped writer yet.
+    pub fn pending_output(&self) -> (usize, u8) {
+        let writer = &self.encoder_state.writer;
+        let bits = writer.pending_bits();
+        (
+            writer.w.len() - self.output_buf_pos + usize::from(bits / 8),
+            bits % 8,
+        )
+    }
+
+    /// The total number of bits output so far, including data that is still buffered.
+    ///
+    /// This does not include input data that has not been compressed yet.
+    pub fn output_bits(&self) -> u64 {
+        self.output_bytes_flushed * 8 + self.encoder_state.bits_buffered()
     }
 }
@@ -1,3 +1,4 @@
+use crate::allocator::StateVec;
 use crate::bitstream::LsbWriter;
 use crate::huffman_table::HuffmanTable;
 use crate::lzvalue::LZType;
@@ -20,6 +21,7 @@ pub enum BType {
 }
 
 /// A struct wrapping a writer that writes data compressed using the provided huffman table
+#[derive(Clone)]
 pub struct EncoderState {
     pub huffman_table: HuffmanTable,
     pub writer: LsbWriter,
@@ -27,7 +29,7 @@ pub struct EncoderState {
 
 impl EncoderState {
     /// Creates a new encoder state using the provided huffman table and writer
-    pub fn new(writer: Vec<u8>) -> EncoderState {
+    pub fn new(writer: StateVec<u8>) -> EncoderState {
         EncoderState {
             huffman_table: HuffmanTable::empty(),
             writer: LsbWriter::new(writer),
@@ -36,17 +38,23 @@ impl EncoderState {
 
     #[cfg(test)]
     /// Creates a new encoder state using the fixed huffman table
-    pub fn fixed(writer: Vec<u8>) -> EncoderState {
+    pub fn fixed(writer: StateVec<u8>) -> EncoderState {
         EncoderState {
             huffman_table: HuffmanTable::fixed_table(),
             writer: LsbWriter::new(writer),
         }
     }
 
-    pub fn inner_vec(&mut self) -> &mut Vec<u8> {
+    pub fn inner_vec(&mut self) -> &mut StateVec<u8> {
         &mut self.writer.w
     }
 
+    /// The number of bits currently held in the output buffer, including bits that have not yet
+    /// been written to the byte buffer.
+    pub fn bits_buffered(&self) -> u64 {
+        self.writer.w.len() as u64 * 8 + u64::from(self.writer.pending_bits())
+    }
+
     /// Encodes a literal value to the writer
     fn write_literal(&mut self, value: u8) {
         let code = self.huffman_table.get_literal(value);
@@ -81,6 +89,16 @@ impl EncoderState {
         };
     }
 
+    /// Write a match in the Deflate64 format.
+    pub fn write_deflate64_match(&mut self, length: u32, distance: u32) {
+        for code in &self
+            .huffman_table
+            .get_deflate64_match_huffman(length, distance)
+        {
+            self.writer.write_bits(code.code, code.length);
+        }
+    }
+
     /// Write the start of a block, returning Err if the write operation fails.
     pub fn write_start_of_block(&mut self, fixed: bool, final_block: bool) {
         if final_block {
@@ -116,7 +134,7 @@ impl EncoderState {
     /// Reset the encoder state with a new writer, returning the old one if flushing
     /// succeeds.
     #[cfg(test)]
-    pub fn reset(&mut self, writer: Vec<u8>) -> Vec<u8> {
+    pub fn reset(&mut self, writer: StateVec<u8>) -> StateVec<u8> {
         // Make sure the writer is flushed
         // Ideally this should be done before this function is called, but we
         // do it here just in case.
@@ -0,0 +1,84 @@
+//! The error type returned by the encoders.
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Message used when the writer of an encoder is used after it has been taken out of it.
+pub const MISSING_WRITER: &str = "The writer has already been taken out of the encoder";
+
+/// An error from compressing data.
+///
+/// The encoders implement `io::Write`, so most functions return an `io::Error`. Errors that are
+/// not caused by the underlying reader or writer are wrapped in one, and `Error::from` converts
+/// such an `io::Error` back into the original error.
+#[derive(Debug)]
+pub enum Error {
+    /// An error from the underlying reader or writer.
+    Io(io::Error),
+    /// The options or format can't be used for the requested operation.
+    InvalidOptions(&'static str),
+    /// The encoder was used in a way its current state doesn't allow, like writing to it after
+    /// it has been finished.
+    InvalidState(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "I/O error: {}", err),
+            Error::InvalidOptions(msg) => write!(f, "Invalid options: {}", msg),
+            Error::InvalidState(msg) => write!(f, "Invalid encoder state: {}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    /// Wrap an I/O error, unwrapping it instead if it is itself a wrapped `Error`.
+    fn from(err: io::Error) -> Error {
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let inner = err.into_inner().expect("Checked above");
+            *inner.downcast::<Error>().expect("Checked above")
+        } else {
+            Error::Io(err)
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidInput, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn io_error_roundtrip() {
+        let err: io::Error = Error::InvalidState("finished").into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        match Error::from(err) {
+            Error::InvalidState(msg) => assert_eq!(msg, "finished"),
+            other => panic!("Unexpected error: {}", other),
+        }
+
+        let err = io::Error::new(io::ErrorKind::Other, "Failed");
+        match Error::from(err) {
+            Error::Io(err) => assert_eq!(err.kind(), io::ErrorKind::Other),
+            other => panic!("Unexpected error: {}", other),
+        }
+    }
+}
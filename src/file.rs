@@ -0,0 +1,199 @@
+//! Convenience functions for compressing a file on disk to a gzip file.
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::UNIX_EPOCH;
+
+use gzip_header::GzBuilder;
+
+use crate::compression_options::CompressionOptions;
+use crate::latin1::encode_latin1_os_lossy;
+use crate::writer::gzip::GzEncoder;
+
+/// Default size of the buffer used when reading the source file.
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 64 * 1024;
+/// Default size of the buffer used when writing the compressed file.
+pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Compress the file at `source` to a gzip file at `dest`, using the default buffer sizes.
+///
+/// See [`compress_file_buffered`](fn.compress_file_buffered.html) for details.
+///
+/// # Examples
+///
+/// ```no_run
+/// use deflate::{compress_file, Compression};
+///
+/// compress_file("backup.tar", "backup.tar.gz", Compression::Default).unwrap();
+/// ```
+pub fn compress_file<P: AsRef<Path>, Q: AsRef<Path>, O: Into<CompressionOptions>>(
+    source: P,
+    dest: Q,
+    options: O,
+) -> io::Result<()> {
+    compress_file_buffered(
+        source,
+        dest,
+        options,
+        DEFAULT_READ_BUFFER_SIZE,
+        DEFAULT_WRITE_BUFFER_SIZE,
+    )
+}
+
+/// Compress the file at `source` to a gzip file at `dest`.
+///
+/// The compressed data is first written to a temporary file in the same directory as `dest`,
+/// which is then renamed to `dest` once all the data has been written and synced to disk. This
+/// means `dest` will never be observed in a partially written state, and an existing file at
+/// `dest` is only replaced if compression succeeds. If an error occurs, the temporary file is
+/// removed.
+///
+/// The modification time and file name fields of the gzip header are set from the source file.
+/// The file name is encoded as ISO 8859-1, with characters that can not be represented replaced.
+///
+/// `read_buffer_size` and `write_buffer_size` set the size of the buffers used when reading the
+/// source file and writing the destination file respectively.
+pub fn compress_file_buffered<P: AsRef<Path>, Q: AsRef<Path>, O: Into<CompressionOptions>>(
+    source: P,
+    dest: Q,
+    options: O,
+    read_buffer_size: usize,
+    write_buffer_size: usize,
+) -> io::Result<()> {
+    let source = source.as_ref();
+    let dest = dest.as_ref();
+
+    let mut input = File::open(source)?;
+    let builder = header_for(source, &input)?;
+
+    let temp_path = temp_path_for(dest)?;
+    let output = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)?;
+
+    let result = compress_to(
+        &mut input,
+        output,
+        builder,
+        options.into(),
+        read_buffer_size,
+        write_buffer_size,
+    )
+    .and_then(|_| fs::rename(&temp_path, dest));
+
+    if result.is_err() {
+        // We are already returning an error, so there is not much we can do if this fails.
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Create a gzip header builder with the modification time and filename fields set from the
+/// source file.
+fn header_for(source: &Path, file: &File) -> io::Result<GzBuilder> {
+    let metadata = file.metadata()?;
+    // The gzip header uses 0 to indicate that no time stamp is available, and can not represent
+    // times outside the range of a 32-bit unsigned integer.
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| {
+            if duration.as_secs() > u64::from(u32::MAX) {
+                0
+            } else {
+                duration.as_secs() as u32
+            }
+        });
+
+    let mut builder = GzBuilder::new().mtime(mtime);
+    if let Some(name) = source.file_name() {
+        builder = builder.filename(encode_latin1_os_lossy(name));
+    }
+    Ok(builder)
+}
+
+/// Find a path for the temporary file in the same directory as `dest`, so it can be atomically
+/// renamed to `dest` afterwards.
+fn temp_path_for(dest: &Path) -> io::Result<PathBuf> {
+    let name = dest.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Destination path has no file name",
+        )
+    })?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".{}.tmp", process::id()));
+    Ok(dest.with_file_name(temp_name))
+}
+
+fn compress_to(
+    input: &mut File,
+    output: File,
+    builder: GzBuilder,
+    options: CompressionOptions,
+    read_buffer_size: usize,
+    write_buffer_size: usize,
+) -> io::Result<()> {
+    let writer = BufWriter::with_capacity(write_buffer_size, output);
+    let mut encoder = GzEncoder::from_builder(builder, writer, options);
+
+    let mut buffer = vec![0; read_buffer_size.max(1)];
+    loop {
+        let read = match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        encoder.write_all(&buffer[..read])?;
+    }
+
+    let output = encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+    output.sync_all()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_gzip, get_test_data};
+    use std::env;
+
+    #[test]
+    fn compress_file_roundtrip() {
+        let data = get_test_data();
+        let dir = env::temp_dir();
+        let source = dir.join(format!("deflate_test_{}.txt", process::id()));
+        let dest = dir.join(format!("deflate_test_{}.txt.gz", process::id()));
+        fs::write(&source, &data).unwrap();
+        // Make sure an existing file is replaced.
+        fs::write(&dest, b"old").unwrap();
+
+        compress_file_buffered(&source, &dest, CompressionOptions::default(), 100, 1000).unwrap();
+
+        let compressed = fs::read(&dest).unwrap();
+        let (header, decompressed) = decompress_gzip(&compressed);
+        assert!(decompressed == data);
+        assert_eq!(
+            header.filename().unwrap(),
+            source.file_name().unwrap().to_str().unwrap().as_bytes()
+        );
+        assert!(header.mtime() > 0);
+        assert!(!temp_path_for(&dest).unwrap().exists());
+
+        fs::remove_file(&source).unwrap();
+        fs::remove_file(&dest).unwrap();
+    }
+
+    #[test]
+    fn compress_file_missing_source() {
+        let dir = env::temp_dir();
+        let source = dir.join(format!("deflate_test_missing_{}", process::id()));
+        let dest = dir.join(format!("deflate_test_missing_{}.gz", process::id()));
+        assert!(compress_file(&source, &dest, CompressionOptions::default()).is_err());
+        assert!(!dest.exists());
+    }
+}
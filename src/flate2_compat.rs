@@ -0,0 +1,885 @@
+//! An API mirroring the writer types of the `flate2` crate.
+//!
+//! The types in this module have the same names and signatures as
+//! `flate2::write::{DeflateEncoder, ZlibEncoder, GzEncoder}` and `flate2::Compression`, so a
+//! project only using these can switch between the two crates by changing the imports:
+//!
+//! ```rust
+//! # use std::io;
+//! #
+//! # fn try_main() -> io::Result<Vec<u8>> {
+//! #
+//! use std::io::Write;
+//!
+//! // Instead of `use flate2::{write::ZlibEncoder, Compression};`
+//! use deflate::flate2_compat::{write::ZlibEncoder, Compression};
+//!
+//! let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+//! encoder.write_all(b"This is some test data")?;
+//! let compressed_data = encoder.finish()?;
+//! # Ok(compressed_data)
+//! #
+//! # }
+//! # fn main() {
+//! #     try_main().unwrap();
+//! # }
+//! ```
+//!
+//! As in `flate2`, `flush()` performs a sync flush, `try_finish()` writes the end of the stream
+//! without consuming the encoder, `finish()` returns the underlying writer, and dropping an
+//! encoder that has not been finished finishes it, ignoring any errors.
+use std::io::Write;
+use std::{cmp, error, fmt};
+
+use crate::checksum::{Adler32Checksum, RollingChecksum};
+use crate::compression_options::{CompressionOptions, MAX_WINDOW_BITS, MIN_WINDOW_BITS};
+use crate::writer::{Encoder, Format};
+
+/// A compression level from 0 to 9, like `flate2::Compression`.
+///
+/// The levels are mapped to the presets of [`CompressionOptions`]: 0 uses `stored()`, 1 uses
+/// `fastest()`, 2 and 3 use `fast()`, 4 to 6 use `default()` and 7 and above use `high()`. The
+/// `maximum()` preset has no level.
+///
+/// [`CompressionOptions`]: ../struct.CompressionOptions.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Compression(u32);
+
+impl Compression {
+    /// Create a compression level from a number between 0 and 9.
+    pub const fn new(level: u32) -> Compression {
+        Compression(level)
+    }
+
+    /// The level with the least compression.
+    pub const fn none() -> Compression {
+        Compression(0)
+    }
+
+    /// The fastest level that still finds matches.
+    pub const fn fast() -> Compression {
+        Compression(1)
+    }
+
+    /// The level with the best compression.
+    pub const fn best() -> Compression {
+        Compression(9)
+    }
+
+    /// The numeric value of the level.
+    pub fn level(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression(6)
+    }
+}
+
+impl From<Compression> for CompressionOptions {
+    fn from(compression: Compression) -> CompressionOptions {
+        match compression.0 {
+            0 => CompressionOptions::stored(),
+            1 => CompressionOptions::fastest(),
+            2..=3 => CompressionOptions::fast(),
+            4..=6 => CompressionOptions::default(),
+            _ => CompressionOptions::high(),
+        }
+    }
+}
+
+/// Whether and how to flush the compressed stream after a call to `Compress::compress`, like
+/// `flate2::FlushCompress`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum FlushCompress {
+    /// Don't flush, the output may lag behind the input.
+    None = 0,
+    /// Output everything compressed so far, padding to a byte boundary with empty blocks.
+    Partial = 1,
+    /// Output everything compressed so far ending with an empty stored block.
+    Sync = 2,
+    /// Like `Sync`, but also forget the data compressed so far, so later output can be
+    /// decompressed without it.
+    Full = 3,
+    /// End the stream, writing any trailer.
+    Finish = 4,
+    /// End the current block without padding the output, like `Z_BLOCK` in zlib. Up to 7 bits
+    /// of the block are kept back until more data is output. This has no equivalent in flate2.
+    Block = 5,
+}
+
+impl FlushCompress {
+    /// Order the flush modes by how much they output, like `RANK` in zlib, to tell whether a
+    /// flush without new input has been done already.
+    fn rank(self) -> u8 {
+        match self {
+            FlushCompress::Block => 1,
+            flush => flush as u8 * 2,
+        }
+    }
+}
+
+/// The progress made by a call to `Compress::compress`, like `flate2::Status`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Status {
+    /// Progress was made.
+    Ok,
+    /// No progress could be made, either because the output buffer is empty or because there is
+    /// no input and the same flush was done already.
+    BufError,
+    /// The stream has been finished and all the output has been returned.
+    StreamEnd,
+}
+
+/// An error returned when `Compress` is used incorrectly, like `flate2::CompressError`.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct CompressError(&'static str);
+
+impl fmt::Display for CompressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl error::Error for CompressError {}
+
+/// A stream compressor working on buffers provided by the caller, mirroring `flate2::Compress`.
+///
+/// All the input passed to `compress` is consumed on each call, compressed data that does not
+/// fit in the output buffer is kept and returned by the following calls. Otherwise this behaves
+/// like the `deflate` function of zlib, which the backends of `flate2` wrap.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::flate2_compat::{Compress, Compression, FlushCompress, Status};
+///
+/// let mut compress = Compress::new(Compression::default(), true);
+/// let mut output = Vec::with_capacity(64);
+/// let status = compress
+///     .compress_vec(b"This is some test data", &mut output, FlushCompress::Finish)
+///     .unwrap();
+/// assert_eq!(status, Status::StreamEnd);
+/// assert_eq!(compress.total_out(), output.len() as u64);
+/// ```
+pub struct Compress {
+    encoder: Encoder<Vec<u8>>,
+    format: Format,
+    options: CompressionOptions,
+    /// How much of the output of the encoder has been returned already.
+    output_pos: usize,
+    total_in: u64,
+    total_out: u64,
+    /// The flush done by the last call that compressed anything. Repeating it without new input
+    /// only returns the output that is still pending.
+    last_flush: FlushCompress,
+    finished: bool,
+}
+
+impl Compress {
+    /// Create a compressor producing a zlib stream if `zlib_header` is set, and a raw DEFLATE
+    /// stream otherwise.
+    pub fn new(level: Compression, zlib_header: bool) -> Compress {
+        Compress::new_with_window_bits(level, zlib_header, MAX_WINDOW_BITS)
+    }
+
+    /// Create a compressor using a window of `2^window_bits` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window_bits` is not between 9 and 15.
+    pub fn new_with_window_bits(
+        level: Compression,
+        zlib_header: bool,
+        window_bits: u8,
+    ) -> Compress {
+        assert!(
+            (MIN_WINDOW_BITS..=MAX_WINDOW_BITS).contains(&window_bits),
+            "window_bits must be between 9 and 15"
+        );
+        let format = if zlib_header {
+            Format::Zlib
+        } else {
+            Format::Raw
+        };
+        let options = CompressionOptions {
+            window_bits,
+            ..CompressionOptions::from(level)
+        };
+        Compress::with_options(format, options)
+    }
+
+    /// Create a compressor producing a gzip stream with a blank header.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window_bits` is not between 9 and 15.
+    #[cfg(feature = "gzip")]
+    pub fn new_gzip(level: Compression, window_bits: u8) -> Compress {
+        let mut compress = Compress::new_with_window_bits(level, false, window_bits);
+        compress.format = Format::Gzip;
+        compress.reset();
+        compress
+    }
+
+    pub(crate) fn with_options(format: Format, options: CompressionOptions) -> Compress {
+        Compress {
+            encoder: Encoder::new(Vec::new(), format, options),
+            format,
+            options,
+            output_pos: 0,
+            total_in: 0,
+            total_out: 0,
+            last_flush: FlushCompress::None,
+            finished: false,
+        }
+    }
+
+    /// The number of bytes of input consumed so far.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// The number of bytes of compressed output returned so far.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Discard the current stream and start a new one with the same settings.
+    pub fn reset(&mut self) {
+        *self = Compress::with_options(self.format, self.options);
+    }
+
+    /// Use `dictionary` as a preset dictionary, returning its Adler-32 checksum.
+    ///
+    /// Has to be called before any input is compressed. Only raw DEFLATE and zlib streams are
+    /// supported, an error is returned for gzip streams. For zlib streams, the checksum is
+    /// included in the header to identify the dictionary.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) -> Result<u32, CompressError> {
+        if self.total_in != 0 {
+            return Err(CompressError("Dictionary set after input was compressed"));
+        }
+        self.encoder
+            .set_dictionary(dictionary)
+            .map_err(|_| CompressError("Dictionary not supported for this stream"))?;
+        let mut checksum = Adler32Checksum::new();
+        checksum.update_from_slice(dictionary);
+        Ok(checksum.current_hash())
+    }
+
+    /// Insert the lowest `bits` bits of `value` into the output, like `deflatePrime` in zlib.
+    ///
+    /// Has to be called before any input is compressed, and at most 16 bits can be inserted at a
+    /// time. For zlib and gzip streams, the bits follow the header.
+    pub fn prime(&mut self, bits: u8, value: u16) -> Result<(), CompressError> {
+        if self.total_in != 0 {
+            return Err(CompressError("Bits inserted after input was compressed"));
+        }
+        self.encoder
+            .prime(bits, value)
+            .map_err(|_| CompressError("Can not insert the bits into this stream"))
+    }
+
+    /// Change the compression level in the middle of the stream, like `flate2::Compress::set_level`.
+    ///
+    /// The input compressed so far is ended with the old level, and the output is returned by
+    /// the following calls to `compress`. The window size and the way matches are searched for
+    /// are kept.
+    pub fn set_level(&mut self, level: Compression) -> Result<(), CompressError> {
+        let options = CompressionOptions {
+            window_bits: self.options.window_bits,
+            mem_level: self.options.mem_level,
+            matcher: self.options.matcher,
+            min_match: self.options.min_match,
+            block_length: self.options.block_length,
+            ..CompressionOptions::from(level)
+        };
+        self.set_options(options)
+    }
+
+    /// Change the compression options in the middle of the stream.
+    ///
+    /// See [`Encoder::set_options`](../write/struct.Encoder.html#method.set_options).
+    pub(crate) fn set_options(&mut self, options: CompressionOptions) -> Result<(), CompressError> {
+        self.encoder
+            .set_options(options)
+            .map_err(|_| CompressError("Can not change to these options in this stream"))?;
+        self.options = options;
+        Ok(())
+    }
+
+    /// The number of whole bytes and extra bits of compressed data that have not been returned
+    /// yet, like `deflatePending` in zlib.
+    ///
+    /// The extra bits, at most 7, can only be returned after a flush that pads the output to a
+    /// byte boundary.
+    pub fn pending(&self) -> (usize, u8) {
+        let (bytes, bits) = self.encoder.pending();
+        (self.encoder.get_ref().len() - self.output_pos + bytes, bits)
+    }
+
+    #[cfg(feature = "capi")]
+    pub(crate) fn format(&self) -> Format {
+        self.format
+    }
+
+    #[cfg(feature = "capi")]
+    pub(crate) fn options(&self) -> CompressionOptions {
+        self.options
+    }
+
+    /// Whether the stream has been finished and all of the output returned.
+    pub(crate) fn is_done(&self) -> bool {
+        self.finished && self.encoder.get_ref().len() == self.output_pos
+    }
+
+    /// Whether there is compressed data that has not been returned yet.
+    pub(crate) fn has_pending_output(&self) -> bool {
+        self.encoder.get_ref().len() > self.output_pos
+    }
+
+    /// Copy as much of the pending output as fits to `output`, returning the number of bytes
+    /// copied.
+    fn copy_output(&mut self, output: &mut [u8]) -> usize {
+        let pending = self.encoder.get_mut();
+        let n = cmp::min(pending.len() - self.output_pos, output.len());
+        output[..n].copy_from_slice(&pending[self.output_pos..self.output_pos + n]);
+        if self.output_pos + n == pending.len() {
+            pending.clear();
+            self.output_pos = 0;
+        } else {
+            self.output_pos += n;
+        }
+        self.total_out += n as u64;
+        n
+    }
+
+    /// Compress `input` and copy as much of the compressed data as fits to `output`.
+    ///
+    /// The amount of input consumed and output produced can be found by comparing `total_in()`
+    /// and `total_out()` before and after the call.
+    pub fn compress(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        flush: FlushCompress,
+    ) -> Result<Status, CompressError> {
+        if self.finished && (flush != FlushCompress::Finish || !input.is_empty()) {
+            return Err(CompressError("Stream already finished"));
+        }
+        if output.is_empty() {
+            return Ok(Status::BufError);
+        }
+
+        // Output left over from the previous call goes first.
+        let written = self.copy_output(output);
+        if self.has_pending_output() {
+            return Ok(Status::Ok);
+        }
+        if input.is_empty()
+            && flush != FlushCompress::Finish
+            && flush.rank() <= self.last_flush.rank()
+        {
+            // The flush has been done already, flushing again would add another empty block
+            // every time the pending output is drained.
+            return Ok(if written > 0 {
+                Status::Ok
+            } else {
+                Status::BufError
+            });
+        }
+
+        let io_error = |_| CompressError("Compression failed");
+        self.encoder.write_all(input).map_err(io_error)?;
+        self.total_in += input.len() as u64;
+        match flush {
+            FlushCompress::None => (),
+            FlushCompress::Partial => self.encoder.partial_flush().map_err(io_error)?,
+            FlushCompress::Sync => self.encoder.flush().map_err(io_error)?,
+            FlushCompress::Full => self.encoder.full_flush().map_err(io_error)?,
+            FlushCompress::Block => {
+                self.encoder.block_flush().map_err(io_error)?;
+            }
+            FlushCompress::Finish => {
+                if !self.finished {
+                    self.finished = true;
+                    self.encoder.try_finish().map_err(io_error)?;
+                }
+            }
+        }
+        self.last_flush = flush;
+
+        self.copy_output(&mut output[written..]);
+        if self.is_done() {
+            Ok(Status::StreamEnd)
+        } else {
+            Ok(Status::Ok)
+        }
+    }
+
+    /// Like `compress`, but also returning the number of bytes of `input` consumed and the number
+    /// of bytes written to `output`, like the changes to `avail_in` and `avail_out` made by the
+    /// `deflate` function of zlib.
+    ///
+    /// Input that is not consumed has to be passed again in the next call, and a call has to be
+    /// repeated with the same flush mode until the output buffer is not filled completely.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use deflate::flate2_compat::{Compress, Compression, FlushCompress, Status};
+    ///
+    /// let mut compress = Compress::new(Compression::default(), false);
+    /// let mut input = &b"This is some test data"[..];
+    /// let mut buf = [0; 8];
+    /// let mut output = Vec::new();
+    /// loop {
+    ///     let (status, consumed, written) = compress
+    ///         .compress_counted(input, &mut buf, FlushCompress::Finish)
+    ///         .unwrap();
+    ///     input = &input[consumed..];
+    ///     output.extend_from_slice(&buf[..written]);
+    ///     if status == Status::StreamEnd {
+    ///         break;
+    ///     }
+    /// }
+    /// assert_eq!(compress.total_out(), output.len() as u64);
+    /// ```
+    pub fn compress_counted(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        flush: FlushCompress,
+    ) -> Result<(Status, usize, usize), CompressError> {
+        let (total_in, total_out) = (self.total_in, self.total_out);
+        let status = self.compress(input, output, flush)?;
+        Ok((
+            status,
+            (self.total_in - total_in) as usize,
+            (self.total_out - total_out) as usize,
+        ))
+    }
+
+    /// Like `compress`, but appending the output to the spare capacity of `output`.
+    ///
+    /// `output` is not grown beyond its current capacity.
+    pub fn compress_vec(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        flush: FlushCompress,
+    ) -> Result<Status, CompressError> {
+        let len = output.len();
+        output.resize(output.capacity(), 0);
+        let total_out = self.total_out;
+        let result = self.compress(input, &mut output[len..], flush);
+        output.truncate(len + (self.total_out - total_out) as usize);
+        result
+    }
+}
+
+/// Encoders compressing data written to them, mirroring `flate2::write`.
+pub mod write {
+    use std::io;
+    use std::io::Write;
+
+    use super::Compression;
+    use crate::writer::{Encoder, Format};
+
+    /// Wraps an `Encoder`, keeping track of the totals `flate2` reports.
+    struct Inner<W: Write> {
+        encoder: Encoder<W>,
+        total_in: u64,
+        trailer_length: u64,
+    }
+
+    impl<W: Write> Inner<W> {
+        fn new(w: W, format: Format, level: Compression, trailer_length: u64) -> Inner<W> {
+            Inner {
+                encoder: Encoder::new(w, format, level),
+                total_in: 0,
+                trailer_length,
+            }
+        }
+
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let consumed = self.encoder.write(buf)?;
+            self.total_in += consumed as u64;
+            Ok(consumed)
+        }
+
+        fn total_out(&self, finished: bool) -> u64 {
+            let trailer = if finished { self.trailer_length } else { 0 };
+            self.encoder.bits_written() / 8 + trailer
+        }
+    }
+
+    macro_rules! flate2_encoder {
+        ($(#[$attr:meta])* $name:ident, $format:expr, $trailer_length:expr) => {
+            $(#[$attr])*
+            pub struct $name<W: Write> {
+                inner: Inner<W>,
+                finished: bool,
+            }
+
+            impl<W: Write> $name<W> {
+                /// Create a new encoder writing the compressed data to `w`.
+                pub fn new(w: W, level: Compression) -> $name<W> {
+                    $name {
+                        inner: Inner::new(w, $format, level, $trailer_length),
+                        finished: false,
+                    }
+                }
+
+                /// Get a reference to the underlying writer.
+                pub fn get_ref(&self) -> &W {
+                    self.inner.encoder.get_ref()
+                }
+
+                /// Get a mutable reference to the underlying writer.
+                ///
+                /// Writing directly to the writer will corrupt the compressed stream unless the
+                /// encoder has been flushed or finished first.
+                pub fn get_mut(&mut self) -> &mut W {
+                    self.inner.encoder.get_mut()
+                }
+
+                /// Write the rest of the compressed stream to the underlying writer without
+                /// consuming the encoder.
+                ///
+                /// This can be called again if it fails. Writing more data afterwards returns an
+                /// error.
+                pub fn try_finish(&mut self) -> io::Result<()> {
+                    self.inner.encoder.try_finish()?;
+                    self.finished = true;
+                    Ok(())
+                }
+
+                /// Write the rest of the compressed stream and return the underlying writer.
+                pub fn finish(self) -> io::Result<W> {
+                    self.inner.encoder.finish()
+                }
+            }
+
+            impl<W: Write> Write for $name<W> {
+                fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                    self.inner.write(buf)
+                }
+
+                /// Perform a sync flush, and flush the underlying writer.
+                fn flush(&mut self) -> io::Result<()> {
+                    self.inner.encoder.flush()
+                }
+            }
+        };
+    }
+
+    macro_rules! flate2_totals {
+        ($name:ident) => {
+            impl<W: Write> $name<W> {
+                /// Finish the current stream, and start a new one writing to `w`, returning the
+                /// old writer.
+                pub fn reset(&mut self, w: W) -> io::Result<W> {
+                    let old = self.inner.encoder.reset(w)?;
+                    self.inner.total_in = 0;
+                    self.finished = false;
+                    Ok(old)
+                }
+
+                /// The number of bytes of input consumed so far.
+                pub fn total_in(&self) -> u64 {
+                    self.inner.total_in
+                }
+
+                /// The number of bytes of compressed output produced so far, including output that
+                /// is still buffered in the encoder.
+                pub fn total_out(&self) -> u64 {
+                    self.inner.total_out(self.finished)
+                }
+            }
+        };
+    }
+
+    flate2_encoder!(
+        /// A raw DEFLATE encoder, mirroring `flate2::write::DeflateEncoder`.
+        DeflateEncoder,
+        Format::Raw,
+        0
+    );
+    flate2_totals!(DeflateEncoder);
+
+    flate2_encoder!(
+        /// A zlib encoder, mirroring `flate2::write::ZlibEncoder`.
+        ZlibEncoder,
+        Format::Zlib,
+        4
+    );
+    flate2_totals!(ZlibEncoder);
+
+    #[cfg(feature = "gzip")]
+    flate2_encoder!(
+        /// A gzip encoder writing a blank header, mirroring `flate2::write::GzEncoder`.
+        GzEncoder,
+        Format::Gzip,
+        crate::consts::GZIP_TRAILER_LENGTH as u64
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::write::{DeflateEncoder, ZlibEncoder};
+    use super::*;
+    use crate::test_utils::{decompress_to_end, decompress_zlib, get_test_data};
+    use std::io::Write;
+
+    #[test]
+    fn levels() {
+        assert_eq!(Compression::default().level(), 6);
+        assert_eq!(
+            CompressionOptions::from(Compression::best()),
+            CompressionOptions::high()
+        );
+        assert_eq!(
+            CompressionOptions::from(Compression::new(8)),
+            CompressionOptions::high()
+        );
+        assert_eq!(
+            CompressionOptions::from(Compression::new(2)),
+            CompressionOptions::fast()
+        );
+        assert_eq!(
+            CompressionOptions::from(Compression::fast()),
+            CompressionOptions::fastest()
+        );
+        assert_eq!(
+            CompressionOptions::from(Compression::none()),
+            CompressionOptions::stored()
+        );
+    }
+
+    #[test]
+    fn same_output_as_native_encoders() {
+        let data = get_test_data();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&data).unwrap();
+        encoder.try_finish().unwrap();
+        let total_out = encoder.total_out();
+        assert_eq!(encoder.total_in(), data.len() as u64);
+        assert!(encoder.write(b"a").is_err());
+        // Finishing again does not output anything more.
+        encoder.try_finish().unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(compressed.len() as u64, total_out);
+        assert!(compressed == crate::deflate_bytes_zlib_conf(&data, CompressionOptions::high()));
+        assert!(decompress_zlib(&compressed) == data);
+    }
+
+    #[test]
+    fn flush_and_reset() {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(b"Some data").unwrap();
+        encoder.flush().unwrap();
+        // A sync flush ends in an empty stored block.
+        assert!(encoder.get_ref().ends_with(&[0, 0, 0xFF, 0xFF]));
+        assert_eq!(encoder.total_out(), encoder.get_ref().len() as u64);
+
+        let first = encoder.reset(Vec::new()).unwrap();
+        assert_eq!(decompress_to_end(&first), b"Some data");
+        assert_eq!(encoder.total_in(), 0);
+        encoder.write_all(b"More data").unwrap();
+        let second = encoder.finish().unwrap();
+        assert_eq!(decompress_to_end(&second), b"More data");
+    }
+
+    #[test]
+    fn compress_counted() {
+        let data = get_test_data();
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut output = Vec::new();
+        let mut buf = [0; 7];
+        let mut input = &data[..];
+        loop {
+            // Pass at most 1000 bytes at a time, like a caller with a small input buffer.
+            let chunk = &input[..cmp::min(input.len(), 1000)];
+            let flush = if chunk.len() == input.len() {
+                FlushCompress::Finish
+            } else {
+                FlushCompress::None
+            };
+            let (status, consumed, written) =
+                compress.compress_counted(chunk, &mut buf, flush).unwrap();
+            assert!(consumed == 0 || consumed == chunk.len());
+            input = &input[consumed..];
+            output.extend_from_slice(&buf[..written]);
+            if status == Status::StreamEnd {
+                break;
+            }
+        }
+        assert!(input.is_empty());
+        assert_eq!(compress.total_out(), output.len() as u64);
+        assert!(decompress_to_end(&output) == data);
+    }
+
+    #[test]
+    fn compress_one_byte_output() {
+        let data = get_test_data();
+        let flushes = [
+            FlushCompress::None,
+            FlushCompress::Partial,
+            FlushCompress::Sync,
+            FlushCompress::Full,
+        ];
+        for &flush in &flushes {
+            let mut compress = Compress::new(Compression::default(), true);
+            let mut output = Vec::new();
+            let mut buf = [0];
+            let chunks = data[..50_000].chunks(10_000);
+            let last = chunks.len() - 1;
+            for (n, mut chunk) in chunks.enumerate() {
+                let flush = if n == last {
+                    FlushCompress::Finish
+                } else {
+                    flush
+                };
+                // Every call has to make progress until all the output has been returned, so the
+                // number of calls is bounded by the amount of output.
+                let mut calls = 0;
+                loop {
+                    let (status, consumed, written) =
+                        compress.compress_counted(chunk, &mut buf, flush).unwrap();
+                    calls += 1;
+                    assert!(calls <= data.len(), "No progress with {:?}", flush);
+                    chunk = &chunk[consumed..];
+                    output.extend_from_slice(&buf[..written]);
+                    match status {
+                        Status::StreamEnd => break,
+                        Status::BufError => {
+                            assert!(chunk.is_empty() && flush != FlushCompress::Finish);
+                            break;
+                        }
+                        Status::Ok => assert!(consumed > 0 || written > 0),
+                    }
+                }
+                assert!(!compress.has_pending_output());
+            }
+            assert_eq!(compress.total_out(), output.len() as u64);
+            assert!(decompress_zlib(&output) == &data[..50_000]);
+        }
+    }
+
+    #[test]
+    fn compress_small_buffers() {
+        let data = get_test_data();
+        let mut compress = Compress::new(Compression::default(), true);
+        let mut output = Vec::new();
+        let mut buf = [0; 100];
+        for chunk in data.chunks(5000) {
+            // The input is only consumed once the pending output has been returned.
+            let total_in = compress.total_in();
+            while compress.total_in() == total_in {
+                compress
+                    .compress(chunk, &mut buf, FlushCompress::None)
+                    .unwrap();
+                output.extend_from_slice(
+                    &buf[..(compress.total_out() - output.len() as u64) as usize],
+                );
+            }
+            assert_eq!(compress.total_in() - total_in, chunk.len() as u64);
+        }
+        loop {
+            let status = compress
+                .compress(&[], &mut buf, FlushCompress::Finish)
+                .unwrap();
+            output.extend_from_slice(&buf[..(compress.total_out() - output.len() as u64) as usize]);
+            if status == Status::StreamEnd {
+                break;
+            }
+        }
+        assert!(output == crate::deflate_bytes_zlib(&data));
+        assert!(compress
+            .compress(b"a", &mut buf, FlushCompress::Finish)
+            .is_err());
+
+        compress.reset();
+        let mut output = Vec::with_capacity(data.len());
+        compress
+            .compress_vec(&data, &mut output, FlushCompress::Sync)
+            .unwrap();
+        assert!(output.ends_with(&[0, 0, 0xFF, 0xFF]));
+        // Flushing again without new input makes no progress.
+        assert_eq!(
+            compress.compress_vec(&[], &mut output, FlushCompress::Sync),
+            Ok(Status::BufError)
+        );
+        assert_eq!(
+            compress.compress_vec(&[], &mut output, FlushCompress::Finish),
+            Ok(Status::StreamEnd)
+        );
+        assert!(decompress_zlib(&output) == data);
+    }
+
+    #[test]
+    fn compress_set_level() {
+        let data = get_test_data();
+        let mut compress = Compress::new(Compression::best(), true);
+        let mut output = Vec::with_capacity(data.len());
+        let (first, second) = data.split_at(50_000);
+        compress
+            .compress_vec(first, &mut output, FlushCompress::None)
+            .unwrap();
+        compress.set_level(Compression::fast()).unwrap();
+        compress
+            .compress_vec(second, &mut output, FlushCompress::Finish)
+            .unwrap();
+        assert!(decompress_zlib(&output) == data);
+    }
+
+    #[test]
+    fn compress_pending() {
+        let data = get_test_data();
+        let mut compress = Compress::new(Compression::default(), false);
+        assert_eq!(compress.pending(), (0, 0));
+        let mut buf = [0; 100];
+        compress
+            .compress(&data[..5000], &mut buf, FlushCompress::Sync)
+            .unwrap();
+        let (pending, bits) = compress.pending();
+        assert_eq!(bits, 0);
+        let mut output = Vec::with_capacity(buf.len() + pending);
+        output.extend_from_slice(&buf);
+        compress
+            .compress_vec(&[], &mut output, FlushCompress::Sync)
+            .unwrap();
+        assert_eq!(output.len(), buf.len() + pending);
+        assert_eq!(compress.pending(), (0, 0));
+    }
+
+    #[test]
+    fn compress_dictionary() {
+        let mut compress = Compress::new(Compression::default(), true);
+        assert_eq!(compress.set_dictionary(b"Wikipedia"), Ok(0x11E6_0398));
+        let mut output = Vec::with_capacity(100);
+        compress
+            .compress_vec(b"Wikipedia", &mut output, FlushCompress::Finish)
+            .unwrap();
+        assert_eq!(output[2..6], [0x11, 0xE6, 0x03, 0x98]);
+        assert_eq!(
+            crate::inflate_bytes_zlib_with_dictionary(b"Wikipedia", &output),
+            Ok(b"Wikipedia".to_vec())
+        );
+        assert!(compress.set_dictionary(b"Wikipedia").is_err());
+
+        let mut compress = Compress::new_with_window_bits(Compression::best(), false, 9);
+        assert_eq!(compress.set_dictionary(b"Wikipedia"), Ok(0x11E6_0398));
+        let mut output = Vec::with_capacity(100);
+        compress
+            .compress_vec(b"Wikipedia", &mut output, FlushCompress::Finish)
+            .unwrap();
+        assert_eq!(
+            crate::test_utils::decompress_with_dictionary(b"Wikipedia", &output),
+            b"Wikipedia"
+        );
+    }
+}
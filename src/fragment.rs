@@ -0,0 +1,266 @@
+//! Compressed fragments that can be cached and joined into a stream later.
+//!
+//! A [`Fragment`] is a piece of raw DEFLATE data ending in a sync flush and without the final
+//! block flag set, together with the length and checksums of the data it was compressed from.
+//! Since it ends on a byte boundary and doesn't refer to any data before it, fragments can be
+//! concatenated in any order. A [`FragmentStream`] produces the header, keeps track of the
+//! combined checksum of the fragments that are added, and produces the end of the stream, so e.g
+//! a web server can compress static parts of a page once and join them with freshly compressed
+//! dynamic parts into one valid gzip response.
+//!
+//! [`Fragment`]: struct.Fragment.html
+//! [`FragmentStream`]: struct.FragmentStream.html
+#[cfg(feature = "gzip")]
+use gzip_header::GzBuilder;
+
+use crate::checksum::{adler32_combine, Adler32Checksum, RollingChecksum};
+#[cfg(feature = "gzip")]
+use crate::checksum::{crc32, crc32_combine};
+use crate::compress::Flush;
+use crate::compression_options::CompressionOptions;
+use crate::deflate_state::DeflateState;
+use crate::error::Error;
+#[cfg(feature = "gzip")]
+use crate::gz_header::GzHeaderBuilder;
+use crate::writer::{compress_until_done, Format};
+use crate::zlib::{get_zlib_header, CompressionLevel};
+
+/// The end of a DEFLATE stream: an empty final block using the fixed huffman codes.
+const FINAL_EMPTY_BLOCK: [u8; 2] = [0x03, 0x00];
+
+/// A piece of compressed data that can be joined with other fragments into a stream.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Fragment {
+    data: Vec<u8>,
+    uncompressed_len: u64,
+    adler32: u32,
+    #[cfg(feature = "gzip")]
+    crc32: u32,
+}
+
+impl Fragment {
+    /// Compress `input` into a fragment.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use deflate::{Compression, Fragment, FragmentStream};
+    /// use deflate::write::Format;
+    ///
+    /// // Compressed once, and cached.
+    /// let header = Fragment::compress(b"<html><body>", Compression::Default);
+    /// let footer = Fragment::compress(b"</body></html>", Compression::Default);
+    ///
+    /// let mut stream = FragmentStream::new(Format::Zlib);
+    /// let mut response = stream.header();
+    /// for fragment in &[header, Fragment::compress(b"Hello!", Compression::Fast), footer] {
+    ///     stream.push(fragment);
+    ///     response.extend_from_slice(fragment.data());
+    /// }
+    /// response.extend_from_slice(&stream.finish());
+    /// ```
+    pub fn compress<O: Into<CompressionOptions>>(input: &[u8], options: O) -> Fragment {
+        let mut state = Box::new(DeflateState::with_direct_output(options.into(), Vec::new()));
+        // The output is never written to the sink, so this can't fail.
+        compress_until_done(input, &mut state, Flush::Sync).expect("Write error!");
+
+        let mut adler32 = Adler32Checksum::new();
+        adler32.update_from_slice(input);
+        Fragment {
+            data: state.take_direct_output(),
+            uncompressed_len: input.len() as u64,
+            adler32: adler32.current_hash(),
+            #[cfg(feature = "gzip")]
+            crc32: crc32(input),
+        }
+    }
+
+    /// Recreate a fragment from its parts, e.g after loading it from a cache.
+    ///
+    /// The parts have to come from a fragment created with `compress`, otherwise the resulting
+    /// stream will be invalid.
+    #[cfg(not(feature = "gzip"))]
+    pub fn from_parts(data: Vec<u8>, uncompressed_len: u64, adler32: u32) -> Fragment {
+        Fragment {
+            data,
+            uncompressed_len,
+            adler32,
+        }
+    }
+
+    /// Recreate a fragment from its parts, e.g after loading it from a cache.
+    ///
+    /// The parts have to come from a fragment created with `compress`, otherwise the resulting
+    /// stream will be invalid.
+    #[cfg(feature = "gzip")]
+    pub fn from_parts(data: Vec<u8>, uncompressed_len: u64, adler32: u32, crc32: u32) -> Fragment {
+        Fragment {
+            data,
+            uncompressed_len,
+            adler32,
+            crc32,
+        }
+    }
+
+    /// The compressed data.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The length of the data the fragment was compressed from.
+    pub fn uncompressed_len(&self) -> u64 {
+        self.uncompressed_len
+    }
+
+    /// The Adler-32 checksum of the data the fragment was compressed from.
+    pub fn adler32(&self) -> u32 {
+        self.adler32
+    }
+
+    /// The CRC-32 of the data the fragment was compressed from.
+    #[cfg(feature = "gzip")]
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+}
+
+/// The header, checksum and end of a stream made of fragments.
+#[derive(Clone, Debug)]
+pub struct FragmentStream {
+    format: Format,
+    header: Vec<u8>,
+    uncompressed_len: u64,
+    adler32: u32,
+    #[cfg(feature = "gzip")]
+    crc32: u32,
+}
+
+impl FragmentStream {
+    /// Create a stream in the provided format, using a gzip header with blank fields for gzip.
+    ///
+    /// # Panics
+    /// Panics for `Format::Custom`, as the checksum of a custom format can't be combined.
+    pub fn new(format: Format) -> FragmentStream {
+        FragmentStream::try_new(format).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like [`new`](#method.new), but returns an error rather than panicking for
+    /// `Format::Custom`.
+    pub fn try_new(format: Format) -> Result<FragmentStream, Error> {
+        let header = match format {
+            Format::Raw => Vec::new(),
+            Format::Zlib => get_zlib_header(CompressionLevel::Default).to_vec(),
+            #[cfg(feature = "gzip")]
+            Format::Gzip => GzBuilder::new().into_header(),
+            Format::Custom => {
+                return Err(Error::InvalidOptions(
+                    "Fragments can't be used with a custom format",
+                ))
+            }
+        };
+        Ok(FragmentStream::with_header(format, header))
+    }
+
+    /// Create a gzip stream using the header described by `builder`.
+    #[cfg(feature = "gzip")]
+    pub fn gzip<B: Into<GzHeaderBuilder>>(builder: B) -> FragmentStream {
+        FragmentStream::with_header(Format::Gzip, builder.into().into_header())
+    }
+
+    fn with_header(format: Format, header: Vec<u8>) -> FragmentStream {
+        FragmentStream {
+            format,
+            header,
+            uncompressed_len: 0,
+            adler32: 1,
+            #[cfg(feature = "gzip")]
+            crc32: 0,
+        }
+    }
+
+    /// The header to output before the first fragment.
+    pub fn header(&self) -> Vec<u8> {
+        self.header.clone()
+    }
+
+    /// Add the checksum of a fragment that is output after the previous ones.
+    pub fn push(&mut self, fragment: &Fragment) {
+        self.adler32 = adler32_combine(self.adler32, fragment.adler32, fragment.uncompressed_len);
+        #[cfg(feature = "gzip")]
+        {
+            self.crc32 = crc32_combine(self.crc32, fragment.crc32, fragment.uncompressed_len);
+        }
+        self.uncompressed_len += fragment.uncompressed_len;
+    }
+
+    /// The total length of the data of the fragments added so far.
+    pub fn uncompressed_len(&self) -> u64 {
+        self.uncompressed_len
+    }
+
+    /// The data to output after the last fragment: a final empty block followed by the trailer.
+    pub fn finish(&self) -> Vec<u8> {
+        let mut end = FINAL_EMPTY_BLOCK.to_vec();
+        match self.format {
+            Format::Zlib => end.extend_from_slice(&self.adler32.to_be_bytes()),
+            #[cfg(feature = "gzip")]
+            Format::Gzip => {
+                end.extend_from_slice(&self.crc32.to_le_bytes());
+                end.extend_from_slice(&(self.uncompressed_len as u32).to_le_bytes());
+            }
+            Format::Raw | Format::Custom => (),
+        }
+        end
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, decompress_zlib, get_test_data};
+
+    fn join(stream: &mut FragmentStream, fragments: &[&Fragment]) -> Vec<u8> {
+        let mut output = stream.header();
+        for fragment in fragments {
+            stream.push(fragment);
+            output.extend_from_slice(fragment.data());
+        }
+        output.extend_from_slice(&stream.finish());
+        output
+    }
+
+    #[test]
+    fn join_fragments() {
+        let data = get_test_data();
+        let (first, second) = data.split_at(30_000);
+        let cached = Fragment::compress(first, CompressionOptions::high());
+        let dynamic = Fragment::compress(second, CompressionOptions::fast());
+        let empty = Fragment::compress(&[], CompressionOptions::default());
+
+        let raw = join(
+            &mut FragmentStream::new(Format::Raw),
+            &[&cached, &empty, &dynamic, &cached],
+        );
+        assert!(decompress_to_end(&raw) == [first, second, first].concat());
+
+        let zlib = join(&mut FragmentStream::new(Format::Zlib), &[&dynamic, &cached]);
+        assert!(decompress_zlib(&zlib) == [second, first].concat());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn join_fragments_gzip() {
+        use crate::test_utils::decompress_gzip;
+        let data = get_test_data();
+        let a = Fragment::compress(&data[..1000], CompressionOptions::default());
+        let b = Fragment::from_parts(a.data().to_vec(), 1000, a.adler32(), a.crc32());
+        let c = Fragment::compress(&data, CompressionOptions::fast());
+
+        let mut stream = FragmentStream::gzip(GzBuilder::new().filename("page.html"));
+        let gzip = join(&mut stream, &[&a, &c, &b]);
+        let (header, decompressed) = decompress_gzip(&gzip);
+        assert_eq!(header.filename(), Some(&b"page.html"[..]));
+        assert!(decompressed == [&data[..1000], &data, &data[..1000]].concat());
+        assert_eq!(stream.uncompressed_len(), data.len() as u64 + 2000);
+    }
+}
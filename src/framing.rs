@@ -0,0 +1,217 @@
+//! The container formats DEFLATE data can be wrapped in.
+//!
+//! A container consists of a header written before the compressed data and a trailer written
+//! after it, usually containing a checksum of the uncompressed data. The formats supported by
+//! the crate are implemented using the [`Framing`] trait, which can also be implemented to
+//! produce other containers (e.g with a custom magic number or a different checksum) using
+//! [`Encoder::with_framing`].
+//!
+//! [`Framing`]: trait.Framing.html
+//! [`Encoder::with_framing`]: ../write/struct.Encoder.html#method.with_framing
+use crate::checksum::{Adler32Checksum, RollingChecksum};
+use crate::compression_options::{clamp_window_bits, MAX_WINDOW_BITS};
+use crate::zlib::{get_zlib_header_with_window, CompressionLevel};
+
+/// A trait for producing the header and trailer of a container around DEFLATE data.
+///
+/// The encoder calls `header` before any compressed data is output, passes all of the
+/// uncompressed data to `update` in order, and calls `trailer` after the end of the compressed
+/// data. When the encoder is reset, or a new gzip member is started, `reset` is called before the
+/// next header.
+pub trait Framing {
+    /// The header to write before the compressed data.
+    fn header(&mut self) -> Vec<u8>;
+
+    /// Update the trailer state (e.g a checksum) with uncompressed data.
+    fn update(&mut self, data: &[u8]);
+
+    /// The trailer to write after the compressed data.
+    fn trailer(&mut self) -> Vec<u8>;
+
+    /// Reset the trailer state to start a new stream.
+    fn reset(&mut self);
+}
+
+/// Raw DEFLATE data, without any header or trailer.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct RawFraming;
+
+impl Framing for RawFraming {
+    fn header(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn update(&mut self, _: &[u8]) {}
+
+    fn trailer(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// The zlib format, with a zlib header and an Adler-32 checksum trailer.
+pub struct ZlibFraming {
+    checksum: Adler32Checksum,
+    window_bits: u8,
+}
+
+impl ZlibFraming {
+    /// Create a new zlib framing.
+    pub fn new() -> ZlibFraming {
+        ZlibFraming::with_window_bits(MAX_WINDOW_BITS)
+    }
+
+    /// Create a new zlib framing with a header indicating a window size of `2^window_bits`
+    /// bytes, which should match the `window_bits` of the compression options.
+    ///
+    /// The value is clamped to the range `9...15`.
+    pub fn with_window_bits(window_bits: u8) -> ZlibFraming {
+        ZlibFraming {
+            checksum: Adler32Checksum::new(),
+            window_bits: clamp_window_bits(window_bits),
+        }
+    }
+}
+
+impl Default for ZlibFraming {
+    fn default() -> ZlibFraming {
+        ZlibFraming::new()
+    }
+}
+
+impl Framing for ZlibFraming {
+    fn header(&mut self) -> Vec<u8> {
+        get_zlib_header_with_window(CompressionLevel::Default, self.window_bits).to_vec()
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.checksum.update_from_slice(data);
+    }
+
+    fn trailer(&mut self) -> Vec<u8> {
+        self.checksum.current_hash().to_be_bytes().to_vec()
+    }
+
+    fn reset(&mut self) {
+        self.checksum = Adler32Checksum::new();
+    }
+}
+
+#[cfg(feature = "gzip")]
+pub use self::gzip::GzipFraming;
+
+#[cfg(feature = "gzip")]
+mod gzip {
+    use super::Framing;
+    use crate::checksum::Crc32;
+    use crate::gz_header::GzHeaderBuilder;
+    use gzip_header::GzBuilder;
+
+    /// The gzip format, with a gzip header and a CRC-32 and size trailer.
+    pub struct GzipFraming {
+        checksum: Crc32,
+        header: Vec<u8>,
+    }
+
+    impl GzipFraming {
+        /// Create a framing using a gzip header with blank fields.
+        pub fn new() -> GzipFraming {
+            GzipFraming::from_builder(GzBuilder::new())
+        }
+
+        /// Create a framing using the header described by `builder`.
+        pub fn from_builder<B: Into<GzHeaderBuilder>>(builder: B) -> GzipFraming {
+            GzipFraming {
+                checksum: Crc32::new(),
+                header: builder.into().into_header(),
+            }
+        }
+    }
+
+    impl Default for GzipFraming {
+        fn default() -> GzipFraming {
+            GzipFraming::new()
+        }
+    }
+
+    impl Framing for GzipFraming {
+        fn header(&mut self) -> Vec<u8> {
+            self.header.clone()
+        }
+
+        fn update(&mut self, data: &[u8]) {
+            self.checksum.update(data);
+        }
+
+        fn trailer(&mut self) -> Vec<u8> {
+            let mut trailer = self.checksum.sum().to_le_bytes().to_vec();
+            trailer.extend_from_slice(&(self.checksum.amount() as u32).to_le_bytes());
+            trailer
+        }
+
+        fn reset(&mut self) {
+            self.checksum = Crc32::new();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression_options::CompressionOptions;
+    use crate::test_utils::get_test_data;
+    use crate::write::{Encoder, Format};
+    use std::io::Write;
+
+    /// A container with a magic number and the length of the data as the trailer.
+    struct LengthFraming {
+        length: u64,
+    }
+
+    impl Framing for LengthFraming {
+        fn header(&mut self) -> Vec<u8> {
+            b"MAGC".to_vec()
+        }
+
+        fn update(&mut self, data: &[u8]) {
+            self.length += data.len() as u64;
+        }
+
+        fn trailer(&mut self) -> Vec<u8> {
+            self.length.to_le_bytes().to_vec()
+        }
+
+        fn reset(&mut self) {
+            self.length = 0;
+        }
+    }
+
+    #[test]
+    fn custom_framing() {
+        let data = get_test_data();
+        let options = CompressionOptions::default();
+        let mut encoder = Encoder::with_framing(Vec::new(), LengthFraming { length: 0 }, options);
+        assert_eq!(encoder.format(), Format::Custom);
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let raw = crate::deflate_bytes_conf(&data, options);
+        assert_eq!(&compressed[..4], b"MAGC");
+        assert!(compressed[4..compressed.len() - 8] == raw[..]);
+        assert_eq!(
+            compressed[compressed.len() - 8..],
+            (data.len() as u64).to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn zlib_framing_same_as_zlib_encoder() {
+        let data = get_test_data();
+        let options = CompressionOptions::fast();
+        let mut encoder = Encoder::with_framing(Vec::new(), ZlibFraming::new(), options);
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(compressed == crate::deflate_bytes_zlib_conf(&data, options));
+    }
+}
@@ -0,0 +1,138 @@
+//! Building gzip headers with the flags `GzBuilder` doesn't support.
+use gzip_header::GzBuilder;
+
+use crate::checksum::crc32;
+
+/// Offset of the flag byte in the gzip header.
+const FLG_OFFSET: usize = 3;
+/// Flag indicating that the data is probably ASCII text.
+const FTEXT: u8 = 1;
+/// Flag indicating that the header ends with a CRC-16 of the header.
+const FHCRC: u8 = 2;
+
+/// A builder for gzip headers, extending `GzBuilder` with the header flags it can't set.
+///
+/// The filename, comment, extra field, modification time and operating system are set on the
+/// wrapped `GzBuilder`. Everywhere a `GzBuilder` is accepted, a `GzHeaderBuilder` can be used
+/// instead.
+///
+/// # Examples
+///
+/// ```rust
+/// # extern crate deflate;
+/// # extern crate gzip_header;
+/// use deflate::{deflate_bytes_gzip_conf, Compression, GzHeaderBuilder};
+/// use gzip_header::GzBuilder;
+///
+/// let builder = GzHeaderBuilder::from(GzBuilder::new().filename("notes.txt").mtime(1_500_000_000))
+///     .text(true);
+/// let compressed = deflate_bytes_gzip_conf(b"Some notes", Compression::Default, builder);
+/// assert_eq!(compressed[3] & 1, 1);
+/// ```
+pub struct GzHeaderBuilder {
+    builder: GzBuilder,
+    text: bool,
+    header_crc: bool,
+}
+
+impl GzHeaderBuilder {
+    /// Create a builder for a header with blank fields and no flags set.
+    pub fn new() -> GzHeaderBuilder {
+        GzHeaderBuilder::from(GzBuilder::new())
+    }
+
+    /// Set the `FTEXT` flag, indicating that the data is probably text.
+    ///
+    /// The flag is only a hint to the decompressor, and doesn't change the data.
+    pub fn text(mut self, text: bool) -> GzHeaderBuilder {
+        self.text = text;
+        self
+    }
+
+    /// Set the `FHCRC` flag, and end the header with the lower 16 bits of the CRC-32 of the
+    /// header bytes before it, including the optional fields.
+    ///
+    /// This lets the decompressor detect a corrupted header. Some strict decoders require it.
+    pub fn header_crc(mut self, header_crc: bool) -> GzHeaderBuilder {
+        self.header_crc = header_crc;
+        self
+    }
+
+    /// Create the bytes of the header.
+    pub fn into_header(self) -> Vec<u8> {
+        let mut header = self.builder.into_header();
+        if self.text {
+            header[FLG_OFFSET] |= FTEXT;
+        }
+        if self.header_crc {
+            header[FLG_OFFSET] |= FHCRC;
+            let crc = crc32(&header) as u16;
+            header.extend_from_slice(&crc.to_le_bytes());
+        }
+        header
+    }
+}
+
+impl Default for GzHeaderBuilder {
+    fn default() -> GzHeaderBuilder {
+        GzHeaderBuilder::new()
+    }
+}
+
+impl From<GzBuilder> for GzHeaderBuilder {
+    fn from(builder: GzBuilder) -> GzHeaderBuilder {
+        GzHeaderBuilder {
+            builder,
+            text: false,
+            header_crc: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::decompress_gzip;
+
+    #[test]
+    fn text_flag() {
+        let plain = GzHeaderBuilder::from(GzBuilder::new().comment("A comment")).into_header();
+        let text = GzHeaderBuilder::from(GzBuilder::new().comment("A comment"))
+            .text(true)
+            .into_header();
+        assert_eq!(plain[FLG_OFFSET] | FTEXT, text[FLG_OFFSET]);
+        assert_eq!(plain[FLG_OFFSET + 1..], text[FLG_OFFSET + 1..]);
+
+        let compressed = crate::deflate_bytes_gzip_conf(
+            b"Some text",
+            crate::Compression::Default,
+            GzHeaderBuilder::new().text(true),
+        );
+        let (_, decompressed) = decompress_gzip(&compressed);
+        assert_eq!(decompressed, b"Some text");
+    }
+
+    #[test]
+    fn header_crc() {
+        let builder = || GzBuilder::new().filename("file.txt").comment("A comment");
+        let plain = GzHeaderBuilder::from(builder()).into_header();
+        let header = GzHeaderBuilder::from(builder())
+            .header_crc(true)
+            .into_header();
+        assert_eq!(plain[FLG_OFFSET] | FHCRC, header[FLG_OFFSET]);
+        assert_eq!(plain.len() + 2, header.len());
+
+        let compressed = crate::deflate_bytes_gzip_conf(
+            b"Some text",
+            crate::Compression::Default,
+            GzHeaderBuilder::from(builder()).header_crc(true),
+        );
+        let (parsed, decompressed) = decompress_gzip(&compressed);
+        assert_eq!(parsed.filename().unwrap(), b"file.txt");
+        assert_eq!(decompressed, b"Some text");
+        assert_eq!(
+            crate::inflate_bytes_gzip(&compressed).unwrap(),
+            b"Some text"
+        );
+    }
+}
@@ -0,0 +1,400 @@
+//! Compression without any heap allocation.
+//!
+//! [`HeaplessCompressor`] keeps its hash chains in fixed-size arrays, with the sizes chosen
+//! using const generics, uses the input slice directly as the window, and writes the compressed
+//! data into a caller-provided slice. It never allocates, so it can be placed in a static or on
+//! the stack on targets without an allocator. To keep the memory usage fixed, the output is a
+//! single block using the fixed huffman codes defined by the DEFLATE specification, which
+//! compresses somewhat worse than the dynamic codes used by the other encoders.
+//!
+//! When the hash chains should live somewhere else, e.g in a memory region reserved by the
+//! caller, [`DeflateBuffers`] compresses the same way using borrowed arrays instead.
+//!
+//! [`HeaplessCompressor`]: struct.HeaplessCompressor.html
+//! [`DeflateBuffers`]: struct.DeflateBuffers.html
+use std::error::Error;
+use std::fmt;
+
+use crate::bit_reverse::reverse_bits;
+use crate::huffman_table::{
+    get_distance_code_and_extra_bits, get_length_code_and_extra_bits, END_OF_BLOCK_POSITION,
+    MAX_MATCH, MIN_MATCH,
+};
+use crate::lzvalue::StoredLength;
+
+/// The default maximum number of positions to check in each hash chain.
+pub const DEFAULT_MAX_CHAIN: u16 = 32;
+
+/// Error returned when the compressed data does not fit in the output slice.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct OutputFull;
+
+impl fmt::Display for OutputFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("The output buffer is full")
+    }
+}
+
+impl Error for OutputFull {}
+
+/// Writes bits LSB first into a slice.
+struct SliceWriter<'a> {
+    output: &'a mut [u8],
+    pos: usize,
+    acc: u32,
+    bits: u8,
+}
+
+impl<'a> SliceWriter<'a> {
+    fn new(output: &'a mut [u8]) -> SliceWriter<'a> {
+        SliceWriter {
+            output,
+            pos: 0,
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    fn write_bits(&mut self, value: u16, length: u8) -> Result<(), OutputFull> {
+        self.acc |= u32::from(value) << self.bits;
+        self.bits += length;
+        while self.bits >= 8 {
+            *self.output.get_mut(self.pos).ok_or(OutputFull)? = self.acc as u8;
+            self.pos += 1;
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+        Ok(())
+    }
+
+    /// Pad the output to a whole byte, returning the number of bytes written.
+    fn finish(mut self) -> Result<usize, OutputFull> {
+        if self.bits > 0 {
+            let padding = 8 - self.bits;
+            self.write_bits(0, padding)?;
+        }
+        Ok(self.pos)
+    }
+}
+
+/// The fixed huffman code for a literal/length symbol, reversed so it can be written LSB first.
+fn fixed_literal_length_code(symbol: u16) -> (u16, u8) {
+    let (code, length) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xc0 + symbol - 280, 8),
+    };
+    (reverse_bits(code, length), length)
+}
+
+/// A compressor that does not allocate, with a window of `WINDOW_SIZE` bytes and a hash table
+/// with `HASH_SIZE` entries.
+///
+/// Both sizes have to be powers of two, and the window size can be at most 32768 bytes. A
+/// smaller window uses less memory, but can only find matches closer to the current position.
+/// The memory used is `2 * WINDOW_SIZE + HASH_SIZE * size_of::<usize>()` bytes.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{HeaplessCompressor, OutputFull};
+///
+/// let mut compressor = HeaplessCompressor::<4096, 1024>::new();
+/// let mut output = [0; 64];
+/// let length = compressor.compress(b"aaaaaaaaaaaaaaaaaaaaaaaaaa", &mut output)?;
+/// let compressed_data = &output[..length];
+/// # let _ = compressed_data;
+/// # Ok::<(), OutputFull>(())
+/// ```
+#[derive(Clone)]
+pub struct HeaplessCompressor<const WINDOW_SIZE: usize, const HASH_SIZE: usize> {
+    /// The position after the last occurrence of each hash value, or 0 if there is none.
+    head: [usize; HASH_SIZE],
+    /// The distance from each position to the previous one with the same hash value, or 0 if
+    /// there is none within the window.
+    prev: [u16; WINDOW_SIZE],
+    max_chain: u16,
+}
+
+/// Compile time check of the window and hash table sizes.
+struct ValidSizes<const WINDOW_SIZE: usize, const HASH_SIZE: usize>;
+
+impl<const WINDOW_SIZE: usize, const HASH_SIZE: usize> ValidSizes<WINDOW_SIZE, HASH_SIZE> {
+    const CHECK: () = assert!(
+        WINDOW_SIZE.is_power_of_two() && WINDOW_SIZE <= 32768 && HASH_SIZE.is_power_of_two(),
+        "The sizes have to be powers of two, and the window can be at most 32768 bytes!"
+    );
+}
+
+impl<const WINDOW_SIZE: usize, const HASH_SIZE: usize> HeaplessCompressor<WINDOW_SIZE, HASH_SIZE> {
+    /// Create a new compressor.
+    ///
+    /// Fails to compile if the sizes are invalid.
+    #[allow(clippy::let_unit_value)]
+    pub const fn new() -> HeaplessCompressor<WINDOW_SIZE, HASH_SIZE> {
+        let () = ValidSizes::<WINDOW_SIZE, HASH_SIZE>::CHECK;
+        HeaplessCompressor {
+            head: [0; HASH_SIZE],
+            prev: [0; WINDOW_SIZE],
+            max_chain: DEFAULT_MAX_CHAIN,
+        }
+    }
+
+    /// Set the maximum number of earlier positions to check when looking for a match.
+    ///
+    /// Higher values may give better compression, but makes compression slower.
+    pub fn set_max_chain(&mut self, max_chain: u16) {
+        self.max_chain = max_chain;
+    }
+
+    /// Compress `input` into `output` as a raw DEFLATE stream, returning the number of bytes
+    /// written.
+    ///
+    /// Returns `Err(OutputFull)` if the compressed data does not fit in `output`, in which case
+    /// the contents of `output` are unspecified. An output slice of `input.len() * 9 / 8 + 3`
+    /// bytes is always large enough, as the fixed codes use at most 9 bits per byte of input.
+    pub fn compress(&mut self, input: &[u8], output: &mut [u8]) -> Result<usize, OutputFull> {
+        let mut buffers = DeflateBuffers::new(&mut self.head, &mut self.prev);
+        buffers.set_max_chain(self.max_chain);
+        buffers.compress(input, output)
+    }
+}
+
+/// Hash chain storage provided by the caller, used to compress without allocating.
+///
+/// This compresses exactly like [`HeaplessCompressor`](struct.HeaplessCompressor.html), but
+/// borrows the hash table of `HASH_SIZE` entries and the chain links for a window of
+/// `WINDOW_SIZE` bytes, so they can be placed wherever the caller wants. The sizes are checked at
+/// compile time in the same way. The input slice is used as the window, and the compressed data
+/// is written to the output slice, so no other memory is needed.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{DeflateBuffers, OutputFull};
+///
+/// let mut head = [0; 1024];
+/// let mut prev = [0; 4096];
+/// let mut output = [0; 64];
+///
+/// let mut buffers = DeflateBuffers::new(&mut head, &mut prev);
+/// let length = buffers.compress(b"aaaaaaaaaaaaaaaaaaaaaaaaaa", &mut output)?;
+/// let compressed_data = &output[..length];
+/// # let _ = compressed_data;
+/// # Ok::<(), OutputFull>(())
+/// ```
+pub struct DeflateBuffers<'a, const WINDOW_SIZE: usize, const HASH_SIZE: usize> {
+    /// The position after the last occurrence of each hash value, or 0 if there is none.
+    head: &'a mut [usize; HASH_SIZE],
+    /// The distance from each position to the previous one with the same hash value, or 0 if
+    /// there is none within the window.
+    prev: &'a mut [u16; WINDOW_SIZE],
+    max_chain: u16,
+}
+
+impl<'a, const WINDOW_SIZE: usize, const HASH_SIZE: usize>
+    DeflateBuffers<'a, WINDOW_SIZE, HASH_SIZE>
+{
+    /// Use `head` as the hash table and `prev` as the hash chains.
+    ///
+    /// The previous contents of the arrays don't matter. Fails to compile if the sizes are
+    /// invalid.
+    #[allow(clippy::let_unit_value)]
+    pub fn new(
+        head: &'a mut [usize; HASH_SIZE],
+        prev: &'a mut [u16; WINDOW_SIZE],
+    ) -> DeflateBuffers<'a, WINDOW_SIZE, HASH_SIZE> {
+        let () = ValidSizes::<WINDOW_SIZE, HASH_SIZE>::CHECK;
+        DeflateBuffers {
+            head,
+            prev,
+            max_chain: DEFAULT_MAX_CHAIN,
+        }
+    }
+
+    /// Set the maximum number of earlier positions to check when looking for a match.
+    ///
+    /// Higher values may give better compression, but makes compression slower.
+    pub fn set_max_chain(&mut self, max_chain: u16) {
+        self.max_chain = max_chain;
+    }
+
+    /// Compress `input` into `output` as a raw DEFLATE stream, returning the number of bytes
+    /// written.
+    ///
+    /// See [`HeaplessCompressor::compress`](struct.HeaplessCompressor.html#method.compress).
+    pub fn compress(&mut self, input: &[u8], output: &mut [u8]) -> Result<usize, OutputFull> {
+        for h in self.head.iter_mut() {
+            *h = 0;
+        }
+
+        let mut writer = SliceWriter::new(output);
+        // A final block using the fixed codes.
+        writer.write_bits(0b011, 3)?;
+
+        let mut pos = 0;
+        while pos < input.len() {
+            let (length, distance) = self.longest_match(input, pos);
+            if length >= usize::from(MIN_MATCH) {
+                let stored_length = StoredLength::new((length - usize::from(MIN_MATCH)) as u8);
+                let length_bits = get_length_code_and_extra_bits(stored_length);
+                let (code, code_length) = fixed_literal_length_code(length_bits.code_number);
+                writer.write_bits(code, code_length)?;
+                writer.write_bits(length_bits.value, length_bits.num_bits)?;
+
+                let distance_bits = get_distance_code_and_extra_bits(distance as u16);
+                writer.write_bits(reverse_bits(distance_bits.code_number, 5), 5)?;
+                writer.write_bits(distance_bits.value, distance_bits.num_bits)?;
+
+                for p in pos..pos + length {
+                    self.insert(input, p);
+                }
+                pos += length;
+            } else {
+                let (code, code_length) = fixed_literal_length_code(u16::from(input[pos]));
+                writer.write_bits(code, code_length)?;
+                self.insert(input, pos);
+                pos += 1;
+            }
+        }
+
+        let (code, code_length) = fixed_literal_length_code(END_OF_BLOCK_POSITION as u16);
+        writer.write_bits(code, code_length)?;
+        writer.finish()
+    }
+
+    fn hash(input: &[u8], pos: usize) -> usize {
+        let value = u32::from(input[pos])
+            | u32::from(input[pos + 1]) << 8
+            | u32::from(input[pos + 2]) << 16;
+        (value.wrapping_mul(0x9e37_79b1) >> 16) as usize & (HASH_SIZE - 1)
+    }
+
+    /// Add the position to the hash chains.
+    fn insert(&mut self, input: &[u8], pos: usize) {
+        if pos + usize::from(MIN_MATCH) > input.len() {
+            return;
+        }
+        let hash = Self::hash(input, pos);
+        let previous = self.head[hash];
+        self.prev[pos & (WINDOW_SIZE - 1)] = if previous != 0 && pos + 1 - previous <= WINDOW_SIZE {
+            (pos + 1 - previous) as u16
+        } else {
+            0
+        };
+        self.head[hash] = pos + 1;
+    }
+
+    /// Find the longest match for the data at `pos` in the window, returning its length and
+    /// distance.
+    fn longest_match(&self, input: &[u8], pos: usize) -> (usize, usize) {
+        if pos + usize::from(MIN_MATCH) > input.len() {
+            return (0, 0);
+        }
+        let max_length = usize::min(usize::from(MAX_MATCH), input.len() - pos);
+        let (mut best_length, mut best_distance) = (0, 0);
+
+        let head = self.head[Self::hash(input, pos)];
+        if head == 0 {
+            return (0, 0);
+        }
+        let mut candidate = head - 1;
+        for _ in 0..self.max_chain {
+            let distance = pos - candidate;
+            if distance > WINDOW_SIZE {
+                break;
+            }
+            let length = input[candidate..]
+                .iter()
+                .zip(&input[pos..pos + max_length])
+                .take_while(|&(a, b)| a == b)
+                .count();
+            if length > best_length {
+                best_length = length;
+                best_distance = distance;
+                if length == max_length {
+                    break;
+                }
+            }
+
+            let step = usize::from(self.prev[candidate & (WINDOW_SIZE - 1)]);
+            if step == 0 || step > candidate {
+                break;
+            }
+            candidate -= step;
+        }
+
+        (best_length, best_distance)
+    }
+}
+
+impl<const WINDOW_SIZE: usize, const HASH_SIZE: usize> Default
+    for HeaplessCompressor<WINDOW_SIZE, HASH_SIZE>
+{
+    fn default() -> HeaplessCompressor<WINDOW_SIZE, HASH_SIZE> {
+        HeaplessCompressor::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, get_test_data};
+    use std::convert::TryInto;
+
+    #[test]
+    fn heapless_roundtrip() {
+        let data = get_test_data();
+        let mut output = vec![0; data.len() * 9 / 8 + 3];
+
+        let mut compressor = Box::new(HeaplessCompressor::<32768, 4096>::new());
+        let length = compressor.compress(&data, &mut output).unwrap();
+        assert!(length < data.len() / 2);
+        assert!(decompress_to_end(&output[..length]) == data);
+
+        let mut small = HeaplessCompressor::<1024, 256>::new();
+        small.set_max_chain(4);
+        let length = small.compress(&data, &mut output).unwrap();
+        assert!(decompress_to_end(&output[..length]) == data);
+    }
+
+    #[test]
+    fn heapless_short() {
+        let mut compressor = HeaplessCompressor::<256, 64>::new();
+        let mut output = [0; 32];
+        for data in &[&[][..], &[7][..], &[1, 2, 1, 2, 1, 2, 1, 2][..]] {
+            let length = compressor.compress(data, &mut output).unwrap();
+            assert_eq!(&decompress_to_end(&output[..length])[..], *data);
+        }
+    }
+
+    #[test]
+    fn heapless_output_full() {
+        let data = get_test_data();
+        let mut compressor = HeaplessCompressor::<4096, 1024>::new();
+        let mut output = [0; 100];
+        assert_eq!(compressor.compress(&data, &mut output), Err(OutputFull));
+        assert_eq!(compressor.compress(&[], &mut []), Err(OutputFull));
+    }
+
+    #[test]
+    fn borrowed_buffers_same_as_heapless() {
+        let data = get_test_data();
+        let mut expected = vec![0; data.len() * 9 / 8 + 3];
+        let length = Box::new(HeaplessCompressor::<8192, 2048>::new())
+            .compress(&data, &mut expected)
+            .unwrap();
+
+        // Start with garbage in the buffers, which should not affect the output.
+        let mut head = vec![usize::MAX; 2048];
+        let mut prev = vec![u16::MAX; 8192];
+        let mut buffers = DeflateBuffers::<8192, 2048>::new(
+            (&mut head[..]).try_into().unwrap(),
+            (&mut prev[..]).try_into().unwrap(),
+        );
+        let mut output = vec![0; expected.len()];
+        assert_eq!(buffers.compress(&data, &mut output), Ok(length));
+        assert!(output[..length] == expected[..length]);
+    }
+}
@@ -1,15 +1,17 @@
 use crate::bitstream::LsbWriter;
+use crate::code_lengths::CodeLengths;
 use crate::deflate_state::LengthBuffers;
 use crate::huffman_table::{
     create_codes_in_place, num_extra_bits_for_distance_code, num_extra_bits_for_length_code,
-    HuffmanTable, FIXED_CODE_LENGTHS, LENGTH_BITS_START, MAX_CODE_LENGTH, NUM_DISTANCE_CODES,
-    NUM_LITERALS_AND_LENGTHS,
+    HuffmanTable, FIXED_CODE_LENGTHS, FIXED_CODE_LENGTHS_DISTANCE, LENGTH_BITS_START,
+    MAX_CODE_LENGTH, NUM_DISTANCE_CODES, NUM_LITERALS_AND_LENGTHS,
 };
 use crate::length_encode::{
     encode_lengths_m, huffman_lengths_from_frequency_m, EncodedLength, COPY_PREVIOUS,
     REPEAT_ZERO_3_BITS, REPEAT_ZERO_7_BITS,
 };
 use crate::output_writer::FrequencyType;
+use crate::priors::FrequencyPriors;
 use crate::stored_block::MAX_STORED_BLOCK_LENGTH;
 
 use std::cmp;
@@ -38,7 +40,7 @@ const MAX_HUFFMAN_CODE_LENGTH: usize = 7;
 
 // How many bytes (not including padding and the 3-bit block type) the stored block header takes up.
 const STORED_BLOCK_HEADER_LENGTH: u64 = 4;
-const BLOCK_MARKER_LENGTH: u8 = 3;
+pub const BLOCK_MARKER_LENGTH: u8 = 3;
 
 /// Creates a new slice from the input slice that stops at the final non-zero value
 pub fn remove_trailing_zeroes<T: From<u8> + PartialEq>(input: &[T], min_length: usize) -> &[T] {
@@ -49,7 +51,8 @@ pub fn remove_trailing_zeroes<T: From<u8> + PartialEq>(input: &[T], min_length:
 /// How many extra bits the huffman length code uses to represent a value.
 fn extra_bits_for_huffman_length_code(code: u8) -> u8 {
     match code {
-        16..=17 => 3,
+        16 => 2,
+        17 => 3,
         18 => 7,
         _ => 0,
     }
@@ -71,11 +74,12 @@ fn calculate_huffman_length(frequencies: &[FrequencyType], code_lengths: &[u8])
 /// code lengths (first return value) and static code lengths (second return value).
 ///
 /// Parameters:
-/// Frequencies, length of dynamic codes, and a function to get how many extra bits in addition
-/// to the length of the huffman code the symbol will use.
+/// Frequencies, length of dynamic codes, length of the fixed codes, and a function to get how many
+/// extra bits in addition to the length of the huffman code the symbol will use.
 fn calculate_block_length<F>(
     frequencies: &[FrequencyType],
     dyn_code_lengths: &[u8],
+    fixed_code_lengths: &[u8],
     get_num_extra_bits: &F,
 ) -> (u64, u64)
 where
@@ -88,7 +92,7 @@ where
 
     let iter = frequencies
         .iter()
-        .zip(dyn_code_lengths.iter().zip(FIXED_CODE_LENGTHS.iter()))
+        .zip(dyn_code_lengths.iter().zip(fixed_code_lengths))
         .enumerate();
 
     // This could maybe be optimised a bit by splitting the iteration of codes using extra bits and
@@ -106,6 +110,34 @@ where
     (d_ll_length, s_ll_length)
 }
 
+/// How many extra bits the literal/length code `code` uses.
+fn literal_length_extra_bits(code: usize) -> u64 {
+    num_extra_bits_for_length_code(code.saturating_sub(LENGTH_BITS_START as usize) as u8).into()
+}
+
+/// How many extra bits the distance code `code` uses.
+fn distance_extra_bits(code: usize) -> u64 {
+    num_extra_bits_for_distance_code(code as u8).into()
+}
+
+/// Calculate how many bits data with the given frequencies will use when compressed with the
+/// fixed codes.
+fn fixed_block_length(l_freqs: &[FrequencyType], d_freqs: &[FrequencyType]) -> u64 {
+    let (_, l_length) = calculate_block_length(
+        l_freqs,
+        &FIXED_CODE_LENGTHS,
+        &FIXED_CODE_LENGTHS,
+        &literal_length_extra_bits,
+    );
+    let (_, d_length) = calculate_block_length(
+        d_freqs,
+        &FIXED_CODE_LENGTHS_DISTANCE,
+        &FIXED_CODE_LENGTHS_DISTANCE,
+        &distance_extra_bits,
+    );
+    l_length + d_length
+}
+
 /// Get how extra padding bits after a block start header a stored block would use.
 ///
 /// # Panics
@@ -163,21 +195,38 @@ pub struct DynamicBlockHeader {
 /// Generate the lengths of the huffman codes we will be using, using the
 /// frequency of the different symbols/lengths/distances, and determine what block type will give
 /// the shortest representation.
+///
+/// `frequency_shift` is the number of times the frequencies have been halved to fit in
+/// `FrequencyType`, and is used to approximate the real size of the block. If `priors` are
+/// provided, they are blended into the frequencies used to generate the code lengths, while the
+/// size of the block is still estimated from the actual frequencies. If `code_lengths` are
+/// provided and have codes for all the symbols used in the block, they are used instead of
+/// generating the lengths.
+///
+/// Also returns the number of bits the block will take up in the output with the chosen block
+/// type, not including the 3-bit block header.
 /// TODO: This needs a test
+#[allow(clippy::too_many_arguments)]
 pub fn gen_huffman_lengths(
     l_freqs: &[FrequencyType],
     d_freqs: &[FrequencyType],
+    frequency_shift: u8,
     num_input_bytes: u64,
     pending_bits: u8,
     l_lengths: &mut [u8; 288],
     d_lengths: &mut [u8; 32],
     length_buffers: &mut LengthBuffers,
-) -> BlockType {
+    priors: Option<&FrequencyPriors>,
+    code_lengths: Option<&CodeLengths>,
+) -> (BlockType, u64) {
     // Avoid corner cases and issues if this is called for an empty block.
     // For blocks this short, a fixed block will be the shortest.
     // TODO: Find the minimum value it's worth doing calculations for.
     if num_input_bytes <= 4 {
-        return BlockType::Fixed;
+        return (
+            BlockType::Fixed,
+            fixed_block_length(l_freqs, d_freqs) << frequency_shift,
+        );
     };
 
     let l_freqs = remove_trailing_zeroes(l_freqs, MIN_NUM_LITERALS_AND_LENGTHS);
@@ -191,21 +240,39 @@ pub fn gen_huffman_lengths(
     // There is however a minimum number of values we have to keep
     // according to the deflate spec.
     // TODO: We could probably compute some of this in parallel.
-    huffman_lengths_from_frequency_m(
-        l_freqs,
-        MAX_CODE_LENGTH,
-        &mut length_buffers.leaf_buf,
-        l_lengths,
-    );
-    huffman_lengths_from_frequency_m(
-        d_freqs,
-        MAX_CODE_LENGTH,
-        &mut length_buffers.leaf_buf,
-        d_lengths,
-    );
+    match code_lengths.filter(|c| c.covers(l_freqs, d_freqs)) {
+        Some(code_lengths) => code_lengths.copy_to(l_lengths, d_lengths),
+        None => {
+            let mut l_blended = [0; NUM_LITERALS_AND_LENGTHS];
+            let mut d_blended = [0; NUM_DISTANCE_CODES];
+            let (l_code_freqs, d_code_freqs) = match priors {
+                Some(priors) => {
+                    let l_blended = &mut l_blended[..l_freqs.len()];
+                    let d_blended = &mut d_blended[..d_freqs.len()];
+                    priors.blend(l_freqs, d_freqs, frequency_shift, l_blended, d_blended);
+                    (&*l_blended, &*d_blended)
+                }
+                None => (l_freqs, d_freqs),
+            };
+            huffman_lengths_from_frequency_m(
+                l_code_freqs,
+                MAX_CODE_LENGTH,
+                &mut length_buffers.leaf_buf,
+                l_lengths,
+            );
+            huffman_lengths_from_frequency_m(
+                d_code_freqs,
+                MAX_CODE_LENGTH,
+                &mut length_buffers.leaf_buf,
+                d_lengths,
+            );
+        }
+    }
 
-    let used_lengths = l_freqs.len();
-    let used_distances = d_freqs.len();
+    // Supplied code lengths can have codes for symbols that are not used in the block, which
+    // still have to be written to describe the same code.
+    let used_lengths = remove_trailing_zeroes(&l_lengths[..], MIN_NUM_LITERALS_AND_LENGTHS).len();
+    let used_distances = remove_trailing_zeroes(&d_lengths[..], MIN_NUM_DISTANCES).len();
 
     // Encode length values
     let mut freqs = [0u16; 19];
@@ -241,14 +308,30 @@ pub fn gen_huffman_lengths(
     // (excluding the 3-bit block header since it's used in all block types).
 
     // Total length of the compressed literals/lengths.
-    let (d_ll_length, s_ll_length) = calculate_block_length(l_freqs, l_lengths, &|c| {
-        num_extra_bits_for_length_code(c.saturating_sub(LENGTH_BITS_START as usize) as u8).into()
-    });
+    let (d_ll_length, s_ll_length) = calculate_block_length(
+        l_freqs,
+        l_lengths,
+        &FIXED_CODE_LENGTHS,
+        &literal_length_extra_bits,
+    );
 
     // Total length of the compressed distances.
-    let (d_dist_length, s_dist_length) = calculate_block_length(d_freqs, d_lengths, &|c| {
-        num_extra_bits_for_distance_code(c as u8).into()
-    });
+    let (d_dist_length, s_dist_length) = calculate_block_length(
+        d_freqs,
+        d_lengths,
+        &FIXED_CODE_LENGTHS_DISTANCE,
+        &distance_extra_bits,
+    );
+
+    // Scale the lengths back up if the frequencies have been rescaled.
+    let (d_ll_length, s_ll_length) = (
+        d_ll_length << frequency_shift,
+        s_ll_length << frequency_shift,
+    );
+    let (d_dist_length, s_dist_length) = (
+        d_dist_length << frequency_shift,
+        s_dist_length << frequency_shift,
+    );
 
     // Total length of the compressed huffman code lengths.
     let huff_table_length = calculate_huffman_length(&freqs, &huffman_table_lengths);
@@ -274,7 +357,7 @@ pub fn gen_huffman_lengths(
     // increases the length of the block (for instance if the input data is mostly random or
     // already compressed), we want to output a stored(uncompressed) block instead to avoid wasting
     // space.
-    if used_length == static_length {
+    let block_type = if used_length == static_length {
         BlockType::Fixed
     } else if used_length == stored_length {
         BlockType::Stored
@@ -283,7 +366,8 @@ pub fn gen_huffman_lengths(
             huffman_table_lengths,
             used_hclens,
         })
-    }
+    };
+    (block_type, used_length)
 }
 
 /// Write the specified huffman lengths to the bit writer
@@ -303,7 +387,8 @@ pub fn write_huffman_lengths(
 
     assert!(literal_len_lengths.len() <= NUM_LITERALS_AND_LENGTHS);
     assert!(literal_len_lengths.len() >= MIN_NUM_LITERALS_AND_LENGTHS);
-    assert!(distance_lengths.len() <= NUM_DISTANCE_CODES);
+    // Deflate64 also uses the two distance codes that are unused in DEFLATE.
+    assert!(distance_lengths.len() <= NUM_DISTANCE_CODES + 2);
     assert!(distance_lengths.len() >= MIN_NUM_DISTANCES);
 
     // Number of length codes - 257.
@@ -23,6 +23,10 @@ pub const MAX_MATCH: u16 = 258;
 pub const MIN_DISTANCE: u16 = 1;
 pub const MAX_DISTANCE: u16 = 32768;
 
+// The maximum length and distance of a match in the Deflate64 format
+pub const DEFLATE64_MAX_MATCH: u32 = 65538;
+pub const DEFLATE64_MAX_DISTANCE: u32 = 65536;
+
 // The position in the literal/length table of the end of block symbol
 pub const END_OF_BLOCK_POSITION: usize = 256;
 
@@ -127,7 +131,7 @@ pub fn num_extra_bits_for_distance_code(code: u8) -> u8 {
 /// A struct representing the data needed to generate the bit codes for
 /// a given value and huffman table.
 #[derive(Copy, Clone)]
-struct ExtraBits {
+pub struct ExtraBits {
     // The position of the length in the huffman table.
     pub code_number: u16,
     // Number of extra bits following the code.
@@ -146,7 +150,7 @@ pub fn get_length_code(length: u16) -> usize {
 }
 
 /// Get the code for the huffman table and the extra bits for the requested length.
-fn get_length_code_and_extra_bits(length: StoredLength) -> ExtraBits {
+pub fn get_length_code_and_extra_bits(length: StoredLength) -> ExtraBits {
     // Length values are stored as unsigned bytes, where the actual length is the value - 3
     // The `StoredLength` struct takes care of this conversion for us.
     let n = LENGTH_CODE[length.stored_length() as usize];
@@ -180,7 +184,7 @@ pub fn get_distance_code(distance: u16) -> u8 {
     }
 }
 
-fn get_distance_code_and_extra_bits(distance: u16) -> ExtraBits {
+pub fn get_distance_code_and_extra_bits(distance: u16) -> ExtraBits {
     let distance_code = get_distance_code(distance);
     let extra = num_extra_bits_for_distance_code(distance_code);
     // FIXME: We should add 1 to the values in distance_base to avoid having to add one here
@@ -192,6 +196,48 @@ fn get_distance_code_and_extra_bits(distance: u16) -> ExtraBits {
     }
 }
 
+/// Get the code for the huffman table and the extra bits for a length in the Deflate64 format.
+///
+/// In Deflate64, the last length code is followed by 16 extra bits giving the length minus 3,
+/// so a length of 258 uses the largest value of the code before it instead.
+pub fn get_deflate64_length_code_and_extra_bits(length: u32) -> ExtraBits {
+    debug_assert!(length >= u32::from(MIN_MATCH) && length <= DEFLATE64_MAX_MATCH);
+    if length < u32::from(MAX_MATCH) {
+        get_length_code_and_extra_bits(StoredLength::new((length - u32::from(MIN_MATCH)) as u8))
+    } else if length == u32::from(MAX_MATCH) {
+        ExtraBits {
+            code_number: LENGTH_BITS_START + 27,
+            num_bits: 5,
+            value: 31,
+        }
+    } else {
+        ExtraBits {
+            code_number: LENGTH_BITS_START + 28,
+            num_bits: 16,
+            value: (length - u32::from(MIN_MATCH)) as u16,
+        }
+    }
+}
+
+/// Get the code for the huffman table and the extra bits for a distance in the Deflate64 format,
+/// which uses the two codes after the ones of DEFLATE for distances above 32768.
+pub fn get_deflate64_distance_code_and_extra_bits(distance: u32) -> ExtraBits {
+    debug_assert!(distance >= u32::from(MIN_DISTANCE) && distance <= DEFLATE64_MAX_DISTANCE);
+    if distance <= u32::from(MAX_DISTANCE) {
+        return get_distance_code_and_extra_bits(distance as u16);
+    }
+    let (code_number, base) = if distance <= 49152 {
+        (30, 32769)
+    } else {
+        (31, 49153)
+    };
+    ExtraBits {
+        code_number,
+        num_bits: 14,
+        value: (distance - base) as u16,
+    }
+}
+
 #[derive(Copy, Clone, Default)]
 pub struct HuffmanCode {
     pub code: u16,
@@ -277,6 +323,7 @@ pub fn create_codes_in_place(code_table: &mut [u16], length_table: &[u8]) {
 }
 
 /// A structure containing the tables of huffman codes for lengths, literals and distances
+#[derive(Clone)]
 pub struct HuffmanTable {
     // Literal, end of block and length codes
     codes: [u16; 288],
@@ -408,6 +455,25 @@ impl HuffmanTable {
         )
     }
 
+    /// Get the huffman codes and extra bits for a match in the Deflate64 format.
+    ///
+    /// Returns the length code, its extra bits, the distance code and its extra bits.
+    #[inline]
+    pub fn get_deflate64_match_huffman(&self, length: u32, distance: u32) -> [HuffmanCode; 4] {
+        let length_data = get_deflate64_length_code_and_extra_bits(length);
+        let distance_data = get_deflate64_distance_code_and_extra_bits(distance);
+        let distance_code = usize::from(distance_data.code_number);
+        [
+            self.get_ll_huff(usize::from(length_data.code_number)),
+            HuffmanCode::new(length_data.value, length_data.num_bits),
+            HuffmanCode::new(
+                self.distance_codes[distance_code],
+                self.distance_code_lengths[distance_code],
+            ),
+            HuffmanCode::new(distance_data.value, distance_data.num_bits),
+        ]
+    }
+
     #[cfg(test)]
     pub fn get_length_distance_code(&self, length: u16, distance: u16) -> LengthAndDistanceBits {
         assert!(length >= MIN_MATCH && length < MAX_DISTANCE);
@@ -0,0 +1,97 @@
+//! Compression of a buffer into its own allocation.
+//!
+//! The compressor copies the input it has consumed into its own window, so the part of the buffer
+//! before the current input position is free to be overwritten. The compressed data is moved there
+//! as it is produced, and output that would overtake the input is held back in the output buffer
+//! of the compressor until enough input has been consumed to make room for it.
+use std::cmp;
+
+use crate::compress::{compress_data_dynamic_n, Flush};
+use crate::compression_options::CompressionOptions;
+use crate::deflate_state::DeflateState;
+use crate::writer::compress_until_done;
+
+/// The amount of input passed to the compressor at a time.
+const CHUNK_SIZE: usize = 1 << 15;
+
+/// Compress the contents of `data` with DEFLATE compression, replacing them with the compressed
+/// data.
+///
+/// When the compressed data is not larger than the input, which is the case for anything
+/// compressible, the allocation of `data` is reused and the only extra memory needed is the
+/// compressor state and the output of the current block. Otherwise `data` is grown to fit the
+/// output. The capacity is left as it is, use `shrink_to_fit` to release the unused part.
+///
+/// The output is identical to the output of `deflate_bytes_conf` with the same options.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{compress_in_place, Compression};
+///
+/// let mut data = b"This is some test data, which is some data to test".to_vec();
+/// compress_in_place(&mut data, Compression::Default);
+/// # let _ = data;
+/// ```
+pub fn compress_in_place<O: Into<CompressionOptions>>(data: &mut Vec<u8>, options: O) {
+    let mut state = Box::new(DeflateState::with_direct_output(options.into(), Vec::new()));
+    // The amount of input consumed, and the amount of output moved into the buffer.
+    let mut read = 0;
+    let mut written = 0;
+    // How much of the output buffer of the compressor has been moved.
+    let mut taken = 0;
+
+    while read < data.len() {
+        let end = cmp::min(read + CHUNK_SIZE, data.len());
+        // The output is never written to the sink, so this can't fail.
+        read += compress_data_dynamic_n(&data[read..end], &mut state, Flush::None)
+            .expect("Write error!");
+
+        let output = state.output_buf();
+        let n = cmp::min(output.len() - taken, read - written);
+        data[written..written + n].copy_from_slice(&output[taken..taken + n]);
+        written += n;
+        taken += n;
+        if taken == output.len() {
+            state.clear_output_buf();
+            taken = 0;
+        }
+    }
+
+    compress_until_done(&[], &mut state, Flush::Finish).expect("Write error!");
+    let output = state.take_direct_output();
+    data.truncate(written);
+    data.extend_from_slice(&output[taken..]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, get_random_data, get_test_data};
+
+    #[test]
+    fn in_place_same_as_deflate_bytes() {
+        let input = get_test_data();
+        for &options in &[CompressionOptions::default(), CompressionOptions::fast()] {
+            let mut data = input.clone();
+            let ptr = data.as_ptr();
+            compress_in_place(&mut data, options);
+            assert_eq!(data.as_ptr(), ptr);
+            assert!(data == crate::deflate_bytes_conf(&input, options));
+        }
+
+        let mut empty = Vec::new();
+        compress_in_place(&mut empty, CompressionOptions::default());
+        assert!(decompress_to_end(&empty).is_empty());
+    }
+
+    #[test]
+    fn in_place_incompressible() {
+        // Data that can't be compressed, so the output ends up larger than the input.
+        let input = get_random_data(200_000);
+        let mut data = input.clone();
+        compress_in_place(&mut data, CompressionOptions::default());
+        assert!(data.len() > input.len());
+        assert!(decompress_to_end(&data) == input);
+    }
+}
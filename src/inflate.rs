@@ -0,0 +1,766 @@
+//! Decompression of raw DEFLATE, zlib and gzip streams.
+//!
+//! The decoder works on complete streams held in memory, and accepts any valid stream, not just
+//! the ones produced by this crate.
+use std::{error, fmt, io};
+
+use crate::bit_reverse::reverse_bits;
+use crate::checksum::{crc32, Adler32Checksum, Crc32, RollingChecksum};
+use crate::huffman_table::{
+    num_extra_bits_for_distance_code, num_extra_bits_for_length_code, END_OF_BLOCK_POSITION,
+    FIXED_CODE_LENGTHS, MAX_CODE_LENGTH,
+};
+use crate::tokens::Token;
+
+/// The base length of each length code.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+
+/// The base distance of each distance code, including the two only used by Deflate64.
+const DISTANCE_BASE: [u16; 32] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577, 32769, 49153,
+];
+
+/// The format of the compressed data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Variant {
+    Deflate,
+    /// The last length code has 16 extra bits giving the length minus 3, and the window is
+    /// 64 KiB.
+    Deflate64,
+}
+
+impl Variant {
+    fn num_distance_codes(self) -> usize {
+        match self {
+            Variant::Deflate => 30,
+            Variant::Deflate64 => 32,
+        }
+    }
+
+    /// The base length and number of extra bits of a length code.
+    fn length_code(self, code: usize) -> (usize, u32) {
+        if self == Variant::Deflate64 && code == LENGTH_BASE.len() - 1 {
+            (3, 16)
+        } else {
+            (
+                usize::from(LENGTH_BASE[code]),
+                u32::from(num_extra_bits_for_length_code(code as u8)),
+            )
+        }
+    }
+}
+
+/// The order the code length code lengths are stored in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// The number of bits looked up at once when decoding a huffman code.
+const FAST_BITS: u32 = 10;
+
+/// An error from decompressing data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum InflateError {
+    /// The input ended before the end of the stream.
+    UnexpectedEof,
+    /// The zlib or gzip header is invalid or uses unsupported features.
+    InvalidHeader,
+    /// The zlib stream was compressed with a preset dictionary.
+    DictionaryRequired,
+    /// A block used the reserved block type.
+    InvalidBlockType,
+    /// The length of a stored block did not match its complement.
+    InvalidStoredLength,
+    /// The code lengths of a dynamic block don't describe a valid set of huffman codes.
+    InvalidCodeLengths,
+    /// A block contained a symbol that is not valid in a compressed stream.
+    InvalidSymbol,
+    /// A match referred to data before the start of the stream.
+    InvalidDistance,
+    /// The checksum or length in the trailer did not match the decompressed data.
+    ChecksumMismatch,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            InflateError::UnexpectedEof => "Unexpected end of compressed data",
+            InflateError::InvalidHeader => "Invalid stream header",
+            InflateError::DictionaryRequired => "A preset dictionary is required",
+            InflateError::InvalidBlockType => "Invalid block type",
+            InflateError::InvalidStoredLength => "Invalid stored block length",
+            InflateError::InvalidCodeLengths => "Invalid huffman code lengths",
+            InflateError::InvalidSymbol => "Invalid symbol",
+            InflateError::InvalidDistance => "Match distance too far back",
+            InflateError::ChecksumMismatch => "Checksum mismatch",
+        })
+    }
+}
+
+impl error::Error for InflateError {}
+
+impl From<InflateError> for io::Error {
+    fn from(err: InflateError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Reads bits from the input, least significant bit first.
+struct BitReader<'a> {
+    input: &'a [u8],
+    /// The position of the next byte to load into `bit_buf`.
+    pos: usize,
+    bit_buf: u64,
+    num_bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(input: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            input,
+            pos: 0,
+            bit_buf: 0,
+            num_bits: 0,
+        }
+    }
+
+    /// Fill the bit buffer, padding with zeroes past the end of the input.
+    fn refill(&mut self) {
+        while self.num_bits <= 56 {
+            let byte = self.input.get(self.pos).map_or(0, |&b| u64::from(b));
+            self.bit_buf |= byte << self.num_bits;
+            self.num_bits += 8;
+            self.pos += 1;
+        }
+    }
+
+    /// Look at the next `n` bits without consuming them, `n` has to be at most 32.
+    fn peek(&mut self, n: u32) -> u32 {
+        if self.num_bits < n {
+            self.refill();
+        }
+        (self.bit_buf & ((1 << n) - 1)) as u32
+    }
+
+    /// Consume `n` bits that have been peeked at.
+    fn consume(&mut self, n: u32) -> Result<(), InflateError> {
+        self.bit_buf >>= n;
+        self.num_bits -= n;
+        if self.bytes_consumed() > self.input.len() {
+            Err(InflateError::UnexpectedEof)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_bits(&mut self, n: u32) -> Result<u32, InflateError> {
+        let value = self.peek(n);
+        self.consume(n)?;
+        Ok(value)
+    }
+
+    /// The number of bytes of input used so far, counting a partially used byte.
+    fn bytes_consumed(&self) -> usize {
+        (self.pos * 8 - self.num_bits as usize).div_ceil(8)
+    }
+
+    /// Skip to the next byte boundary, and return the rest of the input from there.
+    fn align_to_byte(&mut self) -> Result<&'a [u8], InflateError> {
+        self.consume(self.num_bits % 8)?;
+        let pos = self.bytes_consumed();
+        self.bit_buf = 0;
+        self.num_bits = 0;
+        self.pos = pos;
+        Ok(&self.input[pos..])
+    }
+
+    /// Continue reading after `n` bytes taken from the slice returned by `align_to_byte`.
+    fn skip_bytes(&mut self, n: usize) {
+        self.pos += n;
+    }
+}
+
+/// A canonical huffman code, decoded with a lookup table for the short codes and bit by bit
+/// for the rest.
+struct Huffman {
+    /// The number of codes of each length.
+    counts: [u16; MAX_CODE_LENGTH + 1],
+    /// The symbols ordered by their codes.
+    symbols: Vec<u16>,
+    /// Entries for each `FAST_BITS` bit sequence, storing `symbol << 4 | length`, or 0 if the
+    /// code is longer.
+    fast: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, InflateError> {
+        let mut counts = [0u16; MAX_CODE_LENGTH + 1];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        counts[0] = 0;
+
+        // Check that the code is not over-subscribed, and only allow an incomplete code if it
+        // only has a single code, as zlib does.
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(InflateError::InvalidCodeLengths);
+            }
+        }
+        let num_codes: u16 = counts.iter().sum();
+        if left > 0 && num_codes > 1 {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+
+        let mut offsets = [0u16; MAX_CODE_LENGTH + 2];
+        for length in 1..=MAX_CODE_LENGTH {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; usize::from(num_codes)];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                let offset = &mut offsets[usize::from(length)];
+                symbols[usize::from(*offset)] = symbol as u16;
+                *offset += 1;
+            }
+        }
+
+        let mut fast = vec![0; 1 << FAST_BITS];
+        let mut code = 0u16;
+        let mut index = 0;
+        for length in 1..=FAST_BITS as u8 {
+            for _ in 0..counts[usize::from(length)] {
+                let entry = (symbols[index] << 4) | u16::from(length);
+                let mut i = usize::from(reverse_bits(code, length));
+                while i < fast.len() {
+                    fast[i] = entry;
+                    i += 1 << length;
+                }
+                code += 1;
+                index += 1;
+            }
+            code <<= 1;
+        }
+
+        Ok(Huffman {
+            counts,
+            symbols,
+            fast,
+        })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, InflateError> {
+        let bits = reader.peek(MAX_CODE_LENGTH as u32);
+        let entry = self.fast[(bits & ((1 << FAST_BITS) - 1)) as usize];
+        if entry != 0 {
+            reader.consume(u32::from(entry & 0xF))?;
+            return Ok(entry >> 4);
+        }
+
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for length in 1..=MAX_CODE_LENGTH {
+            code |= ((bits >> (length - 1)) & 1) as i32;
+            let count = i32::from(self.counts[length]);
+            if code - first < count {
+                reader.consume(length as u32)?;
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        // This can only happen with incomplete codes.
+        Err(InflateError::InvalidSymbol)
+    }
+}
+
+/// Read the code lengths of a dynamic block and build its huffman codes.
+fn read_dynamic_codes(
+    reader: &mut BitReader,
+    variant: Variant,
+) -> Result<(Huffman, Huffman), InflateError> {
+    let num_literals = reader.read_bits(5)? as usize + 257;
+    let num_distances = reader.read_bits(5)? as usize + 1;
+    let num_code_lengths = reader.read_bits(4)? as usize + 4;
+    if num_literals > 286 || num_distances > variant.num_distance_codes() {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    let mut code_length_lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..num_code_lengths] {
+        code_length_lengths[i] = reader.read_bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_length_lengths)?;
+
+    let mut lengths = [0u8; 286 + 32];
+    let total = num_literals + num_distances;
+    let mut i = 0;
+    while i < total {
+        let symbol = code_lengths.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths[..i]
+                    .last()
+                    .ok_or(InflateError::InvalidCodeLengths)?;
+                (previous, 3 + reader.read_bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.read_bits(3)? as usize),
+            _ => (0, 11 + reader.read_bits(7)? as usize),
+        };
+        if i + repeat > total {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+        lengths[i..i + repeat].iter_mut().for_each(|l| *l = value);
+        i += repeat;
+    }
+    if lengths[END_OF_BLOCK_POSITION] == 0 {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    Ok((
+        Huffman::new(&lengths[..num_literals])?,
+        Huffman::new(&lengths[num_literals..total])?,
+    ))
+}
+
+/// Decode the symbols of a compressed block until the end of block symbol.
+///
+/// If `tokens` is provided, the decoded literals and matches are also added to it.
+fn inflate_block(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+    variant: Variant,
+    mut tokens: Option<&mut Vec<Token>>,
+) -> Result<(), InflateError> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        if symbol < 256 {
+            output.push(symbol as u8);
+            if let Some(ref mut tokens) = tokens {
+                tokens.push(Token::Literal(symbol as u8));
+            }
+            continue;
+        }
+        if usize::from(symbol) == END_OF_BLOCK_POSITION {
+            return Ok(());
+        }
+
+        let code = usize::from(symbol - 257);
+        if code >= LENGTH_BASE.len() {
+            return Err(InflateError::InvalidSymbol);
+        }
+        let (base, extra) = variant.length_code(code);
+        let length = base + reader.read_bits(extra)? as usize;
+
+        let code = usize::from(distances.decode(reader)?);
+        if code >= variant.num_distance_codes() {
+            return Err(InflateError::InvalidSymbol);
+        }
+        let extra = u32::from(num_extra_bits_for_distance_code(code as u8));
+        let distance = usize::from(DISTANCE_BASE[code]) + reader.read_bits(extra)? as usize;
+        if distance > output.len() {
+            return Err(InflateError::InvalidDistance);
+        }
+        if let Some(ref mut tokens) = tokens {
+            tokens.push(Token::Match {
+                length: length as u16,
+                distance: distance as u16,
+            });
+        }
+
+        let start = output.len() - distance;
+        if distance >= length {
+            output.extend_from_within(start..start + length);
+        } else {
+            for i in start..start + length {
+                let byte = output[i];
+                output.push(byte);
+            }
+        }
+    }
+}
+
+/// Decompress a raw DEFLATE stream at the start of `input`, appending the data to `output`.
+///
+/// Matches may refer back to data already in `output`. Returns the number of bytes of `input`
+/// used by the stream.
+fn inflate_raw(input: &[u8], output: &mut Vec<u8>) -> Result<usize, InflateError> {
+    inflate_raw_variant(input, output, Variant::Deflate, None)
+}
+
+/// Decompress a raw DEFLATE stream at the start of `input` like `inflate_raw`, also adding the
+/// literals and matches it's made up of to `tokens`. The bytes of stored blocks are added as
+/// literals.
+pub(crate) fn inflate_raw_tokens(
+    input: &[u8],
+    output: &mut Vec<u8>,
+    tokens: &mut Vec<Token>,
+) -> Result<usize, InflateError> {
+    inflate_raw_variant(input, output, Variant::Deflate, Some(tokens))
+}
+
+/// Decompress a raw stream in the format `variant` at the start of `input`, appending the data
+/// to `output`.
+fn inflate_raw_variant(
+    input: &[u8],
+    output: &mut Vec<u8>,
+    variant: Variant,
+    mut tokens: Option<&mut Vec<Token>>,
+) -> Result<usize, InflateError> {
+    let mut reader = BitReader::new(input);
+    let fixed_literals = FIXED_CODE_LENGTHS;
+    loop {
+        let header = reader.read_bits(3)?;
+        match header >> 1 {
+            0 => {
+                let data = reader.align_to_byte()?;
+                if data.len() < 4 {
+                    return Err(InflateError::UnexpectedEof);
+                }
+                let length = usize::from(u16::from_le_bytes([data[0], data[1]]));
+                let complement = u16::from_le_bytes([data[2], data[3]]);
+                if length as u16 != !complement {
+                    return Err(InflateError::InvalidStoredLength);
+                }
+                let data = data[4..].get(..length).ok_or(InflateError::UnexpectedEof)?;
+                output.extend_from_slice(data);
+                if let Some(ref mut tokens) = tokens {
+                    tokens.extend(data.iter().map(|&b| Token::Literal(b)));
+                }
+                reader.skip_bytes(4 + length);
+            }
+            1 => {
+                let literals = Huffman::new(&fixed_literals)?;
+                let distances = Huffman::new(&[5; 32])?;
+                inflate_block(
+                    &mut reader,
+                    output,
+                    &literals,
+                    &distances,
+                    variant,
+                    tokens.as_deref_mut(),
+                )?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut reader, variant)?;
+                inflate_block(
+                    &mut reader,
+                    output,
+                    &literals,
+                    &distances,
+                    variant,
+                    tokens.as_deref_mut(),
+                )?;
+            }
+            _ => return Err(InflateError::InvalidBlockType),
+        }
+        if header & 1 == 1 {
+            return Ok(reader.bytes_consumed());
+        }
+    }
+}
+
+/// Decompress a raw DEFLATE stream.
+///
+/// Any data after the end of the stream is ignored.
+///
+/// # Examples
+///
+/// ```
+/// let compressed = deflate::deflate_bytes(b"This is some test data");
+/// let data = deflate::inflate_bytes(&compressed).unwrap();
+/// assert_eq!(data, b"This is some test data");
+/// ```
+pub fn inflate_bytes(input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    let mut output = Vec::with_capacity(input.len() * 3);
+    inflate_raw(input, &mut output)?;
+    Ok(output)
+}
+
+/// Decompress a raw Deflate64 stream, like the ones produced by
+/// [`deflate64_bytes`](fn.deflate64_bytes.html).
+///
+/// Any data after the end of the stream is ignored.
+pub fn inflate_bytes_deflate64(input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    let mut output = Vec::with_capacity(input.len() * 3);
+    inflate_raw_variant(input, &mut output, Variant::Deflate64, None)?;
+    Ok(output)
+}
+
+/// Decompress a raw DEFLATE stream compressed with the preset dictionary `dictionary`.
+pub fn inflate_bytes_with_dictionary(
+    dictionary: &[u8],
+    input: &[u8],
+) -> Result<Vec<u8>, InflateError> {
+    let mut output = Vec::with_capacity(dictionary.len() + input.len() * 3);
+    output.extend_from_slice(dictionary);
+    inflate_raw(input, &mut output)?;
+    Ok(output.split_off(dictionary.len()))
+}
+
+/// Decompress a zlib stream, checking the Adler-32 checksum in the trailer.
+///
+/// Streams using a preset dictionary result in `InflateError::DictionaryRequired`, use
+/// `inflate_bytes_zlib_with_dictionary` for those.
+pub fn inflate_bytes_zlib(input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    inflate_zlib(None, input)
+}
+
+/// Decompress a zlib stream compressed with the preset dictionary `dictionary`.
+///
+/// The dictionary has to match the checksum in the header, `InflateError::InvalidHeader` is
+/// returned if it doesn't, or if the header does not indicate a dictionary.
+pub fn inflate_bytes_zlib_with_dictionary(
+    dictionary: &[u8],
+    input: &[u8],
+) -> Result<Vec<u8>, InflateError> {
+    inflate_zlib(Some(dictionary), input)
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut checksum = Adler32Checksum::new();
+    checksum.update_from_slice(data);
+    checksum.current_hash()
+}
+
+fn inflate_zlib(dictionary: Option<&[u8]>, input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    if input.len() < 2 {
+        return Err(InflateError::UnexpectedEof);
+    }
+    let (cmf, flg) = (input[0], input[1]);
+    if cmf & 0x0F != 8 || cmf >> 4 > 7 || (u16::from(cmf) << 8 | u16::from(flg)) % 31 != 0 {
+        return Err(InflateError::InvalidHeader);
+    }
+    let mut start = 2;
+    let dictionary = match (flg & 0x20 != 0, dictionary) {
+        (false, None) => &[][..],
+        (true, None) => return Err(InflateError::DictionaryRequired),
+        (true, Some(dictionary)) => {
+            let id = input.get(2..6).ok_or(InflateError::UnexpectedEof)?;
+            if adler32(dictionary).to_be_bytes() != id {
+                return Err(InflateError::InvalidHeader);
+            }
+            start = 6;
+            dictionary
+        }
+        (false, Some(_)) => return Err(InflateError::InvalidHeader),
+    };
+
+    let mut output = Vec::with_capacity(dictionary.len() + input.len() * 3);
+    output.extend_from_slice(dictionary);
+    let used = inflate_raw(&input[start..], &mut output)?;
+    let output = output.split_off(dictionary.len());
+    let trailer = input[start + used..]
+        .get(..4)
+        .ok_or(InflateError::UnexpectedEof)?;
+    if adler32(&output).to_be_bytes() != trailer {
+        return Err(InflateError::ChecksumMismatch);
+    }
+    Ok(output)
+}
+
+/// Return the length of the gzip header at the start of `input`.
+fn gzip_header_length(input: &[u8]) -> Result<usize, InflateError> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    let fixed = input.get(..10).ok_or(InflateError::UnexpectedEof)?;
+    if fixed[..3] != [0x1F, 0x8B, 8] || fixed[3] & 0xE0 != 0 {
+        return Err(InflateError::InvalidHeader);
+    }
+    let flags = fixed[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let length = input.get(pos..pos + 2).ok_or(InflateError::UnexpectedEof)?;
+        pos += 2 + usize::from(u16::from_le_bytes([length[0], length[1]]));
+    }
+    for &flag in &[FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let rest = input.get(pos..).ok_or(InflateError::UnexpectedEof)?;
+            let end = rest
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or(InflateError::UnexpectedEof)?;
+            pos += end + 1;
+        }
+    }
+    if pos > input.len() {
+        return Err(InflateError::UnexpectedEof);
+    }
+    if flags & FHCRC != 0 {
+        let crc = input.get(pos..pos + 2).ok_or(InflateError::UnexpectedEof)?;
+        if (crc32(&input[..pos]) as u16).to_le_bytes() != crc {
+            return Err(InflateError::ChecksumMismatch);
+        }
+        pos += 2;
+    }
+    Ok(pos)
+}
+
+/// Decompress a gzip file, checking the CRC-32 and length in the trailer of each member.
+///
+/// The header fields are skipped. Like `gzip`, the data of all the members of a multi-member
+/// file is returned.
+pub fn inflate_bytes_gzip(input: &[u8]) -> Result<Vec<u8>, InflateError> {
+    let mut output = Vec::with_capacity(input.len() * 3);
+    let mut rest = input;
+    loop {
+        let start = gzip_header_length(rest)?;
+        let member_start = output.len();
+        let used = inflate_raw(&rest[start..], &mut output)?;
+        let end = start + used + 8;
+        let trailer = rest
+            .get(start + used..end)
+            .ok_or(InflateError::UnexpectedEof)?;
+        let member = &output[member_start..];
+        let mut crc = Crc32::new();
+        crc.update(member);
+        if crc.sum().to_le_bytes() != trailer[..4]
+            || (member.len() as u32).to_le_bytes() != trailer[4..]
+        {
+            return Err(InflateError::ChecksumMismatch);
+        }
+        rest = &rest[end..];
+        if rest.is_empty() {
+            return Ok(output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression_options::CompressionOptions;
+    use crate::test_utils::{decompress_to_end, get_random_data, get_test_data};
+    use crate::writer::DeflateEncoder;
+    use std::io::Write;
+
+    #[test]
+    fn inflate_roundtrip() {
+        let data = get_test_data();
+        for options in &[
+            CompressionOptions::default(),
+            CompressionOptions::fast(),
+            CompressionOptions::high(),
+            CompressionOptions::huffman_only(),
+            CompressionOptions::rle(),
+        ] {
+            let compressed = crate::deflate_bytes_conf(&data, *options);
+            assert!(inflate_bytes(&compressed).unwrap() == data);
+            let compressed = crate::deflate_bytes_zlib_conf(&data, *options);
+            assert!(inflate_bytes_zlib(&compressed).unwrap() == data);
+        }
+        let random = get_random_data(100_000);
+        assert!(inflate_bytes(&crate::deflate_bytes(&random)).unwrap() == random);
+        assert_eq!(inflate_bytes(&crate::deflate_bytes(&[])).unwrap(), []);
+    }
+
+    #[test]
+    fn inflate_stored_and_fixed() {
+        // A stored block followed by a fixed block with a match overlapping its own output.
+        let mut compressed = vec![0x00, 0x03, 0x00, 0xFC, 0xFF, b'a', b'b', b'c'];
+        compressed.extend_from_slice(&[0x4B, 0x04, 0x02, 0x00]);
+        assert_eq!(
+            inflate_bytes(&compressed).unwrap(),
+            decompress_to_end(&compressed)
+        );
+        assert_eq!(inflate_bytes(&compressed).unwrap(), b"abcaaaa");
+    }
+
+    #[test]
+    fn inflate_with_dictionary() {
+        let data = get_test_data();
+        let dictionary = &data[..5000];
+        let mut encoder = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        encoder.set_dictionary(dictionary).unwrap();
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(inflate_bytes_with_dictionary(dictionary, &compressed).unwrap() == data);
+        assert_eq!(
+            inflate_bytes(&compressed),
+            Err(InflateError::InvalidDistance)
+        );
+    }
+
+    #[test]
+    fn inflate_errors() {
+        let data = get_test_data();
+        let compressed = crate::deflate_bytes_zlib(&data[..10_000]);
+        assert_eq!(
+            inflate_bytes_zlib(&compressed[..compressed.len() - 5]),
+            Err(InflateError::UnexpectedEof)
+        );
+        let mut corrupt = compressed.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert_eq!(
+            inflate_bytes_zlib(&corrupt),
+            Err(InflateError::ChecksumMismatch)
+        );
+        assert_eq!(
+            inflate_bytes_zlib(&[0x78, 0x00]),
+            Err(InflateError::InvalidHeader)
+        );
+        assert_eq!(inflate_bytes(&[0x07]), Err(InflateError::InvalidBlockType));
+        assert_eq!(
+            inflate_bytes(&[0x01, 0x03, 0x00, 0x00, 0x00]),
+            Err(InflateError::InvalidStoredLength)
+        );
+        assert_eq!(inflate_bytes(&[]), Err(InflateError::UnexpectedEof));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn inflate_gzip() {
+        use gzip_header::GzBuilder;
+
+        let data = get_test_data();
+        let header = GzBuilder::new()
+            .filename("test.txt")
+            .comment("A comment")
+            .extra(vec![1, 2, 3]);
+        let compressed =
+            crate::deflate_bytes_gzip_conf(&data, CompressionOptions::default(), header);
+        assert!(inflate_bytes_gzip(&compressed).unwrap() == data);
+    }
+
+    #[test]
+    fn inflate_zlib_dictionary() {
+        use crate::writer::{Encoder, Format, ZlibEncoder};
+
+        let data = get_test_data();
+        let dictionary = &data[..5000];
+        let mut encoder = ZlibEncoder::new(Vec::new(), CompressionOptions::default());
+        encoder.set_dictionary(dictionary).unwrap();
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(
+            inflate_bytes_zlib(&compressed),
+            Err(InflateError::DictionaryRequired)
+        );
+        assert_eq!(
+            inflate_bytes_zlib_with_dictionary(&data[..4999], &compressed),
+            Err(InflateError::InvalidHeader)
+        );
+        assert!(inflate_bytes_zlib_with_dictionary(dictionary, &compressed).unwrap() == data);
+
+        // The generic encoder produces the same output.
+        let mut encoder = Encoder::new(Vec::new(), Format::Zlib, CompressionOptions::default());
+        encoder.set_dictionary(dictionary).unwrap();
+        encoder.write_all(&data).unwrap();
+        assert!(encoder.finish().unwrap() == compressed);
+    }
+}
@@ -1,14 +1,47 @@
-use std::cmp;
-
+use crate::allocator::{StateAllocator, StateVec};
 use crate::chained_hash_table::WINDOW_SIZE;
 
 const MAX_MATCH: usize = crate::huffman_table::MAX_MATCH as usize;
 
 /// The maximum size of the buffer.
 pub const BUFFER_SIZE: usize = (WINDOW_SIZE * 2) + MAX_MATCH;
+/// The size of the storage backing an `InputBuffer`.
+///
+/// The extra windows let the buffer slide forward through the storage a few times before the data
+/// has to be moved back to the start of it.
+const STORAGE_SIZE: usize = BUFFER_SIZE + (WINDOW_SIZE * 2);
+
+/// The interface used by the lz77 compressor to access the current window of input data.
+///
+/// The data is laid out as one window of previously seen data (once the first window has been
+/// processed), followed by the window that is currently being processed and the lookahead.
+pub trait InputWindow {
+    /// Add data to the buffer.
+    ///
+    /// Returns a slice of the data that was not added (including the lookahead if any).
+    fn add_data<'a>(&mut self, data: &'a [u8]) -> Option<&'a [u8]>;
+
+    /// Get the current amount of data in the buffer.
+    fn current_end(&self) -> usize;
 
+    /// Slide the input window and add new data.
+    ///
+    /// Returns a slice containing the data that did not fit, or None if all data was consumed.
+    fn slide<'a>(&mut self, data: &'a [u8]) -> Option<&'a [u8]>;
+
+    /// Get a slice of the used part of the buffer.
+    fn get_buffer(&self) -> &[u8];
+}
+
+/// An input window that copies the data into its own storage.
+///
+/// Sliding the window moves the start of it forward in the storage, so the retained window only
+/// has to be copied once the end of the storage is reached.
+#[derive(Clone)]
 pub struct InputBuffer {
-    buffer: Vec<u8>,
+    buffer: StateVec<u8>,
+    /// Start of the window in the storage.
+    start: usize,
 }
 
 impl InputBuffer {
@@ -20,20 +53,38 @@ impl InputBuffer {
     }
 
     pub fn empty() -> InputBuffer {
+        InputBuffer::empty_in(&StateAllocator::global())
+    }
+
+    /// Create an empty buffer, allocating it with `allocator`.
+    pub fn empty_in(allocator: &StateAllocator) -> InputBuffer {
         InputBuffer {
-            buffer: Vec::with_capacity(BUFFER_SIZE),
+            buffer: allocator.vec_with_capacity(STORAGE_SIZE),
+            start: 0,
         }
     }
 
+    /// Remove all the data in the buffer, keeping the allocation.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.start = 0;
+    }
+
+    /// The number of bytes of heap memory allocated for the buffer.
+    pub fn heap_size(&self) -> usize {
+        self.buffer.capacity()
+    }
+
     /// Add data to the buffer.
     ///
     /// Returns a slice of the data that was not added (including the lookahead if any).
     pub fn add_data<'a>(&mut self, data: &'a [u8]) -> Option<&'a [u8]> {
         debug_assert!(self.current_end() <= BUFFER_SIZE);
+        debug_assert!(self.start + BUFFER_SIZE <= STORAGE_SIZE);
         if self.current_end() + data.len() > BUFFER_SIZE {
             // Add data and return how much was left.
             let consumed = {
-                let space_left = BUFFER_SIZE - self.buffer.len();
+                let space_left = BUFFER_SIZE - self.current_end();
                 self.buffer.extend_from_slice(&data[..space_left]);
                 space_left
             };
@@ -47,7 +98,7 @@ impl InputBuffer {
 
     /// Get the current amount of data in the buffer.
     pub fn current_end(&self) -> usize {
-        self.buffer.len()
+        self.buffer.len() - self.start
     }
 
     /// Slide the input window and add new data.
@@ -55,44 +106,108 @@ impl InputBuffer {
     /// Returns a slice containing the data that did not fit, or None if all data was consumed.
     pub fn slide<'a>(&mut self, data: &'a [u8]) -> Option<&'a [u8]> {
         // This should only be used when the buffer is full
-        assert!(self.buffer.len() > WINDOW_SIZE * 2);
-
-        // Do this in a closure to to end the borrow of buffer.
-        let (final_len, upper_len, end) = {
-            // Split into lower window and upper window + lookahead
-            let (lower, upper) = self.buffer.split_at_mut(WINDOW_SIZE);
-            // Copy the upper window to the lower window
-            lower.copy_from_slice(&upper[..WINDOW_SIZE]);
-            let lookahead_len = {
-                // Copy the lookahead to the start of the upper window
-                let (upper_2, lookahead) = upper.split_at_mut(WINDOW_SIZE);
-                let lookahead_len = lookahead.len();
-                debug_assert!(lookahead_len <= MAX_MATCH);
-                upper_2[..lookahead_len].copy_from_slice(lookahead);
-                lookahead_len
-            };
+        assert!(self.current_end() > WINDOW_SIZE * 2);
+
+        self.start += WINDOW_SIZE;
+        if self.start + BUFFER_SIZE > STORAGE_SIZE {
+            // There isn't space for a full window after the current one, so move the upper
+            // window and the lookahead back to the start of the storage.
+            let len = self.current_end();
+            self.buffer.copy_within(self.start.., 0);
+            self.buffer.truncate(len);
+            self.start = 0;
+        }
+        self.add_data(data)
+    }
+
+    /// Get a slice of the used part of the buffer.
+    pub fn get_buffer(&self) -> &[u8] {
+        &self.buffer[self.start..]
+    }
+}
+
+impl InputWindow for InputBuffer {
+    fn add_data<'a>(&mut self, data: &'a [u8]) -> Option<&'a [u8]> {
+        InputBuffer::add_data(self, data)
+    }
+
+    fn current_end(&self) -> usize {
+        InputBuffer::current_end(self)
+    }
+
+    fn slide<'a>(&mut self, data: &'a [u8]) -> Option<&'a [u8]> {
+        InputBuffer::slide(self, data)
+    }
+
+    fn get_buffer(&self) -> &[u8] {
+        InputBuffer::get_buffer(self)
+    }
+}
+
+/// An input window that refers directly into a slice containing all of the input data, avoiding
+/// copying the data into an `InputBuffer`.
+///
+/// The data passed to `add_data` and `slide` has to be the data in the source slice directly
+/// following the current end of the window.
+pub struct SliceWindow<'s> {
+    source: &'s [u8],
+    /// Start of the window in the source slice.
+    start: usize,
+    /// End of the data added to the window so far.
+    end: usize,
+}
 
-            // Length of the upper window minus the lookahead bytes
-            let upper_len = upper.len() - lookahead_len;
-            let end = cmp::min(data.len(), upper_len);
-            upper[lookahead_len..lookahead_len + end].copy_from_slice(&data[..end]);
-            // Remove unused data if any.
-            (lower.len() + lookahead_len + end, upper_len, end)
-        };
-        // Remove unused space.
-        self.buffer.truncate(final_len);
-
-        if data.len() > upper_len {
-            // Return a slice of the data that was not added
-            Some(&data[end..])
+impl<'s> SliceWindow<'s> {
+    pub fn new(source: &'s [u8]) -> SliceWindow<'s> {
+        SliceWindow {
+            source,
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// The part of the source that has not been added to the window yet.
+    #[cfg(test)]
+    pub fn remaining(&self) -> &'s [u8] {
+        &self.source[self.end..]
+    }
+
+    /// Extend the window with as much of `data` as fits.
+    fn extend<'a>(&mut self, data: &'a [u8]) -> Option<&'a [u8]> {
+        debug_assert!(
+            data.is_empty() || data.as_ptr() == self.source[self.end..].as_ptr(),
+            "Data added to a SliceWindow has to directly follow the current window!"
+        );
+        let space_left = BUFFER_SIZE - (self.end - self.start);
+        if data.len() > space_left {
+            self.end += space_left;
+            Some(&data[space_left..])
         } else {
+            self.end += data.len();
             None
         }
     }
+}
 
-    /// Get a mutable slice of the used part of the buffer.
-    pub fn get_buffer(&mut self) -> &mut [u8] {
-        &mut self.buffer
+impl<'s> InputWindow for SliceWindow<'s> {
+    fn add_data<'a>(&mut self, data: &'a [u8]) -> Option<&'a [u8]> {
+        debug_assert!(self.current_end() <= BUFFER_SIZE);
+        self.extend(data)
+    }
+
+    fn current_end(&self) -> usize {
+        self.end - self.start
+    }
+
+    fn slide<'a>(&mut self, data: &'a [u8]) -> Option<&'a [u8]> {
+        // This should only be used when the buffer is full
+        assert!(self.current_end() > WINDOW_SIZE * 2);
+        self.start += WINDOW_SIZE;
+        self.extend(data)
+    }
+
+    fn get_buffer(&self) -> &[u8] {
+        &self.source[self.start..self.end]
     }
 }
 
@@ -144,4 +259,28 @@ mod test {
         }
         assert_eq!(buf.current_end(), WINDOW_SIZE + MAX_MATCH + to_add.len());
     }
+
+    #[test]
+    fn slice_window_matches_buffer() {
+        let data: Vec<u8> = (0..BUFFER_SIZE * 3).map(|n| (n % 251) as u8).collect();
+        let mut buf = InputBuffer::empty();
+        let mut window = SliceWindow::new(&data);
+
+        let rem_buf = buf.add_data(&data);
+        let rem_window = window.add_data(window.remaining());
+        assert_eq!(rem_buf, rem_window);
+        assert!(buf.get_buffer() == InputWindow::get_buffer(&window));
+
+        // Slide enough times for the buffer to have to move the data back to the start of its
+        // storage.
+        let (mut rem_buf, mut rem_window) = (rem_buf, rem_window);
+        for _ in 0..5 {
+            rem_buf = buf.slide(rem_buf.unwrap());
+            rem_window = window.slide(rem_window.unwrap());
+            assert_eq!(rem_buf, rem_window);
+            assert_eq!(buf.current_end(), InputWindow::current_end(&window));
+            assert!(buf.get_buffer() == InputWindow::get_buffer(&window));
+        }
+        assert!(buf.heap_size() >= STORAGE_SIZE);
+    }
 }
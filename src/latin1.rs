@@ -0,0 +1,175 @@
+//! Helpers for encoding the filename and comment fields of a gzip header.
+//!
+//! The [gzip specification](http://www.gzip.org/zlib/rfc-gzip.html#header-trailer) requires the
+//! `FNAME` and `FCOMMENT` fields to consist of ISO 8859-1 (LATIN-1) characters, terminated by a
+//! zero byte. Rust strings are UTF-8, so passing the raw bytes of a string to the header builder
+//! will produce a non-conformant header for anything outside the ASCII range.
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+
+/// The character used in place of characters that can not be encoded when using the lossy
+/// encoding functions.
+pub const REPLACEMENT_CHARACTER: u8 = b'?';
+
+/// Error returned when a string can not be encoded as a gzip header field.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Latin1Error {
+    /// The string contains a character outside of the ISO 8859-1 range.
+    Unencodable {
+        /// The character that could not be encoded.
+        character: char,
+        /// Byte offset of the character in the input string.
+        position: usize,
+    },
+    /// The string contains a zero byte, which would terminate the field early.
+    InteriorNul {
+        /// Byte offset of the zero byte in the input string.
+        position: usize,
+    },
+    /// The `OsStr` is not valid unicode, so the characters it contains are not known.
+    NotUnicode,
+}
+
+impl fmt::Display for Latin1Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Latin1Error::Unencodable {
+                character,
+                position,
+            } => write!(
+                f,
+                "character {:?} at position {} can not be encoded as ISO 8859-1",
+                character, position
+            ),
+            Latin1Error::InteriorNul { position } => {
+                write!(f, "unexpected zero byte at position {}", position)
+            }
+            Latin1Error::NotUnicode => write!(f, "string is not valid unicode"),
+        }
+    }
+}
+
+impl Error for Latin1Error {}
+
+/// Encode a string as ISO 8859-1 (LATIN-1), as required for the gzip filename and comment
+/// fields.
+///
+/// Returns an error if the string contains characters that can not be represented, or a zero
+/// byte.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::encode_latin1;
+///
+/// assert_eq!(encode_latin1("café.txt").unwrap(), b"caf\xe9.txt");
+/// assert!(encode_latin1("日本.txt").is_err());
+/// ```
+pub fn encode_latin1(input: &str) -> Result<Vec<u8>, Latin1Error> {
+    let mut output = Vec::with_capacity(input.len());
+    for (position, character) in input.char_indices() {
+        match character as u32 {
+            0 => return Err(Latin1Error::InteriorNul { position }),
+            c @ 1..=0xFF => output.push(c as u8),
+            _ => {
+                return Err(Latin1Error::Unencodable {
+                    character,
+                    position,
+                })
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// Encode a string as ISO 8859-1 (LATIN-1), replacing characters that can not be represented
+/// (and zero bytes) with `?`.
+pub fn encode_latin1_lossy(input: &str) -> Vec<u8> {
+    input
+        .chars()
+        .map(|character| match character as u32 {
+            c @ 1..=0xFF => c as u8,
+            _ => REPLACEMENT_CHARACTER,
+        })
+        .collect()
+}
+
+/// Encode an `OsStr`, (e.g a file name) as ISO 8859-1 (LATIN-1).
+///
+/// Returns an error if the string is not valid unicode, or if it can not be encoded.
+pub fn encode_latin1_os(input: &OsStr) -> Result<Vec<u8>, Latin1Error> {
+    input
+        .to_str()
+        .ok_or(Latin1Error::NotUnicode)
+        .and_then(encode_latin1)
+}
+
+/// Encode an `OsStr`, (e.g a file name) as ISO 8859-1 (LATIN-1), replacing characters that can
+/// not be represented with `?`.
+pub fn encode_latin1_os_lossy(input: &OsStr) -> Vec<u8> {
+    encode_latin1_lossy(&input.to_string_lossy())
+}
+
+#[cfg(feature = "gzip")]
+mod gz_builder {
+    use super::*;
+    use gzip_header::GzBuilder;
+
+    /// Extension trait for `GzBuilder` to set the filename and comment fields from rust strings,
+    /// making sure they are encoded as required by the gzip specification.
+    pub trait GzBuilderLatin1Ext: Sized {
+        /// Set the filename field, encoding it as ISO 8859-1.
+        fn latin1_filename(self, filename: &str) -> Result<Self, Latin1Error>;
+        /// Set the filename field from an `OsStr` (e.g from a `Path`), encoding it as ISO 8859-1.
+        fn latin1_os_filename(self, filename: &OsStr) -> Result<Self, Latin1Error>;
+        /// Set the comment field, encoding it as ISO 8859-1.
+        fn latin1_comment(self, comment: &str) -> Result<Self, Latin1Error>;
+    }
+
+    impl GzBuilderLatin1Ext for GzBuilder {
+        fn latin1_filename(self, filename: &str) -> Result<GzBuilder, Latin1Error> {
+            Ok(self.filename(encode_latin1(filename)?))
+        }
+
+        fn latin1_os_filename(self, filename: &OsStr) -> Result<GzBuilder, Latin1Error> {
+            Ok(self.filename(encode_latin1_os(filename)?))
+        }
+
+        fn latin1_comment(self, comment: &str) -> Result<GzBuilder, Latin1Error> {
+            Ok(self.comment(encode_latin1(comment)?))
+        }
+    }
+}
+
+#[cfg(feature = "gzip")]
+pub use self::gz_builder::GzBuilderLatin1Ext;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latin1_encode() {
+        assert_eq!(encode_latin1("test.txt").unwrap(), b"test.txt");
+        assert_eq!(encode_latin1("Ærøskøbing").unwrap(), b"\xc6r\xf8sk\xf8bing");
+        assert_eq!(
+            encode_latin1("a€"),
+            Err(Latin1Error::Unencodable {
+                character: '€',
+                position: 1
+            })
+        );
+        assert_eq!(
+            encode_latin1("ab\0"),
+            Err(Latin1Error::InteriorNul { position: 2 })
+        );
+    }
+
+    #[test]
+    fn latin1_encode_lossy() {
+        assert_eq!(encode_latin1_lossy("ø€\0"), b"\xf8??");
+        assert_eq!(encode_latin1_os_lossy(OsStr::new("file_ø")), b"file_\xf8");
+        assert_eq!(encode_latin1_os(OsStr::new("a.gz")).unwrap(), b"a.gz");
+    }
+}
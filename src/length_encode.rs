@@ -1,6 +1,10 @@
 use std::clone::Clone;
 use std::iter::Iterator;
 
+#[cfg(test)]
+use crate::allocator::StateAllocator;
+use crate::allocator::StateVec;
+
 /// An enum representing the different types in the run-length encoded data used to encode
 /// huffman table lengths
 #[derive(Debug, PartialEq, Eq)]
@@ -40,7 +44,7 @@ const MIN_REPEAT: u8 = 3;
 /// Push an `EncodedLength` to the vector and update the frequency table.
 fn update_out_and_freq(
     encoded: EncodedLength,
-    output: &mut Vec<EncodedLength>,
+    output: &mut StateVec<EncodedLength>,
     frequencies: &mut [u16; 19],
 ) {
     let index = match encoded {
@@ -62,12 +66,12 @@ fn not_max_repetitions(length_value: u8, repeats: u8) -> bool {
 
 ///Convenience version for unit tests.
 #[cfg(test)]
-pub fn encode_lengths<'a, I>(lengths: I) -> (Vec<EncodedLength>, [u16; 19])
+pub fn encode_lengths<'a, I>(lengths: I) -> (StateVec<EncodedLength>, [u16; 19])
 where
     I: Iterator<Item = &'a u8> + Clone,
 {
     let mut freqs = [0u16; 19];
-    let mut encoded: Vec<EncodedLength> = Vec::new();
+    let mut encoded = StateAllocator::global().vec_with_capacity(0);
     encode_lengths_m(lengths, &mut encoded, &mut freqs);
     (encoded, freqs)
 }
@@ -82,7 +86,7 @@ where
 /// excessive memcpying.
 pub fn encode_lengths_m<'a, I>(
     lengths: I,
-    mut out: &mut Vec<EncodedLength>,
+    mut out: &mut StateVec<EncodedLength>,
     mut frequencies: &mut [u16; 19],
 ) where
     I: Iterator<Item = &'a u8> + Clone,
@@ -160,7 +164,9 @@ pub fn huffman_lengths_from_frequency(frequencies: &[u16], max_len: usize) -> Ve
     in_place::gen_lengths(frequencies, max_len)
 }
 
-pub type LeafVec = Vec<in_place::Node>;
+pub use self::in_place::Node;
+
+pub type LeafVec = StateVec<Node>;
 
 /// Generate a set of canonical huffman lengths from the given frequencies, with a maximum length
 /// of `max_len`. The lengths are put in the lens slice parameter. Unused lengths are set to 0.
@@ -177,6 +183,10 @@ pub fn huffman_lengths_from_frequency_m(
 }
 
 mod in_place {
+    #[cfg(test)]
+    use crate::allocator::StateAllocator;
+    use crate::huffman_table::MAX_CODE_LENGTH;
+
     type WeightType = u32;
 
     pub fn validate_lengths(lengths: &[u8]) -> bool {
@@ -272,54 +282,75 @@ mod in_place {
         }
     }
 
-    const MAX_NUMBER_OF_CODES: usize = 32;
-    const NUM_CODES_LENGTH: usize = MAX_NUMBER_OF_CODES + 1;
+    /// The number of symbols in the largest alphabet, including the two literal/length codes
+    /// that are never used.
+    const MAX_LEAVES: usize = 288;
+    /// The maximum number of items in a list in `package_merge`.
+    const MAX_ITEMS: usize = 2 * MAX_LEAVES;
 
-    /// Checks if any of the lengths exceed `max_len`, and if that is the case, alters the length
-    /// table so that no codes exceed `max_len`.
-    /// This is ported from miniz (which is released as public domain by Rich Geldreich
-    /// https://github.com/richgel999/miniz/blob/master/miniz.c)
+    /// Generate optimal length-limited huffman code lengths using the package-merge algorithm
+    /// described by Larmore and Hirschberg in A Fast Algorithm for Optimal Length-Limited
+    /// Huffman Codes.
     ///
-    /// This will not generate optimal (minimim-redundancy) codes, however in most cases
-    /// this won't make a large difference.
-    pub fn enforce_max_code_lengths(
-        num_codes: &mut [u16; NUM_CODES_LENGTH],
-        num_used: usize,
-        max_len: usize,
-    ) {
-        debug_assert!(max_len <= 15);
+    /// `leaves` has to be sorted by ascending frequency, and the resulting lengths are added
+    /// to the zero-initialized `lengths`.
+    fn package_merge(frequencies: &[u16], leaves: &[Node], max_len: usize, lengths: &mut [u8]) {
+        let n = leaves.len();
+        debug_assert!((2..=MAX_LEAVES).contains(&n));
+        debug_assert!(max_len <= MAX_CODE_LENGTH && n <= 1 << max_len);
 
-        if num_used <= 1 {
-            return;
-        } else {
-            let mut num_above_max = 0u16;
-            for &l in num_codes[(max_len as usize + 1)..].iter() {
-                num_above_max += l;
-            }
+        let weight = |leaf: usize| WeightType::from(frequencies[leaves[leaf].symbol as usize]);
 
-            num_codes[max_len] += num_above_max;
+        // Whether each item of the list of each level is a leaf, rather than a package of two
+        // items of the level below. Level 0 is the deepest one.
+        let mut is_leaf = [[false; MAX_ITEMS]; MAX_CODE_LENGTH];
+        let mut weights = [0; MAX_ITEMS];
+        let mut merged = [0; MAX_ITEMS];
 
-            let mut total = 0u32;
-            for i in (1..=max_len).rev() {
-                // This should be safe as max_len won't be higher than 15, and num_codes[i] can't
-                // be higher than 288,
-                // and 288 << 15 will not be anywhere close to overflowing 32 bits
-                total += (u32::from(num_codes[i])) << (max_len - i);
+        // The deepest level only consists of the leaves.
+        for (leaf, w) in weights[..n].iter_mut().enumerate() {
+            *w = weight(leaf);
+        }
+        is_leaf[0][..n].iter_mut().for_each(|l| *l = true);
+        let mut num_items = n;
+
+        for level_is_leaf in is_leaf[1..max_len].iter_mut() {
+            // Pair up the items of the level below into packages, and merge them with the leaves.
+            let num_packages = num_items / 2;
+            let mut leaf = 0;
+            let mut package = 0;
+            let mut num_merged = 0;
+            while leaf < n || package < num_packages {
+                let package_weight = if package < num_packages {
+                    weights[2 * package] + weights[2 * package + 1]
+                } else {
+                    WeightType::MAX
+                };
+                if leaf < n && weight(leaf) <= package_weight {
+                    merged[num_merged] = weight(leaf);
+                    level_is_leaf[num_merged] = true;
+                    leaf += 1;
+                } else {
+                    merged[num_merged] = package_weight;
+                    package += 1;
+                }
+                num_merged += 1;
             }
+            weights[..num_merged].copy_from_slice(&merged[..num_merged]);
+            num_items = num_merged;
+        }
 
-            // miniz uses unsigned long here. 32-bits should be sufficient though,
-            // as max_len won't be longer than 15 anyhow.
-            while total != 1u32 << max_len {
-                num_codes[max_len] -= 1;
-                for i in (1..max_len).rev() {
-                    if num_codes[i] != 0 {
-                        num_codes[i] -= 1;
-                        num_codes[i + 1] += 2;
-                        break;
-                    }
-                }
-                total -= 1;
+        // The code is given by the 2n - 2 cheapest items of the top level. Every leaf among the
+        // selected items of a level adds one to the length of its symbol, and every package
+        // selects the two items it was made from in the level below.
+        let mut num_selected = 2 * n - 2;
+        for level_is_leaf in is_leaf[..max_len].iter().rev() {
+            let num_leaves = level_is_leaf[..num_selected].iter().filter(|&&l| l).count();
+            // The leaves are merged in order, so the selected ones are the first ones.
+            for leaf in &leaves[..num_leaves] {
+                lengths[leaf.symbol as usize] += 1;
             }
+            num_selected = 2 * (num_selected - num_leaves);
         }
     }
 
@@ -327,7 +358,7 @@ mod in_place {
     /// Convenience wrapper for tests.
     pub fn gen_lengths(frequencies: &[u16], max_len: usize) -> Vec<u8> {
         let mut lens = vec![0u8; frequencies.len()];
-        let mut leaves = Vec::new();
+        let mut leaves = StateAllocator::global().vec_with_capacity(0);
         in_place_lengths(frequencies, max_len, &mut leaves, lens.as_mut_slice());
         lens
     }
@@ -340,11 +371,11 @@ mod in_place {
     /// This is significantly faster, and seems to generally create lengths that result in length
     /// tables that are better compressible than the algorithm used previously. The downside of this
     /// algorithm is that it's not length-limited, so if too long code lengths are generated,
-    /// it might result in a sub-optimal tables as the length-restricting function isn't optimal.
+    /// the lengths are generated again with the slower package-merge algorithm instead.
     pub fn in_place_lengths(
         frequencies: &[u16],
         max_len: usize,
-        mut leaves: &mut Vec<Node>,
+        mut leaves: &mut super::LeafVec,
         lengths: &mut [u8],
     ) {
         debug_assert!(lengths.len() >= frequencies.len());
@@ -385,26 +416,18 @@ mod in_place {
         step_1(&mut leaves);
         step_2(&mut leaves);
 
-        // Count how many codes of each length used, for usage in the next section.
-        let mut num_codes = [0u16; NUM_CODES_LENGTH];
-        for l in leaves.iter() {
-            num_codes[l.value as usize] += 1;
-        }
-
-        // As the algorithm used here doesn't limit the maximum length that can be generated
-        // we need to make sure none of the lengths exceed `max_len`
-        enforce_max_code_lengths(&mut num_codes, leaves.len(), max_len);
-
-        // Output the actual lengths
-        let mut leaf_it = leaves.iter().rev();
-        // Start at 1 since the length table is already filled with zeroes.
-        for (&n_codes, i) in num_codes[1..=max_len].iter().zip(1..=(max_len as u8)) {
-            for _ in 0..n_codes {
-                lengths[leaf_it.next().unwrap().symbol as usize] = i;
+        // The algorithm used here doesn't limit the maximum length that can be generated, so if
+        // the longest code, which is the one of the least frequent symbol, exceeds `max_len`, we
+        // instead use package-merge to find the optimal lengths within the limit.
+        if leaves[0].value as usize > max_len {
+            // The leaves are still in the same order, so we can look up their frequencies again.
+            package_merge(frequencies, leaves, max_len, lengths);
+        } else {
+            for l in leaves.iter() {
+                lengths[l.symbol as usize] = l.value as u8;
             }
         }
 
-        debug_assert_eq!(leaf_it.next(), None);
         debug_assert!(
             validate_lengths(lengths),
             "The generated length codes were not valid!"
@@ -610,6 +633,49 @@ mod test {
         assert!(res[125] < 3);
     }
 
+    /// Find the lowest number of bits any valid set of lengths limited to `max_len` would use to
+    /// encode `frequencies`, by trying all of them.
+    fn brute_force_cost(frequencies: &[u16], max_len: u8) -> u32 {
+        fn search(frequencies: &[u16], max_len: u8, kraft: u32) -> Option<u32> {
+            match frequencies.split_first() {
+                None => Some(0),
+                Some((&f, rest)) => (1..=max_len)
+                    .filter_map(|l| {
+                        let kraft = kraft + (1 << (max_len - l));
+                        if kraft > 1 << max_len {
+                            return None;
+                        }
+                        search(rest, max_len, kraft).map(|c| c + u32::from(f) * u32::from(l))
+                    })
+                    .min(),
+            }
+        }
+        search(frequencies, max_len, 0).unwrap()
+    }
+
+    /// Test that the lengths are optimal when they have to be limited to fewer bits than an
+    /// unrestricted huffman code would use.
+    #[test]
+    fn length_limited_optimal() {
+        let tests: [(&[u16], u8); 4] = [
+            (&[1, 1, 2, 3, 5, 8, 13, 21], 4),
+            (&[1, 1, 5, 7, 10, 14], 3),
+            (&[2, 1, 1, 300, 4, 1, 90, 1, 9], 4),
+            (&[1, 2, 4, 8, 16, 32, 64, 128], 5),
+        ];
+        for &(frequencies, max_len) in tests.iter() {
+            let lens = huffman_lengths_from_frequency(frequencies, max_len.into());
+            assert!(lens.iter().all(|l| (1..=max_len).contains(l)));
+            assert!(in_place::validate_lengths(&lens));
+            let cost = lens
+                .iter()
+                .zip(frequencies.iter())
+                .map(|(&l, &f)| u32::from(l) * u32::from(f))
+                .sum::<u32>();
+            assert_eq!(cost, brute_force_cost(frequencies, max_len));
+        }
+    }
+
     #[test]
     /// Test if the bit lengths for a set of frequencies are optimal (give the best compression
     /// give the provided frequencies).
@@ -19,8 +19,28 @@
 //! Support for the gzip wrapper (the wrapper that is used in `.gz` files) is disabled by default,
 //! but can be enabled with the `gzip` feature.
 //!
-//! As this library is still in development, the compression output may change slightly
-//! between versions.
+//! The `tokio` feature adds encoders implementing tokio's `AsyncWrite` trait in the
+//! [`async_write`](async_write/index.html) module, for use in async code without having to
+//! compress on a blocking task.
+//!
+//! The `allocator` feature, which requires a nightly compiler, allows the internal buffers of the
+//! encoders to be allocated with a custom allocator using the `new_in` constructors.
+//!
+//! # Deterministic output
+//!
+//! The compressed output only depends on the input, the compression options and, for the
+//! encoders, the sequence of calls made to them (the sizes of the writes, flushes and so on). It
+//! does not depend on the platform, and for the multi-threaded functions not on how the threads
+//! are scheduled, so it can be used where the output has to be reproducible, e.g for content
+//! addressed storage.
+//!
+//! The one exception is the OS field of a gzip header, which `gzip-header` sets from the platform
+//! unless it's set explicitly with `GzBuilder::os`.
+//!
+//! The output for a given input and options is the same across patch versions of the crate, and
+//! only changes in new minor versions. This is enforced by comparing the output with compressed
+//! fixtures in the tests. The fixtures are only regenerated in a change that bumps the minor
+//! version, and every change to the output is listed in `CHANGELOG.md`.
 //!
 //!
 //! # Examples:
@@ -34,6 +54,10 @@
 //! ```
 //!
 //! ## Using a writer:
+//! The [`write`](write/index.html) module has encoders for raw DEFLATE, zlib and gzip data that
+//! implement `Write`, compressing the data written to them incrementally and passing the
+//! compressed blocks on to the wrapped writer.
+//!
 //! ``` rust
 //! use std::io::Write;
 //!
@@ -48,6 +72,7 @@
 //! ```
 
 #![cfg_attr(all(feature = "benchmarks", test), feature(test))]
+#![cfg_attr(feature = "allocator", feature(allocator_api))]
 
 #[cfg(all(test, feature = "benchmarks"))]
 extern crate test as test_std;
@@ -59,69 +84,260 @@ extern crate adler32;
 extern crate byteorder;
 #[cfg(feature = "gzip")]
 extern crate gzip_header;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 
+mod allocator;
+#[cfg(feature = "tokio")]
+mod async_writer;
+mod binary_tree;
 mod bit_reverse;
 mod bitstream;
+mod block_codes;
+pub mod boundary;
+#[cfg(feature = "capi")]
+pub mod capi;
 mod chained_hash_table;
 mod checksum;
+mod code_lengths;
 mod compress;
 mod compression_options;
+pub mod consts;
+mod deflate64;
 mod deflate_state;
 mod encoder_state;
+mod error;
+#[cfg(feature = "gzip")]
+mod file;
+pub mod flate2_compat;
+mod fragment;
+pub mod framing;
+#[cfg(feature = "gzip")]
+mod gz_header;
+mod heapless;
 mod huffman_lengths;
 mod huffman_table;
+mod in_place;
+mod inflate;
 mod input_buffer;
+mod latin1;
 mod length_encode;
 mod lz77;
 mod lzvalue;
+mod mapped;
+mod matcher;
 mod matching;
+mod optimal;
 mod output_writer;
+mod parallel;
+mod pipeline;
+mod prefix;
+mod priors;
+mod reader;
+mod recompress;
+mod repeats;
 mod rle;
+mod size_only;
+mod slice;
+mod stats;
 mod stored_block;
 #[cfg(test)]
 mod test_utils;
+mod tokens;
+mod websocket;
 mod writer;
 mod zlib;
 
-use std::io;
-use std::io::Write;
+use std::cmp;
+use std::mem;
 
 use byteorder::BigEndian;
 #[cfg(feature = "gzip")]
 use byteorder::LittleEndian;
 #[cfg(feature = "gzip")]
-use gzip_header::Crc;
-#[cfg(feature = "gzip")]
 use gzip_header::GzBuilder;
 
 use crate::checksum::RollingChecksum;
 use crate::deflate_state::DeflateState;
 
 use crate::compress::Flush;
+#[cfg(feature = "allocator")]
+pub use allocator::StateAllocator;
+pub use block_codes::{BlockCodes, BlockInfo, BlockKind};
+pub use chained_hash_table::HashFunction;
+pub use checksum::{crc32, Crc32};
+pub use code_lengths::CodeLengths;
 pub use compression_options::{Compression, CompressionOptions, SpecialOptions};
+pub use deflate64::{deflate64_bytes, deflate64_bytes_conf};
+pub use deflate_state::MemoryUsage;
+pub use error::Error;
+#[cfg(feature = "gzip")]
+pub use file::{
+    compress_file, compress_file_buffered, DEFAULT_READ_BUFFER_SIZE, DEFAULT_WRITE_BUFFER_SIZE,
+};
+pub use fragment::{Fragment, FragmentStream};
+#[cfg(feature = "gzip")]
+pub use gz_header::GzHeaderBuilder;
+pub use heapless::{DeflateBuffers, HeaplessCompressor, OutputFull};
+pub use in_place::compress_in_place;
+pub use inflate::{
+    inflate_bytes, inflate_bytes_deflate64, inflate_bytes_gzip, inflate_bytes_with_dictionary,
+    inflate_bytes_zlib, inflate_bytes_zlib_with_dictionary, InflateError,
+};
+#[cfg(feature = "gzip")]
+pub use latin1::GzBuilderLatin1Ext;
+pub use latin1::{
+    encode_latin1, encode_latin1_lossy, encode_latin1_os, encode_latin1_os_lossy, Latin1Error,
+};
 pub use lz77::MatchingType;
+pub use mapped::deflate_mapped;
+pub use matcher::MatcherType;
+pub use parallel::deflate_parallel;
+pub use pipeline::deflate_pipelined;
+pub use prefix::PrefixCache;
+pub use priors::{FrequencyPriors, DEFAULT_PRIOR_WEIGHT};
+pub use recompress::{deflate_bytes_with_map, recompress, BlockBoundary, BlockMap};
+pub use size_only::SizeOnlyCompressor;
+pub use slice::compress_to_slice;
+pub use stats::Stats;
+pub use tokens::{lz77_tokens, parse_tokens, write_token_listing, Token};
+pub use websocket::{MessageCompressor, MessageDecompressor};
 
 use crate::writer::compress_until_done;
 
 /// Encoders implementing a `Write` interface.
+///
+/// Mutable references to the encoders also implement `Write`, so an encoder can be passed to
+/// functions taking a writer by value (e.g `impl Write`) while the caller keeps ownership and
+/// can call `finish()` afterwards:
+///
+/// ```
+/// use std::io::Write;
+///
+/// use deflate::Compression;
+/// use deflate::write::DeflateEncoder;
+///
+/// fn write_greeting<W: Write>(mut writer: W) -> std::io::Result<()> {
+///     write!(writer, "Hello, {}!", "world")
+/// }
+///
+/// let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+/// write_greeting(&mut encoder).unwrap();
+/// let compressed_data = encoder.finish().unwrap();
+/// # let _ = compressed_data;
+/// ```
 pub mod write {
     #[cfg(feature = "gzip")]
     pub use crate::writer::gzip::GzEncoder;
-    pub use crate::writer::{DeflateEncoder, ZlibEncoder};
+    pub use crate::writer::{
+        DeflateEncoder, Encoder, Format, ZipEncoder, ZipEntryInfo, ZlibEncoder,
+    };
 }
 
-fn compress_data_dynamic<RC: RollingChecksum, W: Write>(
+/// Encoders implementing the `AsyncWrite` trait from tokio, enabled by the `tokio` feature.
+#[cfg(feature = "tokio")]
+pub mod async_write {
+    #[cfg(feature = "gzip")]
+    pub use crate::async_writer::AsyncGzEncoder;
+    pub use crate::async_writer::{AsyncDeflateEncoder, AsyncEncoder, AsyncZlibEncoder};
+}
+
+/// Compressors implementing a `Read` interface.
+pub mod read {
+    pub use crate::reader::DeflateReader;
+}
+
+/// Compress `input`, appending the compressed data to `output`.
+///
+/// The data is written directly to the vector rather than going through the `Write` trait.
+fn compress_data_dynamic<RC: RollingChecksum>(
     input: &[u8],
-    writer: &mut W,
+    output: Vec<u8>,
     mut checksum: RC,
     compression_options: CompressionOptions,
-) -> io::Result<()> {
+) -> Vec<u8> {
     checksum.update_from_slice(input);
     // We use a box here to avoid putting the buffers on the stack
     // It's done here rather than in the structs themselves for now to
     // keep the data close in memory.
-    let mut deflate_state = Box::new(DeflateState::new(compression_options, writer));
-    compress_until_done(input, &mut deflate_state, Flush::Finish)
+    let mut deflate_state = Box::new(DeflateState::with_direct_output(
+        compression_options,
+        output,
+    ));
+    // The output is never written to the sink, so this can't fail.
+    compress_until_done(input, &mut deflate_state, Flush::Finish).expect("Write error!");
+    deflate_state.take_direct_output()
+}
+
+/// Inputs up to this size get an output buffer large enough to hold the worst case output, so the
+/// output never has to be reallocated.
+const SMALL_INPUT_LIMIT: usize = 1 << 16;
+
+/// Returns an upper bound on the size of raw DEFLATE data compressed from `input_len` bytes of
+/// input, with any compression options that use the default memory level and block length.
+///
+/// Use `deflate_bound_conf` for options with a lower memory level or a shorter block length, as
+/// the smaller blocks can make incompressible data take more space.
+///
+/// This is the same bound as the one used by zlib's `deflateBound`. It does not include headers and
+/// trailers, so 6 bytes have to be added for zlib, and 18 bytes plus the length of any extra
+/// header fields for gzip.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{compress_to_slice, deflate_bound, Compression};
+///
+/// let data = b"This is some test data";
+/// let mut output = vec![0; deflate_bound(data.len())];
+/// let length = compress_to_slice(data, &mut output, Compression::Default).unwrap();
+/// let compressed_data = &output[..length];
+/// # let _ = compressed_data;
+/// ```
+pub fn deflate_bound(input_len: usize) -> usize {
+    input_len + (input_len >> 12) + (input_len >> 14) + (input_len >> 25) + 13
+}
+
+/// Returns an upper bound on the size of raw DEFLATE data compressed from `input_len` bytes of
+/// input with the given compression options.
+///
+/// This is the same as `deflate_bound` unless the options use a lower memory level or a shorter
+/// block length, in which case every block can end up as a stored block with 5 bytes of overhead.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{compress_to_slice, deflate_bound_conf, CompressionOptions};
+///
+/// let data = b"This is some test data";
+/// let options = CompressionOptions {
+///     mem_level: 1,
+///     ..CompressionOptions::default()
+/// };
+/// let mut output = vec![0; deflate_bound_conf(data.len(), options)];
+/// let length = compress_to_slice(data, &mut output, options).unwrap();
+/// let compressed_data = &output[..length];
+/// # let _ = compressed_data;
+/// ```
+pub fn deflate_bound_conf<O: Into<CompressionOptions>>(input_len: usize, options: O) -> usize {
+    let blocks = input_len / deflate_state::symbol_buffer_length(&options.into()) + 1;
+    cmp::max(deflate_bound(input_len), input_len + blocks * 5 + 8)
+}
+
+/// Calculate the initial capacity to use for the output of the one-shot compression functions.
+///
+/// `wrapper_len` is the length of the header and trailer, and `expected_ratio` the expected size
+/// of the compressed data relative to the input, if known.
+fn output_capacity(input_len: usize, wrapper_len: usize, expected_ratio: Option<f32>) -> usize {
+    let bound = deflate_bound(input_len);
+    let expected = match expected_ratio {
+        Some(ratio) if ratio.is_finite() && ratio >= 0.0 => {
+            (input_len as f64 * f64::from(ratio)).ceil() as usize
+        }
+        _ if input_len <= SMALL_INPUT_LIMIT => bound,
+        _ => input_len / 3,
+    };
+    cmp::min(expected, bound) + wrapper_len
 }
 
 /// Compress the given slice of bytes with DEFLATE compression.
@@ -138,15 +354,12 @@ fn compress_data_dynamic<RC: RollingChecksum, W: Write>(
 /// # let _ = compressed_data;
 /// ```
 pub fn deflate_bytes_conf<O: Into<CompressionOptions>>(input: &[u8], options: O) -> Vec<u8> {
-    let mut writer = Vec::with_capacity(input.len() / 3);
     compress_data_dynamic(
         input,
-        &mut writer,
+        Vec::with_capacity(output_capacity(input.len(), 0, None)),
         checksum::NoChecksum::new(),
         options.into(),
     )
-    .expect("Write error!");
-    writer
 }
 
 /// Compress the given slice of bytes with DEFLATE compression using the default compression
@@ -167,6 +380,31 @@ pub fn deflate_bytes(input: &[u8]) -> Vec<u8> {
     deflate_bytes_conf(input, Compression::Default)
 }
 
+/// Compress the given slice of bytes with DEFLATE compression, appending the compressed data to
+/// `output`.
+///
+/// The existing capacity of `output` is used, and it is only grown if the compressed data doesn't
+/// fit in it, so reusing the same vector for many inputs avoids allocating a new output buffer for
+/// each of them. The appended data is identical to the output of `deflate_bytes_conf`.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{compress_into, Compression};
+///
+/// let mut output = Vec::with_capacity(1024);
+/// for data in &[&b"This is some test data"[..], b"And some more"] {
+///     output.clear();
+///     compress_into(data, &mut output, Compression::Default);
+///     # let _ = &output;
+/// }
+/// ```
+pub fn compress_into<O: Into<CompressionOptions>>(input: &[u8], output: &mut Vec<u8>, options: O) {
+    let mut buffer = mem::take(output);
+    buffer.reserve(output_capacity(input.len(), 0, None));
+    *output = compress_data_dynamic(input, buffer, checksum::NoChecksum::new(), options.into());
+}
+
 /// Compress the given slice of bytes with DEFLATE compression, including a zlib header and trailer.
 ///
 /// Returns a `Vec<u8>` of the compressed data.
@@ -184,14 +422,22 @@ pub fn deflate_bytes(input: &[u8]) -> Vec<u8> {
 /// ```
 pub fn deflate_bytes_zlib_conf<O: Into<CompressionOptions>>(input: &[u8], options: O) -> Vec<u8> {
     use byteorder::WriteBytesExt;
-    let mut writer = Vec::with_capacity(input.len() / 3);
+    let mut writer = Vec::with_capacity(output_capacity(
+        input.len(),
+        consts::ZLIB_HEADER_LENGTH + consts::ZLIB_TRAILER_LENGTH,
+        None,
+    ));
+    let options = options.into();
     // Write header
-    zlib::write_zlib_header(&mut writer, zlib::CompressionLevel::Default)
-        .expect("Write error when writing zlib header!");
+    zlib::write_zlib_header(
+        &mut writer,
+        zlib::CompressionLevel::Default,
+        compression_options::clamp_window_bits(options.window_bits),
+    )
+    .expect("Write error when writing zlib header!");
 
     let mut checksum = checksum::Adler32Checksum::new();
-    compress_data_dynamic(input, &mut writer, &mut checksum, options.into())
-        .expect("Write error when writing compressed data!");
+    let mut writer = compress_data_dynamic(input, writer, &mut checksum, options);
 
     let hash = checksum.current_hash();
 
@@ -243,30 +489,79 @@ pub fn deflate_bytes_zlib(input: &[u8]) -> Vec<u8> {
 /// # }
 /// ```
 #[cfg(feature = "gzip")]
-pub fn deflate_bytes_gzip_conf<O: Into<CompressionOptions>>(
+pub fn deflate_bytes_gzip_conf<O: Into<CompressionOptions>, H: Into<GzHeaderBuilder>>(
+    input: &[u8],
+    options: O,
+    gzip_header: H,
+) -> Vec<u8> {
+    deflate_bytes_gzip_sized(input, options, gzip_header, None)
+}
+
+/// Compress the given slice of bytes with DEFLATE compression, including a gzip header and trailer
+/// using the given gzip header and compression options, using `expected_ratio` to size the output.
+///
+/// `expected_ratio` is the expected size of the compressed data relative to the size of the input,
+/// e.g `0.25` if the data is expected to compress to a quarter of its size. The output buffer is
+/// allocated up front based on this value, which avoids repeatedly reallocating and copying the
+/// output when compressing large amounts of data to memory. The ratio only affects the allocation,
+/// not the compressed data.
+///
+/// Returns a `Vec<u8>` of the compressed data.
+///
+/// # Examples
+///
+/// ```
+/// extern crate gzip_header;
+/// extern crate deflate;
+///
+/// # fn main() {
+/// use deflate::{deflate_bytes_gzip_hint, Compression};
+/// use gzip_header::GzBuilder;
+///
+/// let data = b"This is some test data";
+/// let compressed_data =
+///     deflate_bytes_gzip_hint(data, Compression::Best, GzBuilder::new(), 0.5);
+/// # let _ = compressed_data;
+/// # }
+/// ```
+#[cfg(feature = "gzip")]
+pub fn deflate_bytes_gzip_hint<O: Into<CompressionOptions>, H: Into<GzHeaderBuilder>>(
+    input: &[u8],
+    options: O,
+    gzip_header: H,
+    expected_ratio: f32,
+) -> Vec<u8> {
+    deflate_bytes_gzip_sized(input, options, gzip_header, Some(expected_ratio))
+}
+
+#[cfg(feature = "gzip")]
+fn deflate_bytes_gzip_sized<O: Into<CompressionOptions>, H: Into<GzHeaderBuilder>>(
     input: &[u8],
     options: O,
-    gzip_header: GzBuilder,
+    gzip_header: H,
+    expected_ratio: Option<f32>,
 ) -> Vec<u8> {
     use byteorder::WriteBytesExt;
-    let mut writer = Vec::with_capacity(input.len() / 3);
+    let header = gzip_header.into().into_header();
+    let mut writer = Vec::with_capacity(output_capacity(
+        input.len(),
+        header.len() + consts::GZIP_TRAILER_LENGTH,
+        expected_ratio,
+    ));
 
     // Write header
-    writer
-        .write_all(&gzip_header.into_header())
-        .expect("Write error when writing header!");
+    writer.extend_from_slice(&header);
     let mut checksum = checksum::NoChecksum::new();
-    compress_data_dynamic(input, &mut writer, &mut checksum, options.into())
-        .expect("Write error when writing compressed data!");
+    let mut writer = compress_data_dynamic(input, writer, &mut checksum, options.into());
 
-    let mut crc = Crc::new();
+    let mut crc = Crc32::new();
     crc.update(input);
 
     writer
         .write_u32::<LittleEndian>(crc.sum())
         .expect("Write error when writing checksum!");
     writer
-        .write_u32::<LittleEndian>(crc.amt_as_u32())
+        .write_u32::<LittleEndian>(crc.amount() as u32)
         .expect("Write error when writing amt!");
     writer
 }
@@ -297,7 +592,10 @@ mod test {
 
     #[cfg(feature = "gzip")]
     use test_utils::decompress_gzip;
-    use test_utils::{decompress_to_end, decompress_zlib, get_test_data};
+    use test_utils::{
+        decompress_to_end, decompress_with_dictionary, decompress_zlib, get_random_data,
+        get_test_data,
+    };
 
     type CO = CompressionOptions;
 
@@ -351,6 +649,463 @@ mod test {
         assert!(input == result);
     }
 
+    #[test]
+    fn file_custom_hash() {
+        fn hash(current_hash: u16, to_insert: u8) -> u16 {
+            (current_hash << 7) ^ (u16::from(to_insert).wrapping_mul(0x9E37))
+        }
+        let input = get_test_data();
+        let options = CO {
+            hash_function: Some(hash),
+            ..CO::default()
+        };
+        let compressed = deflate_bytes_conf(&input, options);
+
+        let result = decompress_to_end(&compressed);
+        assert!(input == result);
+        assert!(compressed.len() < input.len());
+    }
+
+    #[test]
+    fn detect_repeats() {
+        // Pages of text, some of which are repeated at various distances.
+        let data = get_test_data();
+        let pages: Vec<&[u8]> = data.chunks(4096).take(6).collect();
+        let mut input = Vec::new();
+        for &n in &[0, 1, 2, 1, 3, 0, 4, 4, 2, 5, 3] {
+            input.extend_from_slice(pages[n]);
+        }
+        input.extend_from_slice(&data[..50_000]);
+
+        for &options in &[CO::default(), CO::fast(), CO::high()] {
+            let with_repeats = deflate_bytes_conf(
+                &input,
+                CO {
+                    detect_repeats: true,
+                    ..options
+                },
+            );
+            assert!(input == decompress_to_end(&with_repeats));
+            assert!(with_repeats.len() <= deflate_bytes_conf(&input, options).len() + 16);
+        }
+    }
+
+    #[test]
+    fn search_lengths() {
+        let data = get_test_data();
+        let options = CO {
+            good_length: 8,
+            nice_length: 16,
+            ..CO::high()
+        };
+        let compressed = deflate_bytes_conf(&data, options);
+        assert!(decompress_to_end(&compressed) == data);
+        assert!(compressed != deflate_bytes_conf(&data, CO::high()));
+    }
+
+    #[test]
+    fn optimal_parsing() {
+        let data = get_test_data();
+        let compressed = deflate_bytes_conf(&data, CO::optimal());
+        assert!(decompress_to_end(&compressed) == data);
+        assert!(compressed.len() < deflate_bytes_conf(&data, CO::high()).len());
+
+        let compressed = deflate_bytes_zlib_conf(&data, CO::optimal());
+        assert!(decompress_zlib(&compressed) == data);
+
+        for data in &[&[][..], &[1][..], &[7, 7, 7, 7, 7, 7, 7, 2][..]] {
+            let compressed = deflate_bytes_conf(data, CO::optimal());
+            assert_eq!(&decompress_to_end(&compressed)[..], *data);
+        }
+    }
+
+    #[test]
+    fn binary_tree_matcher() {
+        let data = get_test_data();
+        let compressed = deflate_bytes_conf(&data, CO::maximum());
+        assert!(decompress_to_end(&compressed) == data);
+
+        let options = CO {
+            matcher: MatcherType::BinaryTree,
+            ..CO::optimal()
+        };
+        roundtrip_zlib(&data, options);
+
+        // Data where the hash chains are long and full of short matches, which the trees find
+        // longer matches in.
+        let random = get_random_data(50_000);
+        let mut redundant = Vec::new();
+        for (i, &r) in random.iter().enumerate() {
+            redundant.extend_from_slice(&[b'a', b'b', (i % 7) as u8, r % 4]);
+        }
+        let compressed = deflate_bytes_conf(&redundant, CO::maximum());
+        assert!(decompress_to_end(&compressed) == redundant);
+        assert!(compressed.len() < deflate_bytes_conf(&redundant, CO::high()).len());
+
+        let mut encoder = write::DeflateEncoder::new(Vec::new(), CO::maximum());
+        encoder.set_dictionary(&data[..1000]).unwrap();
+        for chunk in data.chunks(10_000) {
+            encoder.write_all(chunk).unwrap();
+            encoder.flush().unwrap();
+        }
+        let compressed = encoder.finish().unwrap();
+        assert!(decompress_with_dictionary(&data[..1000], &compressed) == data);
+    }
+
+    #[test]
+    fn binary_tree_flushes() {
+        let data = get_test_data();
+        let options = CO {
+            matcher: MatcherType::BinaryTree,
+            ..CO::high()
+        };
+
+        // The trees need more lookahead than the hash chains, so the last match found before a
+        // flush can end a byte before the end of the input.
+        let input = &data[..15238];
+        let mut encoder = write::DeflateEncoder::new(Vec::new(), options);
+        let mut position = 0;
+        for sizes in &[&[4477, 1348][..], &[454, 2509, 2384], &[2001, 2065]] {
+            for &size in sizes.iter() {
+                encoder
+                    .write_all(&input[position..position + size])
+                    .unwrap();
+                position += size;
+            }
+            encoder.flush().unwrap();
+        }
+        assert!(decompress_to_end(&encoder.finish().unwrap()) == input);
+
+        // Flushes at different points in blocks and windows, including with a full buffer.
+        for window_bits in 9..=15 {
+            for &mem_level in &[1, 3, 8] {
+                let options = CO {
+                    window_bits,
+                    mem_level,
+                    ..CO::maximum()
+                };
+                let input = &data[..60_000];
+                let compressed = deflate_bytes_conf(input, options);
+                assert!(decompress_to_end(&compressed) == input);
+
+                let mut encoder = write::DeflateEncoder::new(Vec::new(), options);
+                for chunk in input.chunks(997 * usize::from(window_bits)) {
+                    encoder.write_all(chunk).unwrap();
+                    encoder.flush().unwrap();
+                }
+                assert!(decompress_to_end(&encoder.finish().unwrap()) == input);
+            }
+        }
+    }
+
+    #[test]
+    fn two_step_lazy() {
+        let data = get_test_data();
+        let options = CO {
+            two_step_lazy: true,
+            ..CO::default()
+        };
+        let compressed = deflate_bytes_conf(&data, options);
+        assert!(decompress_to_end(&compressed) == data);
+        assert!(compressed.len() < deflate_bytes_conf(&data, CO::default()).len());
+
+        // Small symbol buffers fill up often, including after the two literals output before a
+        // match found two bytes ahead.
+        for mem_level in 1..=3 {
+            roundtrip_zlib(
+                &data,
+                CO {
+                    mem_level,
+                    ..options
+                },
+            );
+        }
+
+        let mut encoder = write::DeflateEncoder::new(Vec::new(), options);
+        for chunk in data.chunks(777) {
+            encoder.write_all(chunk).unwrap();
+            encoder.flush().unwrap();
+        }
+        assert!(decompress_to_end(&encoder.finish().unwrap()) == data);
+    }
+
+    #[test]
+    fn block_length() {
+        use std::sync::{Arc, Mutex};
+
+        let data = get_test_data();
+        let block_count = |options: CO| {
+            let blocks = Arc::new(Mutex::new(Vec::new()));
+            let reported = blocks.clone();
+            let mut encoder = write::DeflateEncoder::new(Vec::new(), options);
+            encoder.set_block_callback(move |info| reported.lock().unwrap().push(*info));
+            encoder.write_all(&data).unwrap();
+            let compressed = encoder.finish().unwrap();
+            assert!(decompress_to_end(&compressed) == data);
+            assert_eq!(
+                SizeOnlyCompressor::new(options).compressed_len(&data),
+                compressed.len()
+            );
+            let count = blocks.lock().unwrap().len();
+            count
+        };
+        let with_length = |block_length| CO {
+            block_length: Some(block_length),
+            ..CO::default()
+        };
+        let default = block_count(CO::default());
+        assert!(block_count(with_length(1000)) > default * 10);
+        assert!(block_count(with_length(u16::MAX)) < default);
+        // Out of range values are clamped.
+        assert!(
+            deflate_bytes_conf(&data, with_length(1))
+                == deflate_bytes_conf(&data, with_length(256))
+        );
+
+        // The length can be changed in the middle of the stream.
+        let mut encoder = write::DeflateEncoder::new(Vec::new(), CO::default());
+        encoder.write_all(&data[..50_000]).unwrap();
+        encoder.set_options(with_length(1000)).unwrap();
+        encoder.write_all(&data[50_000..]).unwrap();
+        assert!(decompress_to_end(&encoder.finish().unwrap()) == data);
+    }
+
+    #[test]
+    fn min_match() {
+        let data = get_test_data();
+        let shortest = |options: CO| {
+            lz77_tokens(&data, options)
+                .iter()
+                .filter_map(|t| match *t {
+                    Token::Match { length, .. } => Some(length),
+                    Token::Literal(_) => None,
+                })
+                .min()
+        };
+        assert_eq!(shortest(CO::default()), Some(3));
+        for &min_match in &[4, 5, 8] {
+            let options = CO {
+                min_match,
+                ..CO::default()
+            };
+            assert_eq!(shortest(options), Some(min_match));
+            let compressed = deflate_bytes_conf(&data, options);
+            assert!(decompress_to_end(&compressed) == data);
+        }
+        // The longer of this and the filtered length is used.
+        let filtered = |min_match| CO {
+            min_match,
+            ..CO::filtered()
+        };
+        assert_eq!(shortest(filtered(4)), Some(6));
+        assert_eq!(shortest(filtered(8)), Some(8));
+        // Only matches of the maximum length are used above it, and there are none here.
+        let options = CO {
+            min_match: 1000,
+            ..CO::high()
+        };
+        assert_eq!(shortest(options), None);
+        assert!(decompress_to_end(&deflate_bytes_conf(&data, options)) == data);
+    }
+
+    #[test]
+    fn max_insert_length() {
+        let text = get_test_data();
+        // Many long matches, some of them starting inside earlier ones.
+        let mut data = Vec::new();
+        for i in 0..20_000 {
+            data.extend_from_slice(&text[(i * 7919) % 10_000..][..i % 13 + 20]);
+        }
+        let compressed = deflate_bytes_conf(&data, CO::fastest());
+        assert!(decompress_to_end(&compressed) == data);
+        let fast = deflate_bytes_conf(&data, CO::fast());
+        assert!(compressed != fast);
+        assert!(compressed.len() < fast.len() + fast.len() / 10);
+
+        // Matches ending close to the end of the chunk are added as usual.
+        let mut encoder = write::DeflateEncoder::new(Vec::new(), CO::fastest());
+        for chunk in data.chunks(333) {
+            encoder.write_all(chunk).unwrap();
+            encoder.flush().unwrap();
+        }
+        assert!(decompress_to_end(&encoder.finish().unwrap()) == data);
+    }
+
+    #[test]
+    fn skip_incompressible() {
+        let mut data = get_random_data(500_000);
+        data.extend_from_slice(&get_test_data());
+        let skip = |options| CO {
+            skip_incompressible: true,
+            ..options
+        };
+        let skipping = deflate_bytes_conf(&data, skip(CO::high()));
+        assert!(decompress_to_end(&skipping) == data);
+        let full = deflate_bytes_conf(&data, CO::high());
+        // Little can be gained on random data anyhow.
+        assert!(skipping.len() < full.len() + 100);
+
+        for &options in &[CO::fast(), CO::default(), CO::maximum()] {
+            let options = skip(options);
+            let mut encoder = write::DeflateEncoder::new(Vec::new(), options);
+            for chunk in data.chunks(50_000) {
+                encoder.write_all(chunk).unwrap();
+                encoder.flush().unwrap();
+            }
+            assert!(decompress_to_end(&encoder.finish().unwrap()) == data);
+        }
+    }
+
+    #[test]
+    fn window_bits() {
+        let data = get_test_data();
+        let options = CO {
+            window_bits: 10,
+            ..CO::high()
+        };
+        let compressed = deflate_bytes_zlib_conf(&data, options);
+        // CINFO = 10 - 8 in the high bits of CMF.
+        assert_eq!(compressed[0], 0x28);
+        assert!(decompress_zlib(&compressed) == data);
+        assert!(compressed.len() > deflate_bytes_zlib(&data).len());
+
+        let mut encoder = write::Encoder::new(Vec::new(), write::Format::Zlib, options);
+        encoder.write_all(&data).unwrap();
+        assert!(encoder.finish().unwrap() == compressed);
+
+        // Out of range values are clamped.
+        let options = CO {
+            window_bits: 20,
+            ..CO::default()
+        };
+        assert!(deflate_bytes_zlib_conf(&data, options) == deflate_bytes_zlib(&data));
+    }
+
+    #[test]
+    fn options_for_sample() {
+        let text = get_test_data();
+        let options = CO::for_sample(&text);
+        assert!(options.max_hash_checks > 0 && options.special == SpecialOptions::Normal);
+        assert_eq!(options, CO::for_sample(&text));
+        // Only the start of the sample is compressed.
+        assert_eq!(
+            options,
+            CO::for_sample(&text[..compression_options::SAMPLE_PROBE_LENGTH])
+        );
+
+        let random = get_random_data(50_000);
+        assert_eq!(CO::for_sample(&random), CO::stored());
+
+        let mut runs = Vec::new();
+        for n in 0..2000u32 {
+            runs.extend_from_slice(&[(n * 7 % 251) as u8; 20]);
+        }
+        let options = CO::for_sample(&runs);
+        assert_ne!(options, CO::stored());
+        assert!(decompress_to_end(&deflate_bytes_conf(&runs, options)) == runs);
+
+        assert_eq!(CO::for_sample(&[]), CO::default());
+    }
+
+    #[test]
+    fn mem_level() {
+        let data = get_test_data();
+        let usage = |options| write::DeflateEncoder::new(Vec::new(), options).memory_usage();
+        let default_usage = usage(CO::default());
+        let mut previous: Option<MemoryUsage> = None;
+        for mem_level in 1..=9 {
+            let options = CO {
+                mem_level,
+                ..CO::default()
+            };
+            let compressed = deflate_bytes_conf(&data, options);
+            assert!(decompress_to_end(&compressed) == data);
+            // Higher levels never use less memory.
+            let current = usage(options);
+            if let Some(previous) = previous {
+                assert!(current.hash_table >= previous.hash_table);
+                assert!(current.symbol_buffer >= previous.symbol_buffer);
+            }
+            previous = Some(current);
+        }
+        let low_options = CO {
+            mem_level: 1,
+            ..CO::default()
+        };
+        let low = usage(low_options);
+        assert!(low.hash_table < default_usage.hash_table);
+        assert!(low.symbol_buffer * 64 <= default_usage.symbol_buffer);
+        assert!(deflate_bytes_conf(&data, low_options).len() > deflate_bytes(&data).len());
+
+        // Level 9 uses the same buffers as the default level 8, and out of range values are
+        // clamped.
+        for &mem_level in &[9, 20] {
+            let options = CO {
+                mem_level,
+                ..CO::default()
+            };
+            assert!(deflate_bytes_conf(&data, options) == deflate_bytes(&data));
+        }
+        let options = CO {
+            mem_level: 0,
+            ..CO::default()
+        };
+        assert_eq!(usage(options), low);
+
+        // The buffers are resized when resetting with a different level.
+        let mut encoder = write::DeflateEncoder::new(Vec::new(), CO::default());
+        encoder.write_all(&data).unwrap();
+        let first = encoder.reset_with_options(Vec::new(), options).unwrap();
+        assert!(decompress_to_end(&first) == data);
+        assert_eq!(encoder.memory_usage().hash_table, low.hash_table);
+        encoder.write_all(&data).unwrap();
+        assert!(encoder.finish().unwrap() == deflate_bytes_conf(&data, options));
+    }
+
+    #[test]
+    fn incompressible_not_expanded() {
+        // Incompressible data should be output as stored blocks, which only add a few bytes per
+        // block.
+        let input = get_random_data(300_000);
+        for &options in &[
+            CO::default(),
+            CO::fast(),
+            CO::high(),
+            CO::huffman_only(),
+            CO::rle(),
+        ] {
+            let compressed = deflate_bytes_conf(&input, options);
+            assert!(compressed.len() <= deflate_bound(input.len()));
+            assert!(decompress_to_end(&compressed) == input);
+        }
+    }
+
+    #[test]
+    fn incompressible_small_blocks() {
+        // Smaller blocks add more stored block overhead, which `deflate_bound_conf` accounts for.
+        let input = get_random_data(200_000);
+        let low_mem_level = CO {
+            mem_level: 1,
+            ..CO::default()
+        };
+        let short_blocks = CO {
+            block_length: Some(256),
+            ..CO::default()
+        };
+        for &options in &[low_mem_level, short_blocks] {
+            let compressed = deflate_bytes_conf(&input, options);
+            assert!(compressed.len() > deflate_bound(input.len()));
+            assert!(compressed.len() <= deflate_bound_conf(input.len(), options));
+            assert!(decompress_to_end(&compressed) == input);
+            let mut output = vec![0; deflate_bound_conf(input.len(), options)];
+            assert_eq!(
+                compress_to_slice(&input, &mut output, options),
+                Ok(compressed.len())
+            );
+        }
+        assert_eq!(deflate_bound_conf(1000, CO::default()), deflate_bound(1000));
+    }
+
     #[test]
     fn file_zlib() {
         let test_data = get_test_data();
@@ -410,6 +1165,41 @@ mod test {
         assert!(data == decompressed);
     }
 
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_hint() {
+        let data = get_test_data();
+        let compressed =
+            deflate_bytes_gzip_hint(&data, Compression::Default, GzBuilder::new(), 0.3);
+        assert!(compressed == deflate_bytes_gzip(&data));
+        let (_, decompressed) = decompress_gzip(&compressed);
+        assert!(data == decompressed);
+    }
+
+    #[test]
+    fn direct_output_same_as_writer() {
+        let data = get_test_data();
+        for &options in &[CO::default(), CO::fast(), CO::rle()] {
+            let mut deflate_state = Box::new(DeflateState::new(options, Vec::new()));
+            compress_until_done(&data, &mut deflate_state, Flush::Finish).unwrap();
+            let written = deflate_state.inner.take().unwrap();
+            assert!(deflate_bytes_conf(&data, options) == written);
+        }
+    }
+
+    #[test]
+    fn output_capacity_hint() {
+        // Small inputs get enough space for the worst case.
+        let small = [1u8; 1000];
+        assert!(output_capacity(small.len(), 6, None) >= deflate_bytes_zlib(&small).len());
+        let big = 1 << 24;
+        assert_eq!(output_capacity(big, 0, None), big / 3);
+        assert_eq!(output_capacity(big, 18, Some(0.25)), big / 4 + 18);
+        // The hint is capped by the worst case size.
+        assert_eq!(output_capacity(big, 0, Some(4.0)), deflate_bound(big));
+        assert_eq!(output_capacity(big, 0, Some(-1.0)), big / 3);
+    }
+
     fn chunk_test(chunk_size: usize, level: CompressionOptions) {
         let mut compressed = Vec::with_capacity(32000);
         let data = get_test_data();
@@ -444,6 +1234,7 @@ mod test {
         writer_chunks_level(CompressionOptions::default());
         writer_chunks_level(CompressionOptions::fast());
         writer_chunks_level(CompressionOptions::rle());
+        writer_chunks_level(CompressionOptions::filtered());
     }
 
     /// Check that the frequency values don't overflow.
@@ -488,4 +1279,79 @@ mod test {
         roundtrip_zlib(two, CO::fast());
         roundtrip_zlib(two, CO::default());
     }
+
+    /// Inputs shorter than the minimum match length, with every preset and when written a byte
+    /// at a time with flushes in between.
+    #[test]
+    fn shorter_than_min_match() {
+        let presets = [
+            CO::default(),
+            CO::fast(),
+            CO::high(),
+            CO::huffman_only(),
+            CO::rle(),
+            CO::filtered(),
+            CO::optimal(),
+            CO::maximum(),
+        ];
+        for len in 0..=3 {
+            let data = &[9, 9, 9][..len];
+            for &options in &presets {
+                roundtrip_zlib(data, options);
+                assert_eq!(decompress_to_end(&deflate_bytes_conf(data, options)), data);
+
+                let mut encoder = write::DeflateEncoder::new(Vec::new(), options);
+                for byte in data.chunks(1) {
+                    encoder.write_all(byte).unwrap();
+                    encoder.flush().unwrap();
+                }
+                let compressed = encoder.finish().unwrap();
+                assert_eq!(decompress_to_end(&compressed), data);
+
+                // A dictionary that is too short to hash.
+                let mut encoder = write::DeflateEncoder::new(Vec::new(), options);
+                encoder.set_dictionary(&[1]).unwrap();
+                encoder.write_all(data).unwrap();
+                assert!(!encoder.finish().unwrap().is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn compress_into_reuses_output() {
+        let data = get_test_data();
+        let mut output = Vec::with_capacity(data.len());
+        let ptr = output.as_ptr();
+        compress_into(&data, &mut output, CO::default());
+        assert!(output == deflate_bytes(&data));
+        assert_eq!(output.as_ptr(), ptr);
+
+        // The compressed data is appended to what is already in the vector.
+        let prefix = output.clone();
+        compress_into(&data[..1000], &mut output, CO::fast());
+        assert!(output[..prefix.len()] == prefix[..]);
+        assert_eq!(decompress_to_end(&output[prefix.len()..]), &data[..1000]);
+        assert_eq!(output.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn hash_four_bytes() {
+        let data = get_test_data();
+        for &preset in &[CO::default(), CO::fast(), CO::high(), CO::optimal()] {
+            let options = CO {
+                hash_four_bytes: true,
+                ..preset
+            };
+            roundtrip_zlib(&data, options);
+
+            let mut encoder = write::DeflateEncoder::new(Vec::new(), options);
+            encoder.set_dictionary(&data[..1000]).unwrap();
+            for chunk in data.chunks(10_000) {
+                encoder.write_all(chunk).unwrap();
+                encoder.flush().unwrap();
+            }
+            let compressed = encoder.finish().unwrap();
+            assert_eq!(decompress_with_dictionary(&data[..1000], &compressed), data);
+        }
+    }
 }
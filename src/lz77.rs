@@ -2,19 +2,23 @@
 #![macro_use]
 use std::cmp;
 use std::fmt;
-use std::iter::{self, Iterator};
-use std::ops::{Range, RangeFrom};
-use std::slice::Iter;
+use std::ops::Range;
 
-use crate::chained_hash_table::{update_hash, ChainedHashTable};
+use crate::allocator::StateAllocator;
+use crate::chained_hash_table::HashFunction;
 use crate::compress::Flush;
+use crate::compression_options::DEFAULT_GOOD_LENGTH;
 #[cfg(test)]
 use crate::compression_options::{HIGH_LAZY_IF_LESS_THAN, HIGH_MAX_HASH_CHECKS};
+#[cfg(test)]
 use crate::input_buffer::InputBuffer;
+use crate::input_buffer::InputWindow;
 #[cfg(test)]
 use crate::lzvalue::{LZType, LZValue};
-use crate::matching::longest_match;
+use crate::matcher::{MatchFinder, Matcher, MatcherType};
+use crate::optimal::process_chunk_optimal;
 use crate::output_writer::{BufferStatus, DynamicWriter};
+use crate::repeats::RepeatIndex;
 use crate::rle::process_chunk_greedy_rle;
 
 const MAX_MATCH: usize = crate::huffman_table::MAX_MATCH as usize;
@@ -22,7 +26,7 @@ const MIN_MATCH: usize = crate::huffman_table::MIN_MATCH as usize;
 
 const NO_RLE: u16 = 43212;
 
-/// An enum describing whether we use lazy or greedy matching.
+/// An enum describing whether we use lazy, greedy or optimal matching.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum MatchingType {
     /// Use greedy matching: the matching algorithm simply uses a match right away
@@ -34,6 +38,13 @@ pub enum MatchingType {
     /// As a special case, if max_hash_checks is set to 0, compression using only run-length
     /// (i.e maximum match distance of 1) is performed instead.
     Lazy,
+    /// Use optimal parsing: the longest match at each position of a chunk of input is found
+    /// first, and the combination of literals and matches that is estimated to give the smallest
+    /// output is then used.
+    ///
+    /// This is many times slower than lazy matching, and usually only gives slightly smaller
+    /// output.
+    Optimal,
 }
 
 impl fmt::Display for MatchingType {
@@ -41,14 +52,16 @@ impl fmt::Display for MatchingType {
         match *self {
             MatchingType::Greedy => write!(f, "Greedy matching"),
             MatchingType::Lazy => write!(f, "Lazy matching"),
+            MatchingType::Optimal => write!(f, "Optimal parsing"),
         }
     }
 }
 
 /// A struct that contains the hash table, and keeps track of where we are in the input data
+#[derive(Clone)]
 pub struct LZ77State {
-    /// Struct containing hash chains that will be used to find matches.
-    hash_table: ChainedHashTable,
+    /// The search structure that will be used to find matches.
+    matcher: MatchFinder,
     /// True if this is the first window that is being processed.
     is_first_window: bool,
     /// Set to true when the last block has been processed.
@@ -61,6 +74,16 @@ pub struct LZ77State {
     max_hash_checks: u16,
     /// Only lazy match if we have a match length less than this.
     lazy_if_less_than: u16,
+    /// Whether lazy matching also checks two bytes ahead before using a match.
+    two_step_lazy: bool,
+    /// Whether to stop searching for matches in chunks of input that look incompressible.
+    skip_incompressible: bool,
+    /// Set when the last chunk matches were searched for in looked incompressible.
+    incompressible: bool,
+    /// The number of chunks output without searching for matches since the last one searched.
+    skipped_chunks: u32,
+    /// The lengths controlling how matches are searched for and used.
+    lengths: MatchLengths,
     /// Whether to use greedy or lazy parsing
     matching_type: MatchingType,
     /// Keep track of the previous match and byte in case the buffer is full when lazy matching.
@@ -71,6 +94,8 @@ pub struct LZ77State {
     /// Keep track of if sync flush was used. If this is the case, the two first bytes needs to be
     /// hashed.
     was_synced: bool,
+    /// Index used to find blocks repeating earlier ones, if enabled.
+    repeats: Option<RepeatIndex>,
 }
 
 impl LZ77State {
@@ -79,31 +104,166 @@ impl LZ77State {
         max_hash_checks: u16,
         lazy_if_less_than: u16,
         matching_type: MatchingType,
+        allocator: &StateAllocator,
     ) -> LZ77State {
         LZ77State {
-            hash_table: ChainedHashTable::new(),
+            matcher: MatchFinder::new_in(MatcherType::default(), allocator),
             is_first_window: true,
             is_last_block: false,
             overlap: 0,
             current_block_input_bytes: 0,
             max_hash_checks,
             lazy_if_less_than,
+            two_step_lazy: false,
+            skip_incompressible: false,
+            incompressible: false,
+            skipped_chunks: 0,
+            lengths: MatchLengths::default(),
             matching_type,
             match_state: ChunkState::new(),
             bytes_to_hash: 0,
             was_synced: false,
+            repeats: None,
+        }
+    }
+
+    /// Use a matcher of the type `matcher_type` to find matches, allocating it with `allocator`
+    /// if it's not the one already in use.
+    ///
+    /// This has to be set before any data has been compressed.
+    pub fn set_matcher(&mut self, matcher_type: MatcherType, allocator: &StateAllocator) {
+        if self.matcher.matcher_type() != matcher_type {
+            let window_size = self.matcher.window_size();
+            self.matcher = MatchFinder::new_in(matcher_type, allocator);
+            self.matcher.set_window_size(window_size);
+        }
+    }
+
+    /// Use the provided hash function for the hash chains rather than the default one.
+    pub fn set_hash_function(&mut self, hash_function: Option<HashFunction>) {
+        self.matcher.set_hash_function(hash_function);
+    }
+
+    /// Hash four bytes rather than three for each position in the hash chains.
+    ///
+    /// This has to be set before any data has been compressed.
+    pub fn set_hash_four_bytes(&mut self, four_bytes: bool) {
+        self.matcher.set_hash_four_bytes(four_bytes);
+    }
+
+    /// Use only the lowest `hash_bits` bits of the hash values for the hash chains.
+    ///
+    /// This has to be set before any data has been compressed.
+    pub fn set_hash_bits(&mut self, hash_bits: u8) {
+        self.matcher.set_hash_bits(hash_bits);
+    }
+
+    /// Enable or disable looking for blocks that repeat earlier blocks wholesale.
+    ///
+    /// This has no effect when not using hash chains.
+    pub fn set_detect_repeats(&mut self, detect_repeats: bool) {
+        if !detect_repeats || self.max_hash_checks == 0 {
+            self.repeats = None;
+        } else if self.repeats.is_none() {
+            self.repeats = Some(RepeatIndex::new(self.matcher.window_size()));
         }
     }
 
+    /// Limit the distance of matches to `window_size`, which has to be a power of two no larger
+    /// than the default window size.
+    pub fn set_window_size(&mut self, window_size: usize) {
+        self.matcher.set_window_size(window_size);
+        if self.repeats.is_some() {
+            self.repeats = Some(RepeatIndex::new(window_size));
+        }
+    }
+
+    /// Ignore matches shorter than `min_match`, which is clamped to the match lengths allowed by
+    /// DEFLATE.
+    pub fn set_min_match(&mut self, min_match: usize) {
+        self.lengths.min_match = min_match.clamp(MIN_MATCH, MAX_MATCH);
+    }
+
+    /// Search for fewer matches at positions after a match of at least `good_length` when lazy
+    /// matching, and stop searching when a match of at least `nice_length` is found.
+    pub fn set_search_lengths(&mut self, good_length: usize, nice_length: usize) {
+        self.lengths.good_length = good_length;
+        self.lengths.nice_length = nice_length;
+    }
+
+    /// Only add the positions inside matches of at most `max_insert_length` bytes to the matcher
+    /// when greedy matching.
+    pub fn set_max_insert_length(&mut self, max_insert_length: usize) {
+        self.lengths.max_insert_length = max_insert_length;
+    }
+
+    /// Also check for a better match two bytes ahead before using a match when lazy matching.
+    pub fn set_two_step_lazy(&mut self, two_step_lazy: bool) {
+        self.two_step_lazy = two_step_lazy;
+    }
+
+    /// Stop searching for matches in chunks of input that look incompressible.
+    pub fn set_skip_incompressible(&mut self, skip_incompressible: bool) {
+        self.skip_incompressible = skip_incompressible;
+        if !skip_incompressible {
+            self.incompressible = false;
+        }
+    }
+
+    /// Change the parameters used when searching for matches.
+    pub fn set_matching_parameters(
+        &mut self,
+        max_hash_checks: u16,
+        lazy_if_less_than: u16,
+        matching_type: MatchingType,
+    ) {
+        self.max_hash_checks = max_hash_checks;
+        self.lazy_if_less_than = lazy_if_less_than;
+        self.matching_type = matching_type;
+    }
+
+    /// Fill the window with data preceding the input, so that the start of the input can
+    /// reference it.
+    ///
+    /// This has to be called before any data has been compressed. Only the last window size of
+    /// bytes of `data` are used.
+    pub fn prime_window<B: InputWindow>(&mut self, buffer: &mut B, data: &[u8]) {
+        debug_assert!(self.is_first_window && buffer.current_end() == 0);
+        let data = &data[data.len().saturating_sub(DEFAULT_WINDOW_SIZE)..];
+        if data.is_empty() {
+            return;
+        }
+        let _ = buffer.add_data(data);
+        let lookahead = self.matcher.lookahead();
+        if data.len() >= lookahead {
+            self.matcher.add_initial(data);
+            self.matcher.add_range(data, 0..data.len() - lookahead);
+        }
+        // The window now looks the same as after a sync flush, where the last few bytes can't be
+        // hashed until more data is added.
+        self.overlap = data.len();
+        self.was_synced = true;
+    }
+
+    /// The number of bytes of heap memory used by the hash chains.
+    pub fn hash_table_size(&self) -> usize {
+        self.matcher.heap_size()
+    }
+
     /// Resets the state excluding max_hash_checks and lazy_if_less_than
     pub fn reset(&mut self) {
-        self.hash_table.reset();
+        self.matcher.reset();
         self.is_first_window = true;
         self.is_last_block = false;
         self.overlap = 0;
         self.current_block_input_bytes = 0;
         self.match_state = ChunkState::new();
-        self.bytes_to_hash = 0
+        self.bytes_to_hash = 0;
+        self.incompressible = false;
+        self.skipped_chunks = 0;
+        if let Some(ref mut repeats) = self.repeats {
+            repeats.reset();
+        }
     }
 
     pub fn set_last(&mut self) {
@@ -155,7 +315,7 @@ pub enum ProcessStatus {
     BufferFull(usize),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 /// A struct to keep track of status between calls of `process_chunk_lazy`
 ///
 /// This is needed as the output buffer might become full before having output all pending data.
@@ -189,15 +349,18 @@ pub fn buffer_full(position: usize) -> ProcessStatus {
 }
 
 #[allow(clippy::too_many_arguments)]
-fn process_chunk(
+fn process_chunk<M: Matcher>(
     data: &[u8],
     iterated_data: &Range<usize>,
     mut match_state: &mut ChunkState,
-    hash_table: &mut ChainedHashTable,
+    matcher: &mut M,
     writer: &mut DynamicWriter,
     max_hash_checks: u16,
+    lengths: MatchLengths,
     lazy_if_less_than: usize,
+    two_step_lazy: bool,
     matching_type: MatchingType,
+    repeats: Option<&mut RepeatIndex>,
 ) -> (usize, ProcessStatus) {
     let avoid_rle = if cfg!(test) {
         // Avoid RLE if lazy_if_less than is a specific value.
@@ -210,7 +373,21 @@ fn process_chunk(
     };
     match matching_type {
         MatchingType::Greedy => {
-            process_chunk_greedy(data, iterated_data, hash_table, writer, max_hash_checks)
+            if max_hash_checks > 0 {
+                process_chunk_greedy(
+                    data,
+                    iterated_data,
+                    matcher,
+                    writer,
+                    max_hash_checks,
+                    lengths,
+                    repeats,
+                )
+            } else {
+                // Only use huffman coding if max_hash_checks is set to 0, as no matches would be
+                // found anyhow.
+                process_chunk_literals(data, iterated_data, writer)
+            }
         }
         MatchingType::Lazy => {
             if max_hash_checks > 0 || avoid_rle {
@@ -218,41 +395,134 @@ fn process_chunk(
                     data,
                     iterated_data,
                     &mut match_state,
-                    hash_table,
+                    matcher,
                     writer,
                     max_hash_checks,
+                    lengths,
                     lazy_if_less_than,
+                    two_step_lazy,
+                    repeats,
                 )
             } else {
                 // Use the RLE method if max_hash_checks is set to 0.
                 process_chunk_greedy_rle(data, iterated_data, writer)
             }
         }
+        MatchingType::Optimal => process_chunk_optimal(
+            data,
+            iterated_data,
+            matcher,
+            writer,
+            max_hash_checks,
+            lengths,
+            repeats,
+        ),
     }
 }
 
-/// Add the specified number of bytes to the hash table from the iterators
-/// adding `start` to the position supplied to the hash table.
-fn add_to_hash_table(
-    bytes_to_add: usize,
-    insert_it: &mut iter::Zip<RangeFrom<usize>, Iter<u8>>,
-    hash_it: &mut Iter<u8>,
-    hash_table: &mut ChainedHashTable,
-) {
-    let taker = insert_it.by_ref().take(bytes_to_add);
-    let mut hash_taker = hash_it.by_ref().take(bytes_to_add);
-    // Update the hash manually here to keep it in a register.
-    let mut hash = hash_table.current_hash();
-    // Advance the iterators and add the bytes we jump over to the hash table and
-    // checksum
-    for (ipos, _) in taker {
-        if let Some(&i_hash_byte) = hash_taker.next() {
-            hash = update_hash(hash, i_hash_byte);
-            hash_table.add_with_hash(ipos, hash);
+/// Run `process_chunk` on the chunk `iterated_data` of `data` with the matcher and settings in
+/// `state`.
+fn process_state_chunk(
+    data: &[u8],
+    iterated_data: &Range<usize>,
+    state: &mut LZ77State,
+    writer: &mut DynamicWriter,
+) -> (usize, ProcessStatus) {
+    let end = cmp::min(data.len(), iterated_data.end);
+    let chunk = &data[cmp::min(iterated_data.start, end)..end];
+    // Whether the chunk is large enough to tell if it's incompressible. Huffman only and RLE
+    // compression are about as fast as outputting literals anyhow.
+    let check = state.skip_incompressible
+        && state.max_hash_checks > 0
+        && chunk.len() >= MIN_INCOMPRESSIBLE_CHECK;
+    if check && state.incompressible {
+        if state.skipped_chunks < PROBE_INTERVAL && looks_random(chunk) {
+            state.skipped_chunks += 1;
+            return process_chunk_skipped(data, iterated_data, &mut state.match_state, writer);
         }
+        // Either the data has changed, or it's time to check whether it has started repeating.
+        state.incompressible = false;
+        state.skipped_chunks = 0;
     }
-    // Write the hash back once we are done.
-    hash_table.set_hash(hash);
+
+    let values_before = writer.buffer_length();
+    macro_rules! process {
+        ($matcher:expr) => {
+            process_chunk(
+                data,
+                iterated_data,
+                &mut state.match_state,
+                $matcher,
+                writer,
+                state.max_hash_checks,
+                state.lengths,
+                state.lazy_if_less_than as usize,
+                state.two_step_lazy,
+                state.matching_type,
+                state.repeats.as_mut(),
+            )
+        };
+    }
+    let result = match state.matcher {
+        MatchFinder::HashChains(ref mut hash_table) => process!(hash_table),
+        MatchFinder::BinaryTree(ref mut tree) => process!(tree),
+    };
+
+    if check {
+        if let (_, ProcessStatus::Ok) = result {
+            // Matches only made up a tiny part of the chunk if there were almost as many values
+            // output as bytes.
+            let values = writer.buffer_length().saturating_sub(values_before);
+            state.incompressible = values * INCOMPRESSIBLE_VALUE_RATIO
+                >= chunk.len() * (INCOMPRESSIBLE_VALUE_RATIO - 1)
+                && looks_random(chunk);
+        }
+    }
+    result
+}
+
+/// The smallest part of a chunk that is checked for whether it's incompressible.
+const MIN_INCOMPRESSIBLE_CHECK: usize = 4096;
+/// A chunk is considered incompressible if matches save less than one in this many bytes.
+const INCOMPRESSIBLE_VALUE_RATIO: usize = 64;
+/// The number of chunks output without searching for matches before searching again.
+const PROBE_INTERVAL: u32 = 32;
+/// The number of bytes of a chunk sampled to estimate its entropy.
+const ENTROPY_SAMPLES: usize = 4096;
+/// The entropy in bits per byte above which data is considered random.
+const RANDOM_ENTROPY: f64 = 7.8;
+
+/// Whether the bytes of `data` look close to random, estimated from the entropy of their
+/// frequencies in a sample of it.
+///
+/// Data above the limit could at best be shrunk by a few percent by huffman coding.
+fn looks_random(data: &[u8]) -> bool {
+    let step = cmp::max(data.len() / ENTROPY_SAMPLES, 1);
+    let mut frequencies = [0u32; 256];
+    let mut samples = 0u32;
+    for &b in data.iter().step_by(step) {
+        frequencies[usize::from(b)] += 1;
+        samples += 1;
+    }
+    let samples = f64::from(samples);
+    let sum: f64 = frequencies
+        .iter()
+        .filter(|&&f| f > 0)
+        .map(|&f| f64::from(f) * f64::from(f).log2())
+        .sum();
+    samples.log2() - sum / samples > RANDOM_ENTROPY
+}
+
+/// The end of the part of the chunk described by `iterated_data` that can be added to the hash
+/// chains, and the end of the chunk itself.
+///
+/// The last few bytes of the input can't be hashed, as the bytes following them that are part of
+/// the hash are not available yet.
+#[inline]
+fn chunk_ends(data: &[u8], iterated_data: &Range<usize>, lookahead: usize) -> (usize, usize) {
+    let end = cmp::min(data.len(), iterated_data.end);
+    let hash_end = cmp::min(end, data.len().saturating_sub(lookahead));
+    (hash_end, end)
 }
 
 /// Write the specified literal `byte` to the writer `w`, and return
@@ -278,41 +548,88 @@ fn match_too_far(match_len: usize, match_dist: usize) -> bool {
     match_len == MIN_MATCH && match_dist > TOO_FAR
 }
 
-///Create the iterators used when processing through a chunk of data.
-fn create_iterators<'a>(
-    data: &'a [u8],
-    iterated_data: &Range<usize>,
-) -> (
-    usize,
-    iter::Zip<RangeFrom<usize>, Iter<'a, u8>>,
-    Iter<'a, u8>,
-) {
-    let end = cmp::min(data.len(), iterated_data.end);
-    let start = iterated_data.start;
-    let current_chunk = &data[start..end];
+/// Whether a match at `position` could be longer than the one of `prev_length` bytes found at the
+/// previous byte.
+///
+/// This is not the case if the previous match already has the maximum length, or if there are
+/// not enough bytes left of the input for a longer one.
+#[inline]
+fn match_can_improve(data: &[u8], position: usize, prev_length: usize) -> bool {
+    prev_length < MAX_MATCH && position + prev_length < data.len()
+}
 
-    let insert_it = (start..).zip(current_chunk.iter());
-    let hash_it = {
-        let hash_start = if data.len() - start > 2 {
-            start + 2
-        } else {
-            data.len()
-        };
-        (&data[hash_start..]).iter()
-    };
-    (end, insert_it, hash_it)
+/// Match lengths controlling how matches are searched for and which ones are used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MatchLengths {
+    /// Ignore matches shorter than this.
+    pub min_match: usize,
+    /// Search less for a better match when lazy matching after a match at least this long.
+    pub good_length: usize,
+    /// Stop searching when a match at least this long has been found.
+    pub nice_length: usize,
+    /// Only add the positions inside a match to the matcher when greedy matching if the match
+    /// is at most this long.
+    pub max_insert_length: usize,
+}
+
+impl Default for MatchLengths {
+    fn default() -> MatchLengths {
+        MatchLengths {
+            min_match: MIN_MATCH,
+            good_length: usize::from(DEFAULT_GOOD_LENGTH),
+            nice_length: MAX_MATCH,
+            max_insert_length: MAX_MATCH,
+        }
+    }
 }
 
-fn process_chunk_lazy(
+/// Find a match at `position`, using a repeated block if there is one, and otherwise searching
+/// with `matcher`.
+///
+/// Matches shorter than the minimum match length in `lengths` are ignored.
+#[inline]
+pub fn find_match<M: Matcher>(
+    data: &[u8],
+    matcher: &M,
+    repeats: &mut Option<&mut RepeatIndex>,
+    position: usize,
+    prev_length: usize,
+    max_hash_checks: u16,
+    lengths: MatchLengths,
+) -> (usize, usize) {
+    let found = repeats
+        .as_mut()
+        .and_then(|repeats| repeats.find_match(data, position))
+        .unwrap_or_else(|| {
+            matcher.longest_match(
+                data,
+                position,
+                prev_length,
+                max_hash_checks,
+                lengths.nice_length,
+            )
+        });
+    if found.0 < lengths.min_match {
+        (0, 0)
+    } else {
+        found
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_chunk_lazy<M: Matcher>(
     data: &[u8],
     iterated_data: &Range<usize>,
     state: &mut ChunkState,
-    mut hash_table: &mut ChainedHashTable,
+    matcher: &mut M,
     writer: &mut DynamicWriter,
     max_hash_checks: u16,
+    lengths: MatchLengths,
     lazy_if_less_than: usize,
+    two_step_lazy: bool,
+    mut repeats: Option<&mut RepeatIndex>,
 ) -> (usize, ProcessStatus) {
-    let (end, mut insert_it, mut hash_it) = create_iterators(data, iterated_data);
+    let (hash_end, end) = chunk_ends(data, iterated_data, matcher.lookahead());
 
     const NO_LENGTH: u16 = 0;
 
@@ -337,84 +654,126 @@ fn process_chunk_lazy(
     // from the previous call.
     state.prev_byte = state.cur_byte;
 
-    // Iterate through the slice, adding literals or length/distance pairs
-    while let Some((position, &b)) = insert_it.next() {
+    // Iterate through the part of the chunk that can be hashed, adding literals or
+    // length/distance pairs.
+    let mut position = iterated_data.start;
+    while position < hash_end {
+        let b = data[position];
         state.cur_byte = b;
-        if let Some(&hash_byte) = hash_it.next() {
-            hash_table.add_hash_value(position, hash_byte);
-
-            // Only lazy match if we have a match shorter than a set value
-            // TODO: This should be cleaned up a bit
-            if !ignore_next {
-                let (mut match_len, match_dist) = {
-                    // If there already was a decent match at the previous byte
-                    // and we are lazy matching, do less match checks in this step.
-                    let max_hash_checks = if prev_length >= 32 {
-                        max_hash_checks >> 2
-                    } else {
-                        max_hash_checks
-                    };
+        matcher.add(data, position);
+
+        // Only lazy match if we have a match shorter than a set value, and skip the search
+        // entirely if it can't find anything better than the match at the previous byte.
+        let searched = !ignore_next && match_can_improve(data, position, usize::from(prev_length));
+        if searched {
+            // If there already was a decent match at the previous byte
+            // and we are lazy matching, do less match checks in this step.
+            let max_hash_checks = if usize::from(prev_length) >= lengths.good_length {
+                max_hash_checks >> 2
+            } else {
+                max_hash_checks
+            };
 
-                    // Check if we can find a better match here than the one we had at
-                    // the previous byte.
-                    longest_match(
-                        data,
-                        hash_table,
-                        position,
-                        prev_length as usize,
-                        max_hash_checks,
-                    )
-                };
+            // Check if we can find a better match here than the one we had at
+            // the previous byte.
+            let (mut match_len, match_dist) = find_match(
+                data,
+                &*matcher,
+                &mut repeats,
+                position,
+                prev_length as usize,
+                max_hash_checks,
+                lengths,
+            );
 
-                // If the match is only 3 bytes long and very far back, it's probably not worth
-                // outputting.
-                if match_too_far(match_len, match_dist) {
-                    match_len = NO_LENGTH as usize;
-                };
+            // If the match is only 3 bytes long and very far back, it's probably not worth
+            // outputting.
+            if match_too_far(match_len, match_dist) {
+                match_len = NO_LENGTH as usize;
+            };
+
+            if match_len >= lazy_if_less_than {
+                // We found a decent match, so we won't check for a better one at the next byte.
+                ignore_next = true;
+            }
+            state.current_length = match_len as u16;
+            state.current_distance = match_dist as u16;
+        } else {
+            // We already had a decent match, or one that can't be beaten, so we don't bother
+            // checking for another one.
+            state.current_length = NO_LENGTH;
+            state.current_distance = 0;
+            // Make sure we check again next time.
+            ignore_next = false;
+        };
 
-                if match_len >= lazy_if_less_than {
-                    // We found a decent match, so we won't check for a better one at the next byte.
-                    ignore_next = true;
+        // Whether the byte after this one has been added to the matcher already.
+        let mut next_added = false;
+        if two_step_lazy
+            && searched
+            && prev_length >= state.current_length
+            && prev_length >= MIN_MATCH as u16
+            && position + 1 < hash_end
+            && writer.buffer_length() + 1 < writer.max_length()
+        {
+            // Before using the match at the previous byte, check whether there is a match at the
+            // next byte long enough to make up for outputting the previous byte and this one as
+            // literals instead.
+            matcher.add(data, position + 1);
+            next_added = true;
+            let (next_len, next_dist) = find_match(
+                data,
+                &*matcher,
+                &mut repeats,
+                position + 1,
+                usize::from(prev_length) + 1,
+                max_hash_checks,
+                lengths,
+            );
+            if next_len > 0 && !match_too_far(next_len, next_dist) {
+                debug_assert!(state.add);
+                // The space left in the buffer was checked above, so it can't be full after the
+                // first literal.
+                let _ = writer.write_literal(state.prev_byte);
+                let b_status = writer.write_literal(b);
+
+                state.current_length = next_len as u16;
+                state.current_distance = next_dist as u16;
+                state.cur_byte = data[position + 1];
+                state.add = true;
+                if let BufferStatus::Full = b_status {
+                    return (0, buffer_full(position + 2));
                 }
-                state.current_length = match_len as u16;
-                state.current_distance = match_dist as u16;
-            } else {
-                // We already had a decent match, so we don't bother checking for another one.
-                state.current_length = NO_LENGTH;
-                state.current_distance = 0;
-                // Make sure we check again next time.
-                ignore_next = false;
-            };
 
+                ignore_next = next_len >= lazy_if_less_than;
+                prev_length = state.current_length;
+                prev_distance = state.current_distance;
+                state.prev_byte = state.cur_byte;
+                position += 2;
+                continue;
+            }
+        }
+
+        let next_position =
             if prev_length >= state.current_length && prev_length >= MIN_MATCH as u16 {
                 // The previous match was better so we add it.
-                // Casting note: length and distance is already bounded by the longest match
-                // function. Usize is just used for convenience.
-                let b_status =
-                    writer.write_length_distance(prev_length as u16, prev_distance as u16);
-
-                // We add the bytes to the hash table and checksum.
-                // Since we've already added two of them, we need to add two less than
-                // the length.
-                let bytes_to_add = prev_length - 2;
-
-                add_to_hash_table(
-                    bytes_to_add as usize,
-                    &mut insert_it,
-                    &mut hash_it,
-                    &mut hash_table,
-                );
+                let b_status = writer.write_length_distance(prev_length, prev_distance);
+
+                // The match started at the previous byte, and the first two bytes of it have been
+                // added to the hash chains already, along with the third one if we checked for a
+                // match there.
+                let match_end = position + usize::from(prev_length) - 1;
+                let hash_start = if next_added {
+                    position + 2
+                } else {
+                    position + 1
+                };
+                matcher.add_range(data, hash_start..cmp::min(match_end, hash_end));
 
                 // If the match is longer than the current window, we have note how many
                 // bytes we overlap, since we don't need to do any matching on these bytes
                 // in the next call of this function.
-                // We don't have to worry about setting overlap to 0 if this is false, as the
-                // function will stop after this condition is true, and overlap is not altered
-                // elsewhere.
-                if position + prev_length as usize > end {
-                    // We need to subtract 1 since the byte at pos is also included.
-                    overlap = position + prev_length as usize - end - 1;
-                };
+                overlap = match_end.saturating_sub(end);
 
                 state.add = false;
 
@@ -424,71 +783,81 @@ fn process_chunk_lazy(
 
                 if let BufferStatus::Full = b_status {
                     // MATCH(lazy)
-                    return (overlap, buffer_full(position + prev_length as usize - 1));
+                    return (overlap, buffer_full(match_end));
                 }
 
                 ignore_next = false;
-            } else if state.add {
-                // We found a better match (or there was no previous match)
-                // so output the previous byte.
-                // BETTER OR NO MATCH
-                write_literal!(writer, state.prev_byte, position + 1);
+                match_end
             } else {
-                state.add = true
-            }
-
-            prev_length = state.current_length;
-            prev_distance = state.current_distance;
-            state.prev_byte = b;
-        } else {
-            // If there is a match at this point, it will not have been added, so we need to add it.
-            if prev_length >= MIN_MATCH as u16 {
-                let b_status =
-                    writer.write_length_distance(prev_length as u16, prev_distance as u16);
-
-                state.current_length = 0;
-                state.current_distance = 0;
-                state.add = false;
-
-                // As this will be a 3-length match at the end of the input data, there can't be any
-                // overlap.
-                // TODO: Not sure if we need to signal that the buffer is full here.
-                // It's only needed in the case of syncing.
-                if let BufferStatus::Full = b_status {
-                    // TODO: These bytes should be hashed when doing a sync flush.
-                    // This can't be done here as the new input data does not exist yet.
-                    return (0, buffer_full(end));
+                if state.add {
+                    // We found a better match (or there was no previous match)
+                    // so output the previous byte.
+                    // BETTER OR NO MATCH
+                    write_literal!(writer, state.prev_byte, position + 1);
                 } else {
-                    return (0, ProcessStatus::Ok);
+                    state.add = true
                 }
+                position + 1
             };
 
-            if state.add {
-                // We may still have a leftover byte at this point, so we add it here if needed.
-                state.add = false;
-
-                // ADD
-                write_literal!(writer, state.prev_byte, position + 1);
-            };
+        prev_length = state.current_length;
+        prev_distance = state.current_distance;
+        state.prev_byte = b;
+        position = next_position;
+    }
 
-            // We are at the last two bytes we want to add, so there is no point
-            // searching for matches here.
+    // We are at the last bytes of the input, so there is no point searching for matches here.
+    if position < end {
+        // If there is a match at this point, it will not have been added, so we need to add it.
+        if prev_length >= MIN_MATCH as u16 {
+            state.cur_byte = data[position];
+            let b_status = writer.write_length_distance(prev_length, prev_distance);
+
+            state.current_length = 0;
+            state.current_distance = 0;
+            state.add = false;
+
+            // The match started at the previous byte, and can end before the last few bytes of
+            // the input if the matcher needs more than two bytes of lookahead. These are output
+            // as literals below.
+            let match_end = position + usize::from(prev_length) - 1;
+            overlap = match_end.saturating_sub(end);
+            if let BufferStatus::Full = b_status {
+                // TODO: These bytes should be hashed when doing a sync flush.
+                // This can't be done here as the new input data does not exist yet.
+                return (overlap, buffer_full(match_end));
+            }
+            position = cmp::min(match_end, end);
+        } else if state.add {
+            // We may still have a leftover byte at this point, so we add it here if needed.
+            state.add = false;
+            state.cur_byte = data[position];
+
+            // ADD
+            // The byte at `position` hasn't been output yet, so continue from it if the buffer is
+            // full.
+            write_literal!(writer, state.prev_byte, position);
+        };
 
-            // AFTER ADD
+        // AFTER ADD
+        for (position, &b) in (position..end).zip(&data[position..end]) {
+            state.cur_byte = b;
             write_literal!(writer, b, position + 1);
         }
     }
     (overlap, ProcessStatus::Ok)
 }
 
-fn process_chunk_greedy(
+fn process_chunk_greedy<M: Matcher>(
     data: &[u8],
     iterated_data: &Range<usize>,
-    mut hash_table: &mut ChainedHashTable,
+    matcher: &mut M,
     writer: &mut DynamicWriter,
     max_hash_checks: u16,
+    lengths: MatchLengths,
+    mut repeats: Option<&mut RepeatIndex>,
 ) -> (usize, ProcessStatus) {
-    let (end, mut insert_it, mut hash_it) = create_iterators(data, iterated_data);
+    let (hash_end, end) = chunk_ends(data, iterated_data, matcher.lookahead());
 
     const NO_LENGTH: usize = 0;
 
@@ -496,52 +865,114 @@ fn process_chunk_greedy(
     // the lookahead window.
     let mut overlap = 0;
 
-    // Iterate through the slice, adding literals or length/distance pairs.
-    while let Some((position, &b)) = insert_it.next() {
-        if let Some(&hash_byte) = hash_it.next() {
-            hash_table.add_hash_value(position, hash_byte);
-
-            // TODO: This should be cleaned up a bit.
-            let (match_len, match_dist) =
-                { longest_match(data, hash_table, position, NO_LENGTH, max_hash_checks) };
+    // Iterate through the part of the chunk that can be hashed, adding literals or
+    // length/distance pairs.
+    let mut position = iterated_data.start;
+    while position < hash_end {
+        matcher.add(data, position);
 
-            if match_len >= MIN_MATCH as usize && !match_too_far(match_len, match_dist) {
-                // Casting note: length and distance is already bounded by the longest match
-                // function. Usize is just used for convenience.
-                let b_status = writer.write_length_distance(match_len as u16, match_dist as u16);
+        let (match_len, match_dist) = find_match(
+            data,
+            &*matcher,
+            &mut repeats,
+            position,
+            NO_LENGTH,
+            max_hash_checks,
+            lengths,
+        );
 
-                // We add the bytes to the hash table and checksum.
-                // Since we've already added one of them, we need to add one less than
-                // the length.
-                let bytes_to_add = match_len - 1;
-                add_to_hash_table(bytes_to_add, &mut insert_it, &mut hash_it, &mut hash_table);
+        if match_len >= MIN_MATCH as usize && !match_too_far(match_len, match_dist) {
+            // Casting note: length and distance is already bounded by the longest match
+            // function. Usize is just used for convenience.
+            let b_status = writer.write_length_distance(match_len as u16, match_dist as u16);
+
+            // We add the rest of the bytes of the match to the hash table, the first one has
+            // been added already. The bytes of long matches are skipped if set to, starting over
+            // adding positions at the end of the match.
+            let match_end = position + match_len;
+            if match_len <= lengths.max_insert_length || match_end >= hash_end {
+                matcher.add_range(data, position + 1..cmp::min(match_end, hash_end));
+            } else {
+                matcher.add_initial(&data[match_end..]);
+            }
 
-                // If the match is longer than the current window, we have note how many
-                // bytes we overlap, since we don't need to do any matching on these bytes
-                // in the next call of this function.
-                if position + match_len > end {
-                    // We need to subtract 1 since the byte at pos is also included.
-                    overlap = position + match_len - end;
-                };
+            // If the match is longer than the current window, we have note how many
+            // bytes we overlap, since we don't need to do any matching on these bytes
+            // in the next call of this function.
+            overlap = match_end.saturating_sub(end);
 
-                if let BufferStatus::Full = b_status {
-                    // MATCH
-                    return (overlap, buffer_full(position + match_len));
-                }
-            } else {
-                // NO MATCH
-                write_literal!(writer, b, position + 1);
+            if let BufferStatus::Full = b_status {
+                // MATCH
+                return (overlap, buffer_full(match_end));
             }
+            position = match_end;
         } else {
-            // We are at the last two bytes we want to add, so there is no point
-            // searching for matches here.
-            // END
+            // NO MATCH
+            write_literal!(writer, data[position], position + 1);
+            position += 1;
+        }
+    }
+
+    // We are at the last bytes of the input, so there is no point searching for matches here.
+    // END
+    if position < end {
+        for (position, &b) in (position..end).zip(&data[position..end]) {
             write_literal!(writer, b, position + 1);
         }
     }
     (overlap, ProcessStatus::Ok)
 }
 
+/// Output all the bytes in the chunk as literals, without searching for matches or updating the
+/// hash chains.
+fn process_chunk_literals(
+    data: &[u8],
+    iterated_data: &Range<usize>,
+    writer: &mut DynamicWriter,
+) -> (usize, ProcessStatus) {
+    let end = cmp::min(data.len(), iterated_data.end);
+    for (position, &b) in (iterated_data.start..).zip(&data[iterated_data.start..end]) {
+        write_literal!(writer, b, position + 1);
+    }
+    (0, ProcessStatus::Ok)
+}
+
+/// Output the chunk as literals without searching for matches or adding the positions to the
+/// matcher, after outputting any match or byte pending from the previous chunk.
+///
+/// The positions skipped simply won't be found as matches later.
+fn process_chunk_skipped(
+    data: &[u8],
+    iterated_data: &Range<usize>,
+    state: &mut ChunkState,
+    writer: &mut DynamicWriter,
+) -> (usize, ProcessStatus) {
+    let end = cmp::min(data.len(), iterated_data.end);
+    let mut start = iterated_data.start;
+    if state.add {
+        state.add = false;
+        let (length, distance) = (state.current_length, state.current_distance);
+        state.current_length = 0;
+        state.current_distance = 0;
+        if length >= MIN_MATCH as u16 {
+            // The pending match starts at the byte before the chunk.
+            let b_status = writer.write_length_distance(length, distance);
+            let match_end = start - 1 + usize::from(length);
+            let overlap = match_end.saturating_sub(end);
+            if let BufferStatus::Full = b_status {
+                return (overlap, buffer_full(match_end));
+            }
+            if overlap > 0 {
+                return (overlap, ProcessStatus::Ok);
+            }
+            start = match_end;
+        } else {
+            write_literal!(writer, state.cur_byte, start);
+        }
+    }
+    process_chunk_literals(data, &(start..end), writer)
+}
+
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
 pub enum LZ77Status {
     /// Waiting for more input before doing any processing
@@ -554,10 +985,10 @@ pub enum LZ77Status {
 }
 
 #[cfg(test)]
-pub fn lz77_compress_block_finish(
+pub fn lz77_compress_block_finish<B: InputWindow>(
     data: &[u8],
     state: &mut LZ77State,
-    buffer: &mut InputBuffer,
+    buffer: &mut B,
     mut writer: &mut DynamicWriter,
 ) -> (usize, LZ77Status) {
     let (consumed, status, _) =
@@ -574,10 +1005,10 @@ pub fn lz77_compress_block_finish(
 /// whether there is no input, it's time to finish, or it's time to end the block, and the position
 /// of the first byte in the input buffer that has not been output (but may have been checked for
 /// matches).
-pub fn lz77_compress_block(
+pub fn lz77_compress_block<B: InputWindow>(
     data: &[u8],
     state: &mut LZ77State,
-    buffer: &mut InputBuffer,
+    buffer: &mut B,
     mut writer: &mut DynamicWriter,
     flush: Flush,
 ) -> (usize, LZ77Status, usize) {
@@ -586,38 +1017,44 @@ pub fn lz77_compress_block(
 
     // Indicates whether we should try to process all the data including the lookahead, or if we
     // should wait until we have at least one window size of data before doing anything.
-    let finish = flush == Flush::Finish || flush == Flush::Sync;
-    let sync = flush == Flush::Sync;
+    let finish = flush != Flush::None;
+    // Ending a block without finishing the stream is handled the same way as syncing here.
+    let sync = finish && flush != Flush::Finish;
 
     let mut current_position = 0;
 
     // The current status of the encoding.
     let mut status = LZ77Status::EndBlock;
 
-    // Whether warm up the hash chain with the two first values.
+    // Whether warm up the hash chain with the first values.
     let mut add_initial = true;
 
-    // If we have synced, add the two first bytes to the hash as they couldn't be added before.
+    let lookahead = state.matcher.lookahead();
+    let previous_end = buffer.current_end();
+
+    // Add data to the input buffer and keep a reference to the slice of data not added yet.
+    let mut remaining_data = buffer.add_data(data);
+
+    // If we have synced, add the last few positions before the new data to the matcher, as they
+    // couldn't be added before.
     if state.was_synced {
-        if buffer.current_end() > 2 {
-            let pos_add = buffer.current_end() - 2;
-            for (n, &b) in data.iter().take(2).enumerate() {
-                state.hash_table.add_hash_value(n + pos_add, b);
-            }
+        if previous_end > lookahead {
+            let pos_add = previous_end - lookahead;
+            let added = cmp::min(buffer.current_end() - previous_end, lookahead);
+            state
+                .matcher
+                .add_range(buffer.get_buffer(), pos_add..pos_add + added);
             add_initial = false;
         }
         state.was_synced = false;
     }
 
-    // Add data to the input buffer and keep a reference to the slice of data not added yet.
-    let mut remaining_data = buffer.add_data(data);
-
     loop {
         // Note if there is a pending byte from the previous call to process_chunk,
         // so we get the block input size right.
         let pending_previous = state.pending_byte_as_num();
 
-        assert!(writer.buffer_length() <= (window_size * 2));
+        assert!(writer.buffer_length() <= writer.max_length());
         // The process is a bit different for the first 32k bytes.
         // TODO: There is a lot of duplicate code between the two branches here, we should be able
         // to simplify this.
@@ -625,14 +1062,13 @@ pub fn lz77_compress_block(
             // Don't do anything until we are either flushing, or we have at least one window of
             // data.
             if buffer.current_end() >= (window_size * 2) + MAX_MATCH || finish {
-                if buffer.get_buffer().len() >= 2
+                if buffer.get_buffer().len() >= lookahead
                     && add_initial
                     && state.current_block_input_bytes == 0
                 {
-                    let b = buffer.get_buffer();
-                    // Warm up the hash with the two first values, so we can find  matches at
+                    // Warm up the hash with the first values, so we can find matches at
                     // index 0.
-                    state.hash_table.add_initial_hash_values(b[0], b[1]);
+                    state.matcher.add_initial(buffer.get_buffer());
                     add_initial = false;
                 }
 
@@ -640,15 +1076,15 @@ pub fn lz77_compress_block(
 
                 let start = state.overlap;
 
-                let (overlap, p_status) = process_chunk(
+                if finish && remaining_data.is_none() {
+                    writer.merge_trailing_values(buffer.current_end().saturating_sub(start));
+                }
+
+                let (overlap, p_status) = process_state_chunk(
                     buffer.get_buffer(),
                     &(start..first_chunk_end),
-                    &mut state.match_state,
-                    &mut state.hash_table,
+                    state,
                     &mut writer,
-                    state.max_hash_checks,
-                    state.lazy_if_less_than as usize,
-                    state.matching_type,
                 );
 
                 state.overlap = overlap;
@@ -656,14 +1092,19 @@ pub fn lz77_compress_block(
 
                 // If the buffer is full, we want to end the block.
                 if let ProcessStatus::BufferFull(written) = p_status {
-                    state.overlap = if overlap > 0 { overlap } else { written };
+                    state.overlap = if overlap > 0 {
+                        // The last match went past the end of the first window, so the next
+                        // call continues after it in the second window.
+                        state.is_first_window = false;
+                        overlap
+                    } else {
+                        written
+                    };
                     status = LZ77Status::EndBlock;
                     current_position = written - state.pending_byte_as_num();
-                    state.current_block_input_bytes += (written - start
-                        + overlap
-                        + pending_previous
-                        - state.pending_byte_as_num())
-                        as u64;
+                    // `written` is past the end of the last match, so it includes the overlap.
+                    state.current_block_input_bytes +=
+                        (written - start + pending_previous - state.pending_byte_as_num()) as u64;
                     break;
                 }
 
@@ -698,14 +1139,14 @@ pub fn lz77_compress_block(
                 break;
             }
         } else if buffer.current_end() >= (window_size * 2) + MAX_MATCH || finish {
-            if buffer.current_end() >= window_size + 2 {
-                for (n, &h) in buffer.get_buffer()[window_size + 2..]
-                    .iter()
-                    .enumerate()
-                    .take(state.bytes_to_hash)
-                {
-                    state.hash_table.add_hash_value(window_size + n, h);
-                }
+            if buffer.current_end() >= window_size + lookahead {
+                let to_hash = cmp::min(
+                    state.bytes_to_hash,
+                    buffer.current_end() - window_size - lookahead,
+                );
+                state
+                    .matcher
+                    .add_range(buffer.get_buffer(), window_size..window_size + to_hash);
                 state.bytes_to_hash = 0;
             }
             // This isn't the first chunk, so we start reading at one window in in the
@@ -716,16 +1157,12 @@ pub fn lz77_compress_block(
             // or stop because we are at the end of the input data.
             let end = cmp::min(window_size * 2, buffer.current_end());
 
-            let (overlap, p_status) = process_chunk(
-                buffer.get_buffer(),
-                &(start..end),
-                &mut state.match_state,
-                &mut state.hash_table,
-                &mut writer,
-                state.max_hash_checks,
-                state.lazy_if_less_than as usize,
-                state.matching_type,
-            );
+            if finish && remaining_data.is_none() {
+                writer.merge_trailing_values(buffer.current_end().saturating_sub(start));
+            }
+
+            let (overlap, p_status) =
+                process_state_chunk(buffer.get_buffer(), &(start..end), state, &mut writer);
 
             state.bytes_to_hash = overlap;
 
@@ -741,7 +1178,10 @@ pub fn lz77_compress_block(
                     // If we are at the end of the window, make sure we slide the buffer and the
                     // hash table.
                     if state.max_hash_checks > 0 {
-                        state.hash_table.slide(window_size);
+                        state.matcher.slide(window_size);
+                    }
+                    if let Some(ref mut repeats) = state.repeats {
+                        repeats.slide(window_size);
                     }
                     remaining_data = buffer.slide(remaining_data.unwrap_or(&[]));
                     overlap
@@ -791,7 +1231,10 @@ pub fn lz77_compress_block(
                 // We only need to remember 2^15 bytes back (the maximum distance allowed by the
                 // deflate spec).
                 if state.max_hash_checks > 0 {
-                    state.hash_table.slide(window_size);
+                    state.matcher.slide(window_size);
+                }
+                if let Some(ref mut repeats) = state.repeats {
+                    repeats.slide(window_size);
                 }
 
                 // Also slide the buffer, discarding data we no longer need and adding new data.
@@ -886,7 +1329,12 @@ impl TestStruct {
         matching_type: MatchingType,
     ) -> TestStruct {
         TestStruct {
-            state: LZ77State::new(max_hash_checks, lazy_if_less_than, matching_type),
+            state: LZ77State::new(
+                max_hash_checks,
+                lazy_if_less_than,
+                matching_type,
+                &StateAllocator::global(),
+            ),
             buffer: InputBuffer::empty(),
             writer: DynamicWriter::new(),
         }
@@ -929,9 +1377,14 @@ pub fn lz77_compress_conf(
         lazy_if_less_than,
         matching_type,
     ));
+    Some(lz77_compress_with(&mut test_boxed, data))
+}
+
+/// Compress a slice using the provided state.
+#[cfg(test)]
+fn lz77_compress_with(test: &mut TestStruct, data: &[u8]) -> Vec<LZValue> {
     let mut out = Vec::<LZValue>::with_capacity(data.len() / 3);
     {
-        let test = test_boxed.as_mut();
         let mut slice = data;
 
         while !test.state.is_last_block {
@@ -948,7 +1401,7 @@ pub fn lz77_compress_conf(
         }
     }
 
-    Some(out)
+    out
 }
 
 #[cfg(test)]
@@ -956,25 +1409,16 @@ mod test {
     use super::*;
 
     use crate::chained_hash_table::WINDOW_SIZE;
-    use crate::compression_options::DEFAULT_LAZY_IF_LESS_THAN;
+    use crate::compression_options::{DEFAULT_LAZY_IF_LESS_THAN, DEFAULT_MAX_HASH_CHECKS};
     use crate::lzvalue::{ld, lit, LZType, LZValue};
-    use crate::output_writer::MAX_BUFFER_LENGTH;
-    use crate::test_utils::get_test_data;
+    use crate::output_writer::{MAX_BUFFER_LENGTH, MIN_BLOCK_LENGTH};
+    use crate::test_utils::{get_random_data, get_test_data};
+    use crate::tokens::{write_token_listing, Token};
 
     /// Helper function to print the output from the lz77 compression function
     fn print_output(input: &[LZValue]) {
-        let mut output = vec![];
-        for l in input {
-            match l.value() {
-                LZType::Literal(l) => output.push(l),
-                LZType::StoredLengthDistance(l, d) => {
-                    output.extend(format!("<L {}>", l.actual_length()).into_bytes());
-                    output.extend(format!("<D {}>", d).into_bytes())
-                }
-            }
-        }
-
-        println!("\"{}\"", String::from_utf8(output).unwrap());
+        let tokens: Vec<Token> = input.iter().map(|&v| Token::from(v)).collect();
+        write_token_listing(&tokens, std::io::stdout()).unwrap();
     }
 
     /// Test that a short string from an example on SO compresses correctly
@@ -1026,6 +1470,29 @@ mod test {
         }
     }
 
+    /// Test that the lazy search is only skipped when the previous match can't be beaten.
+    #[test]
+    fn lazy_match_can_improve() {
+        let data = [0u8; 300];
+        assert!(match_can_improve(&data, 1, 0));
+        assert!(match_can_improve(&data, 1, MAX_MATCH - 1));
+        assert!(!match_can_improve(&data, 1, MAX_MATCH));
+        assert!(match_can_improve(&data, 200, 99));
+        assert!(!match_can_improve(&data, 200, 100));
+
+        // A run of the same byte consists of maximum length matches, so the lazy search
+        // is skipped, which should not change the output.
+        let compressed = lz77_compress(&data).unwrap();
+        assert_eq!(compressed[0].value(), lit(0).value());
+        if let LZType::StoredLengthDistance(l, d) = compressed[1].value() {
+            assert_eq!(l.actual_length(), MAX_MATCH as u16);
+            assert_eq!(d, 1);
+        } else {
+            panic!();
+        }
+        assert_eq!(decompress_lz77(&compressed), &data[..]);
+    }
+
     fn roundtrip(data: &[u8]) {
         let compressed = super::lz77_compress(&data).unwrap();
         let decompressed = decompress_lz77(&compressed);
@@ -1083,7 +1550,12 @@ mod test {
         let mut writer = DynamicWriter::new();
 
         let mut buffer = InputBuffer::empty();
-        let mut state = LZ77State::new(4096, DEFAULT_LAZY_IF_LESS_THAN, MatchingType::Lazy);
+        let mut state = LZ77State::new(
+            4096,
+            DEFAULT_LAZY_IF_LESS_THAN,
+            MatchingType::Lazy,
+            &StateAllocator::global(),
+        );
         let status = lz77_compress_block_finish(data, &mut state, &mut buffer, &mut writer);
         assert_eq!(status.1, LZ77Status::Finished);
         assert!(&buffer.get_buffer()[..data.len()] == data);
@@ -1100,7 +1572,12 @@ mod test {
         let mut writer = DynamicWriter::new();
 
         let mut buffer = InputBuffer::empty();
-        let mut state = LZ77State::new(0, DEFAULT_LAZY_IF_LESS_THAN, MatchingType::Lazy);
+        let mut state = LZ77State::new(
+            0,
+            DEFAULT_LAZY_IF_LESS_THAN,
+            MatchingType::Lazy,
+            &StateAllocator::global(),
+        );
         let (bytes_consumed, status) =
             lz77_compress_block_finish(&data, &mut state, &mut buffer, &mut writer);
         assert_eq!(
@@ -1121,6 +1598,40 @@ mod test {
         assert_eq!(status, LZ77Status::EndBlock);
     }
 
+    #[test]
+    /// Test blocks ending on a match that goes past the end of the first window, which happens
+    /// with the small buffers of low memory levels, and more often with two-step lazy matching.
+    fn block_end_past_first_window() {
+        let data = get_test_data();
+        let data = &data[44584..77584];
+        for buffer_length in (MAX_BUFFER_LENGTH >> 2) - 8..(MAX_BUFFER_LENGTH >> 2) + 8 {
+            let mut test = TestStruct::new();
+            test.state.set_two_step_lazy(true);
+            test.writer.set_buffer_length(buffer_length);
+
+            let mut compressed = Vec::new();
+            let mut input_bytes = 0;
+            let mut slice = &data[..];
+            while !test.state.is_last_block {
+                let (consumed, _) = lz77_compress_block_finish(
+                    slice,
+                    &mut test.state,
+                    &mut test.buffer,
+                    &mut test.writer,
+                );
+                slice = &slice[consumed..];
+                compressed.extend(test.writer.get_buffer());
+                input_bytes += test.state.current_block_input_bytes();
+                test.state.reset_input_bytes();
+                test.writer.clear();
+            }
+
+            let decompressed = decompress_lz77(&compressed);
+            assert!(decompressed == data, "buffer length {}", buffer_length);
+            assert_eq!(input_bytes, data.len() as u64);
+        }
+    }
+
     #[test]
     fn multiple_inputs() {
         let data = b"Badger badger bababa test data 25 asfgestghresjkgh";
@@ -1231,10 +1742,32 @@ mod test {
         for _ in 0..MAX_BUFFER_LENGTH - 4 {
             assert!(state.writer.write_literal(0) == BufferStatus::NotFull);
         }
-        state.compress_block(&[1, 2, 3, 1, 2, 3, 4], true);
+        // Add enough data after the match that it's not merged into the current block.
+        let mut data = vec![1, 2, 3, 1, 2, 3, 4];
+        data.resize(data.len() + MIN_BLOCK_LENGTH, 5);
+        state.compress_block(&data, true);
         assert!(*state.writer.get_buffer().last().unwrap() == LZValue::length_distance(3, 3));
     }
 
+    #[test]
+    fn merge_trailing_values() {
+        let data = get_test_data();
+        // Nothing but literals are output when there are no hash checks.
+        let mut state = TestStruct::with_config(0, 0, MatchingType::Greedy);
+        let len = MAX_BUFFER_LENGTH + 500;
+        let (_, status, _) = state.compress_block(&data[..len], true);
+        assert_eq!(status, LZ77Status::Finished);
+        assert_eq!(state.writer.buffer_length(), len);
+        assert!(decompress_lz77(state.writer.get_buffer()) == &data[..len]);
+
+        // With more data left, a new block is started as usual.
+        let mut state = TestStruct::with_config(0, 0, MatchingType::Greedy);
+        let len = MAX_BUFFER_LENGTH + MIN_BLOCK_LENGTH;
+        let (_, status, _) = state.compress_block(&data[..len], true);
+        assert_eq!(status, LZ77Status::EndBlock);
+        assert_eq!(state.writer.buffer_length(), MAX_BUFFER_LENGTH);
+    }
+
     /// Test buffer fill for the lazy match algorithm when adding a pending byte at the end.
     fn buffer_test_add_end(_data: &[u8]) {
         // This is disabled while the buffer size has not been stabilized.
@@ -1264,6 +1797,185 @@ mod test {
         // ------------l2 l4  <-ld4,20-> l1 l1  <---ld5,10-->
         assert!(dec == [2, 4, 5, 5, 5, 5, 1, 1, 5, 5, 2, 4, 5]);
     }
+
+    fn max_distance(values: &[LZValue]) -> u16 {
+        values
+            .iter()
+            .map(|v| match v.value() {
+                LZType::StoredLengthDistance(_, distance) => distance,
+                LZType::Literal(_) => 0,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Check that huffman-only compression outputs every byte as a literal.
+    #[test]
+    fn huffman_only() {
+        let data = get_test_data();
+        let mut test = Box::new(TestStruct::with_config(0, 0, MatchingType::Greedy));
+        let compressed = lz77_compress_with(&mut test, &data);
+        assert_eq!(compressed.len(), data.len());
+        assert_eq!(max_distance(&compressed), 0);
+        assert!(decompress_lz77(&compressed) == data);
+    }
+
+    /// Check that no matches shorter than the minimum length are output.
+    #[test]
+    fn min_match() {
+        let data = get_test_data();
+        for &matching_type in &[MatchingType::Lazy, MatchingType::Greedy] {
+            let mut test = Box::new(TestStruct::with_config(
+                DEFAULT_MAX_HASH_CHECKS,
+                DEFAULT_LAZY_IF_LESS_THAN,
+                matching_type,
+            ));
+            test.state.set_min_match(6);
+            let compressed = lz77_compress_with(&mut test, &data);
+            assert!(compressed.len() < data.len() / 2);
+            for value in &compressed {
+                if let LZType::StoredLengthDistance(length, _) = value.value() {
+                    assert!(length.actual_length() >= 6);
+                }
+            }
+            assert!(decompress_lz77(&compressed) == data);
+        }
+    }
+
+    /// Check that only matches of four bytes or more are found when hashing four bytes.
+    #[test]
+    fn hash_four_bytes() {
+        let data = b"abcXabcYabcdZabcdWabcd";
+        for &matching_type in &[MatchingType::Lazy, MatchingType::Greedy] {
+            let mut test = Box::new(TestStruct::with_config(
+                DEFAULT_MAX_HASH_CHECKS,
+                DEFAULT_LAZY_IF_LESS_THAN,
+                matching_type,
+            ));
+            test.state.set_hash_four_bytes(true);
+            let compressed = lz77_compress_with(&mut test, data);
+            let mut found = false;
+            for value in &compressed {
+                if let LZType::StoredLengthDistance(length, _) = value.value() {
+                    assert!(length.actual_length() >= 4);
+                    found = true;
+                }
+            }
+            assert!(found);
+            assert_eq!(decompress_lz77(&compressed), &data[..]);
+        }
+
+        let data = get_test_data();
+        let mut test = TestStruct::new();
+        test.state.set_hash_four_bytes(true);
+        let compressed = lz77_compress_with(&mut test, &data);
+        assert!(decompress_lz77(&compressed) == data);
+    }
+
+    /// Check that matches are not further back than the configured window size.
+    #[test]
+    fn small_window() {
+        let data = get_test_data();
+        for &matching_type in &[MatchingType::Lazy, MatchingType::Greedy] {
+            let mut test = Box::new(TestStruct::with_config(
+                HIGH_MAX_HASH_CHECKS,
+                HIGH_LAZY_IF_LESS_THAN,
+                matching_type,
+            ));
+            let full = lz77_compress_with(&mut test, &data);
+            assert!(max_distance(&full) > 1024);
+
+            let mut test = Box::new(TestStruct::with_config(
+                HIGH_MAX_HASH_CHECKS,
+                HIGH_LAZY_IF_LESS_THAN,
+                matching_type,
+            ));
+            test.state.set_window_size(1024);
+            let compressed = lz77_compress_with(&mut test, &data);
+            assert!(max_distance(&compressed) <= 1024);
+            assert!(decompress_lz77(&compressed) == data);
+        }
+    }
+
+    /// Check that matches of three bytes far back are output as literals.
+    #[test]
+    fn too_far() {
+        // Filler without any of the bytes in "xyz".
+        let filler = |start: usize, len: usize| (start..start + len).map(|i| (i % 101) as u8);
+        let mut data = b"xyz".to_vec();
+        data.extend(filler(0, 8189));
+        data.extend_from_slice(b"xyz");
+        data.extend(filler(50, 8190));
+        data.extend_from_slice(b"xyz");
+        for &matching_type in &[MatchingType::Lazy, MatchingType::Greedy] {
+            let compressed =
+                lz77_compress_conf(&data, HIGH_MAX_HASH_CHECKS, NO_RLE, matching_type).unwrap();
+            assert!(decompress_lz77(&compressed) == data);
+            let matches: Vec<_> = compressed
+                .iter()
+                .filter_map(|v| match v.value() {
+                    LZType::StoredLengthDistance(l, d) if l.actual_length() == 3 => Some(d),
+                    _ => None,
+                })
+                .collect();
+            // The second "xyz" is just close enough to the first one, the third too far from the
+            // second.
+            assert_eq!(matches, [8192]);
+        }
+    }
+
+    #[test]
+    fn skip_incompressible() {
+        let mut data = get_random_data(300_000);
+        let text = get_test_data();
+        data.extend_from_slice(&text[..100_000]);
+        // Random data repeating within the window.
+        let repeated = data[..20_000].to_vec();
+        for _ in 0..5 {
+            data.extend_from_slice(&repeated);
+        }
+
+        let mut test = Box::new(TestStruct::new());
+        test.state.set_skip_incompressible(true);
+        let compressed = lz77_compress_with(&mut test, &data);
+        assert!(decompress_lz77(&compressed) == data);
+
+        let mut test = Box::new(TestStruct::new());
+        let full = lz77_compress_with(&mut test, &data);
+        // Matching is only skipped in the random part, and picks up again on the text and the
+        // repeated data.
+        assert!(compressed.len() > full.len());
+        assert!(compressed.len() < 300_000 + 50_000 + 20_000);
+        assert!(compressed[compressed.len() - 100..] == full[full.len() - 100..]);
+    }
+
+    #[test]
+    fn skipped_chunk_pending_values() {
+        let data = get_test_data();
+        let mut writer = DynamicWriter::new();
+        // A match starting at the byte before the chunk and extending past it.
+        let mut state = ChunkState::new();
+        state.current_length = 5;
+        state.current_distance = 10;
+        state.cur_byte = data[19];
+        state.add = true;
+        let (overlap, status) = process_chunk_skipped(&data, &(20..22), &mut state, &mut writer);
+        assert_eq!(overlap, 2);
+        assert!(matches!(status, ProcessStatus::Ok));
+        assert_eq!(writer.get_buffer(), [ld(5, 10)]);
+        assert!(!state.add);
+
+        // A byte pending from the previous chunk.
+        writer.clear();
+        state.cur_byte = data[39];
+        state.add = true;
+        let (overlap, _) = process_chunk_skipped(&data, &(40..42), &mut state, &mut writer);
+        assert_eq!(overlap, 0);
+        assert_eq!(
+            writer.get_buffer(),
+            [lit(data[39]), lit(data[40]), lit(data[41])]
+        );
+    }
 }
 
 #[cfg(all(test, feature = "benchmarks"))]
@@ -0,0 +1,192 @@
+//! Compression of data that is already fully available in memory, such as a memory-mapped file.
+//!
+//! The normal encoders copy the input into an internal buffer one window at a time. When the
+//! input is a memory-mapped file, this means every byte is read from the page cache and then
+//! copied, doubling the memory bandwidth used. The function in this module instead reads the data
+//! directly from the provided slice, and advises the operating system that the memory will be
+//! accessed sequentially, so it can read ahead aggressively and drop pages once they are used.
+use std::io;
+use std::io::Write;
+
+use crate::compress::Flush;
+use crate::compression_options::CompressionOptions;
+use crate::deflate_state::DeflateState;
+use crate::input_buffer::SliceWindow;
+use crate::writer::compress_until_done;
+
+/// Tell the operating system that `region` will be read sequentially.
+///
+/// Only the pages that lie entirely inside `region` are advised, as `madvise` works on whole pages
+/// and the memory around the slice may belong to something else. This is only a hint, so any
+/// errors are ignored.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn advise_sequential(region: &[u8]) {
+    use std::os::raw::{c_int, c_long, c_void};
+
+    extern "C" {
+        fn madvise(addr: *mut c_void, len: usize, advice: c_int) -> c_int;
+        fn sysconf(name: c_int) -> c_long;
+    }
+
+    // MADV_SEQUENTIAL has the same value on all the platforms listed above, but _SC_PAGESIZE
+    // doesn't.
+    const MADV_SEQUENTIAL: c_int = 2;
+    #[cfg(target_os = "linux")]
+    const SC_PAGESIZE: c_int = 30;
+    #[cfg(target_os = "android")]
+    const SC_PAGESIZE: c_int = 39;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    const SC_PAGESIZE: c_int = 29;
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    const SC_PAGESIZE: c_int = 47;
+    #[cfg(any(target_os = "netbsd", target_os = "openbsd"))]
+    const SC_PAGESIZE: c_int = 28;
+
+    // # Safety
+    // `sysconf` has no preconditions.
+    let page_size = unsafe { sysconf(SC_PAGESIZE) };
+    if page_size <= 0 {
+        return;
+    }
+    let start = region.as_ptr() as usize;
+    if let Some((aligned_start, len)) = whole_pages(start, start + region.len(), page_size as usize)
+    {
+        // # Safety
+        // The range only covers pages inside `region`, which is borrowed for the duration of the
+        // call. `MADV_SEQUENTIAL` only changes the read-ahead behaviour for the pages, and
+        // doesn't alter the memory contents.
+        unsafe {
+            let _ = madvise(aligned_start as *mut c_void, len, MADV_SEQUENTIAL);
+        }
+    }
+}
+
+/// The start and length of the part of the memory from `start` to `end` made up of whole pages of
+/// `page_size` bytes, if there is any.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn whole_pages(start: usize, end: usize, page_size: usize) -> Option<(usize, usize)> {
+    if !page_size.is_power_of_two() {
+        return None;
+    }
+    let first = start.checked_add(page_size - 1)? & !(page_size - 1);
+    let last = end & !(page_size - 1);
+    if first < last {
+        Some((first, last - first))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+fn advise_sequential(_: &[u8]) {}
+
+/// Compress a region of memory, such as a memory-mapped file, with DEFLATE compression, writing
+/// the compressed data to `writer`.
+///
+/// Unlike the writers in the [`write`](write/index.html) module, the data is read directly from
+/// `region` rather than being copied into an internal buffer first, and the operating system is
+/// advised that the region will be accessed sequentially where this is supported.
+///
+/// The advice is given with `madvise(MADV_SEQUENTIAL)` for the whole pages inside `region`, and
+/// is not reset afterwards, so it stays in effect for those pages of the caller's mapping.
+///
+/// The output is identical to the output of [`deflate_bytes_conf`](fn.deflate_bytes_conf.html)
+/// with the same options.
+///
+/// Returns the writer once all the data has been written.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_mapped, Compression};
+///
+/// // This would normally be the contents of a memory-mapped file.
+/// let region = b"This is some test data";
+/// let compressed_data = deflate_mapped(region, Vec::new(), Compression::Default).unwrap();
+/// # let _ = compressed_data;
+/// ```
+pub fn deflate_mapped<W: Write, O: Into<CompressionOptions>>(
+    region: &[u8],
+    writer: W,
+    options: O,
+) -> io::Result<W> {
+    advise_sequential(region);
+    // We use a box here to avoid putting the buffers on the stack.
+    let mut deflate_state = Box::new(DeflateState::with_input_window(
+        options.into(),
+        writer,
+        SliceWindow::new(region),
+    ));
+    compress_until_done(region, &mut deflate_state, Flush::Finish)?;
+    Ok(deflate_state.inner.take().expect("Missing writer!"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, get_test_data};
+
+    #[test]
+    fn mapped_same_as_buffered() {
+        let data = get_test_data();
+        for &options in &[
+            CompressionOptions::default(),
+            CompressionOptions::fast(),
+            CompressionOptions::rle(),
+            CompressionOptions::high(),
+        ] {
+            let compressed = deflate_mapped(&data, Vec::new(), options).unwrap();
+            assert!(compressed == crate::deflate_bytes_conf(&data, options));
+            assert!(decompress_to_end(&compressed) == data);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn whole_pages_inside() {
+        assert_eq!(whole_pages(4096, 3 * 4096, 4096), Some((4096, 2 * 4096)));
+        assert_eq!(
+            whole_pages(100, 3 * 4096 + 100, 4096),
+            Some((4096, 2 * 4096))
+        );
+        assert_eq!(whole_pages(100, 2 * 4096, 16384), None);
+        assert_eq!(whole_pages(100, 4096, 4096), None);
+        assert_eq!(whole_pages(0, 4096, 3000), None);
+    }
+
+    #[test]
+    fn mapped_short() {
+        for data in &[&[][..], &[1][..], &[5, 5, 5, 5, 5, 6][..]] {
+            let compressed =
+                deflate_mapped(data, Vec::new(), CompressionOptions::default()).unwrap();
+            assert_eq!(&decompress_to_end(&compressed)[..], *data);
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//! The search structures used to find matches in the window.
+//!
+//! The lz77 code only adds positions to and searches for matches through the `Matcher` trait, so
+//! a different search structure can be used by implementing the trait and adding a variant for it
+//! to `MatcherType`, which is used to pick one in the compression options, and `MatchFinder`.
+use std::ops::Range;
+
+use crate::allocator::StateAllocator;
+use crate::binary_tree::BinaryTree;
+use crate::chained_hash_table::{ChainedHashTable, HashFunction};
+use crate::matching::longest_match;
+
+/// The search structure used to find matches.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum MatcherType {
+    /// Hash chains like the ones used by zlib, linking each position to the previous one with
+    /// the same hash of its first few bytes. How many of the positions in a chain are checked
+    /// is limited by `max_hash_checks`.
+    #[default]
+    HashChains,
+    /// Binary search trees of the positions with the same hash of their first four bytes,
+    /// ordered by the data following them, like the `bt4` match finder in LZMA.
+    ///
+    /// Adding positions is slower than with hash chains, but the longest match is found by
+    /// checking only a few positions, even on highly redundant data where hash chains get long
+    /// and slow to search. The tree finds better matches within the same `max_hash_checks`, so
+    /// it's best suited to the highest compression levels. The hashing options only apply to hash
+    /// chains.
+    BinaryTree,
+}
+
+/// A structure positions in the input are added to, which can then be searched for the longest
+/// earlier match for the data at a position.
+///
+/// The positions are relative to the start of the input buffer, and have to be added in order.
+/// Positions that are skipped are simply not found as matches.
+pub trait Matcher {
+    /// The number of bytes following a position that are needed to add it.
+    fn lookahead(&self) -> usize;
+
+    /// Start adding positions at the start of `data`, which has to hold at least `lookahead`
+    /// bytes. This is also used to start over after skipping some positions.
+    fn add_initial(&mut self, data: &[u8]);
+
+    /// Add `position`, which has to be after the last position added.
+    ///
+    /// `data` has to hold the byte `lookahead` bytes after it.
+    fn add(&mut self, data: &[u8], position: usize);
+
+    /// Add all the positions in `positions`.
+    fn add_range(&mut self, data: &[u8], positions: Range<usize>) {
+        for position in positions {
+            self.add(data, position);
+        }
+    }
+
+    /// Find the longest match for the data at `position`, which has to have been added, that is
+    /// longer than `prev_length`.
+    ///
+    /// At most `max_checks` candidates are checked, and the search stops at a match of at least
+    /// `nice_length` bytes. Returns the length and distance of the match, or `(0, 0)` if there
+    /// is none.
+    fn longest_match(
+        &self,
+        data: &[u8],
+        position: usize,
+        prev_length: usize,
+        max_checks: u16,
+        nice_length: usize,
+    ) -> (usize, usize);
+
+    /// Forget about the first `bytes` bytes of input, as the input buffer has been slid back by
+    /// this amount.
+    fn slide(&mut self, bytes: usize);
+
+    /// Forget about all the positions added.
+    fn reset(&mut self);
+
+    /// The maximum distance of the matches found.
+    fn window_size(&self) -> usize;
+
+    /// Limit the distance of matches to `window_size`, which has to be a power of two no larger
+    /// than the default window size.
+    fn set_window_size(&mut self, window_size: usize);
+
+    /// The number of bytes of heap memory used.
+    fn heap_size(&self) -> usize;
+}
+
+impl Matcher for BinaryTree {
+    #[inline]
+    fn lookahead(&self) -> usize {
+        BinaryTree::lookahead(self)
+    }
+
+    fn add_initial(&mut self, _: &[u8]) {}
+
+    #[inline]
+    fn add(&mut self, data: &[u8], position: usize) {
+        BinaryTree::add(self, data, position)
+    }
+
+    #[inline]
+    fn longest_match(
+        &self,
+        data: &[u8],
+        position: usize,
+        prev_length: usize,
+        max_checks: u16,
+        nice_length: usize,
+    ) -> (usize, usize) {
+        BinaryTree::longest_match(self, data, position, prev_length, max_checks, nice_length)
+    }
+
+    fn slide(&mut self, bytes: usize) {
+        BinaryTree::slide(self, bytes)
+    }
+
+    fn reset(&mut self) {
+        BinaryTree::reset(self)
+    }
+
+    fn window_size(&self) -> usize {
+        BinaryTree::window_size(self)
+    }
+
+    fn set_window_size(&mut self, window_size: usize) {
+        BinaryTree::set_window_size(self, window_size)
+    }
+
+    fn heap_size(&self) -> usize {
+        BinaryTree::heap_size(self)
+    }
+}
+
+impl Matcher for ChainedHashTable {
+    #[inline]
+    fn lookahead(&self) -> usize {
+        self.hash_lookahead()
+    }
+
+    fn add_initial(&mut self, data: &[u8]) {
+        self.add_initial_hash_values(&data[..self.hash_lookahead()]);
+    }
+
+    #[inline]
+    fn add(&mut self, data: &[u8], position: usize) {
+        self.add_hash_value(position, data[position + self.hash_lookahead()]);
+    }
+
+    #[inline]
+    fn add_range(&mut self, data: &[u8], positions: Range<usize>) {
+        let lookahead = self.hash_lookahead();
+        // Update the hash manually here to keep it in a register.
+        let mut hash = self.current_hash();
+        for position in positions {
+            hash = self.next_hash(hash, data[position + lookahead]);
+            self.add_with_hash(position, hash);
+        }
+        // Write the hash back once we are done.
+        self.set_hash(hash);
+    }
+
+    #[inline]
+    fn longest_match(
+        &self,
+        data: &[u8],
+        position: usize,
+        prev_length: usize,
+        max_checks: u16,
+        nice_length: usize,
+    ) -> (usize, usize) {
+        longest_match(data, self, position, prev_length, max_checks, nice_length)
+    }
+
+    fn slide(&mut self, bytes: usize) {
+        ChainedHashTable::slide(self, bytes)
+    }
+
+    fn reset(&mut self) {
+        ChainedHashTable::reset(self)
+    }
+
+    fn window_size(&self) -> usize {
+        ChainedHashTable::window_size(self)
+    }
+
+    fn set_window_size(&mut self, window_size: usize) {
+        ChainedHashTable::set_window_size(self, window_size)
+    }
+
+    fn heap_size(&self) -> usize {
+        ChainedHashTable::heap_size(self)
+    }
+}
+
+/// The matcher used by an lz77 state, one variant for each `MatcherType`.
+///
+/// The chunk processing functions are generic over the matcher, so they are called once for the
+/// matcher in use rather than going through this for each position.
+#[derive(Clone)]
+pub enum MatchFinder {
+    HashChains(ChainedHashTable),
+    BinaryTree(BinaryTree),
+}
+
+/// Evaluate `$body` with `$matcher` bound to a reference to the matcher in the `MatchFinder`
+/// reference `$finder`.
+macro_rules! with_matcher {
+    ($finder:expr, $matcher:ident => $body:expr) => {
+        match $finder {
+            MatchFinder::HashChains($matcher) => $body,
+            MatchFinder::BinaryTree($matcher) => $body,
+        }
+    };
+}
+
+impl MatchFinder {
+    /// Create a matcher of the type `matcher_type`, allocating it with `allocator`.
+    pub fn new_in(matcher_type: MatcherType, allocator: &StateAllocator) -> MatchFinder {
+        match matcher_type {
+            MatcherType::HashChains => MatchFinder::HashChains(ChainedHashTable::new_in(allocator)),
+            MatcherType::BinaryTree => MatchFinder::BinaryTree(BinaryTree::new_in(allocator)),
+        }
+    }
+
+    /// The type of the matcher.
+    pub fn matcher_type(&self) -> MatcherType {
+        match *self {
+            MatchFinder::HashChains(_) => MatcherType::HashChains,
+            MatchFinder::BinaryTree(_) => MatcherType::BinaryTree,
+        }
+    }
+
+    /// The hash chains used by the matcher, if any.
+    fn hash_table_mut(&mut self) -> Option<&mut ChainedHashTable> {
+        match *self {
+            MatchFinder::HashChains(ref mut hash_table) => Some(hash_table),
+            MatchFinder::BinaryTree(_) => None,
+        }
+    }
+
+    /// Use the provided hash function rather than the default one, if the matcher uses hashing.
+    pub fn set_hash_function(&mut self, hash_function: Option<HashFunction>) {
+        if let Some(hash_table) = self.hash_table_mut() {
+            hash_table.set_hash_function(hash_function);
+        }
+    }
+
+    /// Hash four bytes rather than three for each position, if the matcher uses hashing.
+    pub fn set_hash_four_bytes(&mut self, four_bytes: bool) {
+        if let Some(hash_table) = self.hash_table_mut() {
+            hash_table.set_hash_four_bytes(four_bytes);
+        }
+    }
+
+    /// Use only the lowest `hash_bits` bits of the hash values, if the matcher uses hashing.
+    pub fn set_hash_bits(&mut self, hash_bits: u8) {
+        if let Some(hash_table) = self.hash_table_mut() {
+            hash_table.set_hash_bits(hash_bits);
+        }
+    }
+}
+
+impl Matcher for MatchFinder {
+    fn lookahead(&self) -> usize {
+        with_matcher!(self, m => m.lookahead())
+    }
+
+    fn add_initial(&mut self, data: &[u8]) {
+        with_matcher!(self, m => m.add_initial(data))
+    }
+
+    fn add(&mut self, data: &[u8], position: usize) {
+        with_matcher!(self, m => m.add(data, position))
+    }
+
+    fn add_range(&mut self, data: &[u8], positions: Range<usize>) {
+        with_matcher!(self, m => m.add_range(data, positions))
+    }
+
+    fn longest_match(
+        &self,
+        data: &[u8],
+        position: usize,
+        prev_length: usize,
+        max_checks: u16,
+        nice_length: usize,
+    ) -> (usize, usize) {
+        with_matcher!(self, m => {
+            m.longest_match(data, position, prev_length, max_checks, nice_length)
+        })
+    }
+
+    fn slide(&mut self, bytes: usize) {
+        with_matcher!(self, m => m.slide(bytes))
+    }
+
+    fn reset(&mut self) {
+        with_matcher!(self, m => m.reset())
+    }
+
+    fn window_size(&self) -> usize {
+        with_matcher!(self, m => m.window_size())
+    }
+
+    fn set_window_size(&mut self, window_size: usize) {
+        with_matcher!(self, m => m.set_window_size(window_size))
+    }
+
+    fn heap_size(&self) -> usize {
+        with_matcher!(self, m => m.heap_size())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    /// Adding a range of positions at once should give the same result as adding them one by
+    /// one.
+    fn add_range_same_as_add() {
+        let data = b"abcabcabcdabcdeabcabcdef";
+        let mut one_by_one = ChainedHashTable::new();
+        let mut range = ChainedHashTable::new();
+        one_by_one.add_initial(data);
+        range.add_initial(data);
+        let end = data.len() - one_by_one.lookahead();
+        for position in 0..end {
+            one_by_one.add(data, position);
+        }
+        range.add_range(data, 0..end);
+        for position in 0..end {
+            assert_eq!(
+                Matcher::longest_match(&one_by_one, data, position, 0, 100, 258),
+                Matcher::longest_match(&range, data, position, 0, 100, 258)
+            );
+        }
+        assert_eq!(
+            Matcher::longest_match(&range, data, 15, 0, 100, 258),
+            (7, 12)
+        );
+    }
+}
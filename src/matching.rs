@@ -1,6 +1,7 @@
 use std::cmp;
+use std::convert::TryInto;
 
-use crate::chained_hash_table::{ChainedHashTable, WINDOW_SIZE};
+use crate::chained_hash_table::ChainedHashTable;
 
 const MAX_MATCH: usize = crate::huffman_table::MAX_MATCH as usize;
 #[cfg(test)]
@@ -11,64 +12,99 @@ const MIN_MATCH: usize = crate::huffman_table::MIN_MATCH as usize;
 /// ones at `pos_to_check`
 #[inline]
 pub fn get_match_length(data: &[u8], current_pos: usize, pos_to_check: usize) -> usize {
-    // Unsafe version using unaligned loads for comparison.
-    // Faster when benching the matching function alone,
-    // but not as significant when running the full thing.
-    /*
-        type Comp = u64;
-
-        use std::mem::size_of;
-
-        let max = cmp::min(data.len() - current_pos, MAX_MATCH);
-        let mut left = max;
-        let s = size_of::<Comp>();
-
-        unsafe {
-            let mut cur = data.as_ptr().offset(current_pos as isize);
-            let mut tc = data.as_ptr().offset(pos_to_check as isize);
-            while left >= s &&
-                  (*(cur as *const Comp) == *(tc as *const Comp)) {
-                      left -= s;
-                      cur = cur.offset(s as isize);
-                      tc = tc.offset(s as isize);
-                  }
-            while left > 0 && *cur == *tc {
-                left -= 1;
-                cur = cur.offset(1);
-                tc = tc.offset(1);
-            }
-        }
-
-        max - left
-    */
-
-    // Slightly faster than naive in single bench.
-    // Does not use unaligned loads.
-    // let l = cmp::min(MAX_MATCH, data.len() - current_pos);
+    let max = cmp::min(data.len() - current_pos, MAX_MATCH);
+    matching_prefix(
+        &data[current_pos..current_pos + max],
+        &data[pos_to_check..pos_to_check + max],
+    )
+}
 
-    // let a = unsafe{&data.get_unchecked(current_pos..current_pos + l)};
-    // let b = unsafe{&data.get_unchecked(pos_to_check..)};
+/// Return the number of bytes at the start of `a` and `b` that are equal.
+///
+/// Both slices have to be the same length. Compares 16 bytes at a time using SSE2 on x86-64.
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn matching_prefix(a: &[u8], b: &[u8]) -> usize {
+    use std::arch::x86_64::{__m128i, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8};
+
+    debug_assert_eq!(a.len(), b.len());
+    let mut len = 0;
+    while len + 16 <= a.len() {
+        // SAFETY: SSE2 is always available on x86-64, and both unaligned loads read 16 bytes that
+        // are inside the slices.
+        let mask = unsafe {
+            let x = _mm_loadu_si128(a.as_ptr().add(len) as *const __m128i);
+            let y = _mm_loadu_si128(b.as_ptr().add(len) as *const __m128i);
+            _mm_movemask_epi8(_mm_cmpeq_epi8(x, y)) as u32
+        };
+        if mask != 0xFFFF {
+            return len + (!mask).trailing_zeros() as usize;
+        }
+        len += 16;
+    }
+    len + matching_prefix_words(&a[len..], &b[len..])
+}
 
-    // let mut len = 0;
+/// Return the number of bytes at the start of `a` and `b` that are equal.
+///
+/// Both slices have to be the same length. Compares 16 bytes at a time using NEON on AArch64.
+#[cfg(all(target_arch = "aarch64", target_endian = "little"))]
+#[inline]
+fn matching_prefix(a: &[u8], b: &[u8]) -> usize {
+    use std::arch::aarch64::{vceqq_u8, vgetq_lane_u64, vld1q_u8, vreinterpretq_u64_u8};
+
+    debug_assert_eq!(a.len(), b.len());
+    let mut len = 0;
+    while len + 16 <= a.len() {
+        // SAFETY: NEON is always available on AArch64, and both loads read 16 bytes that are
+        // inside the slices.
+        let (low, high) = unsafe {
+            let eq = vreinterpretq_u64_u8(vceqq_u8(
+                vld1q_u8(a.as_ptr().add(len)),
+                vld1q_u8(b.as_ptr().add(len)),
+            ));
+            (vgetq_lane_u64(eq, 0), vgetq_lane_u64(eq, 1))
+        };
+        if low != u64::MAX {
+            return len + (low.trailing_ones() / 8) as usize;
+        }
+        if high != u64::MAX {
+            return len + 8 + (high.trailing_ones() / 8) as usize;
+        }
+        len += 16;
+    }
+    len + matching_prefix_words(&a[len..], &b[len..])
+}
 
-    // for (l, r) in a
-    //     .iter()
-    //     .zip(b.iter()) {
-    //         if *l == *r {
-    //             len += 1;
-    //             continue;
-    //         } else {
-    //             break;
-    //         }
-    //     }
-    // len as usize
+/// Return the number of bytes at the start of `a` and `b` that are equal.
+///
+/// Both slices have to be the same length.
+#[cfg(not(any(
+    target_arch = "x86_64",
+    all(target_arch = "aarch64", target_endian = "little")
+)))]
+#[inline]
+fn matching_prefix(a: &[u8], b: &[u8]) -> usize {
+    matching_prefix_words(a, b)
+}
 
-    // Naive version
-    data[current_pos..]
+/// Return the number of bytes at the start of `a` and `b` that are equal, comparing 8 bytes at a
+/// time.
+#[inline]
+fn matching_prefix_words(a: &[u8], b: &[u8]) -> usize {
+    let mut len = 0;
+    for (x, y) in a.chunks_exact(8).zip(b.chunks_exact(8)) {
+        let diff =
+            u64::from_le_bytes(x.try_into().unwrap()) ^ u64::from_le_bytes(y.try_into().unwrap());
+        if diff != 0 {
+            return len + (diff.trailing_zeros() / 8) as usize;
+        }
+        len += 8;
+    }
+    len + a[len..]
         .iter()
-        .zip(data[pos_to_check..].iter())
-        .take(MAX_MATCH)
-        .take_while(|&(&a, &b)| a == b)
+        .zip(&b[len..])
+        .take_while(|&(a, b)| a == b)
         .count()
 }
 
@@ -84,12 +120,14 @@ pub fn get_match_length(data: &[u8], current_pos: usize, pos_to_check: usize) ->
 /// `position`: The position in the data to match against.
 /// `prev_length`: The length of the previous `longest_match` check to compare against.
 /// `max_hash_checks`: The maximum number of matching hash chain positions to check.
+/// `nice_length`: Stop searching when a match at least this long has been found.
 pub fn longest_match(
     data: &[u8],
     hash_table: &ChainedHashTable,
     position: usize,
     prev_length: usize,
     max_hash_checks: u16,
+    nice_length: usize,
 ) -> (usize, usize) {
     // debug_assert_eq!(position, hash_table.current_head() as usize);
 
@@ -99,11 +137,7 @@ pub fn longest_match(
         return (0, 0);
     }
 
-    let limit = if position > WINDOW_SIZE {
-        position - WINDOW_SIZE
-    } else {
-        0
-    };
+    let limit = position.saturating_sub(hash_table.window_size());
 
     // Make sure the length is at least one to simplify the matching code, as
     // otherwise the matching code might underflow.
@@ -149,9 +183,9 @@ pub fn longest_match(
             if length > best_length {
                 best_length = length;
                 best_distance = position - current_head;
-                if length == max_length {
-                    // We are at the max length, so there is no point
-                    // searching any longer
+                if length == max_length || length >= nice_length {
+                    // We are at the max length, or the match is good enough, so there is no
+                    // point searching any longer
                     break;
                 }
             }
@@ -194,11 +228,7 @@ pub fn longest_match_fast(
         return (0, 0);
     }
 
-    let limit = if position > WINDOW_SIZE {
-        position - WINDOW_SIZE
-    } else {
-        0
-    };
+    let limit = position.saturating_sub(hash_table.window_size());
 
     // Make sure the length is at least one to simplify the matching code, as
     // otherwise the matching code might underflow.
@@ -285,12 +315,13 @@ pub fn longest_match_current(data: &[u8], hash_table: &ChainedHashTable) -> (usi
         hash_table.current_head() as usize,
         MIN_MATCH as usize - 1,
         MAX_HASH_CHECKS,
+        MAX_MATCH,
     )
 }
 
 #[cfg(test)]
 mod test {
-    use super::{get_match_length, longest_match, longest_match_fast};
+    use super::{get_match_length, longest_match, longest_match_fast, MAX_MATCH};
     use crate::chained_hash_table::{filled_hash_table, ChainedHashTable, HASH_BYTES};
 
     /// Test that match lengths are calculated correctly
@@ -305,6 +336,26 @@ mod test {
         assert_eq!(l3, 4);
     }
 
+    /// Test that the vectorised comparisons agree with comparing a byte at a time for mismatches
+    /// at every offset.
+    #[test]
+    fn matching_prefix_all_offsets() {
+        let a: Vec<u8> = (0..300).map(|n| (n % 7) as u8).collect();
+        for len in 0..a.len() {
+            for diff in 0..=len {
+                let mut b = a[..len].to_vec();
+                if diff < len {
+                    b[diff] ^= 0x80;
+                }
+                assert_eq!(super::matching_prefix(&a[..len], &b), diff);
+                assert_eq!(super::matching_prefix_words(&a[..len], &b), diff);
+            }
+        }
+        let data = [3u8; 400];
+        assert_eq!(get_match_length(&data, 100, 0), MAX_MATCH);
+        assert_eq!(get_match_length(&data, 390, 1), 10);
+    }
+
     /// Test that we get the longest of the matches
     #[test]
     fn get_longest_match() {
@@ -326,6 +377,29 @@ mod test {
         assert_eq!(length, 4);
     }
 
+    /// Test that the search stops at the first match that is at least `nice_length` long.
+    #[test]
+    fn nice_length_match() {
+        use crate::compression_options::MAX_HASH_CHECKS;
+        let test_data = b"xTest data, Test_data,zTest data";
+        let hash_table = filled_hash_table(&test_data[..23 + 1 + HASH_BYTES - 1]);
+        let position = hash_table.current_head() as usize;
+
+        let search = |nice_length| {
+            longest_match(
+                test_data,
+                &hash_table,
+                position,
+                2,
+                MAX_HASH_CHECKS,
+                nice_length,
+            )
+        };
+        assert_eq!(search(5), (9, 22));
+        // The closer match is long enough, so the longer one further back is not found.
+        assert_eq!(search(4), (4, 11));
+    }
+
     /// Make sure we can get a match at index zero
     #[test]
     fn match_index_zero() {
@@ -336,7 +410,8 @@ mod test {
             hash_table.add_hash_value(n, b);
         }
 
-        let (match_length, match_dist) = longest_match(test_data, &hash_table, 1, 0, 4096);
+        let (match_length, match_dist) =
+            longest_match(test_data, &hash_table, 1, 0, 4096, MAX_MATCH);
 
         assert_eq!(match_dist, 1);
         assert!(match_length == 6);
@@ -355,7 +430,7 @@ mod test {
             let hash_table = filled_hash_table(&data[..start_pos + 1]);
             let pos = hash_table.current_head() as usize;
 
-            let naive_match = longest_match(&data[..], &hash_table, pos, 0, NUM_CHECKS);
+            let naive_match = longest_match(&data[..], &hash_table, pos, 0, NUM_CHECKS, MAX_MATCH);
             let fast_match = longest_match_fast(&data[..], &hash_table, pos, 0, NUM_CHECKS);
 
             if fast_match.0 > naive_match.0 {
@@ -382,7 +457,7 @@ mod test {
 
 #[cfg(all(test, feature = "benchmarks"))]
 mod bench {
-    use super::{longest_match, longest_match_fast};
+    use super::{longest_match, longest_match_fast, MAX_MATCH};
     use chained_hash_table::filled_hash_table;
     use test_std::Bencher;
     use test_utils::get_test_data;
@@ -394,9 +469,9 @@ mod bench {
         let pos = hash_table.current_head() as usize;
         println!(
             "M: {:?}",
-            longest_match(&data[..], &hash_table, pos, 0, 4096)
+            longest_match(&data[..], &hash_table, pos, 0, 4096, MAX_MATCH)
         );
-        b.iter(|| longest_match(&data[..], &hash_table, pos, 0, 4096));
+        b.iter(|| longest_match(&data[..], &hash_table, pos, 0, 4096, MAX_MATCH));
     }
 
     #[bench]
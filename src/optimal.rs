@@ -0,0 +1,274 @@
+//! Optimal parsing of chunks of input, trading a lot of compression speed for smaller output.
+//!
+//! Instead of picking matches as they are found, the longest match at every position in the chunk
+//! is looked up first, and the sequence of literals and matches with the lowest total cost in bits
+//! is then found as the shortest path through the chunk. The cost of each symbol is estimated
+//! from the fixed huffman codes at first, and then refined over a few iterations using the
+//! symbol frequencies of the previous parse.
+use std::cmp;
+use std::ops::Range;
+
+use crate::huffman_table::{
+    get_distance_code, get_length_code, num_extra_bits_for_distance_code,
+    num_extra_bits_for_length_code, END_OF_BLOCK_POSITION, FIXED_CODE_LENGTHS,
+    FIXED_CODE_LENGTHS_DISTANCE, LENGTH_BITS_START, NUM_DISTANCE_CODES, NUM_LITERALS_AND_LENGTHS,
+};
+use crate::lz77::{buffer_full, find_match, MatchLengths, ProcessStatus};
+use crate::matcher::Matcher;
+use crate::output_writer::DynamicWriter;
+use crate::repeats::RepeatIndex;
+
+/// The number of times the chunk is parsed, refining the costs in between.
+const ITERATIONS: usize = 5;
+
+/// The base-2 logarithm of a positive, normal `x`, accurate to about 1e-5.
+///
+/// Only basic arithmetic is used, which IEEE 754 specifies exactly, so the result is the same on
+/// every platform. The `log2` of the platform math library is not, and differences in the costs
+/// could change the parse and with it the output.
+fn portable_log2(x: f32) -> f32 {
+    let bits = x.to_bits();
+    let exponent = ((bits >> 23) & 0xFF) as i32 - 127;
+    // The mantissa scaled to [1, 2).
+    let m = f32::from_bits((bits & 0x007F_FFFF) | 0x3F80_0000);
+    // ln(m) = 2 * atanh((m - 1) / (m + 1)), using the first terms of the series of atanh.
+    let t = (m - 1.0) / (m + 1.0);
+    let t2 = t * t;
+    let ln = 2.0 * t * (1.0 + t2 * (1.0 / 3.0 + t2 * (1.0 / 5.0 + t2 * (1.0 / 7.0 + t2 / 9.0))));
+    exponent as f32 + ln * std::f32::consts::LOG2_E
+}
+
+/// Estimated cost in bits of each literal/length and distance symbol.
+struct CostModel {
+    literal_lengths: [f32; NUM_LITERALS_AND_LENGTHS],
+    distances: [f32; NUM_DISTANCE_CODES],
+}
+
+impl CostModel {
+    /// Costs given by the lengths of the fixed huffman codes.
+    fn fixed() -> CostModel {
+        let mut model = CostModel {
+            literal_lengths: [0.0; NUM_LITERALS_AND_LENGTHS],
+            distances: [0.0; NUM_DISTANCE_CODES],
+        };
+        for (cost, &length) in model
+            .literal_lengths
+            .iter_mut()
+            .zip(&FIXED_CODE_LENGTHS[..])
+        {
+            *cost = f32::from(length);
+        }
+        for (cost, &length) in model.distances.iter_mut().zip(&FIXED_CODE_LENGTHS_DISTANCE) {
+            *cost = f32::from(length);
+        }
+        model
+    }
+
+    /// Costs given by the ideal code lengths for the provided symbol frequencies.
+    ///
+    /// Unused symbols are costed as if they were used once, so they are not ruled out in the next
+    /// parse.
+    fn from_frequencies(
+        l_freqs: &[u32; NUM_LITERALS_AND_LENGTHS],
+        d_freqs: &[u32; NUM_DISTANCE_CODES],
+    ) -> CostModel {
+        fn code_lengths(freqs: &[u32], costs: &mut [f32]) {
+            let total = freqs.iter().map(|&f| cmp::max(f, 1)).sum::<u32>() as f32;
+            for (cost, &freq) in costs.iter_mut().zip(freqs) {
+                *cost = portable_log2(total / cmp::max(freq, 1) as f32);
+            }
+        }
+        let mut model = CostModel {
+            literal_lengths: [0.0; NUM_LITERALS_AND_LENGTHS],
+            distances: [0.0; NUM_DISTANCE_CODES],
+        };
+        code_lengths(l_freqs, &mut model.literal_lengths);
+        code_lengths(d_freqs, &mut model.distances);
+        model
+    }
+
+    fn literal(&self, literal: u8) -> f32 {
+        self.literal_lengths[usize::from(literal)]
+    }
+
+    fn length_distance(&self, length: usize, distance: usize) -> f32 {
+        let l_code = get_length_code(length as u16);
+        let d_code = get_distance_code(distance as u16);
+        self.literal_lengths[l_code]
+            + f32::from(num_extra_bits_for_length_code(
+                (l_code - usize::from(LENGTH_BITS_START)) as u8,
+            ))
+            + self.distances[usize::from(d_code)]
+            + f32::from(num_extra_bits_for_distance_code(d_code))
+    }
+}
+
+/// Find the cheapest way to encode `chunk` under `model`, given the longest match found at each
+/// position.
+///
+/// Returns the length of the symbol used to reach each position, where a length of 1 is a literal,
+/// and matches use the distance of the longest match at the position they start at.
+fn parse(chunk: &[u8], matches: &[(u16, u16)], min_match: usize, model: &CostModel) -> Vec<u16> {
+    let mut costs = vec![f32::INFINITY; chunk.len() + 1];
+    let mut steps = vec![0u16; chunk.len() + 1];
+    costs[0] = 0.0;
+    for (i, (&b, &(match_len, match_dist))) in chunk.iter().zip(matches).enumerate() {
+        let cost = costs[i];
+        let literal_cost = cost + model.literal(b);
+        if literal_cost < costs[i + 1] {
+            costs[i + 1] = literal_cost;
+            steps[i + 1] = 1;
+        }
+        // Matches are not allowed to extend past the chunk, so every parse ends at its end.
+        let max_len = cmp::min(usize::from(match_len), chunk.len() - i);
+        for len in min_match..=max_len {
+            let match_cost = cost + model.length_distance(len, usize::from(match_dist));
+            if match_cost < costs[i + len] {
+                costs[i + len] = match_cost;
+                steps[i + len] = len as u16;
+            }
+        }
+    }
+    steps
+}
+
+/// Walk back through the steps of a parse, and return the lengths of the symbols from the start.
+fn trace(steps: &[u16]) -> Vec<u16> {
+    let mut lengths = Vec::new();
+    let mut position = steps.len() - 1;
+    while position > 0 {
+        let len = steps[position];
+        lengths.push(len);
+        position -= usize::from(len);
+    }
+    lengths.reverse();
+    lengths
+}
+
+/// Count the symbols used by the parse of `chunk` into `lengths`.
+fn frequencies(
+    chunk: &[u8],
+    matches: &[(u16, u16)],
+    lengths: &[u16],
+) -> ([u32; NUM_LITERALS_AND_LENGTHS], [u32; NUM_DISTANCE_CODES]) {
+    let mut l_freqs = [0; NUM_LITERALS_AND_LENGTHS];
+    let mut d_freqs = [0; NUM_DISTANCE_CODES];
+    l_freqs[END_OF_BLOCK_POSITION] = 1;
+    let mut position = 0;
+    for &len in lengths {
+        if len == 1 {
+            l_freqs[usize::from(chunk[position])] += 1;
+        } else {
+            l_freqs[get_length_code(len)] += 1;
+            d_freqs[usize::from(get_distance_code(matches[position].1))] += 1;
+        }
+        position += usize::from(len);
+    }
+    (l_freqs, d_freqs)
+}
+
+/// LZ77-compress the chunk `iterated_data` of `data` using optimal parsing.
+///
+/// Matches never extend past the end of the chunk, so there is never any overlap into the next
+/// one. To avoid having to stop in the middle of a parse, at most as many bytes as there is space
+/// left for in the output buffer are processed, and the buffer is reported as full if that is
+/// fewer than the whole chunk.
+pub fn process_chunk_optimal<M: Matcher>(
+    data: &[u8],
+    iterated_data: &Range<usize>,
+    matcher: &mut M,
+    writer: &mut DynamicWriter,
+    max_hash_checks: u16,
+    lengths: MatchLengths,
+    mut repeats: Option<&mut RepeatIndex>,
+) -> (usize, ProcessStatus) {
+    let start = iterated_data.start;
+    let chunk_end = cmp::min(data.len(), iterated_data.end);
+    // Each byte results in at most one value, and the buffer is full once it reaches the maximum
+    // length, so leave space for one more value.
+    let space = writer
+        .max_length()
+        .saturating_sub(writer.buffer_length() + 1);
+    let end = cmp::min(chunk_end, start + space);
+
+    // Find the longest match at each position in the chunk, adding them to the matcher as we go.
+    // The last few bytes of the input can't be added, so no matches are searched for there.
+    let lookahead = matcher.lookahead();
+    let mut matches = Vec::with_capacity(end - start);
+    for position in start..end {
+        let found = if position + lookahead < data.len() {
+            matcher.add(data, position);
+            find_match(
+                data,
+                &*matcher,
+                &mut repeats,
+                position,
+                0,
+                max_hash_checks,
+                lengths,
+            )
+        } else {
+            (0, 0)
+        };
+        matches.push((found.0 as u16, found.1 as u16));
+    }
+
+    let chunk = &data[start..end];
+    let mut model = CostModel::fixed();
+    let mut symbol_lengths = Vec::new();
+    for _ in 0..ITERATIONS {
+        symbol_lengths = trace(&parse(chunk, &matches, lengths.min_match, &model));
+        let (l_freqs, d_freqs) = frequencies(chunk, &matches, &symbol_lengths);
+        model = CostModel::from_frequencies(&l_freqs, &d_freqs);
+    }
+
+    let mut position = 0;
+    for &len in &symbol_lengths {
+        // There is space left for all the values, so the buffer can't be full here.
+        if len == 1 {
+            writer.write_literal(chunk[position]);
+        } else {
+            writer.write_length_distance(len, matches[position].1);
+        }
+        position += usize::from(len);
+    }
+
+    if end < chunk_end {
+        (0, buffer_full(end))
+    } else {
+        (0, ProcessStatus::Ok)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn log2_close_to_std() {
+        for &x in &[1.0f32, 1.5, 2.0, 3.0, 10.0, 1000.0, 65537.0, 1e9] {
+            assert!((portable_log2(x) - x.log2()).abs() < 1e-4, "{}", x);
+        }
+        assert_eq!(portable_log2(1024.0), 10.0);
+    }
+
+    #[test]
+    fn parse_prefers_cheaper_symbols() {
+        let chunk = b"abcdefabcdef";
+        let mut matches = vec![(0, 0); chunk.len()];
+        // A long match, and a shorter one starting a byte later.
+        matches[6] = (6, 6);
+        matches[7] = (5, 6);
+        let model = CostModel::fixed();
+        let lengths = trace(&parse(chunk, &matches, 3, &model));
+        assert_eq!(lengths, [1, 1, 1, 1, 1, 1, 6]);
+
+        // If literals are very cheap, no matches should be used.
+        let mut cheap = CostModel::fixed();
+        cheap.literal_lengths[..256]
+            .iter_mut()
+            .for_each(|c| *c = 0.5);
+        let lengths = trace(&parse(chunk, &matches, 3, &cheap));
+        assert_eq!(lengths, [1; 12]);
+    }
+}
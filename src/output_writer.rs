@@ -1,5 +1,6 @@
-use std::u16;
+use std::{cmp, mem, u16};
 
+use crate::allocator::{StateAllocator, StateVec};
 use crate::huffman_table::{
     get_distance_code, get_length_code, END_OF_BLOCK_POSITION, NUM_DISTANCE_CODES,
     NUM_LITERALS_AND_LENGTHS,
@@ -8,16 +9,29 @@ use crate::lzvalue::LZValue;
 
 /// The type used for representing how many times a literal, length or distance code has been ouput
 /// to the current buffer.
-/// As we are limiting the blocks to be at most 2^16 bytes long, we can represent frequencies using
-/// 16-bit values.
+/// Frequencies are stored as 16-bit values to keep the tables small. If a counter would overflow,
+/// all the frequencies in the block are halved, and the number of times this has happened is
+/// tracked so the real counts can be approximated when estimating the block size.
 pub type FrequencyType = u16;
 
-/// The maximum number of literals/lengths in the buffer, which in practice also means the maximum
-/// number of literals/lengths output before a new block is started.
-/// This should not be larger than the maximum value `FrequencyType` can represent to prevent
-/// overflowing (which would degrade, or in the worst case break compression).
+/// The maximum number of literals/lengths in the buffer with the memory levels, which in practice
+/// also means the maximum number of literals/lengths output before a new block is started.
+/// This is kept below the maximum value `FrequencyType` can represent so the frequencies rarely
+/// have to be halved, which makes the estimate of the block size less precise.
 pub const MAX_BUFFER_LENGTH: usize = 1024 * 31;
 
+/// The smallest number of literals/lengths a block can be set to hold.
+pub const SMALLEST_BUFFER_LENGTH: usize = 256;
+
+/// The largest number of literals/lengths a block can be set to hold, for when longer blocks are
+/// preferred over an exact estimate of the block size.
+pub const LARGEST_BUFFER_LENGTH: usize = 1024 * 63;
+
+/// If there would be fewer than this many literals/lengths left to put in a new block when the
+/// buffer is full at the end of the input, they are added to the current block instead, as the
+/// header of a new block would take up a large part of the space saved by it.
+pub const MIN_BLOCK_LENGTH: usize = 1024 * 2;
+
 #[derive(Debug, PartialEq)]
 pub enum BufferStatus {
     NotFull,
@@ -25,18 +39,36 @@ pub enum BufferStatus {
 }
 
 /// Struct that buffers lz77 data and keeps track of the usage of different codes
+#[derive(Clone)]
 pub struct DynamicWriter {
-    buffer: Vec<LZValue>,
+    buffer: StateVec<LZValue>,
     // The two last length codes are not actually used, but only participates in code construction
     // Therefore, we ignore them to get the correct number of lengths
     frequencies: [FrequencyType; NUM_LITERALS_AND_LENGTHS],
     distance_frequencies: [FrequencyType; NUM_DISTANCE_CODES],
+    // How many times the frequencies have been halved to avoid overflowing.
+    frequency_shift: u8,
+    // The number of literals/lengths the current block can hold.
+    max_length: usize,
+    // The number of literals/lengths a block normally holds.
+    buffer_length: usize,
+}
+
+/// Scale down a frequency, making sure values that were non-zero stay non-zero so the symbol
+/// will still be assigned a code.
+#[inline]
+fn scale_frequency(frequency: u32, shift: u8) -> FrequencyType {
+    if frequency == 0 {
+        0
+    } else {
+        cmp::max(frequency >> shift, 1) as FrequencyType
+    }
 }
 
 impl DynamicWriter {
     #[inline]
     pub fn check_buffer_length(&self) -> BufferStatus {
-        if self.buffer.len() >= MAX_BUFFER_LENGTH {
+        if self.buffer.len() >= self.max_length {
             BufferStatus::Full
         } else {
             BufferStatus::NotFull
@@ -45,22 +77,57 @@ impl DynamicWriter {
 
     #[inline]
     pub fn write_literal(&mut self, literal: u8) -> BufferStatus {
-        debug_assert!(self.buffer.len() < MAX_BUFFER_LENGTH);
+        debug_assert!(self.buffer.len() < self.max_length);
         self.buffer.push(LZValue::literal(literal));
-        self.frequencies[usize::from(literal)] += 1;
+        self.increment_literal_length(usize::from(literal));
         self.check_buffer_length()
     }
 
+    /// Increment the frequency of the literal/length code `code_num`, halving the frequency
+    /// tables first if the counter is saturated.
+    #[inline]
+    fn increment_literal_length(&mut self, code_num: usize) {
+        if self.frequencies[code_num] == FrequencyType::MAX {
+            self.rescale_frequencies(1);
+        }
+        self.frequencies[code_num] += 1;
+    }
+
+    /// Increment the frequency of the distance code `code_num`, halving the frequency
+    /// tables first if the counter is saturated.
+    #[inline]
+    fn increment_distance(&mut self, code_num: usize) {
+        if self.distance_frequencies[code_num] == FrequencyType::MAX {
+            self.rescale_frequencies(1);
+        }
+        self.distance_frequencies[code_num] += 1;
+    }
+
+    /// Divide all the frequencies by 2^`shift`.
+    ///
+    /// Both tables are scaled by the same amount so the relative weights between literals,
+    /// lengths and distances are kept intact.
+    #[cold]
+    fn rescale_frequencies(&mut self, shift: u8) {
+        for f in self
+            .frequencies
+            .iter_mut()
+            .chain(self.distance_frequencies.iter_mut())
+        {
+            *f = scale_frequency(u32::from(*f), shift);
+        }
+        self.frequency_shift += shift;
+    }
+
     #[inline]
     pub fn write_length_distance(&mut self, length: u16, distance: u16) -> BufferStatus {
         self.buffer.push(LZValue::length_distance(length, distance));
         let l_code_num = get_length_code(length);
-        // As we limit the buffer to 2^16 values, this should be safe from overflowing.
-        self.frequencies[l_code_num] += 1;
+        self.increment_literal_length(l_code_num);
 
         let d_code_num = get_distance_code(distance);
         // The compiler seems to be able to evade the bounds check here somehow.
-        self.distance_frequencies[usize::from(d_code_num)] += 1;
+        self.increment_distance(usize::from(d_code_num));
         self.check_buffer_length()
     }
 
@@ -68,16 +135,69 @@ impl DynamicWriter {
         self.buffer.len()
     }
 
+    /// The number of literals/lengths the current block can hold before the buffer is full.
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    /// Let the current block hold the last `remaining` literals/lengths of the input if they
+    /// would otherwise end up in a block shorter than `MIN_BLOCK_LENGTH`.
+    pub fn merge_trailing_values(&mut self, remaining: usize) {
+        let min_block_length = self.min_block_length();
+        if self.buffer.len() + remaining < self.buffer_length + min_block_length {
+            self.max_length = self.buffer_length + min_block_length;
+        }
+    }
+
+    /// Start a new block after `buffer_length` literals/lengths, resizing the buffer to fit.
+    ///
+    /// This should only be done when the buffer is empty.
+    pub fn set_buffer_length(&mut self, buffer_length: usize) {
+        debug_assert!(self.buffer.is_empty() && buffer_length <= LARGEST_BUFFER_LENGTH);
+        if buffer_length != self.buffer_length {
+            self.buffer_length = buffer_length;
+            self.max_length = buffer_length;
+            let capacity = buffer_length + self.min_block_length();
+            self.buffer.shrink_to(capacity);
+            self.buffer.reserve_exact(capacity);
+        }
+    }
+
+    /// The shortest block worth starting at the end of the input, scaled down along with the
+    /// buffer.
+    fn min_block_length(&self) -> usize {
+        MIN_BLOCK_LENGTH * self.buffer_length / MAX_BUFFER_LENGTH
+    }
+
     pub fn get_buffer(&self) -> &[LZValue] {
         &self.buffer
     }
 
     pub fn new() -> DynamicWriter {
+        DynamicWriter::new_in(&StateAllocator::global())
+    }
+
+    /// Create a new writer, allocating the buffer with `allocator`.
+    pub fn new_in(allocator: &StateAllocator) -> DynamicWriter {
+        DynamicWriter::with_buffer_length_in(MAX_BUFFER_LENGTH, allocator)
+    }
+
+    /// Create a new writer starting a new block after `buffer_length` literals/lengths, which
+    /// can't be larger than `LARGEST_BUFFER_LENGTH`, allocating the buffer with `allocator`.
+    pub fn with_buffer_length_in(
+        buffer_length: usize,
+        allocator: &StateAllocator,
+    ) -> DynamicWriter {
+        debug_assert!(buffer_length <= LARGEST_BUFFER_LENGTH);
         let mut w = DynamicWriter {
-            buffer: Vec::with_capacity(MAX_BUFFER_LENGTH),
+            buffer: allocator.vec_with_capacity(0),
             frequencies: [0; NUM_LITERALS_AND_LENGTHS],
             distance_frequencies: [0; NUM_DISTANCE_CODES],
+            frequency_shift: 0,
+            max_length: buffer_length,
+            buffer_length,
         };
+        w.buffer.reserve_exact(buffer_length + w.min_block_length());
         // This will always be 1,
         // since there will always only be one end of block marker in each block
         w.frequencies[END_OF_BLOCK_POSITION] = 1;
@@ -90,19 +210,8 @@ impl DynamicWriter {
     pub fn write_length_rle(&mut self, length: u16) -> BufferStatus {
         self.buffer.push(LZValue::length_distance(length, 1));
         let l_code_num = get_length_code(length);
-        // As we limit the buffer to 2^16 values, this should be safe from overflowing.
-        if cfg!(debug_assertions) {
-            self.frequencies[l_code_num] += 1;
-        } else {
-            // #Safety
-            // None of the values in the table of length code numbers will give a value
-            // that is out of bounds.
-            // There is a test to ensure that these functions won't produce too large values.
-            unsafe {
-                *self.frequencies.get_unchecked_mut(l_code_num) += 1;
-            }
-        }
-        self.distance_frequencies[0] += 1;
+        self.increment_literal_length(l_code_num);
+        self.increment_distance(0);
         self.check_buffer_length()
     }
 
@@ -110,20 +219,34 @@ impl DynamicWriter {
         (&self.frequencies, &self.distance_frequencies)
     }
 
+    /// How many times the frequencies have been halved to fit in `FrequencyType`.
+    ///
+    /// The actual frequencies are approximately the stored ones multiplied by 2^`shift`.
+    pub fn frequency_shift(&self) -> u8 {
+        self.frequency_shift
+    }
+
     pub fn clear_frequencies(&mut self) {
         self.frequencies = [0; NUM_LITERALS_AND_LENGTHS];
         self.distance_frequencies = [0; NUM_DISTANCE_CODES];
         self.frequencies[END_OF_BLOCK_POSITION] = 1;
+        self.frequency_shift = 0;
     }
 
     pub fn clear_data(&mut self) {
-        self.buffer.clear()
+        self.buffer.clear();
+        self.max_length = self.buffer_length;
     }
 
     pub fn clear(&mut self) {
         self.clear_frequencies();
         self.clear_data();
     }
+
+    /// The number of bytes of heap memory allocated for the buffer.
+    pub fn heap_size(&self) -> usize {
+        self.buffer.capacity() * mem::size_of::<LZValue>()
+    }
 }
 
 #[cfg(test)]
@@ -144,4 +267,23 @@ mod test {
             assert!(get_distance_code(i) < w.distance_frequencies.len() as u8);
         }
     }
+
+    #[test]
+    /// Check that saturated counters are halved rather than overflowing.
+    fn frequency_rescale() {
+        let mut w = DynamicWriter::new();
+        w.frequencies[usize::from(b'a')] = u16::MAX;
+        w.frequencies[usize::from(b'b')] = 1;
+        w.distance_frequencies[3] = 100;
+        w.write_literal(b'a');
+        assert_eq!(w.frequency_shift(), 1);
+        assert_eq!(w.frequencies[usize::from(b'a')], (u16::MAX >> 1) + 1);
+        // Symbols that have been used should not drop to 0.
+        assert_eq!(w.frequencies[usize::from(b'b')], 1);
+        assert_eq!(w.frequencies[usize::from(b'c')], 0);
+        assert_eq!(w.distance_frequencies[3], 50);
+
+        w.clear_frequencies();
+        assert_eq!(w.frequency_shift(), 0);
+    }
 }
@@ -0,0 +1,222 @@
+//! Compression of large inputs using several threads.
+//!
+//! The input is split into chunks that are compressed on separate threads. Each chunk is
+//! compressed with the end of the previous chunk as a preset dictionary, so matches can still
+//! refer back across chunk boundaries, and ends in a sync flush so the compressed chunks can be
+//! joined into a single stream. The checksums of the chunks are combined the same way as for
+//! [`Fragment`](../struct.Fragment.html)s. The output is a valid stream, but not identical to the
+//! output of the single-threaded functions, and is slightly larger due to the flushes.
+use std::cmp;
+use std::io::{self, Write};
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+#[cfg(feature = "gzip")]
+use crate::checksum::crc32;
+use crate::checksum::{Adler32Checksum, RollingChecksum};
+use crate::compress::Flush;
+use crate::compression_options::CompressionOptions;
+use crate::deflate_state::DeflateState;
+use crate::fragment::{Fragment, FragmentStream};
+use crate::writer::{compress_until_done, Format};
+
+/// The amount of input compressed by a thread at a time.
+const CHUNK_SIZE: usize = 1 << 20;
+/// The amount of data preceding a chunk used as its dictionary.
+const DICTIONARY_SIZE: usize = 32768;
+
+/// Compress a chunk of input, using the data before it as a dictionary.
+fn compress_chunk(input: &[u8], start: usize, end: usize, options: CompressionOptions) -> Fragment {
+    let chunk = &input[start..end];
+    let mut state = Box::new(DeflateState::with_direct_output(options, Vec::new()));
+    state.prime_window(&input[start.saturating_sub(DICTIONARY_SIZE)..start]);
+    // The output is never written to the sink, so this can't fail.
+    compress_until_done(chunk, &mut state, Flush::Sync).expect("Write error!");
+
+    let mut adler32 = Adler32Checksum::new();
+    adler32.update_from_slice(chunk);
+    #[cfg(feature = "gzip")]
+    {
+        Fragment::from_parts(
+            state.take_direct_output(),
+            chunk.len() as u64,
+            adler32.current_hash(),
+            crc32(chunk),
+        )
+    }
+    #[cfg(not(feature = "gzip"))]
+    Fragment::from_parts(
+        state.take_direct_output(),
+        chunk.len() as u64,
+        adler32.current_hash(),
+    )
+}
+
+/// Compress `input` on `threads` threads in chunks of `chunk_size` bytes.
+fn compress_chunks(
+    input: &[u8],
+    options: CompressionOptions,
+    threads: usize,
+    chunk_size: usize,
+) -> Vec<Fragment> {
+    let num_chunks = cmp::max(input.len().div_ceil(chunk_size), 1);
+    let next_chunk = AtomicUsize::new(0);
+
+    let mut chunks: Vec<(usize, Fragment)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..cmp::min(threads, num_chunks))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut compressed = Vec::new();
+                    loop {
+                        let n = next_chunk.fetch_add(1, Ordering::Relaxed);
+                        if n >= num_chunks {
+                            break compressed;
+                        }
+                        let start = n * chunk_size;
+                        let end = cmp::min(start + chunk_size, input.len());
+                        compressed.push((n, compress_chunk(input, start, end, options)));
+                    }
+                })
+            })
+            .collect();
+        let mut chunks = Vec::with_capacity(num_chunks);
+        for worker in workers {
+            match worker.join() {
+                Ok(compressed) => chunks.extend(compressed),
+                Err(panic) => panic::resume_unwind(panic),
+            }
+        }
+        chunks
+    });
+
+    chunks.sort_unstable_by_key(|&(n, _)| n);
+    chunks.into_iter().map(|(_, chunk)| chunk).collect()
+}
+
+/// Compress `input` using several threads, writing the compressed data in the provided container
+/// format to `writer`.
+///
+/// The input is split into chunks of 1 MiB, which are compressed in parallel using as many
+/// threads as the system has available, so the speedup is limited for inputs that are not much
+/// larger than that. Each chunk can refer back to the end of the previous chunk, so the
+/// compression ratio is close to the one of the single-threaded functions, but the output is not
+/// identical to theirs. A gzip stream gets a header with blank fields.
+///
+/// Returns the writer once all the data has been written.
+///
+/// Returns an error for `Format::Custom`, as the checksum of a custom format can't be combined.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_parallel, Compression};
+/// use deflate::write::Format;
+///
+/// let data = b"This is some test data";
+/// let compressed_data = deflate_parallel(data, Vec::new(), Format::Zlib, Compression::Default)
+///     .unwrap();
+/// # let _ = compressed_data;
+/// ```
+pub fn deflate_parallel<W: Write, O: Into<CompressionOptions>>(
+    input: &[u8],
+    writer: W,
+    format: Format,
+    options: O,
+) -> io::Result<W> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    deflate_parallel_conf(input, writer, format, options.into(), threads, CHUNK_SIZE)
+}
+
+fn deflate_parallel_conf<W: Write>(
+    input: &[u8],
+    mut writer: W,
+    format: Format,
+    options: CompressionOptions,
+    threads: usize,
+    chunk_size: usize,
+) -> io::Result<W> {
+    let mut stream = FragmentStream::try_new(format)?;
+    let chunks = compress_chunks(input, options, threads, chunk_size);
+    writer.write_all(&stream.header())?;
+    for chunk in &chunks {
+        stream.push(chunk);
+        writer.write_all(chunk.data())?;
+    }
+    writer.write_all(&stream.finish())?;
+    Ok(writer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression_options::Compression;
+    use crate::test_utils::{decompress_to_end, decompress_zlib, get_test_data};
+
+    #[test]
+    fn parallel_roundtrip() {
+        let data = get_test_data();
+        let input = [&data[..], &data, &data].concat();
+        for &threads in &[1, 3] {
+            let compressed = deflate_parallel_conf(
+                &input,
+                Vec::new(),
+                Format::Zlib,
+                CompressionOptions::default(),
+                threads,
+                20_000,
+            )
+            .unwrap();
+            assert!(decompress_zlib(&compressed) == input);
+            // The dictionaries let the chunks refer to the data before them.
+            let independent: usize = input
+                .chunks(20_000)
+                .map(|chunk| {
+                    Fragment::compress(chunk, CompressionOptions::default())
+                        .data()
+                        .len()
+                })
+                .sum();
+            assert!(compressed.len() < independent);
+        }
+
+        let compressed =
+            deflate_parallel(&data, Vec::new(), Format::Raw, CompressionOptions::fast()).unwrap();
+        assert!(decompress_to_end(&compressed) == data);
+    }
+
+    #[test]
+    fn parallel_short() {
+        for data in &[&[][..], &[1][..], &[5, 5, 5, 5, 5, 6][..]] {
+            let compressed = deflate_parallel_conf(
+                data,
+                Vec::new(),
+                Format::Raw,
+                CompressionOptions::default(),
+                4,
+                4,
+            )
+            .unwrap();
+            assert_eq!(&decompress_to_end(&compressed)[..], *data);
+        }
+        assert!(deflate_parallel(&[1], Vec::new(), Format::Custom, Compression::Default).is_err());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn parallel_gzip() {
+        use crate::test_utils::decompress_gzip;
+        let data = get_test_data();
+        let compressed = deflate_parallel_conf(
+            &data,
+            Vec::new(),
+            Format::Gzip,
+            CompressionOptions::high(),
+            2,
+            10_000,
+        )
+        .unwrap();
+        let (_, decompressed) = decompress_gzip(&compressed);
+        assert!(decompressed == data);
+    }
+}
@@ -0,0 +1,224 @@
+//! Compression using two threads in a pipeline.
+//!
+//! Finding matches (lz77 compression) and encoding the result (frequency analysis, huffman table
+//! construction and bit writing) are done on separate threads. The matching thread sends each
+//! block of lz77-compressed data to the encoding thread through a bounded channel, and the buffers
+//! are sent back to be reused once the block has been written. Unlike splitting the input into
+//! chunks that are compressed independently, the output is a single stream identical to the output
+//! of the single-threaded functions.
+use std::io;
+use std::io::Write;
+use std::panic;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+use crate::allocator::StateAllocator;
+use crate::compress::{write_block, write_stored_only, Flush, LARGEST_OUTPUT_BUF_SIZE};
+use crate::compression_options::{CompressionOptions, SpecialOptions};
+use crate::deflate_state::{new_lz77_state, LengthBuffers};
+use crate::encoder_state::EncoderState;
+use crate::input_buffer::InputBuffer;
+use crate::lz77::{lz77_compress_block, LZ77Status};
+use crate::output_writer::DynamicWriter;
+
+/// The number of blocks the matching thread can get ahead of the encoding thread.
+const PIPELINE_DEPTH: usize = 2;
+
+/// A block of lz77-compressed data sent from the matching thread to the encoding thread.
+struct Block {
+    lz77_writer: Box<DynamicWriter>,
+    /// Position of the first input byte of the block.
+    input_start: usize,
+    /// Number of input bytes the block represents.
+    input_bytes: u64,
+    last_block: bool,
+}
+
+/// Run lz77 compression on the input, sending each block to the encoding thread.
+fn match_blocks(
+    input: &[u8],
+    options: CompressionOptions,
+    blocks: SyncSender<Block>,
+    recycled: Receiver<Box<DynamicWriter>>,
+) {
+    let mut lz77_state = new_lz77_state(&options, &StateAllocator::global());
+    let mut input_buffer = InputBuffer::empty();
+    let mut remaining = input;
+    let mut input_start = 0;
+
+    loop {
+        let mut lz77_writer = recycled
+            .try_recv()
+            .unwrap_or_else(|_| Box::new(DynamicWriter::new()));
+        let (written, status, _) = lz77_compress_block(
+            remaining,
+            &mut lz77_state,
+            &mut input_buffer,
+            &mut lz77_writer,
+            Flush::Finish,
+        );
+        remaining = &remaining[written..];
+
+        let input_bytes = lz77_state.current_block_input_bytes();
+        lz77_state.reset_input_bytes();
+        let block = Block {
+            lz77_writer,
+            input_start,
+            input_bytes,
+            last_block: lz77_state.is_last_block(),
+        };
+        input_start += input_bytes as usize;
+
+        // If sending fails, the encoding thread has stopped due to an error.
+        if blocks.send(block).is_err() || status == LZ77Status::Finished {
+            break;
+        }
+    }
+}
+
+/// Encode the blocks received from the matching thread, writing the result to `writer`.
+fn encode_blocks<W: Write>(
+    input: &[u8],
+    writer: &mut W,
+    blocks: Receiver<Block>,
+    recycle: SyncSender<Box<DynamicWriter>>,
+) -> io::Result<()> {
+    let mut encoder_state =
+        EncoderState::new(StateAllocator::global().vec_with_capacity(LARGEST_OUTPUT_BUF_SIZE * 2));
+    let mut length_buffers = LengthBuffers::new();
+    let mut finished = false;
+
+    for block in blocks {
+        let input_end = block.input_start + block.input_bytes as usize;
+        write_block(
+            &mut encoder_state,
+            &mut length_buffers,
+            &block.lz77_writer,
+            block.input_bytes,
+            Some(&input[block.input_start..input_end]),
+            block.last_block,
+            block.last_block,
+            None,
+            None,
+        );
+        finished = block.last_block;
+
+        let mut lz77_writer = block.lz77_writer;
+        lz77_writer.clear();
+        // If the channel is full, the matching thread already has enough buffers.
+        let _ = recycle.try_send(lz77_writer);
+
+        if encoder_state.inner_vec().len() > LARGEST_OUTPUT_BUF_SIZE {
+            writer.write_all(encoder_state.inner_vec())?;
+            encoder_state.inner_vec().clear();
+        }
+    }
+
+    if !finished {
+        // Make sure a block with the last block header has been output.
+        // An empty fixed block is the shortest.
+        encoder_state.set_huffman_to_fixed();
+        encoder_state.write_start_of_block(true, true);
+        encoder_state.write_end_of_block();
+    }
+
+    encoder_state.flush();
+    writer.write_all(encoder_state.inner_vec())
+}
+
+/// Compress `input` with DEFLATE compression using two threads, writing the compressed data to
+/// `writer`.
+///
+/// One thread finds matches in the input while the calling thread encodes and writes the
+/// previous block, which makes compression faster on multi-core systems when using a high
+/// compression level. The output is identical to the output of
+/// [`deflate_bytes_conf`](fn.deflate_bytes_conf.html) with the same options.
+///
+/// Returns the writer once all the data has been written.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_pipelined, Compression};
+///
+/// let data = b"This is some test data";
+/// let compressed_data = deflate_pipelined(data, Vec::new(), Compression::Best).unwrap();
+/// # let _ = compressed_data;
+/// ```
+pub fn deflate_pipelined<W: Write, O: Into<CompressionOptions>>(
+    input: &[u8],
+    mut writer: W,
+    options: O,
+) -> io::Result<W> {
+    let options = options.into();
+    if options.special == SpecialOptions::ForceStored {
+        // There is nothing to do on a second thread.
+        let mut encoder_state =
+            EncoderState::new(StateAllocator::global().vec_with_capacity(input.len() + 5));
+        write_stored_only(input, &mut encoder_state.writer, true);
+        encoder_state.flush();
+        writer.write_all(encoder_state.inner_vec())?;
+        return Ok(writer);
+    }
+    let (block_sender, block_receiver) = sync_channel(PIPELINE_DEPTH);
+    let (recycle_sender, recycle_receiver) = sync_channel(PIPELINE_DEPTH + 2);
+
+    thread::scope(|scope| {
+        let matcher =
+            scope.spawn(move || match_blocks(input, options, block_sender, recycle_receiver));
+        let result = encode_blocks(input, &mut writer, block_receiver, recycle_sender);
+        if let Err(panic) = matcher.join() {
+            panic::resume_unwind(panic);
+        }
+        result
+    })?;
+
+    Ok(writer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, get_test_data};
+
+    #[test]
+    fn pipelined_same_as_single_threaded() {
+        let data = get_test_data();
+        for &options in &[
+            CompressionOptions::default(),
+            CompressionOptions::fast(),
+            CompressionOptions::rle(),
+            CompressionOptions::high(),
+            CompressionOptions::huffman_only(),
+            CompressionOptions::stored(),
+        ] {
+            let compressed = deflate_pipelined(&data, Vec::new(), options).unwrap();
+            assert!(compressed == crate::deflate_bytes_conf(&data, options));
+        }
+    }
+
+    #[test]
+    fn pipelined_short() {
+        for data in &[&[][..], &[1][..], &[5, 5, 5, 5, 5, 6][..]] {
+            let compressed =
+                deflate_pipelined(data, Vec::new(), CompressionOptions::default()).unwrap();
+            assert_eq!(&decompress_to_end(&compressed)[..], *data);
+        }
+    }
+
+    #[test]
+    fn pipelined_write_error() {
+        struct FailingWriter;
+        impl Write for FailingWriter {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::Other, "Failed"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let data = get_test_data();
+        assert!(deflate_pipelined(&data, FailingWriter, CompressionOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+//! Compression of many messages starting with the same prefix.
+//!
+//! Messages like HTTP responses generated from a template or log lines often start with the
+//! same long prefix. [`PrefixCache`] compresses the prefix once, and keeps the state of the
+//! compressor (the hash chains, the pending lz77 data and the output so far) after it. Each
+//! message then starts from a copy of that state, so only the part after the prefix has to be
+//! matched and encoded.
+//!
+//! [`PrefixCache`]: struct.PrefixCache.html
+use std::io;
+
+use crate::checksum::{Adler32Checksum, RollingChecksum};
+use crate::compress::{compress_data_dynamic_n, Flush};
+use crate::compression_options::{clamp_window_bits, CompressionOptions};
+use crate::deflate_state::DeflateState;
+use crate::writer::compress_until_done;
+use crate::zlib::{get_zlib_header_with_window, CompressionLevel};
+
+/// The compressor state after a common prefix, which can be used to compress any number of
+/// messages starting with that prefix.
+///
+/// The output for each message is identical to the output of compressing the prefix followed by
+/// the rest of the message with the streaming encoder of the same format.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{Compression, PrefixCache};
+///
+/// let cache = PrefixCache::new(b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n", Compression::Default);
+/// let first = cache.compress(b"Content-Length: 5\r\n\r\nHello");
+/// let second = cache.compress(b"Content-Length: 3\r\n\r\nBye");
+/// # let _ = (first, second);
+/// ```
+pub struct PrefixCache {
+    state: Box<DeflateState<io::Sink>>,
+    /// The checksum of the prefix, if the output is in the zlib format.
+    checksum: Option<Adler32Checksum>,
+    prefix_len: usize,
+}
+
+/// Feed all of `input` to the compressor without flushing.
+fn write_all(input: &[u8], state: &mut DeflateState<io::Sink>) {
+    let mut remaining = input;
+    while !remaining.is_empty() {
+        // The output is never written to the sink, so this can't fail.
+        let consumed =
+            compress_data_dynamic_n(remaining, state, Flush::None).expect("Write error!");
+        remaining = &remaining[consumed..];
+    }
+}
+
+impl PrefixCache {
+    /// Compress `prefix` as the start of a raw DEFLATE stream.
+    pub fn new<O: Into<CompressionOptions>>(prefix: &[u8], options: O) -> PrefixCache {
+        PrefixCache::with_header(prefix, options.into(), Vec::new(), None)
+    }
+
+    /// Compress `prefix` as the start of a zlib stream.
+    pub fn new_zlib<O: Into<CompressionOptions>>(prefix: &[u8], options: O) -> PrefixCache {
+        let mut checksum = Adler32Checksum::new();
+        checksum.update_from_slice(prefix);
+        let options = options.into();
+        let window_bits = clamp_window_bits(options.window_bits);
+        let header = get_zlib_header_with_window(CompressionLevel::Default, window_bits).to_vec();
+        PrefixCache::with_header(prefix, options, header, Some(checksum))
+    }
+
+    fn with_header(
+        prefix: &[u8],
+        options: CompressionOptions,
+        header: Vec<u8>,
+        checksum: Option<Adler32Checksum>,
+    ) -> PrefixCache {
+        let mut state = Box::new(DeflateState::with_direct_output(options, header));
+        write_all(prefix, &mut state);
+        PrefixCache {
+            state,
+            checksum,
+            prefix_len: prefix.len(),
+        }
+    }
+
+    /// The length of the prefix.
+    pub fn prefix_len(&self) -> usize {
+        self.prefix_len
+    }
+
+    /// Compress a message consisting of the prefix followed by `rest`.
+    pub fn compress(&self, rest: &[u8]) -> Vec<u8> {
+        let mut state = Box::new(self.state.clone_direct());
+        write_all(rest, &mut state);
+        compress_until_done(&[], &mut state, Flush::Finish).expect("Write error!");
+        let mut output = state.take_direct_output();
+
+        if let Some(ref checksum) = self.checksum {
+            let mut checksum = checksum.clone();
+            checksum.update_from_slice(rest);
+            output.extend_from_slice(&checksum.current_hash().to_be_bytes());
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, decompress_zlib, get_test_data};
+    use crate::write::{DeflateEncoder, ZlibEncoder};
+    use std::io::Write;
+
+    #[test]
+    fn prefix_same_as_encoder() {
+        let data = get_test_data();
+        let (prefix, rest) = data.split_at(100_000);
+        let options = CompressionOptions::default();
+
+        let cache = PrefixCache::new(prefix, options);
+        let mut encoder = DeflateEncoder::new(Vec::new(), options);
+        encoder.write_all(prefix).unwrap();
+        encoder.write_all(rest).unwrap();
+        assert!(cache.compress(rest) == encoder.finish().unwrap());
+
+        // The cache can be reused.
+        let compressed = cache.compress(b"Another ending");
+        assert!(decompress_to_end(&compressed) == [prefix, b"Another ending"].concat());
+        assert_eq!(cache.prefix_len(), prefix.len());
+    }
+
+    #[test]
+    fn prefix_zlib() {
+        let data = get_test_data();
+        let prefix = &data[..5000];
+        let cache = PrefixCache::new_zlib(prefix, CompressionOptions::fast());
+        for rest in &[&[][..], &data[5000..6000], &data[..]] {
+            let mut encoder = ZlibEncoder::new(Vec::new(), CompressionOptions::fast());
+            encoder.write_all(prefix).unwrap();
+            encoder.write_all(rest).unwrap();
+            let compressed = cache.compress(rest);
+            assert!(compressed == encoder.finish().unwrap());
+            assert!(decompress_zlib(&compressed) == [prefix, rest].concat());
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Prior symbol frequencies blended into the observed ones when generating huffman codes.
+//!
+//! The codes of a dynamic block are normally built only from the symbols in that block. For
+//! short messages there are few symbols to go by, so the code lengths can be skewed by chance.
+//! If the kind of data is known in advance (e.g JSON), the frequencies measured on a
+//! representative sample can be set as priors on an encoder, which steers the code lengths
+//! towards the expected distribution. Priors only affect the lengths of symbols that occur in
+//! the block, so no code space is wasted on symbols that are not used.
+use std::mem;
+
+use crate::allocator::StateAllocator;
+use crate::compress::Flush;
+use crate::compression_options::CompressionOptions;
+use crate::deflate_state::new_lz77_state;
+use crate::huffman_table::{NUM_DISTANCE_CODES, NUM_LITERALS_AND_LENGTHS};
+use crate::input_buffer::InputBuffer;
+use crate::lz77::{lz77_compress_block, LZ77Status};
+use crate::output_writer::{DynamicWriter, FrequencyType};
+
+/// The default weight of the priors, in number of symbols.
+pub const DEFAULT_PRIOR_WEIGHT: u32 = 1024;
+
+/// Prior frequencies of the literal/length and distance symbols.
+///
+/// The priors count as `weight` observed symbols, distributed according to the prior
+/// frequencies, so they dominate the code lengths of short blocks while longer blocks are mostly
+/// decided by their own data.
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::io;
+/// #
+/// # fn try_main() -> io::Result<Vec<u8>> {
+/// #
+/// use std::io::Write;
+///
+/// use deflate::{Compression, FrequencyPriors};
+/// use deflate::write::DeflateEncoder;
+///
+/// let sample = br#"{"id": 1, "name": "first", "tags": ["a", "b"]}"#;
+/// let priors = FrequencyPriors::from_sample(sample, Compression::Default);
+///
+/// let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+/// encoder.set_frequency_priors(priors);
+/// encoder.write_all(br#"{"id": 2, "name": "second", "tags": []}"#)?;
+/// let compressed_data = encoder.finish()?;
+/// # Ok(compressed_data)
+/// #
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct FrequencyPriors {
+    literal_length: Vec<u32>,
+    distance: Vec<u32>,
+    weight: u32,
+}
+
+impl FrequencyPriors {
+    /// Create priors from the frequencies of the 286 literal/length symbols and the 30 distance
+    /// symbols, using the default weight.
+    ///
+    /// Shorter slices are padded with zeroes.
+    ///
+    /// # Panics
+    /// Panics if either slice has more values than there are symbols.
+    pub fn new(literal_length: &[u32], distance: &[u32]) -> FrequencyPriors {
+        assert!(
+            literal_length.len() <= NUM_LITERALS_AND_LENGTHS
+                && distance.len() <= NUM_DISTANCE_CODES,
+            "Too many frequencies!"
+        );
+        let mut priors = FrequencyPriors {
+            literal_length: vec![0; NUM_LITERALS_AND_LENGTHS],
+            distance: vec![0; NUM_DISTANCE_CODES],
+            weight: DEFAULT_PRIOR_WEIGHT,
+        };
+        priors.literal_length[..literal_length.len()].copy_from_slice(literal_length);
+        priors.distance[..distance.len()].copy_from_slice(distance);
+        priors
+    }
+
+    /// Measure the priors by lz77-compressing `sample` with the provided options.
+    ///
+    /// The options should be the same as the ones the priors will be used with, as they
+    /// affect which matches are found.
+    pub fn from_sample<O: Into<CompressionOptions>>(sample: &[u8], options: O) -> FrequencyPriors {
+        let options = options.into();
+        let mut priors = FrequencyPriors::new(&[], &[]);
+        let mut lz77_state = new_lz77_state(&options, &StateAllocator::global());
+        let mut input_buffer = InputBuffer::empty();
+        let mut lz77_writer = DynamicWriter::new();
+        let mut remaining = sample;
+
+        loop {
+            let (written, status, _) = lz77_compress_block(
+                remaining,
+                &mut lz77_state,
+                &mut input_buffer,
+                &mut lz77_writer,
+                Flush::Finish,
+            );
+            remaining = &remaining[written..];
+            lz77_state.reset_input_bytes();
+
+            let (l_freqs, d_freqs) = lz77_writer.get_frequencies();
+            let shift = lz77_writer.frequency_shift();
+            let counts = priors
+                .literal_length
+                .iter_mut()
+                .zip(l_freqs)
+                .chain(priors.distance.iter_mut().zip(d_freqs));
+            for (prior, &f) in counts {
+                *prior = prior.saturating_add(u32::from(f) << shift);
+            }
+            lz77_writer.clear();
+
+            if status == LZ77Status::Finished {
+                break;
+            }
+        }
+        priors
+    }
+
+    /// Set how many observed symbols the priors count as.
+    pub fn with_weight(mut self, weight: u32) -> FrequencyPriors {
+        self.weight = weight;
+        self
+    }
+
+    /// How many observed symbols the priors count as.
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// The prior frequencies of the literal/length symbols.
+    pub fn literal_length(&self) -> &[u32] {
+        &self.literal_length
+    }
+
+    /// The prior frequencies of the distance symbols.
+    pub fn distance(&self) -> &[u32] {
+        &self.distance
+    }
+
+    /// The amount of heap memory used by the priors.
+    pub fn heap_size(&self) -> usize {
+        (self.literal_length.capacity() + self.distance.capacity()) * mem::size_of::<u32>()
+    }
+
+    /// Blend the priors into the observed frequencies of a block, which have been halved
+    /// `frequency_shift` times, writing the result to `l_out` and `d_out`.
+    pub fn blend(
+        &self,
+        l_freqs: &[FrequencyType],
+        d_freqs: &[FrequencyType],
+        frequency_shift: u8,
+        l_out: &mut [FrequencyType],
+        d_out: &mut [FrequencyType],
+    ) {
+        blend_table(
+            &self.literal_length,
+            self.weight,
+            l_freqs,
+            frequency_shift,
+            l_out,
+        );
+        blend_table(&self.distance, self.weight, d_freqs, frequency_shift, d_out);
+    }
+}
+
+/// Blend one table of priors into the observed frequencies.
+///
+/// Symbols that are not used keep a frequency of 0, and ones that are used stay above 0.
+fn blend_table(
+    priors: &[u32],
+    weight: u32,
+    observed: &[FrequencyType],
+    frequency_shift: u8,
+    out: &mut [FrequencyType],
+) {
+    let prior_total: u64 = priors.iter().map(|&p| u64::from(p)).sum();
+    if prior_total == 0 {
+        out[..observed.len()].copy_from_slice(observed);
+        return;
+    }
+
+    // Work in the scale of the observed frequencies.
+    let prior_scale = f64::from(weight) / prior_total as f64 / f64::from(1u32 << frequency_shift);
+    let blended = |(&f, &p): (&FrequencyType, &u32)| {
+        if f == 0 {
+            0.0
+        } else {
+            f64::from(f) + f64::from(p) * prior_scale
+        }
+    };
+    let max = observed.iter().zip(priors).map(blended).fold(0.0, f64::max);
+    let scale = if max > f64::from(FrequencyType::MAX) {
+        f64::from(FrequencyType::MAX) / max
+    } else {
+        1.0
+    };
+
+    for (o, value) in out.iter_mut().zip(observed.iter().zip(priors).map(blended)) {
+        *o = if value == 0.0 {
+            0
+        } else {
+            ((value * scale) as FrequencyType).max(1)
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, get_test_data};
+    use crate::write::DeflateEncoder;
+    use crate::BlockKind;
+    use std::io::Write;
+
+    #[test]
+    fn blend_keeps_unused_symbols_unused() {
+        let priors = FrequencyPriors::new(&[100, 0, 50], &[]).with_weight(150);
+        let observed = [1, 3, 0];
+        let mut out = [0; 3];
+        blend_table(
+            &priors.literal_length,
+            priors.weight,
+            &observed,
+            0,
+            &mut out,
+        );
+        assert_eq!(out, [101, 3, 0]);
+        // Observed frequencies that have been halved are weighted accordingly.
+        blend_table(
+            &priors.literal_length,
+            priors.weight,
+            &observed,
+            1,
+            &mut out,
+        );
+        assert_eq!(out, [51, 3, 0]);
+    }
+
+    #[test]
+    fn priors_change_code_lengths() {
+        // The message uses 'a' more than 'b', but the priors strongly favour 'b'.
+        let mut message = vec![b'a'; 400];
+        message.extend_from_slice(&[b'b'; 200]);
+        message.extend_from_slice(b"cdefghij");
+        let mut literals = vec![0; 256];
+        literals[usize::from(b'b')] = 1000;
+        let priors = FrequencyPriors::new(&literals, &[]).with_weight(10_000);
+
+        let lengths = |priors: Option<&FrequencyPriors>| {
+            let mut encoder = DeflateEncoder::new(Vec::new(), CompressionOptions::huffman_only());
+            if let Some(priors) = priors {
+                encoder.set_frequency_priors(priors.clone());
+            }
+            encoder.set_record_block_codes(true);
+            encoder.write_all(&message).unwrap();
+            encoder.flush().unwrap();
+            let codes = encoder.take_block_codes();
+            assert!(decompress_to_end(&encoder.finish().unwrap()) == message);
+            assert_eq!(codes[0].kind, BlockKind::Dynamic);
+            let lengths = codes[0].literal_length_lengths.clone();
+            (lengths[usize::from(b'a')], lengths[usize::from(b'b')])
+        };
+
+        let (a, b) = lengths(None);
+        assert!(a <= b);
+        let (a, b) = lengths(Some(&priors));
+        assert!(a > b);
+    }
+
+    #[test]
+    fn priors_from_sample() {
+        let data = get_test_data();
+        let priors = FrequencyPriors::from_sample(&data, CompressionOptions::default());
+        assert!(priors.literal_length()[usize::from(b'e')] > priors.literal_length()[0]);
+        assert!(priors.distance().iter().any(|&d| d > 0));
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        encoder.set_frequency_priors(priors);
+        encoder.write_all(&data).unwrap();
+        assert!(decompress_to_end(&encoder.finish().unwrap()) == data);
+    }
+}
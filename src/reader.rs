@@ -0,0 +1,187 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use crate::compression_options::CompressionOptions;
+use crate::error::Error;
+use crate::writer::{Encoder, Format};
+
+/// The size of the chunks of input read from the wrapped reader.
+const INPUT_BUFFER_SIZE: usize = 32 * 1024;
+
+/// A DEFLATE compressor implementing a [`Read`] interface.
+///
+/// Reading from the `DeflateReader` reads uncompressed data from the wrapped reader in chunks and
+/// returns the compressed data, so it can be used as the source of `io::copy` without holding the
+/// whole input or output in memory.
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::io;
+/// #
+/// # fn try_main() -> io::Result<Vec<u8>> {
+/// #
+/// use std::io::Read;
+///
+/// use deflate::Compression;
+/// use deflate::read::DeflateReader;
+///
+/// let data = &b"This is some test data"[..];
+/// let mut reader = DeflateReader::new(data, Compression::Default);
+/// let mut compressed_data = Vec::new();
+/// reader.read_to_end(&mut compressed_data)?;
+/// # Ok(compressed_data)
+/// #
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+/// [`Read`]: https://doc.rust-lang.org/std/io/trait.Read.html
+pub struct DeflateReader<R: Read> {
+    inner: R,
+    /// Encoder writing the compressed data to a buffer until it's read.
+    encoder: Encoder<Vec<u8>>,
+    /// How much of the compressed data in the buffer of the encoder has been read.
+    output_pos: usize,
+    input_buf: Vec<u8>,
+    finished: bool,
+}
+
+impl<R: Read> DeflateReader<R> {
+    /// Create a new reader compressing the data read from `reader` to raw DEFLATE data.
+    pub fn new<O: Into<CompressionOptions>>(reader: R, options: O) -> DeflateReader<R> {
+        DeflateReader::with_format(reader, Format::Raw, options)
+    }
+
+    /// Create a new reader compressing the data read from `reader` using the provided container
+    /// format.
+    ///
+    /// # Panics
+    /// Panics for `Format::Custom`, which has no framing of its own.
+    pub fn with_format<O: Into<CompressionOptions>>(
+        reader: R,
+        format: Format,
+        options: O,
+    ) -> DeflateReader<R> {
+        DeflateReader::try_with_format(reader, format, options)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like [`with_format`](#method.with_format), but returns an error rather than panicking for
+    /// `Format::Custom`.
+    pub fn try_with_format<O: Into<CompressionOptions>>(
+        reader: R,
+        format: Format,
+        options: O,
+    ) -> Result<DeflateReader<R>, Error> {
+        Ok(DeflateReader {
+            inner: reader,
+            encoder: Encoder::try_new(Vec::new(), format, options)?,
+            output_pos: 0,
+            input_buf: vec![0; INPUT_BUFFER_SIZE],
+            finished: false,
+        })
+    }
+
+    /// Get a reference to the wrapped reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the wrapped reader.
+    ///
+    /// Reading directly from the wrapped reader means the data read won't be compressed.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Consume the `DeflateReader`, returning the wrapped reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Read the next chunk of input and compress it, finishing the stream at the end of the
+    /// input.
+    fn fill_output(&mut self) -> io::Result<()> {
+        let read = loop {
+            match self.inner.read(&mut self.input_buf) {
+                Ok(n) => break n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        };
+        if read == 0 {
+            self.encoder.try_finish()?;
+            self.finished = true;
+        } else {
+            self.encoder.write_all(&self.input_buf[..read])?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DeflateReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let output = self.encoder.get_mut();
+            if self.output_pos < output.len() {
+                let n = cmp::min(buf.len(), output.len() - self.output_pos);
+                buf[..n].copy_from_slice(&output[self.output_pos..self.output_pos + n]);
+                self.output_pos += n;
+                if self.output_pos == output.len() {
+                    output.clear();
+                    self.output_pos = 0;
+                }
+                return Ok(n);
+            }
+            if self.finished {
+                return Ok(0);
+            }
+            self.fill_output()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, decompress_zlib, get_test_data};
+
+    #[test]
+    fn reader_same_as_writer() {
+        let data = get_test_data();
+        let mut reader = DeflateReader::new(&data[..], CompressionOptions::default());
+        let mut compressed = Vec::new();
+        // Read in small pieces to check that the output is split correctly.
+        let mut buf = [0; 1000];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            compressed.extend_from_slice(&buf[..n]);
+        }
+        assert!(compressed == crate::deflate_bytes(&data));
+        assert!(decompress_to_end(&compressed) == data);
+        assert!(reader.into_inner().is_empty());
+    }
+
+    #[test]
+    fn reader_zlib_io_copy() {
+        let data = get_test_data();
+        let mut reader =
+            DeflateReader::with_format(&data[..], Format::Zlib, CompressionOptions::fast());
+        let mut compressed = Vec::new();
+        io::copy(&mut reader, &mut compressed).unwrap();
+        assert!(decompress_zlib(&compressed) == data);
+
+        let mut empty = DeflateReader::new(&[][..], CompressionOptions::default());
+        let mut compressed = Vec::new();
+        empty.read_to_end(&mut compressed).unwrap();
+        assert!(decompress_to_end(&compressed).is_empty());
+    }
+}
@@ -0,0 +1,271 @@
+//! Incremental recompression of data that has been modified after being compressed.
+//!
+//! When a small part of a large buffer changes, the blocks of the compressed stream before the
+//! change are still valid, as they only depend on the data preceding them. By keeping a map of
+//! where each block starts in the input and the output, only the blocks from the first one that
+//! is affected by the change and onwards have to be recompressed, and the result can be appended
+//! to the unchanged part of the old output.
+use std::cmp;
+
+use crate::compress::Flush;
+use crate::compression_options::CompressionOptions;
+use crate::deflate_state::DeflateState;
+use crate::writer::compress_until_done;
+
+/// The position of the start of a DEFLATE block in the input and output data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct BlockBoundary {
+    /// The position in the input data of the first byte represented by the block.
+    pub input_offset: u64,
+    /// The position in the output data of the first bit of the block header.
+    pub output_bit: u64,
+}
+
+/// The start positions of the blocks in a compressed stream.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct BlockMap {
+    boundaries: Vec<BlockBoundary>,
+}
+
+impl BlockMap {
+    /// Create a block map from a list of block boundaries, e.g one that has been stored along
+    /// with the compressed data.
+    ///
+    /// The boundaries should be ordered by their position in the stream.
+    pub fn from_boundaries(boundaries: Vec<BlockBoundary>) -> BlockMap {
+        BlockMap { boundaries }
+    }
+
+    /// The boundaries of the blocks, in the order they appear in the stream.
+    pub fn boundaries(&self) -> &[BlockBoundary] {
+        &self.boundaries
+    }
+}
+
+/// Compress the given slice of bytes with DEFLATE compression, also returning a map of where
+/// each block starts that can be used with [`recompress`](fn.recompress.html) later.
+///
+/// The compressed data is identical to the output of
+/// [`deflate_bytes_conf`](fn.deflate_bytes_conf.html).
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_bytes_with_map, Compression};
+///
+/// let data = b"This is some test data";
+/// let (compressed_data, block_map) = deflate_bytes_with_map(data, Compression::Default);
+/// # let _ = (compressed_data, block_map);
+/// ```
+pub fn deflate_bytes_with_map<O: Into<CompressionOptions>>(
+    input: &[u8],
+    options: O,
+) -> (Vec<u8>, BlockMap) {
+    compress_from(
+        input,
+        0,
+        Vec::with_capacity(input.len() / 3),
+        0,
+        0,
+        options.into(),
+    )
+}
+
+/// Recompress `new_input`, which is a modified version of `old_input`, reusing the part of
+/// `old_output` that is not affected by the changes.
+///
+/// `old_output` and `old_map` should be the compressed data and block map produced from
+/// `old_input` by [`deflate_bytes_with_map`](fn.deflate_bytes_with_map.html) or a previous call
+/// to this function. The blocks before the first one containing a changed byte are copied from
+/// `old_output`, and the rest of the input is compressed from the start of that block onwards,
+/// so the time taken depends on how early the first change is rather than the total size.
+///
+/// The returned data is a valid raw DEFLATE stream of `new_input`, though it may differ slightly
+/// from compressing `new_input` from scratch. If the block map does not fit the data, all of the
+/// input is recompressed. A map that fits, but does not actually belong to `old_output`, will
+/// produce invalid output.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_bytes_with_map, recompress, Compression};
+///
+/// let old = b"This is some test data".repeat(1000);
+/// let (compressed, map) = deflate_bytes_with_map(&old, Compression::Default);
+///
+/// let mut new = old.clone();
+/// new.extend_from_slice(b"Some more data at the end");
+/// let (compressed, map) = recompress(&old, &compressed, &map, &new, Compression::Default);
+/// # let _ = (compressed, map);
+/// ```
+pub fn recompress<O: Into<CompressionOptions>>(
+    old_input: &[u8],
+    old_output: &[u8],
+    old_map: &BlockMap,
+    new_input: &[u8],
+    options: O,
+) -> (Vec<u8>, BlockMap) {
+    let options = options.into();
+    let first_change = old_input
+        .iter()
+        .zip(new_input)
+        .take_while(|&(a, b)| a == b)
+        .count();
+
+    let old_bits = old_output.len() as u64 * 8;
+    let valid_map = old_map
+        .boundaries
+        .windows(2)
+        .all(|w| w[0].input_offset <= w[1].input_offset && w[0].output_bit <= w[1].output_bit)
+        && match old_map.boundaries.last() {
+            Some(b) => b.input_offset <= old_input.len() as u64 && b.output_bit < old_bits,
+            None => false,
+        };
+
+    // Find the last block that starts at or before the first changed byte.
+    let restart = if valid_map {
+        old_map
+            .boundaries
+            .iter()
+            .rposition(|b| b.input_offset <= first_change as u64)
+    } else {
+        None
+    };
+
+    let restart = match restart {
+        Some(index) if index > 0 => index,
+        // If the change is in the first block, there is nothing to reuse.
+        _ => return deflate_bytes_with_map(new_input, options),
+    };
+
+    let boundary = old_map.boundaries[restart];
+    let input_offset = boundary.input_offset as usize;
+    let full_bytes = (boundary.output_bit / 8) as usize;
+    let partial_bits = (boundary.output_bit % 8) as u8;
+
+    let mut output = Vec::with_capacity(cmp::max(old_output.len(), new_input.len() / 3));
+    output.extend_from_slice(&old_output[..full_bytes]);
+    let partial_byte = if partial_bits > 0 {
+        u16::from(old_output[full_bytes]) & ((1 << partial_bits) - 1)
+    } else {
+        0
+    };
+
+    let (output, mut new_map) = compress_from(
+        new_input,
+        input_offset,
+        output,
+        partial_byte,
+        partial_bits,
+        options,
+    );
+
+    let mut boundaries = old_map.boundaries[..restart].to_vec();
+    boundaries.append(&mut new_map.boundaries);
+    (output, BlockMap { boundaries })
+}
+
+/// Compress `input` from `start` onwards, appending the output to `output` after the lowest
+/// `partial_bits` bits of `partial_byte`.
+///
+/// The data before `start` is used to fill the window.
+fn compress_from(
+    input: &[u8],
+    start: usize,
+    output: Vec<u8>,
+    partial_byte: u16,
+    partial_bits: u8,
+    options: CompressionOptions,
+) -> (Vec<u8>, BlockMap) {
+    // We use a box here to avoid putting the buffers on the stack.
+    // As the output is written directly to `output`, the recorded positions include the data
+    // already in it.
+    let mut deflate_state = Box::new(DeflateState::with_direct_output(options, output));
+    deflate_state.block_boundaries = Some(Vec::new());
+    deflate_state.prime_window(&input[..start]);
+    if partial_bits > 0 {
+        deflate_state
+            .encoder_state
+            .writer
+            .write_bits(partial_byte, partial_bits);
+    }
+
+    compress_until_done(&input[start..], &mut deflate_state, Flush::Finish).expect("Write error!");
+
+    let boundaries = deflate_state
+        .block_boundaries
+        .take()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|b| BlockBoundary {
+            input_offset: b.input_offset + start as u64,
+            output_bit: b.output_bit,
+        })
+        .collect();
+    let output = deflate_state.take_direct_output();
+    (output, BlockMap { boundaries })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{decompress_to_end, get_test_data};
+
+    #[test]
+    fn map_output_matches() {
+        let data = get_test_data();
+        let (compressed, map) = deflate_bytes_with_map(&data, CompressionOptions::default());
+        assert!(compressed == crate::deflate_bytes_conf(&data, CompressionOptions::default()));
+        assert!(map.boundaries().len() > 1);
+        assert_eq!(map.boundaries()[0].input_offset, 0);
+        assert_eq!(map.boundaries()[0].output_bit, 0);
+    }
+
+    #[test]
+    fn recompress_edits() {
+        let old = get_test_data();
+        for &options in &[
+            CompressionOptions::default(),
+            CompressionOptions::fast(),
+            CompressionOptions::rle(),
+        ] {
+            let (old_compressed, old_map) = deflate_bytes_with_map(&old, options);
+            let len = old.len();
+
+            let mut edited = old.clone();
+            edited[len - 100] = b'#';
+            let mut appended = old.clone();
+            appended.extend_from_slice(&old[..5000]);
+            let truncated = old[..len - 50000].to_vec();
+
+            for new in &[edited, appended, truncated, old.clone()] {
+                let (compressed, map) = recompress(&old, &old_compressed, &old_map, new, options);
+                assert!(decompress_to_end(&compressed) == *new);
+                // Part of the old output should be reused.
+                if let Some(second) = map.boundaries().get(1) {
+                    let reused = second.output_bit as usize / 8;
+                    assert!(compressed[..reused] == old_compressed[..reused]);
+                }
+
+                // The new map should be usable for further edits.
+                let mut again = new.clone();
+                again.push(0);
+                let (compressed, _) = recompress(new, &compressed, &map, &again, options);
+                assert!(decompress_to_end(&compressed) == again);
+            }
+        }
+    }
+
+    #[test]
+    fn recompress_invalid_map() {
+        let old = get_test_data();
+        let mut new = old.clone();
+        new[1000] = 0;
+        let map = BlockMap::from_boundaries(vec![BlockBoundary {
+            input_offset: 0,
+            output_bit: 1 << 40,
+        }]);
+        let (compressed, _) = recompress(&old, &[], &map, &new, CompressionOptions::default());
+        assert!(decompress_to_end(&compressed) == new);
+    }
+}
@@ -0,0 +1,157 @@
+//! Detection of input that repeats earlier data wholesale.
+//!
+//! Data like virtual machine images and database dumps consist largely of blocks that are exact
+//! copies of earlier blocks (often at page-aligned distances). The hash chains find these
+//! matches too, but have to search for every match separately. [`RepeatIndex`] keeps a
+//! fingerprint of each aligned block of input in the window, and when a block is identical to an
+//! earlier one, maximal matches at that distance are output for the rest of the block without
+//! searching the hash chains.
+//!
+//! [`RepeatIndex`]: struct.RepeatIndex.html
+use std::cmp;
+use std::convert::TryInto;
+
+use crate::huffman_table::{MAX_MATCH, MIN_MATCH};
+
+/// The size of the blocks that are fingerprinted.
+pub const REPEAT_BLOCK_SIZE: usize = 4096;
+/// The maximum distance of a match.
+const WINDOW_SIZE: usize = 32768;
+/// The number of blocks that fit in the window.
+const NUM_BLOCKS: usize = WINDOW_SIZE / REPEAT_BLOCK_SIZE;
+
+/// Compute a fingerprint of a block.
+fn fingerprint(block: &[u8]) -> u64 {
+    block.chunks_exact(8).fold(0, |hash, word| {
+        let word = u64::from_le_bytes(word.try_into().unwrap());
+        (hash.rotate_left(5) ^ word).wrapping_mul(0x517c_c1b7_2722_0a95)
+    })
+}
+
+/// Fingerprints of the last blocks in the window, and the repeat currently being output.
+#[derive(Clone, Debug)]
+pub struct RepeatIndex {
+    /// The fingerprint and start position of each block in the window, indexed by the block
+    /// number modulo `NUM_BLOCKS`.
+    blocks: [Option<(u64, usize)>; NUM_BLOCKS],
+    /// The start of the next block that has not been fingerprinted.
+    next_block: usize,
+    /// The end of the current repeated block.
+    repeat_end: usize,
+    /// The distance of the current repeated block to the earlier copy.
+    repeat_distance: usize,
+    /// The maximum distance of a match.
+    max_distance: usize,
+}
+
+impl RepeatIndex {
+    /// Create an index only finding repeats at most `max_distance` bytes back.
+    pub fn new(max_distance: usize) -> RepeatIndex {
+        RepeatIndex {
+            blocks: [None; NUM_BLOCKS],
+            next_block: 0,
+            repeat_end: 0,
+            repeat_distance: 0,
+            max_distance: cmp::min(max_distance, WINDOW_SIZE),
+        }
+    }
+
+    /// Forget all the blocks.
+    pub fn reset(&mut self) {
+        *self = RepeatIndex::new(self.max_distance);
+    }
+
+    /// Update the positions after the buffer has been slid back by `amount` bytes, forgetting
+    /// the blocks that are no longer in the buffer.
+    ///
+    /// `amount` has to be a multiple of the block size.
+    pub fn slide(&mut self, amount: usize) {
+        debug_assert_eq!(amount & (REPEAT_BLOCK_SIZE - 1), 0);
+        for block in self.blocks.iter_mut() {
+            *block = match *block {
+                Some((hash, start)) if start >= amount => Some((hash, start - amount)),
+                _ => None,
+            };
+        }
+        self.next_block = self.next_block.saturating_sub(amount);
+        self.repeat_end = self.repeat_end.saturating_sub(amount);
+    }
+
+    /// Return the length and distance of a match at `position` in `data` if it is part of a block
+    /// that repeats an earlier one, fingerprinting the block first if needed.
+    #[inline]
+    pub fn find_match(&mut self, data: &[u8], position: usize) -> Option<(usize, usize)> {
+        if position >= self.next_block {
+            self.index_block(data, position);
+        }
+        if position < self.repeat_end {
+            let length = cmp::min(usize::from(MAX_MATCH), self.repeat_end - position);
+            if length >= usize::from(MIN_MATCH) {
+                return Some((length, self.repeat_distance));
+            }
+        }
+        None
+    }
+
+    /// Fingerprint the block containing `position`, and check if it repeats an earlier block.
+    fn index_block(&mut self, data: &[u8], position: usize) {
+        let start = position & !(REPEAT_BLOCK_SIZE - 1);
+        let end = start + REPEAT_BLOCK_SIZE;
+        if end > data.len() {
+            // Wait until the whole block is available.
+            return;
+        }
+        self.next_block = end;
+
+        let block = &data[start..end];
+        let hash = fingerprint(block);
+        // Use the closest earlier copy, as shorter distances are cheaper to encode.
+        let earlier = self
+            .blocks
+            .iter()
+            .flatten()
+            .filter(|&&(h, s)| h == hash && s < start && start - s < self.max_distance)
+            .map(|&(_, s)| s)
+            .filter(|&s| data[s..s + REPEAT_BLOCK_SIZE] == *block)
+            .max();
+        if let Some(earlier) = earlier {
+            self.repeat_end = end;
+            self.repeat_distance = start - earlier;
+        }
+        self.blocks[(start / REPEAT_BLOCK_SIZE) % NUM_BLOCKS] = Some((hash, start));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_repeated_blocks() {
+        let mut data: Vec<u8> = (0..REPEAT_BLOCK_SIZE * 2)
+            .map(|n| (n * 7 % 251) as u8)
+            .collect();
+        let repeated = data[REPEAT_BLOCK_SIZE..].to_vec();
+        data.extend_from_slice(&repeated);
+        data.extend_from_slice(&[0; 10]);
+
+        let mut index = RepeatIndex::new(WINDOW_SIZE);
+        // The first blocks are not repeats.
+        assert_eq!(index.find_match(&data, 0), None);
+        assert_eq!(index.find_match(&data, REPEAT_BLOCK_SIZE + 5), None);
+        // A position within the block containing the copy.
+        let position = REPEAT_BLOCK_SIZE * 2 + 100;
+        assert_eq!(
+            index.find_match(&data, position),
+            Some((usize::from(MAX_MATCH), REPEAT_BLOCK_SIZE))
+        );
+        // The match is cut off at the end of the block.
+        assert_eq!(
+            index.find_match(&data, REPEAT_BLOCK_SIZE * 3 - 10),
+            Some((10, REPEAT_BLOCK_SIZE))
+        );
+
+        index.slide(REPEAT_BLOCK_SIZE);
+        assert_eq!(index.blocks.iter().flatten().count(), 2);
+    }
+}
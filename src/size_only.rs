@@ -0,0 +1,164 @@
+//! Working out the size of the compressed data without producing it.
+//!
+//! [`SizeOnlyCompressor`] runs the same matching and block splitting as the other encoders and
+//! builds the huffman codes for each block from the symbol frequencies, but only adds up how many
+//! bits each block would take up instead of writing them out. This is useful when several
+//! candidate inputs are compared by how well they compress, e.g the different filters a PNG
+//! encoder can apply to each row, as it skips encoding the symbols and copying the output.
+//!
+//! [`SizeOnlyCompressor`]: struct.SizeOnlyCompressor.html
+use std::cmp;
+
+use crate::allocator::StateAllocator;
+use crate::compress::Flush;
+use crate::compression_options::{CompressionOptions, SpecialOptions};
+use crate::deflate_state::{new_lz77_state, symbol_buffer_length, LengthBuffers};
+use crate::huffman_lengths::{gen_huffman_lengths, BLOCK_MARKER_LENGTH};
+use crate::huffman_table::{
+    END_OF_BLOCK_POSITION, FIXED_CODE_LENGTHS, NUM_DISTANCE_CODES, NUM_LITERALS_AND_LENGTHS,
+};
+use crate::input_buffer::InputBuffer;
+use crate::lz77::{lz77_compress_block, LZ77State, LZ77Status};
+use crate::output_writer::DynamicWriter;
+use crate::stored_block::STORED_ONLY_BLOCK_LENGTH;
+
+/// The length of the header and trailer of a stored block, not counting the data.
+const STORED_BLOCK_OVERHEAD: u64 = 5;
+
+/// Computes the exact length of the raw DEFLATE data the other encoders would output, without
+/// producing the data itself.
+///
+/// The buffers and hash chains are kept between calls, so using one `SizeOnlyCompressor` for many
+/// inputs avoids allocating them each time.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_bytes_conf, Compression, SizeOnlyCompressor};
+///
+/// let data = b"This is some test data";
+/// let mut compressor = SizeOnlyCompressor::new(Compression::Default);
+/// let length = compressor.compressed_len(data);
+/// assert_eq!(length, deflate_bytes_conf(data, Compression::Default).len());
+/// ```
+pub struct SizeOnlyCompressor {
+    options: CompressionOptions,
+    lz77_state: LZ77State,
+    input_buffer: InputBuffer,
+    lz77_writer: DynamicWriter,
+    length_buffers: LengthBuffers,
+    l_lengths: [u8; NUM_LITERALS_AND_LENGTHS + 2],
+    d_lengths: [u8; NUM_DISTANCE_CODES + 2],
+}
+
+impl SizeOnlyCompressor {
+    /// Creates a new compressor using the specified compression options.
+    pub fn new<O: Into<CompressionOptions>>(options: O) -> SizeOnlyCompressor {
+        let options = options.into();
+        let allocator = StateAllocator::global();
+        SizeOnlyCompressor {
+            options,
+            lz77_state: new_lz77_state(&options, &allocator),
+            input_buffer: InputBuffer::empty_in(&allocator),
+            lz77_writer: DynamicWriter::with_buffer_length_in(
+                symbol_buffer_length(&options),
+                &allocator,
+            ),
+            length_buffers: LengthBuffers::new_in(&allocator),
+            l_lengths: [0; NUM_LITERALS_AND_LENGTHS + 2],
+            d_lengths: [0; NUM_DISTANCE_CODES + 2],
+        }
+    }
+
+    /// The number of bits `input` takes up when compressed as raw DEFLATE data, not including the
+    /// padding at the end of the last byte.
+    pub fn compressed_bits(&mut self, input: &[u8]) -> u64 {
+        if self.options.special == SpecialOptions::ForceStored {
+            let blocks = cmp::max(input.len().div_ceil(STORED_ONLY_BLOCK_LENGTH), 1);
+            return (input.len() as u64 + blocks as u64 * STORED_BLOCK_OVERHEAD) * 8;
+        }
+
+        self.lz77_state.reset();
+        self.input_buffer.clear();
+        self.lz77_writer.clear();
+
+        let mut remaining = input;
+        let mut bits = 0;
+        loop {
+            let (written, status, _) = lz77_compress_block(
+                remaining,
+                &mut self.lz77_state,
+                &mut self.input_buffer,
+                &mut self.lz77_writer,
+                Flush::Finish,
+            );
+            remaining = &remaining[written..];
+
+            let (l_freqs, d_freqs) = self.lz77_writer.get_frequencies();
+            let (_, block_bits) = gen_huffman_lengths(
+                l_freqs,
+                d_freqs,
+                self.lz77_writer.frequency_shift(),
+                self.lz77_state.current_block_input_bytes(),
+                (bits % 8) as u8,
+                &mut self.l_lengths,
+                &mut self.d_lengths,
+                &mut self.length_buffers,
+                None,
+                None,
+            );
+            bits += u64::from(BLOCK_MARKER_LENGTH) + block_bits;
+            self.lz77_writer.clear();
+            self.lz77_state.reset_input_bytes();
+
+            if status == LZ77Status::Finished {
+                break;
+            }
+        }
+
+        if !self.lz77_state.is_last_block() {
+            // The encoders end the stream with an empty fixed block in this case.
+            bits += u64::from(BLOCK_MARKER_LENGTH)
+                + u64::from(FIXED_CODE_LENGTHS[END_OF_BLOCK_POSITION]);
+        }
+        bits
+    }
+
+    /// The number of bytes `input` takes up when compressed as raw DEFLATE data, which is the
+    /// length of the output of `deflate_bytes_conf` with the same options.
+    ///
+    /// The zlib format adds 6 bytes to this, and gzip at least 18.
+    pub fn compressed_len(&mut self, input: &[u8]) -> usize {
+        self.compressed_bits(input).div_ceil(8) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{get_random_data, get_test_data};
+
+    #[test]
+    fn same_length_as_output() {
+        let data = get_test_data();
+        let random = get_random_data(100_000);
+        let inputs: [&[u8]; 6] = [&[], &[1], b"Deflate late", &data[..200], &data, &random];
+        for &options in &[
+            CompressionOptions::default(),
+            CompressionOptions::fast(),
+            CompressionOptions::rle(),
+            CompressionOptions::high(),
+            CompressionOptions::huffman_only(),
+            CompressionOptions::stored(),
+        ] {
+            let mut compressor = SizeOnlyCompressor::new(options);
+            // Run twice to check that no state is kept between inputs.
+            for _ in 0..2 {
+                for &input in &inputs {
+                    let compressed = crate::deflate_bytes_conf(input, options);
+                    assert_eq!(compressor.compressed_len(input), compressed.len());
+                }
+            }
+        }
+    }
+}
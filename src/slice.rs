@@ -0,0 +1,110 @@
+//! Compression into a slice provided by the caller.
+//!
+//! The compressed data is copied into the slice as each block is finished, so apart from the
+//! compressor state only the output of the current block is held in memory.
+use std::{cmp, io};
+
+use crate::compress::{compress_data_dynamic_n, Flush};
+use crate::compression_options::CompressionOptions;
+use crate::deflate_state::DeflateState;
+use crate::heapless::OutputFull;
+use crate::writer::compress_until_done;
+
+/// The amount of input passed to the compressor at a time.
+const CHUNK_SIZE: usize = 1 << 15;
+
+/// Copy the output of `state` to `output` at `written`, clearing the output buffer of `state`.
+fn take_output(
+    state: &mut DeflateState<io::Sink>,
+    output: &mut [u8],
+    written: &mut usize,
+) -> Result<(), OutputFull> {
+    let data = state.output_buf();
+    let end = *written + data.len();
+    output
+        .get_mut(*written..end)
+        .ok_or(OutputFull)?
+        .copy_from_slice(data);
+    *written = end;
+    state.clear_output_buf();
+    Ok(())
+}
+
+/// Compress `input` with DEFLATE compression into `output`, returning the length of the
+/// compressed data.
+///
+/// Returns `OutputFull` if the compressed data does not fit in `output`, in which case the
+/// contents of `output` are unspecified. A slice of at least
+/// `deflate_bound_conf(input.len(), options)` bytes is always large enough.
+///
+/// The output is identical to the output of `deflate_bytes_conf` with the same options.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{compress_to_slice, Compression, OutputFull};
+///
+/// let mut output = [0; 64];
+/// let length = compress_to_slice(b"This is some test data", &mut output, Compression::Default)?;
+/// let compressed_data = &output[..length];
+/// # let _ = compressed_data;
+/// # Ok::<(), OutputFull>(())
+/// ```
+pub fn compress_to_slice<O: Into<CompressionOptions>>(
+    input: &[u8],
+    output: &mut [u8],
+    options: O,
+) -> Result<usize, OutputFull> {
+    let mut state = Box::new(DeflateState::with_direct_output(options.into(), Vec::new()));
+    let mut read = 0;
+    let mut written = 0;
+
+    while read < input.len() {
+        let end = cmp::min(read + CHUNK_SIZE, input.len());
+        // The output is never written to the sink, so this can't fail.
+        read += compress_data_dynamic_n(&input[read..end], &mut state, Flush::None)
+            .expect("Write error!");
+        take_output(&mut state, output, &mut written)?;
+    }
+
+    compress_until_done(&[], &mut state, Flush::Finish).expect("Write error!");
+    take_output(&mut state, output, &mut written)?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::deflate_bound;
+    use crate::test_utils::{get_random_data, get_test_data};
+
+    #[test]
+    fn to_slice_same_as_deflate_bytes() {
+        for input in &[get_test_data(), get_random_data(100_000), Vec::new()] {
+            for &options in &[CompressionOptions::default(), CompressionOptions::rle()] {
+                let mut output = vec![0; deflate_bound(input.len())];
+                let length = compress_to_slice(input, &mut output, options).unwrap();
+                assert!(output[..length] == crate::deflate_bytes_conf(input, options)[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn to_slice_too_small() {
+        let input = get_test_data();
+        let compressed = crate::deflate_bytes(&input);
+        let mut output = vec![0; compressed.len()];
+        assert_eq!(
+            compress_to_slice(&input, &mut output, CompressionOptions::default()),
+            Ok(compressed.len())
+        );
+        assert_eq!(
+            compress_to_slice(
+                &input,
+                &mut output[..compressed.len() - 1],
+                CompressionOptions::default()
+            ),
+            Err(OutputFull)
+        );
+    }
+}
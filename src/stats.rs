@@ -0,0 +1,127 @@
+//! Statistics gathered while compressing, for use when tuning the compression options.
+use crate::block_codes::BlockKind;
+use crate::huffman_table::{get_distance_code, MAX_MATCH, NUM_DISTANCE_CODES};
+use crate::lzvalue::{LZType, LZValue};
+
+/// Statistics about the blocks output by an encoder.
+///
+/// Only data that has been output as blocks is included, calling `flush()` first makes sure all
+/// the input written so far is. Empty blocks only used for flushing or ending the stream are not
+/// counted.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Stats {
+    /// The number of input bytes represented by the blocks.
+    pub input_bytes: u64,
+    /// The number of bits taken up by the blocks in the output, not including any header or
+    /// trailer.
+    pub output_bits: u64,
+    /// The number of literals output in fixed and dynamic blocks.
+    pub literals: u64,
+    /// The number of length/distance pairs output in fixed and dynamic blocks.
+    pub matches: u64,
+    /// The number of matches of each length, indexed by the length, so the first three entries
+    /// are always zero.
+    pub match_lengths: Vec<u64>,
+    /// The number of matches using each of the 30 distance codes.
+    pub distance_codes: Vec<u64>,
+    /// The number of stored blocks.
+    ///
+    /// Stored blocks longer than 65535 bytes have to be split into several DEFLATE blocks, these
+    /// are counted as one.
+    pub stored_blocks: u64,
+    /// The number of blocks using the fixed huffman codes.
+    pub fixed_blocks: u64,
+    /// The number of blocks using dynamic huffman codes.
+    pub dynamic_blocks: u64,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            input_bytes: 0,
+            output_bits: 0,
+            literals: 0,
+            matches: 0,
+            match_lengths: vec![0; usize::from(MAX_MATCH) + 1],
+            distance_codes: vec![0; NUM_DISTANCE_CODES],
+            stored_blocks: 0,
+            fixed_blocks: 0,
+            dynamic_blocks: 0,
+        }
+    }
+}
+
+impl Stats {
+    /// The number of bytes taken up by the blocks in the output, rounded up.
+    pub fn output_bytes(&self) -> u64 {
+        self.output_bits.div_ceil(8)
+    }
+
+    /// Add a block that has just been output, containing the symbols in `values`.
+    pub(crate) fn add_block(
+        &mut self,
+        kind: BlockKind,
+        input_bytes: u64,
+        output_bits: u64,
+        values: &[LZValue],
+    ) {
+        self.input_bytes += input_bytes;
+        self.output_bits += output_bits;
+        match kind {
+            BlockKind::Stored => {
+                self.stored_blocks += 1;
+                // The symbols were not used.
+                return;
+            }
+            BlockKind::Fixed => self.fixed_blocks += 1,
+            BlockKind::Dynamic => self.dynamic_blocks += 1,
+        }
+        for value in values {
+            match value.value() {
+                LZType::Literal(_) => self.literals += 1,
+                LZType::StoredLengthDistance(length, distance) => {
+                    self.matches += 1;
+                    self.match_lengths[usize::from(length.actual_length())] += 1;
+                    self.distance_codes[usize::from(get_distance_code(distance))] += 1;
+                }
+            }
+        }
+    }
+
+    /// The number of bytes of heap memory used.
+    pub(crate) fn heap_size(&self) -> usize {
+        (self.match_lengths.capacity() + self.distance_codes.capacity())
+            * std::mem::size_of::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lzvalue::{ld, lit};
+
+    #[test]
+    fn add_blocks() {
+        let mut stats = Stats::default();
+        let values = [lit(1), lit(2), ld(3, 1), ld(258, 32768), lit(3)];
+        stats.add_block(BlockKind::Dynamic, 264, 100, &values);
+        stats.add_block(BlockKind::Stored, 5, 72, &values);
+        assert_eq!(stats.input_bytes, 269);
+        assert_eq!(stats.output_bits, 172);
+        assert_eq!(stats.output_bytes(), 22);
+        assert_eq!(stats.literals, 3);
+        assert_eq!(stats.matches, 2);
+        assert_eq!(stats.match_lengths[3], 1);
+        assert_eq!(stats.match_lengths[258], 1);
+        assert_eq!(stats.distance_codes[0], 1);
+        assert_eq!(stats.distance_codes[29], 1);
+        assert_eq!(
+            (
+                stats.stored_blocks,
+                stats.fixed_blocks,
+                stats.dynamic_blocks
+            ),
+            (1, 0, 1)
+        );
+    }
+}
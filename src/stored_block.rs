@@ -9,7 +9,12 @@ const BLOCK_SIZE: u16 = 32000;
 
 const STORED_FIRST_BYTE: u8 = 0b0000_0000;
 pub const STORED_FIRST_BYTE_FINAL: u8 = 0b0000_0001;
+/// The length of the stored blocks input is split into when compressing it fails, which is kept
+/// below the limit of the format.
 pub const MAX_STORED_BLOCK_LENGTH: usize = (u16::MAX as usize) / 2;
+/// The length of the blocks output when only stored blocks are used, the largest the format
+/// allows.
+pub const STORED_ONLY_BLOCK_LENGTH: usize = u16::MAX as usize;
 
 pub fn write_stored_header(writer: &mut LsbWriter, final_block: bool) {
     let header = if final_block {
@@ -55,3 +55,32 @@ pub fn decompress_gzip(compressed: &[u8]) -> (GzHeader, Vec<u8>) {
 pub fn decompress_zlib(compressed: &[u8]) -> Vec<u8> {
     miniz_oxide::inflate::decompress_to_vec_zlib(&compressed).expect("Decompression failed!")
 }
+
+/// Decompress a raw deflate stream compressed with a preset dictionary.
+///
+/// The dictionary is put in a stored block in front of the stream, so references into it are
+/// resolved, and removed from the output afterwards.
+pub fn decompress_with_dictionary(dictionary: &[u8], compressed: &[u8]) -> Vec<u8> {
+    assert!(dictionary.len() <= 0xFFFF);
+    let len = dictionary.len() as u16;
+    let mut input = vec![0];
+    input.extend_from_slice(&len.to_le_bytes());
+    input.extend_from_slice(&(!len).to_le_bytes());
+    input.extend_from_slice(dictionary);
+    input.extend_from_slice(compressed);
+    decompress_to_end(&input).split_off(dictionary.len())
+}
+
+/// Generate `len` bytes of pseudo-random data, which can't be compressed.
+pub fn get_random_data(len: usize) -> Vec<u8> {
+    // xorshift32
+    let mut value = 0x1234_5678u32;
+    (0..len)
+        .map(|_| {
+            value ^= value << 13;
+            value ^= value >> 17;
+            value ^= value << 5;
+            (value >> 24) as u8
+        })
+        .collect()
+}
@@ -0,0 +1,233 @@
+//! Listing the literals and matches compressed data is made up of.
+//!
+//! [`lz77_tokens`] runs only the matching stage of the encoder, for use with a different entropy
+//! coder or for analysing the matches found.
+//!
+//! Two encoders can differ in compression ratio on the same input for many reasons, but most of
+//! the difference usually comes from how they split the input into literals and matches. Comparing
+//! the bitstreams doesn't say much about that, so [`parse_tokens`] decodes a compressed stream
+//! from any encoder back into its tokens, and [`write_token_listing`] writes them out as text, one
+//! per line, which can be compared with a regular diff tool.
+//!
+//! [`lz77_tokens`]: fn.lz77_tokens.html
+//! [`parse_tokens`]: fn.parse_tokens.html
+//! [`write_token_listing`]: fn.write_token_listing.html
+use std::ascii;
+use std::io;
+
+use crate::allocator::StateAllocator;
+use crate::compress::Flush;
+use crate::compression_options::{CompressionOptions, SpecialOptions};
+use crate::deflate_state::{new_lz77_state, symbol_buffer_length};
+use crate::inflate::{inflate_raw_tokens, InflateError};
+use crate::input_buffer::InputBuffer;
+use crate::lz77::{lz77_compress_block, LZ77Status};
+use crate::lzvalue::{LZType, LZValue};
+use crate::output_writer::DynamicWriter;
+
+/// A literal byte or a match, the units lz77 compression splits the input into.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Token {
+    /// A byte that is output as it is.
+    Literal(u8),
+    /// A copy of `length` bytes, starting `distance` bytes back in the data.
+    Match { length: u16, distance: u16 },
+}
+
+impl Token {
+    /// The number of bytes of data the token represents.
+    pub fn data_length(&self) -> usize {
+        match *self {
+            Token::Literal(_) => 1,
+            Token::Match { length, .. } => usize::from(length),
+        }
+    }
+}
+
+impl From<LZValue> for Token {
+    fn from(value: LZValue) -> Token {
+        match value.value() {
+            LZType::Literal(byte) => Token::Literal(byte),
+            LZType::StoredLengthDistance(length, distance) => Token::Match {
+                length: length.actual_length(),
+                distance,
+            },
+        }
+    }
+}
+
+/// Split `data` into literals and matches the way the encoders do with `options`, without
+/// encoding them.
+///
+/// The tokens are the same as the ones the encoders output, with the exception of blocks the
+/// encoders decide to output as stored blocks as they would not be compressed, which are kept as
+/// they are here. With stored-only options every byte is a literal.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{lz77_tokens, Compression, Token};
+///
+/// let tokens = lz77_tokens(b"Deflate late", Compression::Default);
+/// assert_eq!(tokens.len(), 9);
+/// assert_eq!(tokens[8], Token::Match { length: 4, distance: 5 });
+/// ```
+pub fn lz77_tokens<O: Into<CompressionOptions>>(data: &[u8], options: O) -> Vec<Token> {
+    let options = options.into();
+    if options.special == SpecialOptions::ForceStored {
+        return data.iter().map(|&b| Token::Literal(b)).collect();
+    }
+
+    let allocator = StateAllocator::global();
+    let mut lz77_state = new_lz77_state(&options, &allocator);
+    let mut input_buffer = InputBuffer::empty_in(&allocator);
+    let mut writer =
+        DynamicWriter::with_buffer_length_in(symbol_buffer_length(&options), &allocator);
+    let mut tokens = Vec::new();
+    let mut remaining = data;
+    loop {
+        let (written, status, _) = lz77_compress_block(
+            remaining,
+            &mut lz77_state,
+            &mut input_buffer,
+            &mut writer,
+            Flush::Finish,
+        );
+        remaining = &remaining[written..];
+        tokens.extend(writer.get_buffer().iter().map(|&v| Token::from(v)));
+        writer.clear();
+        lz77_state.reset_input_bytes();
+        if status == LZ77Status::Finished {
+            return tokens;
+        }
+    }
+}
+
+/// Decode the raw DEFLATE stream `compressed` into the tokens it's made up of.
+///
+/// The bytes in stored blocks are returned as literals. Any data after the end of the stream is
+/// ignored.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_bytes, parse_tokens, Token};
+///
+/// let compressed = deflate_bytes(b"Deflate late");
+/// let tokens = parse_tokens(&compressed).unwrap();
+/// assert_eq!(tokens.len(), 9);
+/// assert_eq!(tokens[8], Token::Match { length: 4, distance: 5 });
+/// ```
+pub fn parse_tokens(compressed: &[u8]) -> Result<Vec<Token>, InflateError> {
+    let mut output = Vec::with_capacity(compressed.len() * 3);
+    let mut tokens = Vec::new();
+    inflate_raw_tokens(compressed, &mut output, &mut tokens)?;
+    Ok(tokens)
+}
+
+/// Write a listing of `tokens` to `writer`, one token per line.
+///
+/// Each line starts with the position in the decompressed data of the first byte the token
+/// represents. Literals are then written as `L` followed by the value of the byte and the byte
+/// itself, escaped if it's not printable ASCII, and matches as `M` followed by the length and the
+/// distance.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{deflate_bytes, parse_tokens, write_token_listing};
+///
+/// let tokens = parse_tokens(&deflate_bytes(b"Deflate late\n")).unwrap();
+/// let mut listing = Vec::new();
+/// write_token_listing(&tokens, &mut listing).unwrap();
+/// let listing = String::from_utf8(listing).unwrap();
+/// assert!(listing.starts_with("0 L 68 'D'\n1 L 101 'e'\n"));
+/// assert!(listing.ends_with("8 M 4 5\n12 L 10 '\\n'\n"));
+/// ```
+pub fn write_token_listing<W: io::Write>(tokens: &[Token], mut writer: W) -> io::Result<()> {
+    let mut position = 0;
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => {
+                let escaped: String = ascii::escape_default(byte).map(char::from).collect();
+                writeln!(writer, "{} L {} '{}'", position, byte, escaped)?;
+            }
+            Token::Match { length, distance } => {
+                writeln!(writer, "{} M {} {}", position, length, distance)?;
+            }
+        }
+        position += token.data_length();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lz77::lz77_compress;
+    use crate::test_utils::get_test_data;
+
+    #[test]
+    fn tokens_match_lz77_output() {
+        let data = b"Deflate late";
+        let expected: Vec<Token> = lz77_compress(data)
+            .unwrap()
+            .iter()
+            .map(|&v| Token::from(v))
+            .collect();
+        assert_eq!(parse_tokens(&crate::deflate_bytes(data)).unwrap(), expected);
+        assert_eq!(lz77_tokens(data, CompressionOptions::high()), expected);
+    }
+
+    #[test]
+    fn lz77_tokens_same_as_output() {
+        let data = get_test_data();
+        for &options in &[
+            CompressionOptions::default(),
+            CompressionOptions::fast(),
+            CompressionOptions::rle(),
+            CompressionOptions::high(),
+            CompressionOptions::huffman_only(),
+            CompressionOptions::stored(),
+        ] {
+            let compressed = crate::deflate_bytes_conf(&data, options);
+            assert!(lz77_tokens(&data, options) == parse_tokens(&compressed).unwrap());
+        }
+        assert_eq!(lz77_tokens(&[], CompressionOptions::default()), []);
+    }
+
+    #[test]
+    fn tokens_rebuild_data() {
+        let data = get_test_data();
+        let tokens = parse_tokens(&crate::deflate_bytes(&data)).unwrap();
+        let mut rebuilt = Vec::new();
+        for token in &tokens {
+            match *token {
+                Token::Literal(byte) => rebuilt.push(byte),
+                Token::Match { length, distance } => {
+                    for _ in 0..length {
+                        rebuilt.push(rebuilt[rebuilt.len() - usize::from(distance)]);
+                    }
+                }
+            }
+        }
+        assert!(rebuilt == data);
+    }
+
+    #[test]
+    fn stored_as_literals() {
+        let compressed = crate::deflate_bytes_conf(b"abc", crate::CompressionOptions::stored());
+        assert_eq!(
+            parse_tokens(&compressed).unwrap(),
+            [
+                Token::Literal(b'a'),
+                Token::Literal(b'b'),
+                Token::Literal(b'c')
+            ]
+        );
+        assert_eq!(
+            parse_tokens(&compressed[..3]),
+            Err(InflateError::UnexpectedEof)
+        );
+    }
+}
@@ -0,0 +1,174 @@
+//! Compression of websocket messages with the permessage-deflate extension (RFC 7692).
+//!
+//! Each message is compressed as a part of a raw DEFLATE stream ending with a sync flush, with
+//! the `00 00 FF FF` the flush ends with stripped off. Unless "no context takeover" has been
+//! negotiated, the window is kept between messages so later messages can refer to earlier ones.
+use std::io;
+use std::io::Write;
+
+use crate::compression_options::{CompressionOptions, MAX_WINDOW_BITS, MIN_WINDOW_BITS};
+use crate::error::Error;
+use crate::inflate::{inflate_bytes_with_dictionary, InflateError};
+use crate::writer::{Encoder, Format};
+
+/// The end of the empty stored block a sync flush ends with.
+const SYNC_TRAILER: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+/// A final empty stored block, appended when decompressing to end the stream.
+const FINAL_BLOCK: [u8; 5] = [0x01, 0x00, 0x00, 0xFF, 0xFF];
+
+/// Compresses the messages sent on one websocket connection.
+///
+/// # Examples
+///
+/// ```
+/// use deflate::{CompressionOptions, MessageCompressor, MessageDecompressor};
+///
+/// let mut compressor = MessageCompressor::new(CompressionOptions::default(), 15, false).unwrap();
+/// let mut decompressor = MessageDecompressor::new(15, false);
+/// for message in &[&b"Hello, world"[..], b"Hello again"] {
+///     let payload = compressor.compress_message(message).unwrap();
+///     assert_eq!(decompressor.decompress_message(&payload).unwrap(), *message);
+/// }
+/// ```
+pub struct MessageCompressor {
+    encoder: Encoder<Vec<u8>>,
+    no_context_takeover: bool,
+}
+
+impl MessageCompressor {
+    /// Create a compressor using a window of `2^max_window_bits` bytes, the
+    /// `client_max_window_bits` or `server_max_window_bits` parameter negotiated for this end of
+    /// the connection.
+    ///
+    /// If `no_context_takeover` is set, each message is compressed on its own.
+    ///
+    /// Returns an error if `max_window_bits` is not between 9 and 15, a window of 256 bytes is
+    /// not supported.
+    pub fn new<O: Into<CompressionOptions>>(
+        options: O,
+        max_window_bits: u8,
+        no_context_takeover: bool,
+    ) -> Result<MessageCompressor, Error> {
+        if !(MIN_WINDOW_BITS..=MAX_WINDOW_BITS).contains(&max_window_bits) {
+            return Err(Error::InvalidOptions(
+                "The window size has to be between 9 and 15 bits",
+            ));
+        }
+        let options = CompressionOptions {
+            window_bits: max_window_bits,
+            ..options.into()
+        };
+        Ok(MessageCompressor {
+            encoder: Encoder::new(Vec::new(), Format::Raw, options),
+            no_context_takeover,
+        })
+    }
+
+    /// Compress `message`, returning the payload to send.
+    pub fn compress_message(&mut self, message: &[u8]) -> io::Result<Vec<u8>> {
+        self.encoder.write_all(message)?;
+        if self.no_context_takeover {
+            self.encoder.full_flush()?;
+        } else {
+            self.encoder.flush()?;
+        }
+        let mut payload = std::mem::take(self.encoder.get_mut());
+        debug_assert!(payload.ends_with(&SYNC_TRAILER));
+        payload.truncate(payload.len() - SYNC_TRAILER.len());
+        Ok(payload)
+    }
+}
+
+/// Decompresses the messages received on one websocket connection.
+///
+/// See [`MessageCompressor`](struct.MessageCompressor.html) for an example.
+pub struct MessageDecompressor {
+    /// The end of the previously decompressed messages, used as the dictionary of the next one.
+    window: Vec<u8>,
+    window_size: usize,
+    no_context_takeover: bool,
+}
+
+impl MessageDecompressor {
+    /// Create a decompressor for messages compressed with a window of up to
+    /// `2^max_window_bits` bytes.
+    ///
+    /// If `no_context_takeover` is set, each message is expected to be compressed on its own.
+    pub fn new(max_window_bits: u8, no_context_takeover: bool) -> MessageDecompressor {
+        MessageDecompressor {
+            window: Vec::new(),
+            window_size: 1 << max_window_bits.min(MAX_WINDOW_BITS),
+            no_context_takeover,
+        }
+    }
+
+    /// Decompress the payload of a message.
+    pub fn decompress_message(&mut self, payload: &[u8]) -> Result<Vec<u8>, InflateError> {
+        let mut input = Vec::with_capacity(payload.len() + SYNC_TRAILER.len() + FINAL_BLOCK.len());
+        input.extend_from_slice(payload);
+        input.extend_from_slice(&SYNC_TRAILER);
+        input.extend_from_slice(&FINAL_BLOCK);
+        let message = inflate_bytes_with_dictionary(&self.window, &input)?;
+
+        if !self.no_context_takeover {
+            self.window.extend_from_slice(&message);
+            let excess = self.window.len().saturating_sub(self.window_size);
+            self.window.drain(..excess);
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::get_test_data;
+
+    #[test]
+    fn message_roundtrip() {
+        let data = get_test_data();
+        let messages: Vec<&[u8]> = data.chunks(3000).take(20).chain(Some(&[][..])).collect();
+        for &no_context_takeover in &[false, true] {
+            let mut compressor =
+                MessageCompressor::new(CompressionOptions::default(), 10, no_context_takeover)
+                    .unwrap();
+            let mut decompressor = MessageDecompressor::new(10, no_context_takeover);
+            let mut total = 0;
+            for message in &messages {
+                let payload = compressor.compress_message(message).unwrap();
+                assert!(!payload.ends_with(&SYNC_TRAILER));
+                assert_eq!(decompressor.decompress_message(&payload).unwrap(), *message);
+                if no_context_takeover {
+                    // The message can be decompressed without the previous ones.
+                    let mut fresh = MessageDecompressor::new(10, true);
+                    assert_eq!(fresh.decompress_message(&payload).unwrap(), *message);
+                }
+                total += payload.len();
+            }
+            assert!(total < data.len());
+        }
+    }
+
+    #[test]
+    fn empty_message() {
+        let mut compressor =
+            MessageCompressor::new(CompressionOptions::default(), 15, false).unwrap();
+        let payload = compressor.compress_message(&[]).unwrap();
+        assert!(payload.len() <= 2);
+        // RFC 7692 section 7.2.3.6: other implementations send an empty message as a single 0 byte.
+        let mut decompressor = MessageDecompressor::new(15, false);
+        assert_eq!(decompressor.decompress_message(&payload).unwrap(), []);
+        assert_eq!(decompressor.decompress_message(&[0x00]).unwrap(), []);
+        assert!(MessageCompressor::new(CompressionOptions::default(), 8, false).is_err());
+    }
+
+    #[test]
+    fn decompress_rfc_example() {
+        // RFC 7692 section 7.2.3.2: "Hello" sent twice, sharing the window.
+        let mut decompressor = MessageDecompressor::new(15, false);
+        let first = [0xF2, 0x48, 0xCD, 0xC9, 0xC9, 0x07, 0x00];
+        let second = [0xF2, 0x00, 0x11, 0x00, 0x00];
+        assert_eq!(decompressor.decompress_message(&first).unwrap(), b"Hello");
+        assert_eq!(decompressor.decompress_message(&second).unwrap(), b"Hello");
+    }
+}
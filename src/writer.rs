@@ -1,22 +1,32 @@
 use std::io::Write;
-use std::{io, thread};
-
-use byteorder::{BigEndian, WriteBytesExt};
-
-use crate::checksum::{Adler32Checksum, RollingChecksum};
+use std::{io, mem, thread};
+
+#[cfg(feature = "allocator")]
+use crate::allocator::StateAllocator;
+use crate::block_codes::{BlockCodes, BlockInfo};
+use crate::boundary::{clamp_boundary, AccessPoint, Boundary, BoundaryContext, BoundaryPolicy};
+use crate::checksum::{Adler32Checksum, Crc32, RollingChecksum};
+use crate::code_lengths::CodeLengths;
 use crate::compress::compress_data_dynamic_n;
 use crate::compress::Flush;
-use crate::compression_options::CompressionOptions;
-use crate::deflate_state::DeflateState;
-use crate::zlib::{write_zlib_header, CompressionLevel};
+use crate::compression_options::{clamp_window_bits, CompressionOptions};
+use crate::deflate_state::{DeflateState, MemoryUsage};
+use crate::error::Error;
+#[cfg(feature = "gzip")]
+use crate::framing::GzipFraming;
+use crate::framing::{Framing, RawFraming, ZlibFraming};
+use crate::input_buffer::InputWindow;
+use crate::priors::FrequencyPriors;
+use crate::stats::Stats;
+use crate::zlib::{get_zlib_header_with_dictionary, write_zlib_header, CompressionLevel};
 
 const ERR_STR: &str = "Error! The wrapped writer is missing.\
                        This is a bug, please file an issue.";
 
 /// Keep compressing until all the input has been compressed and output or the writer returns `Err`.
-pub fn compress_until_done<W: Write>(
+pub fn compress_until_done<W: Write, B: InputWindow>(
     mut input: &[u8],
-    deflate_state: &mut DeflateState<W>,
+    deflate_state: &mut DeflateState<W, B>,
     flush_mode: Flush,
 ) -> io::Result<()> {
     // This should only be used for flushing.
@@ -24,7 +34,7 @@ pub fn compress_until_done<W: Write>(
     loop {
         match compress_data_dynamic_n(input, deflate_state, flush_mode) {
             Ok(0) => {
-                if deflate_state.output_buf().is_empty() {
+                if deflate_state.output_buf().is_empty() || deflate_state.direct_output {
                     break;
                 } else {
                     // If the output buffer isn't empty, keep going until it is, as there is still
@@ -59,6 +69,146 @@ pub fn compress_until_done<W: Write>(
     Ok(())
 }
 
+/// Enable or disable recording the codes used for each block in `deflate_state`.
+fn set_record_block_codes<W: Write>(deflate_state: &mut DeflateState<W>, record: bool) {
+    if !record {
+        deflate_state.block_codes = None;
+    } else if deflate_state.block_codes.is_none() {
+        deflate_state.block_codes = Some(Vec::new());
+    }
+}
+
+/// Take the codes recorded for the blocks output so far from `deflate_state`.
+fn take_block_codes<W: Write>(deflate_state: &mut DeflateState<W>) -> Vec<BlockCodes> {
+    match deflate_state.block_codes {
+        Some(ref mut codes) => mem::take(codes),
+        None => Vec::new(),
+    }
+}
+
+/// Enable or disable recording access points in `deflate_state`.
+fn set_record_access_points<W: Write>(deflate_state: &mut DeflateState<W>, record: bool) {
+    if !record {
+        deflate_state.access_points = None;
+    } else if deflate_state.access_points.is_none() {
+        deflate_state.access_points = Some(Vec::new());
+    }
+}
+
+/// Take the access points recorded so far from `deflate_state`.
+fn take_access_points<W: Write>(deflate_state: &mut DeflateState<W>) -> Vec<AccessPoint> {
+    match deflate_state.access_points {
+        Some(ref mut points) => mem::take(points),
+        None => Vec::new(),
+    }
+}
+
+/// Enable or disable gathering statistics about the blocks output by `deflate_state`.
+fn set_record_stats<W: Write>(deflate_state: &mut DeflateState<W>, record: bool) {
+    if !record {
+        deflate_state.stats = None;
+    } else if deflate_state.stats.is_none() {
+        deflate_state.stats = Some(Stats::default());
+    }
+}
+
+/// Compress all of `input`, retrying if the output buffer had to be flushed to the writer first.
+fn compress_all<W: Write>(mut input: &[u8], deflate_state: &mut DeflateState<W>) -> io::Result<()> {
+    let flush_mode = deflate_state.flush_mode;
+    while !input.is_empty() {
+        match compress_data_dynamic_n(input, deflate_state, flush_mode) {
+            Ok(n) => input = &input[n..],
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Compress data from `buf`, consulting the boundary policy if one is set.
+///
+/// Returns the number of bytes that were consumed, and the boundary the policy wants inserted
+/// after them, if any.
+fn compress_with_policy<W: Write>(
+    buf: &[u8],
+    deflate_state: &mut DeflateState<W>,
+) -> io::Result<(usize, Option<Boundary>)> {
+    let boundary = match deflate_state.boundary_policy {
+        None => {
+            let flush_mode = deflate_state.flush_mode;
+            return compress_data_dynamic_n(buf, deflate_state, flush_mode).map(|n| (n, None));
+        }
+        Some(_) if buf.is_empty() => return Ok((0, None)),
+        Some(ref mut policy) => {
+            let (literal_length_frequencies, distance_frequencies) =
+                deflate_state.lz77_writer.get_frequencies();
+            let context = BoundaryContext {
+                bytes_since_boundary: deflate_state.bytes_since_boundary,
+                total_in: deflate_state.bytes_written,
+                literal_length_frequencies,
+                distance_frequencies,
+            };
+            policy.next_boundary(buf, &context)
+        }
+    };
+
+    // The policy has seen all the bytes up to the boundary, so we have to make sure all of them
+    // are consumed.
+    let consumed = match boundary {
+        Some((n, _)) => clamp_boundary(n, buf.len()),
+        None => buf.len(),
+    };
+    compress_all(&buf[..consumed], deflate_state)?;
+    deflate_state.bytes_since_boundary += consumed as u64;
+    if boundary.is_some() {
+        deflate_state.bytes_since_boundary = 0;
+    }
+    Ok((consumed, boundary.map(|(_, b)| b)))
+}
+
+/// End the current block without padding the output to a byte boundary, returning the number of
+/// bits of the block end that are kept back until more data is output.
+fn block_flush<W: Write>(deflate_state: &mut DeflateState<W>) -> io::Result<u8> {
+    compress_until_done(&[], deflate_state, Flush::Block)?;
+    Ok(deflate_state.encoder_state.writer.pending_bits())
+}
+
+/// Change the compression options in the middle of the stream, ending the current block first
+/// if any data has been written.
+fn set_options<W: Write>(
+    deflate_state: &mut DeflateState<W>,
+    options: CompressionOptions,
+) -> io::Result<()> {
+    deflate_state.check_options_change(&options)?;
+    if options == deflate_state.compression_options {
+        return Ok(());
+    }
+    if deflate_state.bytes_written != 0 {
+        compress_until_done(&[], deflate_state, Flush::Block)?;
+    }
+    deflate_state.change_options(options);
+    Ok(())
+}
+
+/// Insert a block or stream boundary in the output.
+///
+/// Starting new gzip members has to be handled by the caller, they are treated as full flushes
+/// here.
+fn insert_boundary<W: Write>(
+    deflate_state: &mut DeflateState<W>,
+    boundary: Boundary,
+) -> io::Result<()> {
+    match boundary {
+        Boundary::EndBlock => compress_until_done(&[], deflate_state, Flush::Block),
+        Boundary::FullFlush | Boundary::NewMember => {
+            compress_until_done(&[], deflate_state, Flush::Sync)?;
+            deflate_state.reset_window();
+            deflate_state.record_access_point();
+            Ok(())
+        }
+    }
+}
+
 /// A DEFLATE encoder/compressor.
 ///
 /// A struct implementing a [`Write`] interface that takes unencoded data and compresses it to
@@ -100,6 +250,19 @@ impl<W: Write> DeflateEncoder<W> {
         }
     }
 
+    /// Creates a new encoder using the provided compression options, allocating the internal
+    /// buffers with `allocator`.
+    #[cfg(feature = "allocator")]
+    pub fn new_in<O: Into<CompressionOptions>>(
+        writer: W,
+        options: O,
+        allocator: StateAllocator,
+    ) -> DeflateEncoder<W> {
+        DeflateEncoder {
+            deflate_state: DeflateState::new_in(options.into(), writer, allocator),
+        }
+    }
+
     /// Encode all pending data to the contained writer, consume this `DeflateEncoder`,
     /// and return the contained writer if writing succeeds.
     pub fn finish(mut self) -> io::Result<W> {
@@ -109,6 +272,36 @@ impl<W: Write> DeflateEncoder<W> {
         Ok(self.deflate_state.inner.take().expect(ERR_STR))
     }
 
+    /// Encode all pending data to the contained writer without marking the last block as the
+    /// final one, consume this `DeflateEncoder`, and return the contained writer if writing
+    /// succeeds.
+    ///
+    /// The output ends on a byte boundary, with the empty stored block written by `flush()`, and
+    /// doesn't refer to any data compressed by other encoders. Output produced this way can
+    /// therefore be concatenated with that of other encoders into one stream, which has to be
+    /// ended with a final block, e.g the empty block with fixed codes `[0x03, 0x00]`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::io::Write;
+    ///
+    /// use deflate::Compression;
+    /// use deflate::write::DeflateEncoder;
+    ///
+    /// let mut stream = Vec::new();
+    /// for part in &[&b"Hello, "[..], b"world!"] {
+    ///     let mut encoder = DeflateEncoder::new(Vec::new(), Compression::Default);
+    ///     encoder.write_all(part).unwrap();
+    ///     stream.extend_from_slice(&encoder.finish_non_final().unwrap());
+    /// }
+    /// stream.extend_from_slice(&[0x03, 0x00]);
+    /// ```
+    pub fn finish_non_final(mut self) -> io::Result<W> {
+        compress_until_done(&[], &mut self.deflate_state, Flush::Sync)?;
+        Ok(self.deflate_state.inner.take().expect(ERR_STR))
+    }
+
     /// Resets the encoder (except the compression options), replacing the current writer
     /// with a new one, returning the old one.
     pub fn reset(&mut self, w: W) -> io::Result<W> {
@@ -116,16 +309,226 @@ impl<W: Write> DeflateEncoder<W> {
         self.deflate_state.reset(w)
     }
 
+    /// Resets the encoder, replacing the current writer with a new one and using new compression
+    /// options, returning the old writer.
+    ///
+    /// The internal buffers are kept, so a single encoder can be used to compress many streams
+    /// without allocating.
+    pub fn reset_with_options<O: Into<CompressionOptions>>(
+        &mut self,
+        w: W,
+        options: O,
+    ) -> io::Result<W> {
+        self.output_all()?;
+        self.deflate_state.reset_with_options(w, options.into())
+    }
+
     /// Output all pending data as if encoding is done, but without resetting anything
     fn output_all(&mut self) -> io::Result<()> {
         compress_until_done(&[], &mut self.deflate_state, Flush::Finish)
     }
+
+    /// Set a policy deciding where to insert additional block or stream boundaries in the output.
+    ///
+    /// See the [`boundary`](../boundary/index.html) module for details.
+    pub fn set_boundary_policy<P: BoundaryPolicy + Send + 'static>(&mut self, policy: P) {
+        self.deflate_state.boundary_policy = Some(Box::new(policy));
+    }
+
+    /// Set a function to call with information about each block after it has been output.
+    ///
+    /// This can be used to e.g build an index of the blocks while compressing. The output bit
+    /// positions include any header.
+    pub fn set_block_callback<F: FnMut(&BlockInfo) + Send + 'static>(&mut self, callback: F) {
+        self.deflate_state.block_callback = Some(Box::new(callback));
+    }
+
+    /// Perform a full flush, like `Z_FULL_FLUSH` in zlib.
+    ///
+    /// Like `flush()`, this outputs all pending data followed by an empty stored block, but also
+    /// clears the window and the hash chains, so the compressed data written afterwards doesn't
+    /// refer to anything before the flush and can be decompressed on its own. This can be used
+    /// to create points where decompression can be restarted, at the cost of compressing the
+    /// data after the flush slightly worse.
+    pub fn full_flush(&mut self) -> io::Result<()> {
+        insert_boundary(&mut self.deflate_state, Boundary::FullFlush)
+    }
+
+    /// Perform a partial flush, like `Z_PARTIAL_FLUSH` in zlib.
+    ///
+    /// This ends the current block and outputs an empty block with fixed codes, so all the data
+    /// written so far can be decompressed from the output. Unlike `flush()`, the output is not
+    /// padded to a byte boundary, which avoids the 4 bytes of overhead of the empty stored block.
+    /// Up to 7 bits of the empty block are kept back until more data is output.
+    pub fn partial_flush(&mut self) -> io::Result<()> {
+        compress_until_done(&[], &mut self.deflate_state, Flush::Partial)
+    }
+
+    /// End the current block, like the `Z_BLOCK` flush mode of zlib.
+    ///
+    /// Unlike `partial_flush()` and `flush()`, no empty block follows and the output is not
+    /// padded to a byte boundary, so the next block starts right after this one. Returns the
+    /// number of bits, at most 7, of the block end that are kept back until more data is output.
+    /// Together with the output length this gives the bit position where the next block starts.
+    pub fn block_flush(&mut self) -> io::Result<u8> {
+        block_flush(&mut self.deflate_state)
+    }
+
+    /// Change the compression options in the middle of the stream, like `deflateParams` in zlib.
+    ///
+    /// The current block is ended first, so the data written so far is compressed with the old
+    /// options, and the window is kept, so later data can still reference earlier data. This
+    /// makes it possible to e.g lower the compression level while the system is busy without
+    /// starting a new stream.
+    ///
+    /// Only the options deciding how matches are searched for and the block type can be
+    /// changed. Returns an error if the window size, memory level, hashing or repeat detection
+    /// differs from the current options. Switching to or from only outputting stored blocks
+    /// clears the window.
+    pub fn set_options<O: Into<CompressionOptions>>(&mut self, options: O) -> io::Result<()> {
+        set_options(&mut self.deflate_state, options.into())
+    }
+
+    /// Set prior symbol frequencies to blend into the observed ones when generating the huffman
+    /// codes of dynamic blocks.
+    ///
+    /// See [`FrequencyPriors`](../struct.FrequencyPriors.html) for details.
+    pub fn set_frequency_priors(&mut self, priors: FrequencyPriors) {
+        self.deflate_state.frequency_priors = Some(priors);
+    }
+
+    /// Set the huffman code lengths to use for dynamic blocks instead of generating them from
+    /// the symbol frequencies, or go back to generating them with `None`.
+    ///
+    /// The lengths are used for the blocks output after this call, which includes the data
+    /// that has been written but not output yet. Flush the encoder first to keep the lengths
+    /// from applying to that data. See [`CodeLengths`](../struct.CodeLengths.html) for details.
+    pub fn set_code_lengths(&mut self, code_lengths: Option<CodeLengths>) {
+        self.deflate_state.code_lengths = code_lengths;
+    }
+
+    /// Use `dictionary` as a preset dictionary, like `deflateSetDictionary` in zlib.
+    ///
+    /// The compressed data can refer back to the last 32 KiB of the dictionary, which can improve
+    /// compression of short messages a lot if they contain strings common in the dictionary.
+    /// The decompressor has to be given the same dictionary to decompress the data.
+    ///
+    /// Returns an error if any data has been written to the encoder, or a dictionary has already
+    /// been set.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) -> io::Result<()> {
+        self.deflate_state.set_dictionary(dictionary)
+    }
+
+    /// Insert the lowest `bits` bits of `value` into the output before the compressed data, like
+    /// `deflatePrime` in zlib.
+    ///
+    /// This can be used to continue a bit stream that doesn't end on a byte boundary, e.g when
+    /// joining DEFLATE streams. It can be called several times to insert more bits.
+    ///
+    /// Returns an error if `bits` is more than 16, or if any data has been written to the encoder.
+    pub fn prime(&mut self, bits: u8, value: u16) -> io::Result<()> {
+        self.deflate_state.prime_bits(bits, value)
+    }
+
+    /// Return the total number of bits of output produced so far.
+    ///
+    /// This includes output that is still buffered in the encoder, so it can be used to find the
+    /// exact bit position of the compressed stream, e.g to calculate alignment or splice points.
+    /// Input that has been written to the encoder but not yet compressed is not accounted for,
+    /// calling `flush()` first will ensure all of it is.
+    pub fn bits_written(&self) -> u64 {
+        self.deflate_state.output_bits()
+    }
+
+    /// Return the number of whole bytes and extra bits, at most 7, of compressed data held in
+    /// the encoder that have not been written to the wrapped writer yet, like `deflatePending` in
+    /// zlib.
+    ///
+    /// The extra bits can only be output by a flush that pads the output to a byte boundary.
+    /// Input that has been written to the encoder but not yet compressed is not accounted for.
+    pub fn pending(&self) -> (usize, u8) {
+        self.deflate_state.pending_output()
+    }
+
+    /// Return the total number of bytes of input consumed by the encoder.
+    ///
+    /// The count is kept across flushes and calls to `reset`, so it covers all the streams
+    /// written by the encoder since it was created.
+    pub fn total_in(&self) -> u64 {
+        self.deflate_state.total_in
+    }
+
+    /// Return the total number of bytes of output written to the wrapped writers.
+    ///
+    /// Like `total_in`, this is kept across flushes and calls to `reset`. Output that is
+    /// still buffered in the encoder is not included, see `pending()` for that.
+    pub fn total_out(&self) -> u64 {
+        self.deflate_state.total_out
+    }
+
+    /// Return the amount of heap memory currently used by the encoder, broken down by component.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.deflate_state.memory_usage()
+    }
+
+    /// Enable or disable recording the huffman codes chosen for each block that is output.
+    ///
+    /// Disabling recording discards any codes that have not been taken yet.
+    pub fn set_record_block_codes(&mut self, record: bool) {
+        set_record_block_codes(&mut self.deflate_state, record);
+    }
+
+    /// Take the codes recorded for the blocks output since the last call, in the order they were
+    /// output.
+    ///
+    /// The output bit positions include any header. Calling `flush()` first makes sure all the
+    /// input written so far has been output.
+    pub fn take_block_codes(&mut self) -> Vec<BlockCodes> {
+        take_block_codes(&mut self.deflate_state)
+    }
+
+    /// Enable or disable recording access points, the positions in the output where
+    /// decompression can start without the data before them.
+    ///
+    /// A point is recorded at each full flush, whether done by `full_flush()` or a boundary
+    /// policy like [`IntervalPolicy`](../boundary/struct.IntervalPolicy.html), and at the start of
+    /// each new gzip member. Disabling recording discards any points that have not been taken
+    /// yet.
+    pub fn set_record_access_points(&mut self, record: bool) {
+        set_record_access_points(&mut self.deflate_state, record);
+    }
+
+    /// Take the access points recorded since the last call, in the order they were output.
+    ///
+    /// The input offsets and output bit positions are relative to the start of the stream, and
+    /// the output positions include any header. Finishing the stream doesn't add any points, so
+    /// the index is complete once all the input has been written.
+    pub fn take_access_points(&mut self) -> Vec<AccessPoint> {
+        take_access_points(&mut self.deflate_state)
+    }
+
+    /// Enable or disable gathering statistics about the blocks that are output.
+    ///
+    /// Disabling it discards the statistics gathered so far.
+    pub fn set_record_stats(&mut self, record: bool) {
+        set_record_stats(&mut self.deflate_state, record);
+    }
+
+    /// The statistics gathered about the blocks output so far, if enabled.
+    ///
+    /// Calling `flush()` first makes sure all the input written so far has been output.
+    pub fn stats(&self) -> Option<&Stats> {
+        self.deflate_state.stats.as_ref()
+    }
 }
 
 impl<W: Write> io::Write for DeflateEncoder<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let flush_mode = self.deflate_state.flush_mode;
-        compress_data_dynamic_n(buf, &mut self.deflate_state, flush_mode)
+        let (consumed, boundary) = compress_with_policy(buf, &mut self.deflate_state)?;
+        if let Some(boundary) = boundary {
+            insert_boundary(&mut self.deflate_state, boundary)?;
+        }
+        Ok(consumed)
     }
 
     /// Flush the encoder.
@@ -153,6 +556,171 @@ impl<W: Write> Drop for DeflateEncoder<W> {
     }
 }
 
+/// The CRC-32 and sizes of a compressed ZIP entry, needed for its local file header or data
+/// descriptor and its central directory record.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct ZipEntryInfo {
+    /// The CRC-32 checksum of the uncompressed data.
+    pub crc32: u32,
+    /// The number of bytes of compressed data written.
+    pub compressed_size: u64,
+    /// The number of bytes of uncompressed data.
+    pub uncompressed_size: u64,
+}
+
+/// An encoder for the entries of ZIP archives.
+///
+/// A struct implementing a [`Write`] interface that takes unencoded data and compresses it to
+/// the provided writer as raw DEFLATE data, like [`DeflateEncoder`], while keeping track of the
+/// CRC-32 checksum and the size of the data. These are returned when finishing, so the caller
+/// can fill in the fields of the entry's headers.
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::io;
+/// #
+/// # fn try_main() -> io::Result<Vec<u8>> {
+/// #
+/// use std::io::Write;
+///
+/// use deflate::Compression;
+/// use deflate::write::ZipEncoder;
+///
+/// let data = b"This is some test data";
+/// let mut encoder = ZipEncoder::new(Vec::new(), Compression::Default);
+/// encoder.write_all(data)?;
+/// let (compressed_data, info) = encoder.finish()?;
+/// assert_eq!(info.crc32, deflate::crc32(data));
+/// assert_eq!(info.compressed_size, compressed_data.len() as u64);
+/// assert_eq!(info.uncompressed_size, data.len() as u64);
+/// # Ok(compressed_data)
+/// #
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+/// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+/// [`DeflateEncoder`]: struct.DeflateEncoder.html
+pub struct ZipEncoder<W: Write> {
+    inner: DeflateEncoder<W>,
+    checksum: Crc32,
+}
+
+impl<W: Write> ZipEncoder<W> {
+    /// Creates a new encoder using the provided compression options.
+    pub fn new<O: Into<CompressionOptions>>(writer: W, options: O) -> ZipEncoder<W> {
+        ZipEncoder {
+            inner: DeflateEncoder::new(writer, options),
+            checksum: Crc32::new(),
+        }
+    }
+
+    /// Creates a new encoder using the provided compression options, allocating the internal
+    /// buffers with `allocator`.
+    #[cfg(feature = "allocator")]
+    pub fn new_in<O: Into<CompressionOptions>>(
+        writer: W,
+        options: O,
+        allocator: StateAllocator,
+    ) -> ZipEncoder<W> {
+        ZipEncoder {
+            inner: DeflateEncoder::new_in(writer, options, allocator),
+            checksum: Crc32::new(),
+        }
+    }
+
+    /// Encode all pending data to the contained writer, consume this `ZipEncoder`, and return
+    /// the contained writer along with the checksum and sizes of the entry if writing succeeds.
+    pub fn finish(mut self) -> io::Result<(W, ZipEntryInfo)> {
+        self.inner.output_all()?;
+        let info = self.entry_info();
+        Ok((self.inner.deflate_state.inner.take().expect(ERR_STR), info))
+    }
+
+    /// Resets the encoder (except the compression options), replacing the current writer
+    /// with a new one to start a new entry, returning the old one along with the checksum and
+    /// sizes of the entry that was written to it.
+    pub fn reset(&mut self, writer: W) -> io::Result<(W, ZipEntryInfo)> {
+        self.inner.output_all()?;
+        let info = self.entry_info();
+        self.checksum = Crc32::new();
+        Ok((self.inner.deflate_state.reset(writer)?, info))
+    }
+
+    fn entry_info(&self) -> ZipEntryInfo {
+        ZipEntryInfo {
+            crc32: self.checksum.sum(),
+            // The stream is padded to a whole byte when finished.
+            compressed_size: self.inner.bits_written().div_ceil(8),
+            uncompressed_size: self.checksum.amount(),
+        }
+    }
+
+    /// Get the crc32 checksum of the data consumed so far.
+    pub fn checksum(&self) -> u32 {
+        self.checksum.sum()
+    }
+
+    /// Get the number of bytes of uncompressed data consumed so far.
+    pub fn uncompressed_size(&self) -> u64 {
+        self.checksum.amount()
+    }
+
+    /// Return the total number of bits of output produced so far.
+    ///
+    /// See [`DeflateEncoder::bits_written`](struct.DeflateEncoder.html#method.bits_written).
+    pub fn bits_written(&self) -> u64 {
+        self.inner.bits_written()
+    }
+
+    /// Return the number of whole bytes and extra bits of compressed data held in the encoder
+    /// that have not been written to the wrapped writer yet.
+    ///
+    /// See [`DeflateEncoder::pending`](struct.DeflateEncoder.html#method.pending).
+    pub fn pending(&self) -> (usize, u8) {
+        self.inner.pending()
+    }
+
+    /// Return the total number of bytes of input consumed by the encoder.
+    ///
+    /// See [`DeflateEncoder::total_in`](struct.DeflateEncoder.html#method.total_in).
+    pub fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    /// Return the total number of bytes of output written to the wrapped writers.
+    ///
+    /// See [`DeflateEncoder::total_out`](struct.DeflateEncoder.html#method.total_out).
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+}
+
+impl<W: Write> io::Write for ZipEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let consumed = self.inner.write(buf)?;
+        // Only the part of the buffer that was consumed is added to the checksum.
+        self.checksum.update(&buf[..consumed]);
+        Ok(consumed)
+    }
+
+    /// Flush the encoder.
+    ///
+    /// This will flush the encoder, emulating the Sync flush method from Zlib.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The Adler-32 checksum of `data`, identifying a preset dictionary in a zlib header.
+fn adler32_of(data: &[u8]) -> u32 {
+    let mut checksum = Adler32Checksum::new();
+    checksum.update_from_slice(data);
+    checksum.current_hash()
+}
+
 /// A Zlib encoder/compressor.
 ///
 /// A struct implementing a [`Write`] interface that takes unencoded data and compresses it to
@@ -186,6 +754,8 @@ pub struct ZlibEncoder<W: Write> {
     deflate_state: DeflateState<W>,
     checksum: Adler32Checksum,
     header_written: bool,
+    // The Adler-32 checksum of the preset dictionary, if one is used.
+    dictionary_id: Option<u32>,
 }
 
 impl<W: Write> ZlibEncoder<W> {
@@ -195,6 +765,23 @@ impl<W: Write> ZlibEncoder<W> {
             deflate_state: DeflateState::new(options.into(), writer),
             checksum: Adler32Checksum::new(),
             header_written: false,
+            dictionary_id: None,
+        }
+    }
+
+    /// Create a new `ZlibEncoder` using the provided compression options, allocating the
+    /// internal buffers with `allocator`.
+    #[cfg(feature = "allocator")]
+    pub fn new_in<O: Into<CompressionOptions>>(
+        writer: W,
+        options: O,
+        allocator: StateAllocator,
+    ) -> ZlibEncoder<W> {
+        ZlibEncoder {
+            deflate_state: DeflateState::new_in(options.into(), writer, allocator),
+            checksum: Adler32Checksum::new(),
+            header_written: false,
+            dictionary_id: None,
         }
     }
 
@@ -220,14 +807,40 @@ impl<W: Write> ZlibEncoder<W> {
     pub fn reset(&mut self, writer: W) -> io::Result<W> {
         self.output_all()?;
         self.header_written = false;
+        self.dictionary_id = None;
         self.checksum = Adler32Checksum::new();
         self.deflate_state.reset(writer)
     }
 
+    /// Resets the encoder, replacing the current writer with a new one and using new compression
+    /// options, returning the old writer.
+    pub fn reset_with_options<O: Into<CompressionOptions>>(
+        &mut self,
+        writer: W,
+        options: O,
+    ) -> io::Result<W> {
+        self.output_all()?;
+        self.header_written = false;
+        self.dictionary_id = None;
+        self.checksum = Adler32Checksum::new();
+        self.deflate_state
+            .reset_with_options(writer, options.into())
+    }
+
     /// Check if a zlib header should be written.
     fn check_write_header(&mut self) -> io::Result<()> {
         if !self.header_written {
-            write_zlib_header(self.deflate_state.output_buf(), CompressionLevel::Default)?;
+            let window_bits = clamp_window_bits(self.deflate_state.compression_options.window_bits);
+            match self.dictionary_id {
+                Some(id) => self.deflate_state.output_buf().extend_from_slice(
+                    &get_zlib_header_with_dictionary(CompressionLevel::Default, window_bits, id),
+                ),
+                None => write_zlib_header(
+                    self.deflate_state.output_buf(),
+                    CompressionLevel::Default,
+                    window_bits,
+                )?,
+            }
             self.header_written = true;
         }
         Ok(())
@@ -236,69 +849,785 @@ impl<W: Write> ZlibEncoder<W> {
     /// Write the trailer, which for zlib is the Adler32 checksum.
     fn write_trailer(&mut self) -> io::Result<()> {
         let hash = self.checksum.current_hash();
-
-        self.deflate_state
-            .inner
-            .as_mut()
-            .expect(ERR_STR)
-            .write_u32::<BigEndian>(hash)
+        self.deflate_state.write_trailer(&hash.to_be_bytes())
     }
 
     /// Return the adler32 checksum of the currently consumed data.
     pub fn checksum(&self) -> u32 {
         self.checksum.current_hash()
     }
-}
 
-impl<W: Write> io::Write for ZlibEncoder<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.check_write_header()?;
-        let flush_mode = self.deflate_state.flush_mode;
-        let res = compress_data_dynamic_n(buf, &mut self.deflate_state, flush_mode);
-        match res {
-            // If this is returned, the whole buffer was consumed
-            Ok(0) => self.checksum.update_from_slice(buf),
-            // Otherwise, only part of it was consumed, so only that part
-            // added to the checksum.
-            Ok(n) => self.checksum.update_from_slice(&buf[0..n]),
-            _ => (),
-        };
-        res
+    /// Set a policy deciding where to insert additional block or stream boundaries in the output.
+    ///
+    /// See the [`boundary`](../boundary/index.html) module for details.
+    pub fn set_boundary_policy<P: BoundaryPolicy + Send + 'static>(&mut self, policy: P) {
+        self.deflate_state.boundary_policy = Some(Box::new(policy));
     }
 
-    /// Flush the encoder.
+    /// Set a function to call with information about each block after it has been output.
     ///
-    /// This will flush the encoder, emulating the Sync flush method from Zlib.
-    /// This essentially finishes the current block, and sends an additional empty stored block to
-    /// the writer.
-    fn flush(&mut self) -> io::Result<()> {
-        compress_until_done(&[], &mut self.deflate_state, Flush::Sync)
+    /// This can be used to e.g build an index of the blocks while compressing. The output bit
+    /// positions include any header.
+    pub fn set_block_callback<F: FnMut(&BlockInfo) + Send + 'static>(&mut self, callback: F) {
+        self.deflate_state.block_callback = Some(Box::new(callback));
     }
-}
 
-impl<W: Write> Drop for ZlibEncoder<W> {
-    /// When the encoder is dropped, output the rest of the data.
+    /// Perform a full flush, like `Z_FULL_FLUSH` in zlib.
     ///
-    /// WARNING: This may silently fail if writing fails, so using this to finish encoding
-    /// for writers where writing might fail is not recommended, for that call
-    /// [`finish()`](#method.finish) instead.
-    fn drop(&mut self) {
-        if self.deflate_state.inner.is_some() && !thread::panicking() {
-            let _ = self.output_all();
-        }
+    /// Like `flush()`, this outputs all pending data followed by an empty stored block, but also
+    /// clears the window and the hash chains, so the compressed data written afterwards doesn't
+    /// refer to anything before the flush and can be decompressed on its own. This can be used
+    /// to create points where decompression can be restarted, at the cost of compressing the
+    /// data after the flush slightly worse.
+    pub fn full_flush(&mut self) -> io::Result<()> {
+        self.check_write_header()?;
+        insert_boundary(&mut self.deflate_state, Boundary::FullFlush)
     }
-}
 
-#[cfg(feature = "gzip")]
-pub mod gzip {
-
-    use std::io::{Cursor, Write};
-    use std::{io, thread};
+    /// Perform a partial flush, like `Z_PARTIAL_FLUSH` in zlib.
+    ///
+    /// This ends the current block and outputs an empty block with fixed codes, so all the data
+    /// written so far can be decompressed from the output. Unlike `flush()`, the output is not
+    /// padded to a byte boundary, which avoids the 4 bytes of overhead of the empty stored block.
+    /// Up to 7 bits of the empty block are kept back until more data is output.
+    pub fn partial_flush(&mut self) -> io::Result<()> {
+        self.check_write_header()?;
+        compress_until_done(&[], &mut self.deflate_state, Flush::Partial)
+    }
+
+    /// End the current block, like the `Z_BLOCK` flush mode of zlib.
+    ///
+    /// Unlike `partial_flush()` and `flush()`, no empty block follows and the output is not
+    /// padded to a byte boundary, so the next block starts right after this one. Returns the
+    /// number of bits, at most 7, of the block end that are kept back until more data is output.
+    /// Together with the output length this gives the bit position where the next block starts.
+    pub fn block_flush(&mut self) -> io::Result<u8> {
+        self.check_write_header()?;
+        block_flush(&mut self.deflate_state)
+    }
+
+    /// Change the compression options in the middle of the stream, like `deflateParams` in zlib.
+    ///
+    /// See [`DeflateEncoder::set_options`](struct.DeflateEncoder.html#method.set_options).
+    pub fn set_options<O: Into<CompressionOptions>>(&mut self, options: O) -> io::Result<()> {
+        set_options(&mut self.deflate_state, options.into())
+    }
+
+    /// Set prior symbol frequencies to blend into the observed ones when generating the huffman
+    /// codes of dynamic blocks.
+    ///
+    /// See [`FrequencyPriors`](../struct.FrequencyPriors.html) for details.
+    pub fn set_frequency_priors(&mut self, priors: FrequencyPriors) {
+        self.deflate_state.frequency_priors = Some(priors);
+    }
+
+    /// Set the huffman code lengths to use for dynamic blocks instead of generating them from
+    /// the symbol frequencies, or go back to generating them with `None`.
+    ///
+    /// The lengths are used for the blocks output after this call, which includes the data
+    /// that has been written but not output yet. Flush the encoder first to keep the lengths
+    /// from applying to that data. See [`CodeLengths`](../struct.CodeLengths.html) for details.
+    pub fn set_code_lengths(&mut self, code_lengths: Option<CodeLengths>) {
+        self.deflate_state.code_lengths = code_lengths;
+    }
+
+    /// Use `dictionary` as a preset dictionary, like `deflateSetDictionary` in zlib.
+    ///
+    /// The FDICT flag is set in the header, followed by the Adler-32 checksum of the dictionary
+    /// identifying it. The decompressor has to be given the same dictionary to decompress the
+    /// data.
+    ///
+    /// Returns an error if any data has been written to the encoder.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) -> io::Result<()> {
+        if self.header_written {
+            return Err(Error::InvalidState(
+                "The dictionary has to be set before compressing any data",
+            )
+            .into());
+        }
+        self.deflate_state.set_dictionary(dictionary)?;
+        self.dictionary_id = Some(adler32_of(dictionary));
+        Ok(())
+    }
+
+    /// Return the total number of bits of output produced so far.
+    ///
+    /// This includes output that is still buffered in the encoder and the zlib header, but not
+    /// the trailer, so it can be used to find the exact bit position of the compressed stream,
+    /// e.g to calculate alignment or splice points. Input that has been written to the encoder
+    /// but not yet compressed is not accounted for, calling `flush()` first will ensure all of it
+    /// is.
+    pub fn bits_written(&self) -> u64 {
+        self.deflate_state.output_bits()
+    }
+
+    /// Return the number of whole bytes and extra bits of compressed data held in the encoder
+    /// that have not been written to the wrapped writer yet.
+    ///
+    /// See [`DeflateEncoder::pending`](struct.DeflateEncoder.html#method.pending).
+    pub fn pending(&self) -> (usize, u8) {
+        self.deflate_state.pending_output()
+    }
+
+    /// Return the total number of bytes of input consumed by the encoder.
+    ///
+    /// See [`DeflateEncoder::total_in`](struct.DeflateEncoder.html#method.total_in).
+    pub fn total_in(&self) -> u64 {
+        self.deflate_state.total_in
+    }
+
+    /// Return the total number of bytes of output written to the wrapped writers.
+    ///
+    /// This includes the header and trailer of each stream.
+    ///
+    /// See [`DeflateEncoder::total_out`](struct.DeflateEncoder.html#method.total_out).
+    pub fn total_out(&self) -> u64 {
+        self.deflate_state.total_out
+    }
+
+    /// Return the amount of heap memory currently used by the encoder, broken down by component.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.deflate_state.memory_usage()
+    }
+
+    /// Enable or disable recording the huffman codes chosen for each block that is output.
+    ///
+    /// Disabling recording discards any codes that have not been taken yet.
+    pub fn set_record_block_codes(&mut self, record: bool) {
+        set_record_block_codes(&mut self.deflate_state, record);
+    }
+
+    /// Take the codes recorded for the blocks output since the last call, in the order they were
+    /// output.
+    ///
+    /// The output bit positions include any header. Calling `flush()` first makes sure all the
+    /// input written so far has been output.
+    pub fn take_block_codes(&mut self) -> Vec<BlockCodes> {
+        take_block_codes(&mut self.deflate_state)
+    }
+
+    /// Enable or disable recording access points, the positions in the output where
+    /// decompression can start without the data before them.
+    ///
+    /// See [`DeflateEncoder::set_record_access_points`].
+    ///
+    /// [`DeflateEncoder::set_record_access_points`]:
+    /// struct.DeflateEncoder.html#method.set_record_access_points
+    pub fn set_record_access_points(&mut self, record: bool) {
+        set_record_access_points(&mut self.deflate_state, record);
+    }
+
+    /// Take the access points recorded since the last call, in the order they were output.
+    ///
+    /// The input offsets and output bit positions are relative to the start of the stream, and
+    /// the output positions include the header.
+    pub fn take_access_points(&mut self) -> Vec<AccessPoint> {
+        take_access_points(&mut self.deflate_state)
+    }
+
+    /// Enable or disable gathering statistics about the blocks that are output.
+    ///
+    /// Disabling it discards the statistics gathered so far.
+    pub fn set_record_stats(&mut self, record: bool) {
+        set_record_stats(&mut self.deflate_state, record);
+    }
+
+    /// The statistics gathered about the blocks output so far, if enabled.
+    ///
+    /// Calling `flush()` first makes sure all the input written so far has been output.
+    pub fn stats(&self) -> Option<&Stats> {
+        self.deflate_state.stats.as_ref()
+    }
+}
+
+impl<W: Write> io::Write for ZlibEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_write_header()?;
+        let (consumed, boundary) = compress_with_policy(buf, &mut self.deflate_state)?;
+        // Only the part of the buffer that was consumed is added to the checksum.
+        self.checksum.update_from_slice(&buf[..consumed]);
+        if let Some(boundary) = boundary {
+            insert_boundary(&mut self.deflate_state, boundary)?;
+        }
+        Ok(consumed)
+    }
+
+    /// Flush the encoder.
+    ///
+    /// This will flush the encoder, emulating the Sync flush method from Zlib.
+    /// This essentially finishes the current block, and sends an additional empty stored block to
+    /// the writer.
+    fn flush(&mut self) -> io::Result<()> {
+        compress_until_done(&[], &mut self.deflate_state, Flush::Sync)
+    }
+}
+
+impl<W: Write> Drop for ZlibEncoder<W> {
+    /// When the encoder is dropped, output the rest of the data.
+    ///
+    /// WARNING: This may silently fail if writing fails, so using this to finish encoding
+    /// for writers where writing might fail is not recommended, for that call
+    /// [`finish()`](#method.finish) instead.
+    fn drop(&mut self) {
+        if self.deflate_state.inner.is_some() && !thread::panicking() {
+            let _ = self.output_all();
+        }
+    }
+}
+
+/// The container format to wrap the compressed data in.
+///
+/// The `Gzip` variant only exists with the `gzip` feature, so matches on this enum need a
+/// wildcard arm to compile whether or not another crate in the build enables the feature.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum Format {
+    /// Raw DEFLATE data, without any header or trailer.
+    Raw,
+    /// Zlib format, with a zlib header and an Adler-32 checksum trailer.
+    Zlib,
+    /// Gzip format, with a gzip header with blank fields and a CRC-32 and size trailer.
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// A container produced by a custom [`Framing`](../framing/trait.Framing.html).
+    Custom,
+}
+
+impl Format {
+    /// Create the framing producing this format.
+    ///
+    /// Returns an error for `Format::Custom`, which has no framing of its own.
+    fn framing(self, options: &CompressionOptions) -> Result<Box<dyn Framing + Send>, Error> {
+        Ok(match self {
+            Format::Raw => Box::new(RawFraming),
+            Format::Zlib => Box::new(ZlibFraming::with_window_bits(options.window_bits)),
+            #[cfg(feature = "gzip")]
+            Format::Gzip => Box::new(GzipFraming::new()),
+            Format::Custom => {
+                return Err(Error::InvalidOptions(
+                    "A custom format needs to be given a framing",
+                ))
+            }
+        })
+    }
+}
+
+/// An encoder/compressor where the container format is chosen at runtime.
+///
+/// A struct implementing a [`Write`] interface that takes unencoded data and compresses it to
+/// the provided writer using DEFLATE compression, wrapped in the header and trailer of the
+/// chosen [`Format`](enum.Format.html). This is useful when the format is not known until runtime
+/// (e.g when it depends on the `Accept-Encoding` header of a HTTP request), as it avoids having
+/// a separate code path for each of the encoder types.
+///
+/// The output is identical to the output of the encoder for the respective format.
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::io;
+/// #
+/// # fn try_main() -> io::Result<Vec<u8>> {
+/// #
+/// use std::io::Write;
+///
+/// use deflate::Compression;
+/// use deflate::write::{Encoder, Format};
+///
+/// let data = b"This is some test data";
+/// let mut encoder = Encoder::new(Vec::new(), Format::Zlib, Compression::Default);
+/// encoder.write_all(data)?;
+/// let compressed_data = encoder.finish()?;
+/// # Ok(compressed_data)
+/// #
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+/// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+pub struct Encoder<W: Write> {
+    deflate_state: DeflateState<W>,
+    format: Format,
+    framing: Box<dyn Framing + Send>,
+    header: Vec<u8>,
+    // Whether the stream has been finished with `try_finish`.
+    finished: bool,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Create a new `Encoder` producing data in the format `format` using the provided
+    /// compression options.
+    ///
+    /// # Panics
+    /// Panics if `format` is `Format::Custom`, use [`with_framing`](#method.with_framing) for
+    /// custom formats.
+    pub fn new<O: Into<CompressionOptions>>(writer: W, format: Format, options: O) -> Encoder<W> {
+        Encoder::try_new(writer, format, options).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like [`new`](#method.new), but returns an error rather than panicking if `format` is
+    /// `Format::Custom`.
+    pub fn try_new<O: Into<CompressionOptions>>(
+        writer: W,
+        format: Format,
+        options: O,
+    ) -> Result<Encoder<W>, Error> {
+        let options = options.into();
+        let framing = format.framing(&options)?;
+        Ok(Encoder::from_state(
+            DeflateState::new(options, writer),
+            format,
+            framing,
+        ))
+    }
+
+    /// Create a new `Encoder` wrapping the compressed data in the header and trailer produced
+    /// by `framing`, using the provided compression options.
+    ///
+    /// The format of the encoder is `Format::Custom`.
+    pub fn with_framing<F: Framing + Send + 'static, O: Into<CompressionOptions>>(
+        writer: W,
+        framing: F,
+        options: O,
+    ) -> Encoder<W> {
+        Encoder::from_state(
+            DeflateState::new(options.into(), writer),
+            Format::Custom,
+            Box::new(framing),
+        )
+    }
+
+    fn from_state(
+        deflate_state: DeflateState<W>,
+        format: Format,
+        mut framing: Box<dyn Framing + Send>,
+    ) -> Encoder<W> {
+        Encoder {
+            deflate_state,
+            format,
+            header: framing.header(),
+            framing,
+            finished: false,
+        }
+    }
+
+    /// Create a new `Encoder` producing data in the format `format` using the provided
+    /// compression options, allocating the internal buffers with `allocator`.
+    ///
+    /// # Panics
+    /// Panics if `format` is `Format::Custom`.
+    #[cfg(feature = "allocator")]
+    pub fn new_in<O: Into<CompressionOptions>>(
+        writer: W,
+        format: Format,
+        options: O,
+        allocator: StateAllocator,
+    ) -> Encoder<W> {
+        let options = options.into();
+        let framing = format
+            .framing(&options)
+            .unwrap_or_else(|err| panic!("{}", err));
+        Encoder::from_state(
+            DeflateState::new_in(options, writer, allocator),
+            format,
+            framing,
+        )
+    }
+
+    /// The container format this encoder produces.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Set a policy deciding where to insert additional block or stream boundaries in the output.
+    ///
+    /// See the [`boundary`](../boundary/index.html) module for details.
+    pub fn set_boundary_policy<P: BoundaryPolicy + Send + 'static>(&mut self, policy: P) {
+        self.deflate_state.boundary_policy = Some(Box::new(policy));
+    }
+
+    /// Set a function to call with information about each block after it has been output.
+    ///
+    /// This can be used to e.g build an index of the blocks while compressing. The output bit
+    /// positions include any header.
+    pub fn set_block_callback<F: FnMut(&BlockInfo) + Send + 'static>(&mut self, callback: F) {
+        self.deflate_state.block_callback = Some(Box::new(callback));
+    }
+
+    /// Perform a full flush, like `Z_FULL_FLUSH` in zlib.
+    ///
+    /// Like `flush()`, this outputs all pending data followed by an empty stored block, but also
+    /// clears the window and the hash chains, so the compressed data written afterwards doesn't
+    /// refer to anything before the flush and can be decompressed on its own. This can be used
+    /// to create points where decompression can be restarted, at the cost of compressing the
+    /// data after the flush slightly worse.
+    pub fn full_flush(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Err(Error::InvalidState("Can not flush a finished encoder").into());
+        }
+        self.check_write_header();
+        insert_boundary(&mut self.deflate_state, Boundary::FullFlush)
+    }
+
+    /// Perform a partial flush, like `Z_PARTIAL_FLUSH` in zlib.
+    ///
+    /// This ends the current block and outputs an empty block with fixed codes, so all the data
+    /// written so far can be decompressed from the output. Unlike `flush()`, the output is not
+    /// padded to a byte boundary, which avoids the 4 bytes of overhead of the empty stored block.
+    /// Up to 7 bits of the empty block are kept back until more data is output.
+    pub fn partial_flush(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Err(Error::InvalidState("Can not flush a finished encoder").into());
+        }
+        self.check_write_header();
+        compress_until_done(&[], &mut self.deflate_state, Flush::Partial)
+    }
+
+    /// End the current block, like the `Z_BLOCK` flush mode of zlib.
+    ///
+    /// Unlike `partial_flush()` and `flush()`, no empty block follows and the output is not
+    /// padded to a byte boundary, so the next block starts right after this one. Returns the
+    /// number of bits, at most 7, of the block end that are kept back until more data is output.
+    /// Together with the output length this gives the bit position where the next block starts.
+    pub fn block_flush(&mut self) -> io::Result<u8> {
+        if self.finished {
+            return Err(Error::InvalidState("Can not flush a finished encoder").into());
+        }
+        self.check_write_header();
+        block_flush(&mut self.deflate_state)
+    }
+
+    /// Change the compression options in the middle of the stream, like `deflateParams` in zlib.
+    ///
+    /// See [`DeflateEncoder::set_options`](struct.DeflateEncoder.html#method.set_options).
+    pub fn set_options<O: Into<CompressionOptions>>(&mut self, options: O) -> io::Result<()> {
+        if self.finished {
+            return Err(
+                Error::InvalidState("Can not change the options of a finished encoder").into(),
+            );
+        }
+        set_options(&mut self.deflate_state, options.into())
+    }
+
+    /// Set prior symbol frequencies to blend into the observed ones when generating the huffman
+    /// codes of dynamic blocks.
+    ///
+    /// See [`FrequencyPriors`](../struct.FrequencyPriors.html) for details.
+    pub fn set_frequency_priors(&mut self, priors: FrequencyPriors) {
+        self.deflate_state.frequency_priors = Some(priors);
+    }
+
+    /// Set the huffman code lengths to use for dynamic blocks instead of generating them from
+    /// the symbol frequencies, or go back to generating them with `None`.
+    ///
+    /// The lengths are used for the blocks output after this call, which includes the data
+    /// that has been written but not output yet. Flush the encoder first to keep the lengths
+    /// from applying to that data. See [`CodeLengths`](../struct.CodeLengths.html) for details.
+    pub fn set_code_lengths(&mut self, code_lengths: Option<CodeLengths>) {
+        self.deflate_state.code_lengths = code_lengths;
+    }
+
+    /// Use `dictionary` as a preset dictionary, like `deflateSetDictionary` in zlib.
+    ///
+    /// See [`DeflateEncoder::set_dictionary`](struct.DeflateEncoder.html#method.set_dictionary).
+    /// Only raw DEFLATE and zlib data can be compressed with a dictionary, an error is returned
+    /// for other formats. For zlib, the header identifies the dictionary as described in
+    /// [`ZlibEncoder::set_dictionary`](struct.ZlibEncoder.html#method.set_dictionary).
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) -> io::Result<()> {
+        match self.format {
+            Format::Raw => self.deflate_state.set_dictionary(dictionary),
+            Format::Zlib => {
+                if self.header.is_empty() {
+                    return Err(Error::InvalidState(
+                        "The dictionary has to be set before compressing any data",
+                    )
+                    .into());
+                }
+                self.deflate_state.set_dictionary(dictionary)?;
+                let window_bits =
+                    clamp_window_bits(self.deflate_state.compression_options.window_bits);
+                self.header = get_zlib_header_with_dictionary(
+                    CompressionLevel::Default,
+                    window_bits,
+                    adler32_of(dictionary),
+                )
+                .to_vec();
+                Ok(())
+            }
+            _ => Err(Error::InvalidOptions(
+                "Preset dictionaries are only supported for raw DEFLATE and zlib data",
+            )
+            .into()),
+        }
+    }
+
+    /// Insert the lowest `bits` bits of `value` into the output, like `deflatePrime` in zlib.
+    ///
+    /// See [`DeflateEncoder::prime`](struct.DeflateEncoder.html#method.prime). If the format has
+    /// a header, it is written first and the bits follow it, so a preset dictionary can't be set
+    /// afterwards.
+    pub fn prime(&mut self, bits: u8, value: u16) -> io::Result<()> {
+        if self.finished {
+            return Err(Error::InvalidState("Can not insert bits into a finished encoder").into());
+        }
+        self.check_write_header();
+        self.deflate_state.prime_bits(bits, value)
+    }
+
+    /// Return the total number of bits of output produced so far.
+    ///
+    /// This includes output that is still buffered in the encoder and any header once data has
+    /// been written, but not the trailer, so it can be used to find the exact bit position of the
+    /// compressed stream, e.g to calculate alignment or splice points. Input that has been
+    /// written to the encoder but not yet compressed is not accounted for, calling `flush()`
+    /// first will ensure all of it is.
+    pub fn bits_written(&self) -> u64 {
+        self.deflate_state.output_bits()
+    }
+
+    /// Return the number of whole bytes and extra bits of compressed data held in the encoder
+    /// that have not been written to the wrapped writer yet.
+    ///
+    /// See [`DeflateEncoder::pending`](struct.DeflateEncoder.html#method.pending).
+    pub fn pending(&self) -> (usize, u8) {
+        self.deflate_state.pending_output()
+    }
+
+    /// Return the total number of bytes of input consumed by the encoder.
+    ///
+    /// See [`DeflateEncoder::total_in`](struct.DeflateEncoder.html#method.total_in).
+    pub fn total_in(&self) -> u64 {
+        self.deflate_state.total_in
+    }
+
+    /// Return the total number of bytes of output written to the wrapped writers.
+    ///
+    /// This includes the header and trailer of each stream.
+    ///
+    /// See [`DeflateEncoder::total_out`](struct.DeflateEncoder.html#method.total_out).
+    pub fn total_out(&self) -> u64 {
+        self.deflate_state.total_out
+    }
+
+    /// Return the amount of heap memory currently used by the encoder, broken down by component.
+    ///
+    /// A header that has not been written yet is counted as part of the output buffer.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = self.deflate_state.memory_usage();
+        usage.output_buffer += self.header.capacity();
+        usage
+    }
+
+    /// Enable or disable recording the huffman codes chosen for each block that is output.
+    ///
+    /// Disabling recording discards any codes that have not been taken yet.
+    pub fn set_record_block_codes(&mut self, record: bool) {
+        set_record_block_codes(&mut self.deflate_state, record);
+    }
+
+    /// Take the codes recorded for the blocks output since the last call, in the order they were
+    /// output.
+    ///
+    /// The output bit positions include any header. Calling `flush()` first makes sure all the
+    /// input written so far has been output.
+    pub fn take_block_codes(&mut self) -> Vec<BlockCodes> {
+        take_block_codes(&mut self.deflate_state)
+    }
+
+    /// Enable or disable recording access points, the positions in the output where
+    /// decompression can start without the data before them.
+    ///
+    /// See [`DeflateEncoder::set_record_access_points`].
+    ///
+    /// [`DeflateEncoder::set_record_access_points`]:
+    /// struct.DeflateEncoder.html#method.set_record_access_points
+    pub fn set_record_access_points(&mut self, record: bool) {
+        set_record_access_points(&mut self.deflate_state, record);
+    }
+
+    /// Take the access points recorded since the last call, in the order they were output.
+    ///
+    /// The input offsets and output bit positions are relative to the start of the stream, and
+    /// the output positions include the header.
+    pub fn take_access_points(&mut self) -> Vec<AccessPoint> {
+        take_access_points(&mut self.deflate_state)
+    }
+
+    /// Enable or disable gathering statistics about the blocks that are output.
+    ///
+    /// Disabling it discards the statistics gathered so far.
+    pub fn set_record_stats(&mut self, record: bool) {
+        set_record_stats(&mut self.deflate_state, record);
+    }
+
+    /// The statistics gathered about the blocks output so far, if enabled.
+    ///
+    /// Calling `flush()` first makes sure all the input written so far has been output, and
+    /// `try_finish()` that the final block is included.
+    pub fn stats(&self) -> Option<&Stats> {
+        self.deflate_state.stats.as_ref()
+    }
+
+    /// Write header to the output buffer if it hasn't been done yet.
+    fn check_write_header(&mut self) {
+        if !self.header.is_empty() {
+            self.deflate_state
+                .output_buf()
+                .extend_from_slice(&self.header);
+            self.header.clear();
+        }
+    }
+
+    /// Output all pending data, including the trailer if any, as if encoding is done,
+    /// but without resetting anything.
+    fn output_all(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.check_write_header();
+        compress_until_done(&[], &mut self.deflate_state, Flush::Finish)?;
+        let trailer = self.framing.trailer();
+        self.deflate_state.write_trailer(&trailer)?;
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Finish the current gzip member and start a new one, or do a full flush for other formats.
+    fn start_new_member(&mut self) -> io::Result<()> {
+        match self.format {
+            #[cfg(feature = "gzip")]
+            Format::Gzip => {
+                self.output_all()?;
+                let flushed = self.deflate_state.output_bytes_flushed
+                    + crate::consts::GZIP_TRAILER_LENGTH as u64;
+                self.deflate_state.reset_state()?;
+                self.finished = false;
+                self.deflate_state.output_bytes_flushed = flushed;
+                self.deflate_state.record_access_point();
+                self.framing.reset();
+                self.header = self.framing.header();
+                Ok(())
+            }
+            _ => insert_boundary(&mut self.deflate_state, Boundary::FullFlush),
+        }
+    }
+
+    /// Encode all pending data and the trailer to the contained writer, without consuming the
+    /// `Encoder`.
+    ///
+    /// Calling this again, or calling `finish()` or dropping the encoder afterwards, does not
+    /// output anything more, and writing more data returns an error.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        self.output_all()
+    }
+
+    /// Get a reference to the contained writer.
+    pub fn get_ref(&self) -> &W {
+        self.deflate_state.inner.as_ref().expect(ERR_STR)
+    }
+
+    /// Get a mutable reference to the contained writer.
+    ///
+    /// Writing directly to the writer will corrupt the compressed stream unless the encoder has
+    /// been flushed or finished first.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.deflate_state.inner.as_mut().expect(ERR_STR)
+    }
+
+    /// Encode all pending data to the contained writer, consume this `Encoder`,
+    /// and return the contained writer if writing succeeds.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.output_all()?;
+        // We have to move the inner writer out of the encoder, and replace it with `None`
+        // to let the `Encoder` drop safely.
+        Ok(self.deflate_state.inner.take().expect(ERR_STR))
+    }
+
+    /// Resets the encoder (except the compression options and format), replacing the current
+    /// writer with a new one, returning the old one.
+    pub fn reset(&mut self, writer: W) -> io::Result<W> {
+        self.output_all()?;
+        self.finished = false;
+        self.framing.reset();
+        self.header = self.framing.header();
+        self.deflate_state.reset(writer)
+    }
+
+    /// Resets the encoder (except the format), replacing the current writer with a new one and
+    /// using new compression options, returning the old writer.
+    pub fn reset_with_options<O: Into<CompressionOptions>>(
+        &mut self,
+        writer: W,
+        options: O,
+    ) -> io::Result<W> {
+        let options = options.into();
+        self.output_all()?;
+        self.finished = false;
+        if let Ok(framing) = self.format.framing(&options) {
+            self.framing = framing;
+        }
+        self.framing.reset();
+        self.header = self.framing.header();
+        self.deflate_state.reset_with_options(writer, options)
+    }
+}
+
+impl<W: Write> io::Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(Error::InvalidState("Can not write to a finished encoder").into());
+        }
+        self.check_write_header();
+        let (consumed, boundary) = compress_with_policy(buf, &mut self.deflate_state)?;
+        // Only the part of the buffer that was consumed is added to the checksum.
+        self.framing.update(&buf[..consumed]);
+        match boundary {
+            Some(Boundary::NewMember) => self.start_new_member()?,
+            Some(boundary) => insert_boundary(&mut self.deflate_state, boundary)?,
+            None => (),
+        }
+        Ok(consumed)
+    }
+
+    /// Flush the encoder.
+    ///
+    /// This will flush the encoder, emulating the Sync flush method from Zlib.
+    /// This essentially finishes the current block, and sends an additional empty stored block to
+    /// the writer.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.finished {
+            return self.get_mut().flush();
+        }
+        compress_until_done(&[], &mut self.deflate_state, Flush::Sync)
+    }
+}
+
+impl<W: Write> Drop for Encoder<W> {
+    /// When the encoder is dropped, output the rest of the data.
+    ///
+    /// WARNING: This may silently fail if writing fails, so using this to finish encoding
+    /// for writers where writing might fail is not recommended, for that call
+    /// [`finish()`](#method.finish) instead.
+    fn drop(&mut self) {
+        if self.deflate_state.inner.is_some() && !thread::panicking() {
+            let _ = self.output_all();
+        }
+    }
+}
+
+#[cfg(feature = "gzip")]
+pub mod gzip {
+
+    use std::io::{Cursor, Write};
+    use std::{io, thread};
 
     use super::*;
 
+    use crate::checksum::Crc32;
+    use crate::gz_header::GzHeaderBuilder;
     use byteorder::{LittleEndian, WriteBytesExt};
-    use gzip_header::{Crc, GzBuilder};
+    use gzip_header::GzBuilder;
 
     /// A Gzip encoder/compressor.
     ///
@@ -331,8 +1660,10 @@ pub mod gzip {
     /// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
     pub struct GzEncoder<W: Write> {
         inner: DeflateEncoder<W>,
-        checksum: Crc,
+        checksum: Crc32,
         header: Vec<u8>,
+        // The header to use for new members started by a boundary policy.
+        member_header: Vec<u8>,
     }
 
     impl<W: Write> GzEncoder<W> {
@@ -344,15 +1675,35 @@ pub mod gzip {
 
         /// Create a new GzEncoder from the provided `GzBuilder`. This allows customising
         /// the detalis of the header, such as the filename and comment fields.
-        pub fn from_builder<O: Into<CompressionOptions>>(
-            builder: GzBuilder,
+        pub fn from_builder<O: Into<CompressionOptions>, B: Into<GzHeaderBuilder>>(
+            builder: B,
             writer: W,
             options: O,
         ) -> GzEncoder<W> {
+            let header = builder.into().into_header();
             GzEncoder {
                 inner: DeflateEncoder::new(writer, options),
-                checksum: Crc::new(),
-                header: builder.into_header(),
+                checksum: Crc32::new(),
+                member_header: header.clone(),
+                header,
+            }
+        }
+
+        /// Create a new GzEncoder from the provided `GzBuilder`, allocating the internal
+        /// buffers with `allocator`.
+        #[cfg(feature = "allocator")]
+        pub fn from_builder_in<O: Into<CompressionOptions>, B: Into<GzHeaderBuilder>>(
+            builder: B,
+            writer: W,
+            options: O,
+            allocator: StateAllocator,
+        ) -> GzEncoder<W> {
+            let header = builder.into().into_header();
+            GzEncoder {
+                inner: DeflateEncoder::new_in(writer, options, allocator),
+                checksum: Crc32::new(),
+                member_header: header.clone(),
+                header,
             }
         }
 
@@ -367,81 +1718,296 @@ pub mod gzip {
             }
         }
 
-        /// Output all pending data ,including the trailer(checksum + count) as if encoding is done.
-        /// but without resetting anything.
-        fn output_all(&mut self) -> io::Result<()> {
-            self.check_write_header();
-            self.inner.output_all()?;
-            self.write_trailer()
+        /// Output all pending data ,including the trailer(checksum + count) as if encoding is done.
+        /// but without resetting anything.
+        fn output_all(&mut self) -> io::Result<()> {
+            self.check_write_header();
+            self.inner.output_all()?;
+            self.write_trailer()
+        }
+
+        /// Encode all pending data to the contained writer, consume this `GzEncoder`,
+        /// and return the contained writer if writing succeeds.
+        pub fn finish(mut self) -> io::Result<W> {
+            self.output_all()?;
+            // We have to move the inner writer out of the encoder, and replace it with `None`
+            // to let the `DeflateEncoder` drop safely.
+            Ok(self.inner.deflate_state.inner.take().expect(ERR_STR))
+        }
+
+        fn reset_no_header(&mut self, writer: W) -> io::Result<W> {
+            self.output_all()?;
+            self.checksum = Crc32::new();
+            self.inner.deflate_state.reset(writer)
+        }
+
+        /// Resets the encoder (except the compression options), replacing the current writer
+        /// with a new one, returning the old one. (Using a blank header).
+        pub fn reset(&mut self, writer: W) -> io::Result<W> {
+            let w = self.reset_no_header(writer);
+            self.header = GzBuilder::new().into_header();
+            self.member_header = self.header.clone();
+            w
+        }
+
+        /// Resets the encoder, replacing the current writer with a new one and using new
+        /// compression options, returning the old writer. (Using a blank header).
+        pub fn reset_with_options<O: Into<CompressionOptions>>(
+            &mut self,
+            writer: W,
+            options: O,
+        ) -> io::Result<W> {
+            self.output_all()?;
+            self.checksum = Crc32::new();
+            self.header = GzBuilder::new().into_header();
+            self.member_header = self.header.clone();
+            self.inner
+                .deflate_state
+                .reset_with_options(writer, options.into())
+        }
+
+        /// Resets the encoder (excelt the compression options), replacing the current writer
+        /// with a new one, returning the old one, and using the provided `GzBuilder` to
+        /// create the header.
+        pub fn reset_with_builder<B: Into<GzHeaderBuilder>>(
+            &mut self,
+            writer: W,
+            builder: B,
+        ) -> io::Result<W> {
+            let w = self.reset_no_header(writer);
+            self.header = builder.into().into_header();
+            self.member_header = self.header.clone();
+            w
+        }
+
+        /// Write the checksum and number of bytes mod 2^32 to the output writer.
+        fn write_trailer(&mut self) -> io::Result<()> {
+            let crc = self.checksum.sum();
+            let amount = self.checksum.amount() as u32;
+
+            // We use a buffer here to make sure we don't end up writing only half the header if
+            // writing fails.
+            let mut buf = [0u8; 8];
+            let mut temp = Cursor::new(&mut buf[..]);
+            temp.write_u32::<LittleEndian>(crc).unwrap();
+            temp.write_u32::<LittleEndian>(amount).unwrap();
+            self.inner.deflate_state.write_trailer(temp.into_inner())
+        }
+
+        /// Get the crc32 checksum of the data comsumed so far.
+        pub fn checksum(&self) -> u32 {
+            self.checksum.sum()
+        }
+
+        /// Set a policy deciding where to insert additional block or stream boundaries in the
+        /// output.
+        ///
+        /// New members started by the policy use the same header as the first member.
+        ///
+        /// See the [`boundary`](../boundary/index.html) module for details.
+        pub fn set_boundary_policy<P: BoundaryPolicy + Send + 'static>(&mut self, policy: P) {
+            self.inner.set_boundary_policy(policy);
+        }
+
+        /// Set a function to call with information about each block after it has been output.
+        ///
+        /// This can be used to e.g build an index of the blocks while compressing. The output
+        /// bit positions include the gzip header.
+        pub fn set_block_callback<F: FnMut(&BlockInfo) + Send + 'static>(&mut self, callback: F) {
+            self.inner.set_block_callback(callback);
+        }
+
+        /// Perform a full flush, like `Z_FULL_FLUSH` in zlib.
+        ///
+        /// Like `flush()`, this outputs all pending data followed by an empty stored block, but also
+        /// clears the window and the hash chains, so the compressed data written afterwards doesn't
+        /// refer to anything before the flush and can be decompressed on its own. This can be used
+        /// to create points where decompression can be restarted, at the cost of compressing the
+        /// data after the flush slightly worse.
+        pub fn full_flush(&mut self) -> io::Result<()> {
+            self.check_write_header();
+            insert_boundary(&mut self.inner.deflate_state, Boundary::FullFlush)
+        }
+
+        /// Perform a partial flush, like `Z_PARTIAL_FLUSH` in zlib.
+        ///
+        /// This ends the current block and outputs an empty block with fixed codes, so all the data
+        /// written so far can be decompressed from the output. Unlike `flush()`, the output is not
+        /// padded to a byte boundary, which avoids the 4 bytes of overhead of the empty stored block.
+        /// Up to 7 bits of the empty block are kept back until more data is output.
+        pub fn partial_flush(&mut self) -> io::Result<()> {
+            self.check_write_header();
+            compress_until_done(&[], &mut self.inner.deflate_state, Flush::Partial)
+        }
+
+        /// End the current block, like the `Z_BLOCK` flush mode of zlib.
+        ///
+        /// Unlike `partial_flush()` and `flush()`, no empty block follows and the output is not
+        /// padded to a byte boundary, so the next block starts right after this one. Returns the
+        /// number of bits, at most 7, of the block end that are kept back until more data is output.
+        /// Together with the output length this gives the bit position where the next block starts.
+        pub fn block_flush(&mut self) -> io::Result<u8> {
+            self.check_write_header();
+            block_flush(&mut self.inner.deflate_state)
+        }
+
+        /// Change the compression options in the middle of the stream, like `deflateParams` in zlib.
+        ///
+        /// See [`DeflateEncoder::set_options`](../struct.DeflateEncoder.html#method.set_options).
+        pub fn set_options<O: Into<CompressionOptions>>(&mut self, options: O) -> io::Result<()> {
+            self.inner.set_options(options)
+        }
+
+        /// Set prior symbol frequencies to blend into the observed ones when generating the
+        /// huffman codes of dynamic blocks.
+        ///
+        /// See [`FrequencyPriors`](../../struct.FrequencyPriors.html) for details.
+        pub fn set_frequency_priors(&mut self, priors: FrequencyPriors) {
+            self.inner.set_frequency_priors(priors);
+        }
+
+        /// Set the huffman code lengths to use for dynamic blocks instead of generating them
+        /// from the symbol frequencies, or go back to generating them with `None`.
+        ///
+        /// See [`CodeLengths`](../../struct.CodeLengths.html) for details.
+        pub fn set_code_lengths(&mut self, code_lengths: Option<CodeLengths>) {
+            self.inner.set_code_lengths(code_lengths);
+        }
+
+        /// Finish the current member, and start a new one with the header from `builder`.
+        ///
+        /// This creates a multi-member gzip file, like the ones produced by concatenating gzip
+        /// files or appending to them. Each member has its own header, CRC-32 and size, and
+        /// decompressors supporting multiple members, like `gzip` itself, output the data of all
+        /// of them. The new member does not refer to data in the previous ones.
+        ///
+        /// If nothing has been written to the current member yet, only its header is replaced.
+        /// Members started by a boundary policy still use the header of the first member.
+        pub fn start_new_member<B: Into<GzHeaderBuilder>>(&mut self, builder: B) -> io::Result<()> {
+            if self.header.is_empty() {
+                self.finish_member()?;
+            }
+            self.header = builder.into().into_header();
+            Ok(())
+        }
+
+        /// Finish the current member, and start a new one using the member header.
+        fn finish_member(&mut self) -> io::Result<()> {
+            self.output_all()?;
+            let deflate_state = &mut self.inner.deflate_state;
+            let flushed =
+                deflate_state.output_bytes_flushed + crate::consts::GZIP_TRAILER_LENGTH as u64;
+            deflate_state.reset_state()?;
+            deflate_state.output_bytes_flushed = flushed;
+            deflate_state.record_access_point();
+            self.checksum = Crc32::new();
+            self.header = self.member_header.clone();
+            Ok(())
+        }
+
+        /// Return the total number of bits of output produced so far.
+        ///
+        /// This includes output that is still buffered in the encoder and the gzip header once
+        /// data has been written, but not the trailer. Input that has been written to the encoder
+        /// but not yet compressed is not accounted for, calling `flush()` first will ensure all
+        /// of it is.
+        pub fn bits_written(&self) -> u64 {
+            self.inner.bits_written()
+        }
+
+        /// Return the number of whole bytes and extra bits of compressed data held in the encoder
+        /// that have not been written to the wrapped writer yet.
+        ///
+        /// See [`DeflateEncoder::pending`](../struct.DeflateEncoder.html#method.pending).
+        pub fn pending(&self) -> (usize, u8) {
+            self.inner.pending()
+        }
+
+        /// Return the total number of bytes of input consumed by the encoder.
+        ///
+        /// See [`DeflateEncoder::total_in`](../struct.DeflateEncoder.html#method.total_in).
+        pub fn total_in(&self) -> u64 {
+            self.inner.total_in()
+        }
+
+        /// Return the total number of bytes of output written to the wrapped writers.
+        ///
+        /// This includes the header and trailer of each member.
+        ///
+        /// See [`DeflateEncoder::total_out`](../struct.DeflateEncoder.html#method.total_out).
+        pub fn total_out(&self) -> u64 {
+            self.inner.total_out()
         }
 
-        /// Encode all pending data to the contained writer, consume this `GzEncoder`,
-        /// and return the contained writer if writing succeeds.
-        pub fn finish(mut self) -> io::Result<W> {
-            self.output_all()?;
-            // We have to move the inner writer out of the encoder, and replace it with `None`
-            // to let the `DeflateEncoder` drop safely.
-            Ok(self.inner.deflate_state.inner.take().expect(ERR_STR))
+        /// Return the amount of heap memory currently used by the encoder, broken down by
+        /// component.
+        pub fn memory_usage(&self) -> MemoryUsage {
+            self.inner.memory_usage()
         }
 
-        fn reset_no_header(&mut self, writer: W) -> io::Result<W> {
-            self.output_all()?;
-            self.checksum = Crc::new();
-            self.inner.deflate_state.reset(writer)
+        /// Enable or disable recording the huffman codes chosen for each block that is output.
+        ///
+        /// Disabling recording discards any codes that have not been taken yet.
+        pub fn set_record_block_codes(&mut self, record: bool) {
+            self.inner.set_record_block_codes(record);
         }
 
-        /// Resets the encoder (except the compression options), replacing the current writer
-        /// with a new one, returning the old one. (Using a blank header).
-        pub fn reset(&mut self, writer: W) -> io::Result<W> {
-            let w = self.reset_no_header(writer);
-            self.header = GzBuilder::new().into_header();
-            w
+        /// Take the codes recorded for the blocks output since the last call, in the order they
+        /// were output.
+        ///
+        /// The output bit positions include the gzip header. Calling `flush()` first makes sure
+        /// all the input written so far has been output.
+        pub fn take_block_codes(&mut self) -> Vec<BlockCodes> {
+            self.inner.take_block_codes()
         }
 
-        /// Resets the encoder (excelt the compression options), replacing the current writer
-        /// with a new one, returning the old one, and using the provided `GzBuilder` to
-        /// create the header.
-        pub fn reset_with_builder(&mut self, writer: W, builder: GzBuilder) -> io::Result<W> {
-            let w = self.reset_no_header(writer);
-            self.header = builder.into_header();
-            w
+        /// Enable or disable recording access points, the positions in the output where
+        /// decompression can start without the data before them.
+        ///
+        /// See [`DeflateEncoder::set_record_access_points`].
+        ///
+        /// [`DeflateEncoder::set_record_access_points`]:
+        /// ../struct.DeflateEncoder.html#method.set_record_access_points
+        pub fn set_record_access_points(&mut self, record: bool) {
+            self.inner.set_record_access_points(record);
         }
 
-        /// Write the checksum and number of bytes mod 2^32 to the output writer.
-        fn write_trailer(&mut self) -> io::Result<()> {
-            let crc = self.checksum.sum();
-            let amount = self.checksum.amt_as_u32();
+        /// Take the access points recorded since the last call, in the order they were output.
+        ///
+        /// The input offsets are relative to the start of the first member, and the output
+        /// positions include the gzip headers. A point is recorded at the start of each new
+        /// member, where decompression can start with the member header, and at each full
+        /// flush, where raw DEFLATE decompression can start.
+        pub fn take_access_points(&mut self) -> Vec<AccessPoint> {
+            self.inner.take_access_points()
+        }
 
-            // We use a buffer here to make sure we don't end up writing only half the header if
-            // writing fails.
-            let mut buf = [0u8; 8];
-            let mut temp = Cursor::new(&mut buf[..]);
-            temp.write_u32::<LittleEndian>(crc).unwrap();
-            temp.write_u32::<LittleEndian>(amount).unwrap();
-            self.inner
-                .deflate_state
-                .inner
-                .as_mut()
-                .expect(ERR_STR)
-                .write_all(temp.into_inner())
+        /// Enable or disable gathering statistics about the blocks that are output.
+        ///
+        /// Disabling it discards the statistics gathered so far.
+        pub fn set_record_stats(&mut self, record: bool) {
+            self.inner.set_record_stats(record);
         }
 
-        /// Get the crc32 checksum of the data comsumed so far.
-        pub fn checksum(&self) -> u32 {
-            self.checksum.sum()
+        /// The statistics gathered about the blocks output so far, if enabled.
+        ///
+        /// Calling `flush()` first makes sure all the input written so far has been output.
+        pub fn stats(&self) -> Option<&Stats> {
+            self.inner.stats()
         }
     }
 
     impl<W: Write> io::Write for GzEncoder<W> {
         fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
             self.check_write_header();
-            let res = self.inner.write(buf);
-            match res {
-                Ok(0) => self.checksum.update(buf),
-                Ok(n) => self.checksum.update(&buf[0..n]),
-                _ => (),
-            };
-            res
+            let (consumed, boundary) = compress_with_policy(buf, &mut self.inner.deflate_state)?;
+            self.checksum.update(&buf[..consumed]);
+            match boundary {
+                Some(Boundary::NewMember) => self.finish_member()?,
+                Some(boundary) => insert_boundary(&mut self.inner.deflate_state, boundary)?,
+                None => (),
+            }
+            Ok(consumed)
         }
 
         /// Flush the encoder.
@@ -470,7 +2036,30 @@ pub mod gzip {
     #[cfg(test)]
     mod test {
         use super::*;
+        use crate::boundary::Boundary;
+        use crate::compression_options::Compression;
         use crate::test_utils::{decompress_gzip, get_test_data};
+
+        /// Decompress a single raw deflate stream at the start of `input`, returning the
+        /// decompressed data and the number of bytes of `input` the stream used.
+        fn decompress_stream_prefix(input: &[u8]) -> (Vec<u8>, usize) {
+            use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+            use miniz_oxide::inflate::TINFLStatus;
+            use std::io::Cursor;
+
+            let mut decompressor = DecompressorOxide::new();
+            let mut output = vec![0; input.len() * 20 + 1024 * 1024];
+            let (status, consumed, written) = decompress(
+                &mut decompressor,
+                input,
+                &mut Cursor::new(&mut output[..]),
+                inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+            );
+            assert_eq!(status, TINFLStatus::Done, "Decompression failed!");
+            output.truncate(written);
+            (output, consumed)
+        }
+
         #[test]
         fn gzip_writer() {
             let data = get_test_data();
@@ -490,13 +2079,132 @@ pub mod gzip {
             assert_eq!(dec.comment().unwrap(), comment);
             assert!(res == data);
         }
+
+        /// Splits the input into a new member every 20000 bytes.
+        struct MemberPolicy;
+
+        impl BoundaryPolicy for MemberPolicy {
+            fn next_boundary(
+                &mut self,
+                data: &[u8],
+                context: &BoundaryContext,
+            ) -> Option<(usize, Boundary)> {
+                let left = (20000 - context.bytes_since_boundary) as usize;
+                if left <= data.len() {
+                    Some((left, Boundary::NewMember))
+                } else {
+                    None
+                }
+            }
+        }
+
+        #[test]
+        fn gzip_new_member() {
+            let data = get_test_data();
+            let mut compressor = GzEncoder::from_builder(
+                GzBuilder::new().comment(&b"Member"[..]),
+                Vec::new(),
+                Compression::Default,
+            );
+            compressor.set_boundary_policy(MemberPolicy);
+            compressor.write_all(&data).unwrap();
+            let compressed = compressor.finish().unwrap();
+
+            // Decompress each member separately.
+            let mut decompressed = Vec::new();
+            let mut rest = &compressed[..];
+            let mut members = 0;
+            while !rest.is_empty() {
+                let mut cursor = Cursor::new(rest);
+                let header = gzip_header::read_gz_header(&mut cursor).unwrap();
+                assert_eq!(header.comment().unwrap(), b"Member");
+                let (member, consumed) =
+                    decompress_stream_prefix(&rest[cursor.position() as usize..]);
+                assert!(member.len() <= 20000);
+                decompressed.extend_from_slice(&member);
+                rest = &rest[cursor.position() as usize + consumed + 8..];
+                members += 1;
+            }
+            assert_eq!(members, (data.len() + 19999) / 20000);
+            assert!(decompressed == data);
+        }
+
+        #[test]
+        fn gzip_start_new_member() {
+            let data = get_test_data();
+            let (first, second) = data.split_at(50_000);
+            let mut compressor = GzEncoder::new(Vec::new(), Compression::Default);
+            // Nothing has been written yet, so this only replaces the header.
+            compressor
+                .start_new_member(GzBuilder::new().filename("first"))
+                .unwrap();
+            compressor.write_all(first).unwrap();
+            compressor
+                .start_new_member(GzBuilder::new().filename("second"))
+                .unwrap();
+            compressor.write_all(second).unwrap();
+            assert_eq!(compressor.checksum(), crate::crc32(second));
+            let compressed = compressor.finish().unwrap();
+
+            let mut cursor = Cursor::new(&compressed[..]);
+            let header = gzip_header::read_gz_header(&mut cursor).unwrap();
+            assert_eq!(header.filename().unwrap(), b"first");
+            let start = cursor.position() as usize;
+            let (member, consumed) = decompress_stream_prefix(&compressed[start..]);
+            assert!(member == first);
+            let mut cursor = Cursor::new(&compressed[start + consumed + 8..]);
+            let header = gzip_header::read_gz_header(&mut cursor).unwrap();
+            assert_eq!(header.filename().unwrap(), b"second");
+            assert!(crate::inflate_bytes_gzip(&compressed).unwrap() == data);
+        }
+
+        #[test]
+        fn gzip_access_points() {
+            use crate::boundary::IntervalPolicy;
+
+            let data = get_test_data();
+            let mut compressor = GzEncoder::new(Vec::new(), Compression::Default);
+            compressor.set_boundary_policy(IntervalPolicy::new(20_000));
+            compressor.set_record_access_points(true);
+            compressor.write_all(&data).unwrap();
+            let points = compressor.take_access_points();
+            let compressed = compressor.finish().unwrap();
+            assert_eq!(points.len(), (data.len() - 1) / 20_000);
+
+            for (n, point) in points.iter().enumerate() {
+                assert_eq!(point.input_offset, (n as u64 + 1) * 20_000);
+                assert_eq!(point.output_bit % 8, 0);
+                let start = (point.output_bit / 8) as usize;
+                let (rest, _) = decompress_stream_prefix(&compressed[start..]);
+                assert!(rest[..] == data[point.input_offset as usize..]);
+            }
+            assert!(crate::inflate_bytes_gzip(&compressed).unwrap() == data);
+        }
+
+        #[test]
+        fn gzip_format_encoder() {
+            let data = get_test_data();
+            let mut compressor = Encoder::new(Vec::new(), Format::Gzip, Compression::Default);
+            compressor.write_all(&data).unwrap();
+            let compressed = compressor.finish().unwrap();
+
+            let mut expected = GzEncoder::new(Vec::new(), Compression::Default);
+            expected.write_all(&data).unwrap();
+            assert!(compressed == expected.finish().unwrap());
+            let (_, res) = decompress_gzip(&compressed);
+            assert!(res == data);
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::chained_hash_table::WINDOW_SIZE;
+    use crate::compress::LARGEST_OUTPUT_BUF_SIZE;
     use crate::compression_options::CompressionOptions;
+    use crate::lzvalue::LZValue;
+    use crate::output_writer::{MAX_BUFFER_LENGTH, MIN_BLOCK_LENGTH};
     use crate::test_utils::{decompress_to_end, decompress_zlib, get_test_data};
     use std::io::Write;
 
@@ -568,6 +2276,81 @@ mod test {
         assert!(res1 == res2);
     }
 
+    #[test]
+    fn writer_reset_with_options() {
+        let data = get_test_data();
+        let mut compressor = ZlibEncoder::new(Vec::new(), CompressionOptions::high());
+        compressor.write_all(&data).unwrap();
+        let usage = compressor.memory_usage();
+        let res1 = compressor
+            .reset_with_options(Vec::new(), CompressionOptions::rle())
+            .unwrap();
+        compressor.write_all(&data).unwrap();
+        // The buffers are reused rather than reallocated.
+        assert_eq!(compressor.memory_usage().window, usage.window);
+        assert_eq!(compressor.memory_usage().hash_table, usage.hash_table);
+        let res2 = compressor.finish().unwrap();
+
+        assert!(res1 == crate::deflate_bytes_zlib_conf(&data, CompressionOptions::high()));
+        assert!(res2 == crate::deflate_bytes_zlib_conf(&data, CompressionOptions::rle()));
+        assert!(decompress_zlib(&res2) == data);
+    }
+
+    #[test]
+    fn writer_set_options() {
+        let data = get_test_data();
+        let options = [
+            CompressionOptions::fast(),
+            CompressionOptions::high(),
+            CompressionOptions::optimal(),
+            CompressionOptions::stored(),
+            CompressionOptions::rle(),
+            CompressionOptions::default(),
+        ];
+        let mut compressor = ZlibEncoder::new(Vec::new(), CompressionOptions::default());
+        for (chunk, &options) in data.chunks(data.len() / 5).zip(options.iter().cycle()) {
+            compressor.write_all(chunk).unwrap();
+            compressor.set_options(options).unwrap();
+        }
+        let window = CompressionOptions {
+            window_bits: 10,
+            ..CompressionOptions::default()
+        };
+        assert!(compressor.set_options(window).is_err());
+        let compressed = compressor.finish().unwrap();
+        assert!(decompress_zlib(&compressed) == data);
+
+        // Earlier data can still be referenced after lowering the level.
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::high());
+        compressor.write_all(&data[..20_000]).unwrap();
+        compressor
+            .set_options(CompressionOptions::default())
+            .unwrap();
+        compressor.write_all(&data[..20_000]).unwrap();
+        let compressed = compressor.finish().unwrap();
+        let single = crate::deflate_bytes_conf(&data[..20_000], CompressionOptions::high());
+        assert!(compressed.len() < single.len() + 200);
+        assert!(decompress_to_end(&compressed) == [&data[..20_000], &data[..20_000]].concat());
+    }
+
+    #[test]
+    fn encoder_errors() {
+        match Encoder::try_new(Vec::new(), Format::Custom, CompressionOptions::default()) {
+            Err(Error::InvalidOptions(_)) => (),
+            _ => panic!("A custom format without a framing should be rejected"),
+        }
+
+        let mut encoder = Encoder::new(Vec::new(), Format::Raw, CompressionOptions::default());
+        encoder.write_all(b"data").unwrap();
+        encoder.try_finish().unwrap();
+        let err = encoder.write(b"more").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        match Error::from(err) {
+            Error::InvalidState(_) => (),
+            other => panic!("Unexpected error: {}", other),
+        }
+    }
+
     #[test]
     fn writer_sync() {
         let data = get_test_data();
@@ -595,6 +2378,476 @@ mod test {
         assert!(decompressed == data);
     }
 
+    #[test]
+    /// Check that encoders can be used through mutable references by APIs that take a writer by
+    /// value.
+    fn writer_mut_ref() {
+        fn write_data<T: Write>(mut writer: T, data: &[u8]) {
+            writer.write_all(data).unwrap();
+            write!(writer, "{}", 1234).unwrap();
+        }
+
+        let data = get_test_data();
+        let mut expected = data.clone();
+        expected.extend_from_slice(b"1234");
+
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        write_data(&mut compressor, &data);
+        let compressed = compressor.finish().unwrap();
+        assert!(decompress_to_end(&compressed) == expected);
+
+        let mut compressor = ZlibEncoder::new(Vec::new(), CompressionOptions::default());
+        write_data(&mut compressor, &data);
+        let compressed = compressor.finish().unwrap();
+        assert!(decompress_zlib(&compressed) == expected);
+    }
+
+    #[test]
+    /// Check that the runtime format selection gives the same output as the specific encoders.
+    fn format_encoder() {
+        let data = get_test_data();
+        let options = CompressionOptions::default();
+
+        let mut raw = Encoder::new(Vec::new(), Format::Raw, options);
+        raw.write_all(&data).unwrap();
+        let raw = raw.finish().unwrap();
+        let mut expected = DeflateEncoder::new(Vec::new(), options);
+        expected.write_all(&data).unwrap();
+        assert!(raw == expected.finish().unwrap());
+
+        let mut zlib = Encoder::new(Vec::new(), Format::Zlib, options);
+        assert_eq!(zlib.format(), Format::Zlib);
+        zlib.write_all(&data).unwrap();
+        let first = zlib.reset(Vec::new()).unwrap();
+        zlib.write_all(&data).unwrap();
+        let second = zlib.finish().unwrap();
+        let mut expected = ZlibEncoder::new(Vec::new(), options);
+        expected.write_all(&data).unwrap();
+        let expected = expected.finish().unwrap();
+        assert!(first == expected);
+        assert!(second == expected);
+        assert!(decompress_zlib(&first) == data);
+    }
+
+    #[test]
+    fn writer_memory_usage() {
+        let data = get_test_data();
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        let usage = compressor.memory_usage();
+        assert_eq!(usage.hash_table, 2 * 4 * WINDOW_SIZE);
+        assert!(usage.window >= 2 * WINDOW_SIZE);
+        // The symbol buffer has room for the trailing symbols that can be added to the last block.
+        assert_eq!(
+            usage.symbol_buffer,
+            (MAX_BUFFER_LENGTH + MIN_BLOCK_LENGTH) * mem::size_of::<LZValue>()
+        );
+        compressor.write_all(&data).unwrap();
+        let written = compressor.memory_usage();
+        // The compressed data is kept in the output buffer until it's written out.
+        assert!(written.output_buffer > usage.output_buffer);
+        assert_eq!(written.hash_table, usage.hash_table);
+        assert_eq!(written.symbol_buffer, usage.symbol_buffer);
+
+        let zlib = ZlibEncoder::new(Vec::new(), CompressionOptions::default());
+        assert_eq!(zlib.memory_usage(), usage);
+    }
+
+    #[test]
+    fn record_stats() {
+        let data = get_test_data();
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        assert!(compressor.stats().is_none());
+        compressor.set_record_stats(true);
+        compressor.write_all(&data).unwrap();
+        compressor.flush().unwrap();
+
+        let stats = compressor.stats().unwrap().clone();
+        assert_eq!(stats.input_bytes, data.len() as u64);
+        assert!(stats.output_bits <= compressor.bits_written());
+        assert!(stats.dynamic_blocks > 0);
+        assert_eq!(stats.stored_blocks, 0);
+        // All of the input is covered by the literals and matches.
+        let match_bytes: u64 = stats
+            .match_lengths
+            .iter()
+            .enumerate()
+            .map(|(length, &count)| length as u64 * count)
+            .sum();
+        assert_eq!(stats.literals + match_bytes, stats.input_bytes);
+        assert_eq!(stats.matches, stats.distance_codes.iter().sum::<u64>());
+
+        compressor.set_record_stats(false);
+        assert!(compressor.stats().is_none());
+        let compressed = compressor.finish().unwrap();
+        assert!(decompress_to_end(&compressed) == data);
+    }
+
+    #[test]
+    fn streaming_output() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        // Finished blocks are passed on to the inner writer as they are written, so the memory
+        // used doesn't grow with the size of the input.
+        struct Counter(Rc<Cell<usize>>);
+        impl Write for Counter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.set(self.0.get() + buf.len());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let data = get_test_data();
+        let written = Rc::new(Cell::new(0));
+        let mut compressor =
+            DeflateEncoder::new(Counter(written.clone()), CompressionOptions::default());
+        compressor.write_all(&data).unwrap();
+        let usage = compressor.memory_usage();
+        let output = written.get();
+        assert!(output > 0);
+        for _ in 0..8 {
+            compressor.write_all(&data).unwrap();
+            let current = compressor.memory_usage();
+            assert_eq!(current.window, usage.window);
+            assert_eq!(current.symbol_buffer, usage.symbol_buffer);
+            assert_eq!(current.output_buffer, usage.output_buffer);
+        }
+        // Most of the output should have been written before finishing.
+        assert!(written.get() > output * 8);
+        let before_finish = written.get();
+        compressor.finish().unwrap();
+        assert!(written.get() - before_finish < LARGEST_OUTPUT_BUF_SIZE * 2);
+    }
+
+    #[test]
+    fn incremental_input() {
+        // Only the new input is passed to the compressor, so writing a stream much larger than
+        // the window in small pieces should neither grow the window nor change the output.
+        let data = get_test_data();
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        let window = compressor.memory_usage().window;
+        for _ in 0..4 {
+            for chunk in data.chunks(7) {
+                compressor.write_all(chunk).unwrap();
+                assert_eq!(compressor.memory_usage().window, window);
+            }
+        }
+        let compressed = compressor.finish().unwrap();
+
+        let input = [&data[..], &data, &data, &data].concat();
+        assert!(compressed == crate::deflate_bytes(&input));
+        assert!(decompress_to_end(&compressed) == input);
+    }
+
+    #[test]
+    fn zip_writer() {
+        let data = get_test_data();
+        let mut compressor = ZipEncoder::new(Vec::new(), CompressionOptions::high());
+        compressor.write_all(&data[..20000]).unwrap();
+        compressor.flush().unwrap();
+        compressor.write_all(&data[20000..]).unwrap();
+        assert_eq!(compressor.uncompressed_size(), data.len() as u64);
+        let (compressed, info) = compressor.finish().unwrap();
+        assert_eq!(info.crc32, crate::checksum::crc32(&data));
+        assert_eq!(info.compressed_size, compressed.len() as u64);
+        assert_eq!(info.uncompressed_size, data.len() as u64);
+        assert!(decompress_to_end(&compressed) == data);
+
+        let mut compressor = ZipEncoder::new(Vec::new(), CompressionOptions::stored());
+        compressor.write_all(b"first").unwrap();
+        let (first, first_info) = compressor.reset(Vec::new()).unwrap();
+        let (second, second_info) = compressor.finish().unwrap();
+        assert_eq!(decompress_to_end(&first), b"first");
+        assert_eq!(first_info.compressed_size, first.len() as u64);
+        assert_eq!(first_info.crc32, crate::checksum::crc32(b"first"));
+        assert_eq!(second_info.uncompressed_size, 0);
+        assert_eq!(second_info.crc32, 0);
+        assert_eq!(second_info.compressed_size, second.len() as u64);
+    }
+
+    #[test]
+    fn writer_prime() {
+        let data = get_test_data();
+        // An empty non-final block with fixed codes: the block header followed by the 7-bit end
+        // of block code, which leaves the stream 10 bits in.
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        compressor.prime(0, 0xFFFF).unwrap();
+        compressor.prime(3, 0b010).unwrap();
+        compressor.prime(7, 0).unwrap();
+        assert!(compressor.prime(17, 0).is_err());
+        assert_eq!(compressor.bits_written(), 10);
+        compressor.write_all(&data).unwrap();
+        assert!(compressor.prime(1, 0).is_err());
+        let compressed = compressor.finish().unwrap();
+        assert!(decompress_to_end(&compressed) == data);
+
+        let mut compressor = Encoder::new(Vec::new(), Format::Zlib, CompressionOptions::default());
+        compressor.prime(10, 0b010).unwrap();
+        compressor.write_all(&data).unwrap();
+        let compressed = compressor.finish().unwrap();
+        assert!(decompress_zlib(&compressed) == data);
+    }
+
+    #[test]
+    fn writer_finish_non_final() {
+        let data = get_test_data();
+        let mut stream = Vec::new();
+        for (n, part) in data.chunks(40000).enumerate() {
+            let options = if n % 2 == 0 {
+                CompressionOptions::default()
+            } else {
+                CompressionOptions::stored()
+            };
+            let mut compressor = DeflateEncoder::new(Vec::new(), options);
+            compressor.write_all(part).unwrap();
+            let fragment = compressor.finish_non_final().unwrap();
+            // The stream is left open and the output ends with the empty stored block.
+            assert_eq!(fragment[0] & 1, 0);
+            assert!(fragment.ends_with(&[0x00, 0x00, 0xFF, 0xFF]));
+            stream.extend_from_slice(&fragment);
+        }
+        stream.extend_from_slice(&[0x03, 0x00]);
+        assert!(decompress_to_end(&stream) == data);
+    }
+
+    #[test]
+    fn writer_bits_written() {
+        let data = get_test_data();
+        let mut compressor = ZlibEncoder::new(Vec::new(), CompressionOptions::default());
+        assert_eq!(compressor.bits_written(), 0);
+        compressor.write_all(&data).unwrap();
+        compressor.flush().unwrap();
+        // After a sync flush the output is byte aligned and all of it has been output.
+        let bits = compressor.bits_written();
+        assert_eq!(bits % 8, 0);
+        assert_eq!(
+            bits / 8,
+            compressor.deflate_state.inner.as_ref().unwrap().len() as u64
+        );
+        compressor.write_all(&data[..1000]).unwrap();
+        assert!(compressor.bits_written() >= bits);
+        let compressed = compressor.finish().unwrap();
+        assert!(
+            decompress_zlib(&compressed)
+                == data[..]
+                    .iter()
+                    .chain(&data[..1000])
+                    .cloned()
+                    .collect::<Vec<u8>>()
+        );
+
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        compressor.write_all(&data).unwrap();
+        compressor.output_all().unwrap();
+        let bits = compressor.bits_written();
+        let compressed = compressor.finish().unwrap();
+        assert_eq!(bits, compressed.len() as u64 * 8);
+    }
+
+    /// A policy inserting a boundary of the given kind every `interval` bytes.
+    struct IntervalPolicy {
+        interval: u64,
+        kind: Boundary,
+    }
+
+    impl BoundaryPolicy for IntervalPolicy {
+        fn next_boundary(
+            &mut self,
+            data: &[u8],
+            context: &BoundaryContext,
+        ) -> Option<(usize, Boundary)> {
+            let left = (self.interval - context.bytes_since_boundary) as usize;
+            if left <= data.len() {
+                Some((left, self.kind))
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn writer_boundary_policy() {
+        use crate::boundary::RsyncablePolicy;
+
+        let data = get_test_data();
+        for &kind in &[Boundary::EndBlock, Boundary::FullFlush, Boundary::NewMember] {
+            let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+            compressor.set_boundary_policy(IntervalPolicy {
+                interval: 10000,
+                kind,
+            });
+            compressor.write_all(&data).unwrap();
+            let compressed = compressor.finish().unwrap();
+            assert!(decompress_to_end(&compressed) == data);
+
+            let mut compressor = ZlibEncoder::new(Vec::new(), CompressionOptions::fast());
+            compressor.set_boundary_policy(IntervalPolicy {
+                interval: 777,
+                kind,
+            });
+            compressor.write_all(&data).unwrap();
+            let compressed = compressor.finish().unwrap();
+            assert!(decompress_zlib(&compressed) == data);
+        }
+
+        let mut compressor = Encoder::new(Vec::new(), Format::Zlib, CompressionOptions::default());
+        compressor.set_boundary_policy(RsyncablePolicy::new());
+        compressor.write_all(&data).unwrap();
+        let compressed = compressor.finish().unwrap();
+        assert!(decompress_zlib(&compressed) == data);
+    }
+
+    #[test]
+    fn writer_full_flush_independent() {
+        // After a full flush, the rest of the stream should be decompressable on its own.
+        let data = get_test_data();
+        let split = data.len() / 2;
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        compressor.set_boundary_policy(IntervalPolicy {
+            interval: split as u64,
+            kind: Boundary::FullFlush,
+        });
+        compressor.write_all(&data[..split]).unwrap();
+        let flush_point = compressor.deflate_state.inner.as_ref().unwrap().len();
+        compressor.write_all(&data[split..]).unwrap();
+        let compressed = compressor.finish().unwrap();
+        assert!(decompress_to_end(&compressed[flush_point..]) == data[split..]);
+    }
+
+    #[test]
+    fn full_flush_method() {
+        let data = get_test_data();
+        let split = 50_000;
+        let mut compressor = ZlibEncoder::new(Vec::new(), CompressionOptions::default());
+        compressor.write_all(&data[..split]).unwrap();
+        compressor.full_flush().unwrap();
+        let flush_point = compressor.deflate_state.inner.as_ref().unwrap().len();
+        assert_eq!(
+            compressor.deflate_state.inner.as_ref().unwrap()[flush_point - 4..],
+            [0, 0, 255, 255]
+        );
+        compressor.write_all(&data[split..]).unwrap();
+        let compressed = compressor.finish().unwrap();
+        assert!(decompress_zlib(&compressed) == data);
+        // Skip the adler32 trailer.
+        let rest = &compressed[flush_point..compressed.len() - 4];
+        assert!(decompress_to_end(rest) == data[split..]);
+    }
+
+    #[test]
+    /// The whole bytes of each block are output, and the returned number of pending bits gives
+    /// where the block ends.
+    fn block_flush_method() {
+        let data = get_test_data();
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        compressor.set_record_block_codes(true);
+        let mut written = 0;
+        for &end in &[10_000, 10_001, 50_000] {
+            compressor.write_all(&data[written..end]).unwrap();
+            let pending = compressor.block_flush().unwrap();
+            written = end;
+
+            assert!(pending < 8);
+            assert_eq!(compressor.pending(), (0, pending));
+            let codes = compressor.take_block_codes();
+            let last = codes.last().unwrap();
+            let output_len = compressor.deflate_state.inner.as_ref().unwrap().len() as u64;
+            assert_eq!(
+                output_len * 8 + u64::from(pending),
+                last.output_bit + last.output_bits
+            );
+        }
+        compressor.write_all(&data[written..]).unwrap();
+        let compressed = compressor.finish().unwrap();
+        assert!(decompress_to_end(&compressed) == data);
+    }
+
+    #[test]
+    fn access_points() {
+        use crate::boundary::IntervalPolicy;
+
+        let data = get_test_data();
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        compressor.set_boundary_policy(IntervalPolicy::new(30_000));
+        compressor.set_record_access_points(true);
+        compressor.write_all(&data[..100_000]).unwrap();
+        compressor.full_flush().unwrap();
+        compressor.write_all(&data[100_000..]).unwrap();
+        let points = compressor.take_access_points();
+        let compressed = compressor.finish().unwrap();
+
+        let offsets: Vec<_> = points.iter().map(|p| p.input_offset).collect();
+        assert_eq!(offsets[..4], [30_000, 60_000, 90_000, 100_000]);
+        // The policy counts from its own last boundary.
+        assert_eq!(offsets[4], 120_000);
+        for point in &points {
+            let start = (point.output_bit / 8) as usize;
+            let rest = decompress_to_end(&compressed[start..]);
+            assert!(rest[..] == data[point.input_offset as usize..]);
+        }
+    }
+
+    #[test]
+    fn totals() {
+        let data = get_test_data();
+        let mut compressor = ZlibEncoder::new(Vec::new(), CompressionOptions::default());
+        assert_eq!((compressor.total_in(), compressor.total_out()), (0, 0));
+        compressor.write_all(&data[..50_000]).unwrap();
+        compressor.flush().unwrap();
+        assert_eq!(compressor.total_in(), 50_000);
+        let output_len = compressor.deflate_state.inner.as_ref().unwrap().len();
+        assert_eq!(compressor.total_out(), output_len as u64);
+
+        // The totals carry on into the next stream.
+        let first = compressor.reset(Vec::new()).unwrap();
+        assert_eq!(compressor.total_out(), first.len() as u64);
+        compressor.write_all(&data[50_000..]).unwrap();
+        let total_in = compressor.total_in();
+        let second = compressor.finish().unwrap();
+        assert_eq!(total_in, data.len() as u64);
+        assert!(decompress_zlib(&second) == data[50_000..]);
+    }
+
+    #[test]
+    fn partial_flush_method() {
+        use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+        use miniz_oxide::inflate::TINFLStatus;
+        use std::io::Cursor;
+
+        let data = get_test_data();
+        let mut compressor = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        let mut sync = DeflateEncoder::new(Vec::new(), CompressionOptions::default());
+        let mut written = 0;
+        for &end in &[10_000, 10_001, 50_000] {
+            compressor.write_all(&data[written..end]).unwrap();
+            compressor.partial_flush().unwrap();
+            sync.write_all(&data[written..end]).unwrap();
+            sync.flush().unwrap();
+            written = end;
+
+            // All the data written so far can be decompressed from the output so far.
+            let output = compressor.deflate_state.inner.as_ref().unwrap();
+            let mut decompressor = DecompressorOxide::new();
+            let mut decompressed = vec![0; end * 2];
+            let (status, _, out) = decompress(
+                &mut decompressor,
+                output,
+                &mut Cursor::new(&mut decompressed[..]),
+                inflate_flags::TINFL_FLAG_HAS_MORE_INPUT
+                    | inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+            );
+            assert_eq!(status, TINFLStatus::NeedsMoreInput);
+            assert!(decompressed[..out] == data[..end]);
+            assert!(output.len() < sync.deflate_state.inner.as_ref().unwrap().len());
+        }
+        compressor.write_all(&data[written..]).unwrap();
+        let compressed = compressor.finish().unwrap();
+        assert!(decompress_to_end(&compressed) == data);
+    }
+
     #[test]
     /// Make sure compression works with the writer when the input is between 1 and 2 window sizes.
     fn issue_18() {
@@ -609,6 +2862,34 @@ mod test {
         assert!(decompressed == data);
     }
 
+    #[test]
+    fn preset_dictionary() {
+        use crate::test_utils::decompress_with_dictionary;
+        let data = get_test_data();
+        let dictionary = &data[..40_000];
+        let message = &data[20_000..21_000];
+
+        for &options in &[
+            CompressionOptions::default(),
+            CompressionOptions::fast(),
+            CompressionOptions::rle(),
+        ] {
+            let mut encoder = DeflateEncoder::new(Vec::new(), options);
+            encoder.set_dictionary(dictionary).unwrap();
+            // Only one dictionary can be set.
+            assert!(encoder.set_dictionary(dictionary).is_err());
+            encoder.write_all(message).unwrap();
+            assert!(encoder.set_dictionary(dictionary).is_err());
+            let compressed = encoder.finish().unwrap();
+            // Only the last window of the dictionary is used.
+            let window = &dictionary[dictionary.len() - WINDOW_SIZE..];
+            assert!(decompress_with_dictionary(window, &compressed) == message);
+            if options.max_hash_checks > 0 {
+                assert!(compressed.len() < crate::deflate_bytes_conf(message, options).len() / 2);
+            }
+        }
+    }
+
     #[test]
     fn writer_sync_multiple() {
         use std::cmp;
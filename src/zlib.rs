@@ -2,8 +2,8 @@
 //! header.
 //!
 //! The Zlib header contains some metadata (a window size and a compression level), and optionally
-//! a block of data serving as an extra dictionary for the compressor/decompressor.
-//! The dictionary is not implemented in this library.
+//! the Adler-32 checksum of a preset dictionary used by the compressor, which the decompressor
+//! has to be given as well.
 //! The data in the header aside from the dictionary doesn't actually have any effect on the
 //! decompressed data, it only offers some hints for the decompressor on how the data was
 //! compressed.
@@ -19,6 +19,8 @@ const DEFAULT_CMF: u8 = DEFAULT_CM | DEFAULT_CINFO;
 // No dict by default.
 #[cfg(test)]
 const DEFAULT_FDICT: u8 = 0;
+// FDICT = 1 means the header is followed by the Adler-32 checksum of a preset dictionary.
+const FDICT: u8 = 1 << 5;
 // FLEVEL = 0 means fastest compression algorithm.
 const _DEFAULT_FLEVEL: u8 = 0 << 7;
 
@@ -49,9 +51,13 @@ fn add_fcheck(cmf: u8, flg: u8) -> u8 {
 }
 
 /// Write a zlib header with an empty dictionary to the writer using the specified
-/// compression level preset.
-pub fn write_zlib_header<W: Write>(writer: &mut W, level: CompressionLevel) -> Result<()> {
-    writer.write_all(&get_zlib_header(level))
+/// compression level preset and a window size of `2^window_bits` bytes.
+pub fn write_zlib_header<W: Write>(
+    writer: &mut W,
+    level: CompressionLevel,
+    window_bits: u8,
+) -> Result<()> {
+    writer.write_all(&get_zlib_header_with_window(level, window_bits))
 }
 
 /// Get the zlib header for the `CompressionLevel` level using the default window size and no
@@ -61,6 +67,30 @@ pub fn get_zlib_header(level: CompressionLevel) -> [u8; 2] {
     [cmf, add_fcheck(cmf, level as u8)]
 }
 
+/// Get the zlib header for the `CompressionLevel` level and a window size of
+/// `2^window_bits` bytes, with no dictionary.
+pub fn get_zlib_header_with_window(level: CompressionLevel, window_bits: u8) -> [u8; 2] {
+    debug_assert!((9..=15).contains(&window_bits));
+    // CINFO is the base-2 logarithm of the window size minus 8.
+    let cmf = DEFAULT_CM | (window_bits - 8) << 4;
+    [cmf, add_fcheck(cmf, level as u8)]
+}
+
+/// Get the zlib header for the `CompressionLevel` level and a window size of
+/// `2^window_bits` bytes, with the FDICT flag set and followed by `dictionary_id`, the Adler-32
+/// checksum of the preset dictionary.
+pub fn get_zlib_header_with_dictionary(
+    level: CompressionLevel,
+    window_bits: u8,
+    dictionary_id: u32,
+) -> [u8; 6] {
+    debug_assert!((9..=15).contains(&window_bits));
+    let cmf = DEFAULT_CM | (window_bits - 8) << 4;
+    let flg = add_fcheck(cmf, level as u8 | FDICT);
+    let id = dictionary_id.to_be_bytes();
+    [cmf, flg, id[0], id[1], id[2], id[3]]
+}
+
 #[cfg(test)]
 mod test {
     use super::DEFAULT_CMF;
@@ -76,6 +106,20 @@ mod test {
         assert_eq!(((usize::from(cmf) * 256) + usize::from(flg)) % 31, 0);
     }
 
+    #[test]
+    fn test_header_window() {
+        assert_eq!(
+            get_zlib_header_with_window(CompressionLevel::Default, 15),
+            get_zlib_header(CompressionLevel::Default)
+        );
+        let header = get_zlib_header_with_window(CompressionLevel::Default, 10);
+        assert_eq!(header[0], 0x28);
+        assert_eq!(
+            ((usize::from(header[0]) * 256) + usize::from(header[1])) % 31,
+            0
+        );
+    }
+
     #[test]
     fn test_header() {
         let header = get_zlib_header(CompressionLevel::Fastest);
@@ -84,4 +128,16 @@ mod test {
             0
         );
     }
+
+    #[test]
+    fn test_header_dictionary() {
+        let header = get_zlib_header_with_dictionary(CompressionLevel::Default, 15, 0x0102_0304);
+        assert_eq!(header[0], DEFAULT_CMF);
+        assert_eq!(header[1] & FDICT, FDICT);
+        assert_eq!(
+            ((usize::from(header[0]) * 256) + usize::from(header[1])) % 31,
+            0
+        );
+        assert_eq!(header[2..], [1, 2, 3, 4]);
+    }
 }
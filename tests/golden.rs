@@ -0,0 +1,86 @@
+//! Golden output tests for the deterministic output guarantee.
+//!
+//! The compressed output of a fixed input with each of the option presets is compared with
+//! fixtures checked into `tests/golden`. A change to the output of any of them is a breaking
+//! change, only allowed in a new minor version. To regenerate the fixtures after such a change,
+//! run the tests with `DEFLATE_UPDATE_GOLDEN=1` set and commit the new files together with the
+//! minor version bump in `Cargo.toml` and an entry in `CHANGELOG.md` describing the change.
+extern crate deflate;
+#[cfg(feature = "gzip")]
+extern crate gzip_header;
+extern crate miniz_oxide;
+
+use std::env;
+use std::fs;
+
+use deflate::CompressionOptions;
+
+/// Text, pseudo-random bytes and a long run, to exercise all the block types.
+fn golden_input() -> Vec<u8> {
+    let text = fs::read("tests/pg11.txt").unwrap();
+    let mut input = text[..20_000].to_vec();
+    let mut state = 12_345u32;
+    for _ in 0..4000 {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        input.push((state >> 16) as u8);
+    }
+    input.extend(std::iter::repeat(b'a').take(3000));
+    input.extend_from_slice(&text[5000..9000]);
+    input
+}
+
+fn check_golden(name: &str, compressed: &[u8]) {
+    let path = format!("tests/golden/{}.bin", name);
+    if env::var_os("DEFLATE_UPDATE_GOLDEN").is_some() {
+        fs::write(&path, compressed).unwrap();
+        return;
+    }
+    let expected = fs::read(&path).unwrap();
+    assert!(
+        compressed == &expected[..],
+        "The output for {} differs from {}",
+        name,
+        path
+    );
+}
+
+#[test]
+fn golden_raw() {
+    let input = golden_input();
+    let presets = [
+        ("default", CompressionOptions::default()),
+        ("fast", CompressionOptions::fast()),
+        ("fastest", CompressionOptions::fastest()),
+        ("high", CompressionOptions::high()),
+        ("huffman_only", CompressionOptions::huffman_only()),
+        ("rle", CompressionOptions::rle()),
+        ("optimal", CompressionOptions::optimal()),
+        ("maximum", CompressionOptions::maximum()),
+        ("filtered", CompressionOptions::filtered()),
+        ("stored", CompressionOptions::stored()),
+    ];
+    for &(name, options) in &presets {
+        let compressed = deflate::deflate_bytes_conf(&input, options);
+        assert!(miniz_oxide::inflate::decompress_to_vec(&compressed).unwrap() == input);
+        check_golden(name, &compressed);
+    }
+}
+
+#[test]
+fn golden_zlib() {
+    let input = golden_input();
+    check_golden("zlib", &deflate::deflate_bytes_zlib(&input));
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn golden_gzip() {
+    let input = golden_input();
+    // The OS field defaults to the platform, so it has to be set for the output to be the same
+    // everywhere.
+    let header = gzip_header::GzBuilder::new().os(gzip_header::FileSystemType::Unix);
+    check_golden(
+        "gzip",
+        &deflate::deflate_bytes_gzip_conf(&input, CompressionOptions::default(), header),
+    );
+}